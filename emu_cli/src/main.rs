@@ -1,71 +1,120 @@
 use emu_lib::memory::MemoryDevice;
+use std::io::IsTerminal;
 use std::path::PathBuf;
-use emu_lib::cpu::Cpu;
 
-use emu_lib::cpu::{
-    registers::{AllRegisters, GPByteRegisters},
-};
 use emu_lib::cpu::instruction::ExecutableInstruction;
 use emu_lib::cpu::z80::Z80;
+use emu_lib::debug::script::ScriptEngine;
+use emu_lib::debug::source::LineTable;
 use emu_lib::emulator::Emulator;
 use emu_lib::io::IO;
 use emu_lib::io::iodevice::IORegister;
 use emu_lib::memory::{memdevices::RAM, Memory};
 
 mod memdsp;
+mod monitor;
 use memdsp::MemViz;
+use monitor::{describe_stop, Monitor};
 
-fn print_registers(registers: &AllRegisters) {
-    println!("PC: {:04X}, SP: {:04X}", registers.pc, registers.sp);
-    fn print_gp(gp: &GPByteRegisters, suffix: &str) {
-        println!(
-            "AF{suffix}: {:04X}, BC{suffix}: {:04X}, DE{suffix}: {:04X}, HL{suffix}: {:04X}",
-            gp.af, gp.bc, gp.de, gp.hl
-        );
-    }
-    for (i, gp_regs) in registers.gp.iter().enumerate() {
-        print_gp(gp_regs, &String::from("'").repeat(i));
-    }
-    for (key, value) in &registers.other8bit {
-        print!("{}: {}, ", key.to_uppercase(), value);
-    }
-    for (key, value) in &registers.other16bit {
-        print!("{}: {}, ", key.to_uppercase(), value);
+/// T-states between frame interrupts of a 48K Spectrum
+const FRAME_TSTATES: usize = 69_888;
+
+const USAGE: &str = "\
+Usage: emu_cli [--rom <file>] [--symbols <file>] [--source <file>] [--script <file>] [--monitor] [--headless]
+  --rom <file>      ROM loaded at 0, roms/zx48.rom by default
+  --symbols <file>  symbols for the monitor, z88dk map, sjasmplus sym or SLD, pasmo or NoICE
+  --source <file>   line table for source level debugging, sjasmplus SLD or a listing
+  --script <file>   Rhai script run on the emulator, then exits unless --monitor is given
+  --monitor         read debugger commands from stdin instead of running freely
+  --headless        no display, all memory is RAM";
+
+struct Options {
+    rom: PathBuf,
+    symbols: Vec<PathBuf>,
+    source: Option<PathBuf>,
+    script: Option<PathBuf>,
+    monitor: bool,
+    headless: bool,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut options = Options {
+            rom: PathBuf::from("roms/zx48.rom"),
+            symbols: Vec::new(),
+            source: None,
+            script: None,
+            monitor: false,
+            headless: false,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--rom" => options.rom = args.next().ok_or("--rom needs a file")?.into(),
+                "--symbols" => options
+                    .symbols
+                    .push(args.next().ok_or("--symbols needs a file")?.into()),
+                "--source" => {
+                    options.source = Some(args.next().ok_or("--source needs a file")?.into())
+                }
+                "--script" => {
+                    options.script = Some(args.next().ok_or("--script needs a file")?.into())
+                }
+                "--monitor" => options.monitor = true,
+                "--headless" => options.headless = true,
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+        Ok(options)
     }
-    println!();
 }
 
 fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
     let refresh_rate = 50.08;
-    let dsp = MemViz::new(2.0, refresh_rate);
-    let bitmap_mem = dsp.bmp_buffer();
-    let bitmap_len = bitmap_mem.size();
-    let attribute_mem = dsp.attribute_buffer();
-    let attribute_len = attribute_mem.size();
-    let border_io = dsp.border_io();
-    let timer_io = dsp.timer_io();
-    // dsp.randomize();
-    // thread::sleep(Duration::from_secs(2));
-    println!("Creating emulator");
+    if !options.monitor && options.script.is_none() {
+        println!("Creating emulator");
+    }
     let mut memory = Memory::new();
-    memory.add_device(Box::new(RAM::new(0x4000)));
-    memory.add_device(bitmap_mem);
-    memory.add_device(attribute_mem);
-    memory.add_device(Box::new(RAM::new(0x10000-bitmap_len-attribute_len-0x4000)));
-    let mut emulator: Emulator<Z80> = Emulator::new_w_mem(memory);
     let mut io = IO::new();
     //vec with all 0..FF except 0xFE
     let mut other_io = vec![];
     for v in 0..0x100 {
-        if v!=0xFE {
+        if v!=0xFE || options.headless {
             other_io.push(v as u8);
         }
     }
     io.add_device(Box::new(IORegister::new(other_io))).unwrap();
-    io.add_device(timer_io).expect("Failed to add device");
-    io.add_device(border_io).expect("Failed to add device");
+    let dsp = (!options.headless).then(|| MemViz::new(2.0, refresh_rate));
+    match &dsp {
+        Some(dsp) => {
+            let bitmap_mem = dsp.bmp_buffer();
+            let bitmap_len = bitmap_mem.size();
+            let attribute_mem = dsp.attribute_buffer();
+            let attribute_len = attribute_mem.size();
+            memory.add_device(Box::new(RAM::new(0x4000)));
+            memory.add_device(bitmap_mem);
+            memory.add_device(attribute_mem);
+            memory.add_device(Box::new(RAM::new(0x10000-bitmap_len-attribute_len-0x4000)));
+            io.add_device(dsp.timer_io()).expect("Failed to add device");
+            io.add_device(dsp.border_io()).expect("Failed to add device");
+        }
+        None => memory.add_device(Box::new(RAM::new(0x10000))),
+    }
+    let mut emulator: Emulator<Z80> = Emulator::new_w_mem(memory);
     emulator.io = io;
-    let rom_path: PathBuf = PathBuf::from("roms/zx48.rom");
+    if let Some(dsp) = &dsp {
+        let timer = dsp.timer();
+        emulator
+            .scheduler
+            .schedule_periodic(FRAME_TSTATES, FRAME_TSTATES, move |_| timer.interrupt());
+    }
+    let rom_path = options.rom;
     // let z80_file = include_bytes!("../roms/f.z80");
     //a .z80 file, byte 5 and 6 of the file store the pc
     // let pc = u16::from_le_bytes([z80_file[5],z80_file[6]]);
@@ -81,6 +130,38 @@ fn main() {
             panic!("Error loading rom: {:?}", e);
         }
     };
+    for path in &options.symbols {
+        if let Err(e) = emulator.symbols.import(path) {
+            eprintln!("Error loading symbols from {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = &options.source {
+        match LineTable::load(path) {
+            Ok(lines) => emulator.lines = lines,
+            Err(e) => {
+                eprintln!("Error loading source lines from {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = &options.script {
+        if let Err(e) = ScriptEngine::new().run_file(&mut emulator, path) {
+            eprintln!("Error in script {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+        if !options.monitor {
+            return;
+        }
+    }
+    if options.monitor {
+        let stdin = std::io::stdin();
+        let interactive = stdin.is_terminal();
+        Monitor::new(&mut emulator, std::io::stdout())
+            .run(stdin.lock(), interactive)
+            .expect("Failed to run the monitor");
+        return;
+    }
     println!("Running emulator");
     // print_registers(emulator.cpu.registers());
     let freq = 3_500_000.0;
@@ -96,17 +177,5 @@ fn main() {
         freq as f64 / refresh_rate,
     );
     println!("Emulator stopped");
-    match stop_reason {
-        emu_lib::emulator::StopReason::Breakpoint => println!("Breakpoint"),
-        emu_lib::emulator::StopReason::Halt => println!("Halted"),
-        emu_lib::emulator::StopReason::Error(e) => {
-            let pc = emulator.cpu.registers().pc;
-            let instruction = emulator
-                .cpu
-                .parser()
-                .ins_from_machinecode(&emulator.memory, *pc)
-                .expect("Error decoding instruction");
-            println!("Error: {} while executing \"{}\"", e, instruction)
-        }
-    }
+    println!("{}", describe_stop(&emulator, &stop_reason));
 }
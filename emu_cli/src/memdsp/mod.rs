@@ -36,13 +36,11 @@ impl MemViz {
         let border_io = DisplayIO{val:Arc::new(Mutex::new(0))};
         let border_io_clone = border_io.clone();
         let timer_io = TimerIO{should_interrupt:Arc::new(Mutex::new(false))};
-        let timer_io_clone = timer_io.clone();
         let thread = Some(thread::spawn(move || {
             let mut fbdisplay = FBZXDisplay::new(
                 bitmap_buffer_clone,
                 attribute_buffer_clone,
                 border_io_clone,
-                timer_io_clone,
                 scale,
                 event_receiver,
                 refresh_rate
@@ -75,6 +73,11 @@ impl MemViz {
         Box::new(self.timer_io.clone())
     }
 
+    /// Handle to raise the frame interrupt of the device given by [`MemViz::timer_io`]
+    pub fn timer(&self) -> TimerIO {
+        self.timer_io.clone()
+    }
+
     pub fn randomize(&mut self) {
         let mut bmp_buff = self.bitmap_buffer.buffer.lock().expect("Could not lock buffer");
         for v in bmp_buff.iter_mut() {
@@ -164,10 +167,14 @@ impl IODevice for DisplayIO {
         Ok(())
     }
 
-    fn step(&mut self) {
+    fn step(&mut self, _elapsed: usize, _cycle: usize) {
 
     }
 
+    fn next_step(&self) -> Option<usize> {
+        None
+    }
+
     fn will_interrupt(&self) -> Option<InterruptType> {
         None
     }
@@ -175,6 +182,16 @@ impl IODevice for DisplayIO {
     fn ack_int(&mut self) -> Result<(), &'static str> {
         Ok(())
     }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![*self.val.lock().expect("Could not lock border")]
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        *self.val.lock().map_err(|_| "Could not acquire lock")? =
+            *state.first().ok_or("Missing border state")?;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -200,7 +217,11 @@ impl IODevice for TimerIO {
         Err("Cannot write timer")
     }
 
-    fn step(&mut self) {
+    fn step(&mut self, _elapsed: usize, _cycle: usize) {
+    }
+
+    fn next_step(&self) -> Option<usize> {
+        None
     }
 
     fn will_interrupt(&self) -> Option<InterruptType> {
@@ -215,4 +236,14 @@ impl IODevice for TimerIO {
         *self.should_interrupt.lock().map_err(|_| "Could not acquire lock")? = false;
         Ok(())
     }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![*self.should_interrupt.lock().expect("Could not lock interrupt") as u8]
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        *self.should_interrupt.lock().map_err(|_| "Could not acquire lock")? =
+            *state.first().ok_or("Missing timer state")? != 0;
+        Ok(())
+    }
 }
\ No newline at end of file
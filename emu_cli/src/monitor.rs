@@ -0,0 +1,541 @@
+use std::io::{BufRead, Write};
+
+use emu_lib::cpu::instruction::ExecutableInstruction;
+use emu_lib::cpu::registers::{AllRegisters, GPByteRegisters};
+use emu_lib::cpu::Cpu;
+use emu_lib::debug::breakpoint::Breakpoint;
+use emu_lib::debug::expression::{Expression, MachineContext};
+use emu_lib::emulator::{Emulator, StopReason};
+use emu_lib::memory::MemoryDevice;
+
+/// T-states run by `c` between checks for a stop
+const CHUNK_TICKS: f64 = 100_000.0;
+/// Source lines shown before and after the current one
+const SOURCE_CONTEXT: u32 = 3;
+
+const HELP: &str = "\
+r                     registers
+m <addr> [len]        memory dump, 0x40 bytes by default
+d [addr] [count]      disassemble, 10 instructions from PC by default
+b [addr] [condition]  add a breakpoint, list them without an address
+bd <id>               delete a breakpoint
+s [count]             step instructions
+n                     step over calls
+sl                    step to the next source line
+l                     show the source around PC
+c                     continue until something stops execution
+set <reg>=<value>     set a register
+load <file> [addr]    load a binary at addr, or a machine state without one
+save <file> [start end]
+                      save memory from start to end inclusive, or the machine state
+q                     quit
+Numbers are hexadecimal, registers, symbols, source lines like `main.asm:12` and debugger
+expressions like `hl+2` or `(sp)` work too. Names that are also numbers, like `bc` or a
+symbol `beef`, need `$bc` for the number or `%bc` for the name.
+";
+
+pub fn print_registers(out: &mut impl Write, registers: &AllRegisters) -> std::io::Result<()> {
+    writeln!(out, "PC: {:04X}, SP: {:04X}", registers.pc, registers.sp)?;
+    fn print_gp(out: &mut impl Write, gp: &GPByteRegisters, suffix: &str) -> std::io::Result<()> {
+        writeln!(
+            out,
+            "AF{suffix}: {:04X}, BC{suffix}: {:04X}, DE{suffix}: {:04X}, HL{suffix}: {:04X}",
+            gp.af, gp.bc, gp.de, gp.hl
+        )
+    }
+    for (i, gp_regs) in registers.gp.iter().enumerate() {
+        print_gp(out, gp_regs, &String::from("'").repeat(i))?;
+    }
+    let mut other: Vec<String> = registers
+        .other16bit
+        .iter()
+        .map(|(key, value)| format!("{}: {:04X}", key.to_uppercase(), value))
+        .chain(
+            registers
+                .other8bit
+                .iter()
+                .map(|(key, value)| format!("{}: {:02X}", key.to_uppercase(), value)),
+        )
+        .collect();
+    other.sort();
+    writeln!(out, "{}", other.join(", "))
+}
+
+/// Describes why execution stopped
+pub fn describe_stop<T: Cpu + 'static>(emulator: &Emulator<T>, reason: &StopReason) -> String {
+    match reason {
+        StopReason::Breakpoint(id) => format!("Breakpoint {}", id),
+        StopReason::Halt => "Halted".to_string(),
+        StopReason::Watchpoint(hit) => format!(
+            "Watchpoint {} at {:04X}: {:02X} -> {:02X} by instruction at {:04X}",
+            hit.id, hit.address, hit.old, hit.new, hit.pc
+        ),
+        StopReason::IoBreakpoint(hit) => format!(
+            "IO breakpoint {} on port {:02X}: {:?} {:02X} by instruction at {:04X}",
+            hit.id, hit.port, hit.direction, hit.value, hit.pc
+        ),
+        StopReason::CycleLimit => "Cycle limit reached".to_string(),
+        StopReason::InstructionLimit => "Instruction limit reached".to_string(),
+        StopReason::Condition => "Condition met".to_string(),
+        StopReason::Pause => "Paused".to_string(),
+        StopReason::IllegalOpcode { address, message } => {
+            format!("Illegal opcode at {:04X}: {}", address, message)
+        }
+        StopReason::Error(e) => {
+            let pc = emulator.cpu.pc();
+            match emulator
+                .cpu
+                .parser()
+                .ins_from_machinecode(&emulator.memory, pc)
+            {
+                Ok(instruction) => format!("Error: {} while executing \"{}\"", e, instruction),
+                Err(_) => format!("Error: {} at {:04X}", e, pc),
+            }
+        }
+    }
+}
+
+/// Line based debugger for an emulator, reads commands and writes the results to `out`.
+pub struct Monitor<'a, T: Cpu, W: Write> {
+    emulator: &'a mut Emulator<T>,
+    out: W,
+}
+
+impl<'a, T: Cpu + 'static, W: Write> Monitor<'a, T, W> {
+    pub fn new(emulator: &'a mut Emulator<T>, out: W) -> Monitor<'a, T, W> {
+        Monitor { emulator, out }
+    }
+
+    /// Runs commands from `input` until it ends or `q`, the prompt is for interactive use.
+    pub fn run(&mut self, input: impl BufRead, prompt: bool) -> std::io::Result<()> {
+        let mut lines = input.lines();
+        loop {
+            if prompt {
+                write!(self.out, "{:04X}> ", self.emulator.cpu.pc())?;
+                self.out.flush()?;
+            }
+            let Some(line) = lines.next() else {
+                return Ok(());
+            };
+            match self.execute(&line?) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => writeln!(self.out, "Error: {}", e)?,
+            }
+        }
+    }
+
+    /// Runs one command, returns true when the monitor should quit.
+    pub fn execute(&mut self, line: &str) -> Result<bool, String> {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args: Vec<&str> = rest.split_whitespace().collect();
+        match command {
+            "" => {}
+            "q" | "quit" => return Ok(true),
+            "h" | "help" | "?" => self.print(HELP)?,
+            "r" => {
+                let registers = self.emulator.cpu.registers();
+                print_registers(&mut self.out, &registers).map_err(|e| e.to_string())?;
+            }
+            "m" => {
+                let start = self.value(args.first().ok_or("Missing address")?)?;
+                let length = match args.get(1) {
+                    Some(length) => self.value(length)? as usize,
+                    None => 0x40,
+                };
+                self.dump(start, length)?;
+            }
+            "d" => {
+                let start = match args.first() {
+                    Some(start) => self.value(start)?,
+                    None => self.emulator.cpu.pc(),
+                };
+                let count = match args.get(1) {
+                    Some(count) => self.value(count)? as usize,
+                    None => 10,
+                };
+                self.disassemble(start, count)?;
+            }
+            "b" => match args.first() {
+                None => self.list_breakpoints()?,
+                Some(address) => {
+                    let address = self.value(address)?;
+                    let condition = args[1..].join(" ");
+                    let breakpoint = match condition.is_empty() {
+                        true => Breakpoint::new(address),
+                        false => Breakpoint::conditional(address, &condition)
+                            .map_err(|e| e.to_string())?,
+                    };
+                    let id = self.emulator.breakpoints.add(breakpoint);
+                    self.print(&format!("Breakpoint {} at {:04X}\n", id, address))?;
+                }
+            },
+            "bd" => {
+                let id = args
+                    .first()
+                    .and_then(|id| id.parse().ok())
+                    .ok_or("Missing breakpoint id")?;
+                self.emulator
+                    .breakpoints
+                    .remove(id)
+                    .ok_or(format!("No breakpoint {}", id))?;
+            }
+            "s" => {
+                let count = match args.first() {
+                    Some(count) => self.value(count)? as usize,
+                    None => 1,
+                };
+                for _ in 0..count {
+                    if let Err(e) = self.emulator.step() {
+                        return self.stopped(Some(StopReason::Error(e)));
+                    }
+                }
+                self.stopped(None)?;
+            }
+            "n" => {
+                let reason = self.emulator.step_over().err();
+                self.stopped(reason)?;
+            }
+            "sl" => {
+                let reason = self.emulator.step_line().err();
+                self.stopped(reason)?;
+            }
+            "l" => {
+                let pc = self.emulator.cpu.pc();
+                let source = self
+                    .emulator
+                    .lines
+                    .source_context(pc, SOURCE_CONTEXT)
+                    .ok_or(format!("No source for {:04X}", pc))?;
+                self.print(&source)?;
+            }
+            "c" => {
+                let reason = loop {
+                    let result = self.emulator.run_ticks(
+                        CHUNK_TICKS,
+                        &None::<fn(&mut Emulator<T>, &dyn ExecutableInstruction<T>)>,
+                    );
+                    if let Err(reason) = result {
+                        break reason;
+                    }
+                };
+                self.stopped(Some(reason))?;
+            }
+            "set" => {
+                let (name, value) = rest
+                    .split_once('=')
+                    .or_else(|| rest.trim().split_once(char::is_whitespace))
+                    .ok_or("Expected set <register>=<value>")?;
+                let (name, value) = (name.trim().to_lowercase(), self.value(value.trim())?);
+                if !self.emulator.cpu.registers_mut().set(&name, value) {
+                    return Err(format!("Unknown register {}", name));
+                }
+            }
+            "load" => {
+                let file = args.first().ok_or("Missing file name")?;
+                let data = std::fs::read(file).map_err(|e| format!("{}: {}", file, e))?;
+                match args.get(1) {
+                    Some(address) => {
+                        let address = self.value(address)?;
+                        for (offset, byte) in data.iter().enumerate() {
+                            self.emulator
+                                .memory
+                                .write_8_force(address.wrapping_add(offset as u16), *byte)
+                                .map_err(|e| format!("{:?}", e))?;
+                        }
+                        self.print(&format!("Loaded {} bytes at {:04X}\n", data.len(), address))?;
+                    }
+                    None => self.emulator.load(data, false, true)?,
+                }
+            }
+            "save" => {
+                let file = args.first().ok_or("Missing file name")?;
+                let data = match (args.get(1), args.get(2)) {
+                    (Some(start), Some(end)) => {
+                        let (start, end) = (self.value(start)?, self.value(end)?);
+                        (start..=end)
+                            .map(|address| self.emulator.memory.peek_8(address))
+                            .collect::<Result<Vec<u8>, _>>()
+                            .map_err(|e| format!("{:?}", e))?
+                    }
+                    (None, _) => self.emulator.save()?,
+                    (Some(_), None) => return Err("Expected save <file> <start> <end>".to_string()),
+                };
+                std::fs::write(file, data).map_err(|e| format!("{}: {}", file, e))?;
+            }
+            _ => return Err(format!("Unknown command {}, h for help", command)),
+        }
+        Ok(false)
+    }
+
+    fn print(&mut self, text: &str) -> Result<(), String> {
+        self.out
+            .write_all(text.as_bytes())
+            .map_err(|e| e.to_string())
+    }
+
+    /// A register name, a symbol, a source line, a hexadecimal number or a debugger
+    /// expression
+    fn value(&self, text: &str) -> Result<u16, String> {
+        if let Some(name) = text.strip_prefix('%') {
+            return self
+                .named(name)
+                .map(|(_, value)| value)
+                .ok_or_else(|| format!("Unknown register, source line or symbol {}", name));
+        }
+        if let Some(digits) = text.strip_prefix('$') {
+            return u16::from_str_radix(digits, 16).map_err(|e| e.to_string());
+        }
+        let number = u16::from_str_radix(text, 16).ok();
+        match (self.named(text), number) {
+            (Some((kind, _)), Some(_)) => {
+                return Err(format!(
+                    "{} is both a {} and a number, write %{} or ${}",
+                    text, kind, text, text
+                ))
+            }
+            (Some((_, value)), None) | (None, Some(value)) => return Ok(value),
+            (None, None) => {}
+        }
+        let context = MachineContext::new(&self.emulator.cpu, &self.emulator.memory)
+            .with_symbols(&self.emulator.symbols);
+        Expression::parse(text)
+            .and_then(|expression| expression.evaluate(&context))
+            .map(|value| value as u16)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Value of a register, source line or symbol called `name`, with what it is.
+    fn named(&self, name: &str) -> Option<(&'static str, u16)> {
+        if let Some(value) = self.emulator.cpu.registers().get(&name.to_lowercase()) {
+            return Some(("register", value));
+        }
+        if let Some(value) = self.emulator.lines.resolve(name) {
+            return Some(("source line", value));
+        }
+        self.emulator
+            .symbols
+            .address(name)
+            .map(|value| ("symbol", value))
+    }
+
+    fn dump(&mut self, start: u16, length: usize) -> Result<(), String> {
+        let mut text = String::new();
+        for line in (0..length).step_by(16) {
+            let address = start.wrapping_add(line as u16);
+            let bytes: Vec<Option<u8>> = (0..16.min(length - line))
+                .map(|offset| {
+                    let address = address.wrapping_add(offset as u16);
+                    self.emulator.memory.peek_8(address).ok()
+                })
+                .collect();
+            let hex: Vec<String> = bytes
+                .iter()
+                .map(|byte| byte.map_or("--".to_string(), |byte| format!("{:02X}", byte)))
+                .collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|byte| match byte {
+                    Some(byte @ 0x20..=0x7E) => *byte as char,
+                    _ => '.',
+                })
+                .collect();
+            text.push_str(&format!(
+                "{:04X}  {:<47}  {}\n",
+                address,
+                hex.join(" "),
+                ascii
+            ));
+        }
+        self.print(&text)
+    }
+
+    fn disassemble(&mut self, start: u16, count: usize) -> Result<(), String> {
+        let mut text = String::new();
+        let mut address = start;
+        for _ in 0..count {
+            let marker = match (
+                address == self.emulator.cpu.pc(),
+                self.emulator.breakpoints.contains(address),
+            ) {
+                (true, _) => '>',
+                (false, true) => '*',
+                (false, false) => ' ',
+            };
+            let parsed = self
+                .emulator
+                .cpu
+                .parser()
+                .ins_from_machinecode(&self.emulator.memory, address);
+            let (bytes, instruction) = match parsed {
+                Ok(instruction) => (
+                    instruction.to_bytes(),
+                    self.emulator
+                        .symbols
+                        .instruction_text(instruction.as_ref(), address),
+                ),
+                Err(_) => {
+                    let byte = self.emulator.memory.peek_8(address).unwrap_or(0);
+                    (vec![byte], format!("DB 0x{:02X}", byte))
+                }
+            };
+            if let Some(name) = self.emulator.symbols.name(address) {
+                text.push_str(&format!("{}:\n", name));
+            }
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            text.push_str(&format!(
+                "{}{:04X}  {:<12} {}\n",
+                marker,
+                address,
+                hex.join(" "),
+                instruction
+            ));
+            address = address.wrapping_add(bytes.len() as u16);
+        }
+        self.print(&text)
+    }
+
+    fn list_breakpoints(&mut self) -> Result<(), String> {
+        let mut text = String::new();
+        for (id, breakpoint) in self.emulator.breakpoints.iter() {
+            text.push_str(&format!("{}: {:04X}", id, breakpoint.address));
+            if let Some(condition) = &breakpoint.condition {
+                text.push_str(&format!(" if {}", condition));
+            }
+            if !breakpoint.enabled {
+                text.push_str(" (disabled)");
+            }
+            text.push_str(&format!(" hits {}\n", breakpoint.hit_count));
+        }
+        self.print(&text)
+    }
+
+    /// Reports why execution stopped and shows the source if there is a line table, and the
+    /// next instruction
+    fn stopped(&mut self, reason: Option<StopReason>) -> Result<bool, String> {
+        if let Some(reason) = reason {
+            let text = describe_stop(self.emulator, &reason);
+            self.print(&format!("{}\n", text))?;
+        }
+        let pc = self.emulator.cpu.pc();
+        if let Some(source) = self.emulator.lines.source_context(pc, SOURCE_CONTEXT) {
+            self.print(&source)?;
+        }
+        self.disassemble(self.emulator.cpu.pc(), 1)?;
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emu_lib::cpu::z80::Z80;
+    use emu_lib::memory::Memory;
+
+    fn run_script(program: &[u8], script: &str) -> (Emulator<Z80>, String) {
+        let mut emulator: Emulator<Z80> = Emulator::new_w_mem(Memory::new_full_ram());
+        for (address, byte) in program.iter().enumerate() {
+            emulator.memory.write_8(address as u16, *byte).unwrap();
+        }
+        let mut out = Vec::new();
+        Monitor::new(&mut emulator, &mut out)
+            .run(script.as_bytes(), false)
+            .unwrap();
+        (emulator, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_script() {
+        // LD HL, 0x4000; LD (HL), 0x41; CALL 0x10; HALT; 0x10: INC HL; RET
+        let mut program = vec![0u8; 0x12];
+        program[..9].copy_from_slice(&[0x21, 0x00, 0x40, 0x36, 0x41, 0xCD, 0x10, 0x00, 0x76]);
+        program[0x10..].copy_from_slice(&[0x23, 0xC9]);
+        let script =
+            "set sp=ff00\nb 5\nb\nc\nm hl 4\nn\nr\nd 10 2\nbd 0\nbogus\nset hl=1234\nc\nq\ns\n";
+        let (emulator, out) = run_script(&program, script);
+        let expected = "\
+Breakpoint 0 at 0005
+0: 0005 hits 0
+Breakpoint 0
+>0005  CD 10 00     CALL 0x0010
+4000  41 00 00 00                                      A...
+>0008  76           HALT
+PC: 0008, SP: FF00
+AF: 0000, BC: 0000, DE: 0000, HL: 4001
+AF': 0000, BC': 0000, DE': 0000, HL': 0000
+I: 00, IX: 0000, IY: 0000, R: 05
+ 0010  23           INC HL
+ 0011  C9           RET
+Error: Unknown command bogus, h for help
+Halted
+>0009  00           NOP
+";
+        assert_eq!(out, expected);
+        assert_eq!(emulator.cpu.registers.gp.hl, 0x1234);
+    }
+
+    #[test]
+    fn test_ambiguous_values() {
+        let (mut emulator, _) = run_script(&[], "");
+        emulator.cpu.registers.gp.bc = 0x1234;
+        let mut out = Vec::new();
+        let monitor = Monitor::new(&mut emulator, &mut out);
+        assert!(monitor.value("bc").unwrap_err().contains("%bc"));
+        assert_eq!(monitor.value("%bc"), Ok(0x1234));
+        assert_eq!(monitor.value("$bc"), Ok(0xBC));
+        assert_eq!(monitor.value("0xbc"), Ok(0xBC));
+        assert_eq!(monitor.value("hl"), Ok(0));
+        assert_eq!(monitor.value("12"), Ok(0x12));
+        assert!(monitor.value("%xy").is_err());
+    }
+
+    #[test]
+    fn test_ambiguous_symbols() {
+        let (mut emulator, _) = run_script(&[], "");
+        emulator.symbols.insert("beef", 0x8000);
+        emulator.symbols.insert("main", 0x8100);
+        let mut out = Vec::new();
+        let monitor = Monitor::new(&mut emulator, &mut out);
+        let error = monitor.value("beef").unwrap_err();
+        assert!(error.contains("symbol") && error.contains("%beef"));
+        assert_eq!(monitor.value("%beef"), Ok(0x8000));
+        assert_eq!(monitor.value("$beef"), Ok(0xBEEF));
+        assert_eq!(monitor.value("main"), Ok(0x8100));
+        assert_eq!(monitor.value("%main"), Ok(0x8100));
+    }
+
+    #[test]
+    fn test_source_lines() {
+        let listing = "    ORG 0x0000\n\
+                       \x20   LD B, 0x02              ; 0000: 06 02\n\
+                       loop:\n\
+                       \x20   DEC B                   ; 0002: 05\n\
+                       \x20   JR NZ, loop             ; 0003: 20 FD\n\
+                       \x20   HALT                    ; 0005: 76\n";
+        let path = std::env::temp_dir().join(format!("emu_monitor_{}.asm", std::process::id()));
+        std::fs::write(&path, listing).unwrap();
+        let mut emulator: Emulator<Z80> = Emulator::new_w_mem(Memory::new_full_ram());
+        for (address, byte) in [0x06, 0x02, 0x05, 0x20, 0xFD, 0x76].iter().enumerate() {
+            emulator.memory.write_8(address as u16, *byte).unwrap();
+        }
+        emulator.lines = emu_lib::debug::source::LineTable::load(&path).unwrap();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let script = format!("b {}:5\nc\nsl\n", name);
+        let mut out = Vec::new();
+        Monitor::new(&mut emulator, &mut out)
+            .run(script.as_bytes(), false)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "Breakpoint 0 at 0003");
+        assert_eq!(lines[1], "Breakpoint 0");
+        assert_eq!(lines[2], format!("{}:5", path.display()));
+        assert_eq!(lines[6], ">    5      JR NZ, loop             ; 0003: 20 FD");
+        assert_eq!(lines[8], ">0003  20 FD        JR NZ, 0x0002");
+        assert_eq!(lines[9], format!("{}:4", path.display()));
+        assert_eq!(lines[13], ">    4      DEC B                   ; 0002: 05");
+        assert_eq!(lines[16], ">0002  05           DEC B");
+    }
+}
@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusCycleKind {
+    OpcodeFetch,
+    MemoryRead,
+    MemoryWrite,
+    IoRead,
+    IoWrite,
+}
+
+impl BusCycleKind {
+    pub fn is_write(self) -> bool {
+        matches!(self, BusCycleKind::MemoryWrite | BusCycleKind::IoWrite)
+    }
+
+    pub fn is_io(self) -> bool {
+        matches!(self, BusCycleKind::IoRead | BusCycleKind::IoWrite)
+    }
+}
+
+/// One machine cycle that transferred data over the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusCycle {
+    pub kind: BusCycleKind,
+    pub address: u16,
+    pub data: u8,
+    /// T-state at which the machine cycle starts, counted from the start of the step
+    pub offset: u16,
+}
+
+/// Collects the accesses made through [`Memory`](crate::memory::Memory) and
+/// [`IO`](crate::io::IO) while attached to them, the CPU turns them into the machine cycles
+/// of the step.
+#[derive(Debug, Default)]
+pub struct BusRecorder {
+    cycles: Mutex<Vec<BusCycle>>,
+}
+
+impl BusRecorder {
+    pub fn new() -> BusRecorder {
+        BusRecorder::default()
+    }
+
+    pub fn record(&self, kind: BusCycleKind, address: u16, data: u8) {
+        self.cycles
+            .lock()
+            .expect("Failed to get bus recorder lock")
+            .push(BusCycle {
+                kind,
+                address,
+                data,
+                offset: 0,
+            });
+    }
+
+    pub fn take(&self) -> Vec<BusCycle> {
+        std::mem::take(&mut *self.cycles.lock().expect("Failed to get bus recorder lock"))
+    }
+
+    pub fn replace(&self, cycles: Vec<BusCycle>) {
+        *self.cycles.lock().expect("Failed to get bus recorder lock") = cycles;
+    }
+
+    pub fn cycles(&self) -> Vec<BusCycle> {
+        self.cycles
+            .lock()
+            .expect("Failed to get bus recorder lock")
+            .clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemoryAccessKind {
+    Read,
+    Write,
+    /// First byte of an executed instruction
+    Execute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub kind: MemoryAccessKind,
+    pub address: u16,
+    /// Value before the access, the same as `new` unless it is a write
+    pub old: u8,
+    pub new: u8,
+}
+
+/// Collects the data accesses made through [`Memory`](crate::memory::Memory) while attached
+/// to it. The CPU drops the reads made while decoding and records the executed instruction.
+#[derive(Debug, Default)]
+pub struct AccessRecorder {
+    accesses: Mutex<Vec<MemoryAccess>>,
+}
+
+impl AccessRecorder {
+    pub fn new() -> AccessRecorder {
+        AccessRecorder::default()
+    }
+
+    pub fn record(&self, kind: MemoryAccessKind, address: u16, old: u8, new: u8) {
+        self.accesses
+            .lock()
+            .expect("Failed to get access recorder lock")
+            .push(MemoryAccess {
+                kind,
+                address,
+                old,
+                new,
+            });
+    }
+
+    /// Forgets the reads recorded so far, used after fetching an instruction
+    pub fn discard_reads(&self) {
+        self.accesses
+            .lock()
+            .expect("Failed to get access recorder lock")
+            .retain(|access| access.kind != MemoryAccessKind::Read);
+    }
+
+    pub fn take(&self) -> Vec<MemoryAccess> {
+        std::mem::take(
+            &mut *self
+                .accesses
+                .lock()
+                .expect("Failed to get access recorder lock"),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IoDirection {
+    /// `IN`, the CPU reads from the port
+    Read,
+    /// `OUT`, the CPU writes to the port
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoAccess {
+    pub direction: IoDirection,
+    pub port: u8,
+    pub value: u8,
+}
+
+/// Collects the port accesses made through [`IO`](crate::io::IO) while attached to it.
+#[derive(Debug, Default)]
+pub struct IoRecorder {
+    accesses: Mutex<Vec<IoAccess>>,
+}
+
+impl IoRecorder {
+    pub fn new() -> IoRecorder {
+        IoRecorder::default()
+    }
+
+    pub fn record(&self, direction: IoDirection, port: u8, value: u8) {
+        self.accesses
+            .lock()
+            .expect("Failed to get IO recorder lock")
+            .push(IoAccess {
+                direction,
+                port,
+                value,
+            });
+    }
+
+    pub fn take(&self) -> Vec<IoAccess> {
+        std::mem::take(
+            &mut *self
+                .accesses
+                .lock()
+                .expect("Failed to get IO recorder lock"),
+        )
+    }
+}
@@ -45,4 +45,7 @@ impl Cpu for I8080 {
     fn set_halted(&mut self, halted: bool) {
         self.halted = halted;
     }
+    fn interrupt_cycles(&self) -> u16 {
+        unimplemented!()
+    }
 }
@@ -1,10 +1,14 @@
 #![allow(unused)]
+use crate::cpu::metadata::InstructionMetadata;
 use crate::cpu::Cpu;
 use crate::io::IO;
 use crate::memory::{Memory, MemoryDevice};
 use std::fmt::{Debug, Display};
 use thiserror::Error;
 
+/// `cycles` starts as the T-states of the not taken path, conditional and repeating
+/// instructions update it on execution to what was actually consumed. Both paths are in
+/// [`BaseInstruction::branch_cycles`].
 #[derive(Debug, Clone, Copy)]
 pub struct InstructionCommon {
     pub length: u16,
@@ -25,6 +29,14 @@ impl InstructionCommon {
 pub trait BaseInstruction: Display + Debug + Send + Sync + 'static {
     fn common(&self) -> &InstructionCommon;
     fn to_bytes(&self) -> Vec<u8>;
+    /// T-states of the not taken and the taken path, for instructions whose cycles depend on
+    /// a condition or a counter.
+    fn branch_cycles(&self) -> Option<(u16, u16)> {
+        None
+    }
+    /// Static description of the instruction, length and cycles are the ones of
+    /// [`BaseInstruction::common`] and [`BaseInstruction::branch_cycles`].
+    fn metadata(&self) -> InstructionMetadata;
 }
 
 pub trait ExecutableInstruction<T: Cpu>: BaseInstruction {
@@ -97,11 +109,16 @@ macro_rules! push_8 {
 
 pub(crate) use push_8;
 
+// High byte goes first, in the same order as the hardware
 macro_rules! push_16 {
     ($val:expr, $memory:expr, $sp:expr) => {
+        let bytes = u16::to_le_bytes($val);
         $sp = $sp.wrapping_sub(2);
         $memory
-            .write_16($sp, $val)
+            .write_8($sp.wrapping_add(1), bytes[1])
+            .map_err(|_| "Error pushing value to stack")?;
+        $memory
+            .write_8($sp, bytes[0])
             .map_err(|_| "Error pushing value to stack")?;
     };
 }
@@ -0,0 +1,137 @@
+use crate::cpu::registers::Flags;
+
+/// Operand of a decoded instruction, register names are lowercase (`"a"`, `"hl"`, `"ixh"`, `"af'"`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Register(&'static str),
+    Immediate8(u8),
+    Immediate16(u16),
+    /// Displacement of a relative jump, from the address of the next instruction
+    Relative(i8),
+    /// Memory pointed to by a register pair, `(hl)`
+    Indirect(&'static str),
+    /// Memory pointed to by an index register plus displacement, `(ix+d)`
+    Indexed(&'static str, i8),
+    /// Memory at a fixed address, `(nn)`
+    Absolute(u16),
+    /// IO port given as an immediate, `(n)`
+    Port(u8),
+    /// IO port given by register C, `(c)`
+    PortC,
+    Condition(&'static str),
+    Bit(u8),
+    InterruptMode(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlowKind {
+    #[default]
+    Sequential,
+    Jump,
+    Call,
+    Return,
+    Restart,
+    Halt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchTarget {
+    Absolute(u16),
+    /// Displacement from the address of the next instruction
+    Relative(i8),
+    /// Jump through a register, `JP (HL)`
+    Register(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccessKind {
+    #[default]
+    None,
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl AccessKind {
+    pub fn reads(self) -> bool {
+        matches!(self, AccessKind::Read | AccessKind::ReadWrite)
+    }
+
+    pub fn writes(self) -> bool {
+        matches!(self, AccessKind::Write | AccessKind::ReadWrite)
+    }
+
+    pub fn merge(self, other: AccessKind) -> AccessKind {
+        match (
+            self.reads() || other.reads(),
+            self.writes() || other.writes(),
+        ) {
+            (true, true) => AccessKind::ReadWrite,
+            (true, false) => AccessKind::Read,
+            (false, true) => AccessKind::Write,
+            (false, false) => AccessKind::None,
+        }
+    }
+}
+
+/// Static description of an instruction, everything that can be known without executing it.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct InstructionMetadata {
+    /// Uppercase mnemonic, the same as the first word of the assembly text
+    pub mnemonic: &'static str,
+    pub length: u16,
+    /// T-states when the branch is not taken or a block instruction finishes
+    pub cycles: u16,
+    /// T-states when the branch is taken or a block instruction repeats
+    pub cycles_taken: Option<u16>,
+    pub operands: Vec<Operand>,
+    pub flow: FlowKind,
+    /// The branch, return or repeat depends on flags or a counter register
+    pub conditional: bool,
+    pub target: Option<BranchTarget>,
+    /// Block instruction that repeats until its counter runs out
+    pub repeats: bool,
+    pub memory: AccessKind,
+    pub io: AccessKind,
+    pub reads: Vec<&'static str>,
+    pub writes: Vec<&'static str>,
+    pub flags_read: Flags,
+    pub flags_written: Flags,
+}
+
+impl InstructionMetadata {
+    /// Resolves the static branch target of the instruction located at `address`.
+    pub fn target_address(&self, address: u16) -> Option<u16> {
+        match self.target? {
+            BranchTarget::Absolute(target) => Some(target),
+            BranchTarget::Relative(offset) => Some(
+                address
+                    .wrapping_add(self.length)
+                    .wrapping_add(offset as u16),
+            ),
+            BranchTarget::Register(_) => None,
+        }
+    }
+
+    pub fn is_branch(&self) -> bool {
+        !matches!(self.flow, FlowKind::Sequential | FlowKind::Halt)
+    }
+
+    /// Calls and restarts come back to the next instruction.
+    pub fn is_subroutine_call(&self) -> bool {
+        matches!(self.flow, FlowKind::Call | FlowKind::Restart)
+    }
+
+    /// Execution may continue with the next instruction in memory.
+    pub fn falls_through(&self) -> bool {
+        self.conditional || !matches!(self.flow, FlowKind::Jump | FlowKind::Return)
+    }
+
+    pub fn reads_register(&self, name: &str) -> bool {
+        self.reads.contains(&name)
+    }
+
+    pub fn writes_register(&self, name: &str) -> bool {
+        self.writes.contains(&name)
+    }
+}
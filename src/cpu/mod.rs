@@ -8,6 +8,7 @@ use crate::memory::Memory;
 
 pub mod i8080;
 pub mod instruction;
+pub mod metadata;
 pub mod registers;
 #[cfg(test)]
 pub mod test;
@@ -25,4 +26,6 @@ pub trait Cpu: Send + Copy + Clone + Default + Serialize + for<'a> Deserialize<'
     fn pc(&self) -> u16;
     fn halted(&self) -> bool;
     fn set_halted(&mut self, halted: bool);
+    /// T-states the last step spent on interrupt handling, not included in the instruction cycles
+    fn interrupt_cycles(&self) -> u16;
 }
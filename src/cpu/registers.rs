@@ -91,6 +91,48 @@ pub struct AllRegisters<'a> {
     pub other8bit: HashMap<&'static str, &'a u8>,
 }
 
+impl AllRegisters<'_> {
+    /// Looks a register up by its lowercase name, the alternate set is suffixed with `'`.
+    pub fn get(&self, name: &str) -> Option<u16> {
+        let (name, set) = match name.strip_suffix('\'') {
+            Some(name) => (name, 1),
+            None => (name, 0),
+        };
+        if let Some(gp) = self.gp.get(set) {
+            let value = match name {
+                "a" => Some(gp.a as u16),
+                "f" => Some(gp.f.into_bits() as u16),
+                "b" => Some(gp.b as u16),
+                "c" => Some(gp.c as u16),
+                "d" => Some(gp.d as u16),
+                "e" => Some(gp.e as u16),
+                "h" => Some(gp.h as u16),
+                "l" => Some(gp.l as u16),
+                "af" => Some(gp.af),
+                "bc" => Some(gp.bc),
+                "de" => Some(gp.de),
+                "hl" => Some(gp.hl),
+                _ => None,
+            };
+            if value.is_some() {
+                return value;
+            }
+        }
+        if set != 0 {
+            return None;
+        }
+        match name {
+            "sp" => Some(*self.sp),
+            "pc" => Some(*self.pc),
+            _ => self
+                .other16bit
+                .get(name)
+                .map(|value| **value)
+                .or_else(|| self.other8bit.get(name).map(|value| **value as u16)),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AllMutRegisters<'a> {
     pub gp: Vec<&'a mut GPByteRegisters>,
@@ -99,3 +141,52 @@ pub struct AllMutRegisters<'a> {
     pub other16bit: HashMap<&'static str, &'a mut u16>,
     pub other8bit: HashMap<&'static str, &'a mut u8>,
 }
+
+impl AllMutRegisters<'_> {
+    /// Sets a register by the names accepted by [`AllRegisters::get`], 8 bit registers take the
+    /// low byte of `value`. Returns false when there is no such register.
+    pub fn set(&mut self, name: &str, value: u16) -> bool {
+        let (name, set) = match name.strip_suffix('\'') {
+            Some(name) => (name, 1),
+            None => (name, 0),
+        };
+        if let Some(gp) = self.gp.get_mut(set) {
+            let byte = value as u8;
+            match name {
+                "a" => gp.a = byte,
+                "f" => gp.f = Flags::from_bits(byte),
+                "b" => gp.b = byte,
+                "c" => gp.c = byte,
+                "d" => gp.d = byte,
+                "e" => gp.e = byte,
+                "h" => gp.h = byte,
+                "l" => gp.l = byte,
+                "af" => gp.af = value,
+                "bc" => gp.bc = value,
+                "de" => gp.de = value,
+                "hl" => gp.hl = value,
+                _ if set != 0 => return false,
+                _ => return self.set_other(name, value),
+            }
+            return true;
+        }
+        set == 0 && self.set_other(name, value)
+    }
+
+    fn set_other(&mut self, name: &str, value: u16) -> bool {
+        match name {
+            "sp" => *self.sp = value,
+            "pc" => *self.pc = value,
+            _ => {
+                if let Some(register) = self.other16bit.get_mut(name) {
+                    **register = value;
+                } else if let Some(register) = self.other8bit.get_mut(name) {
+                    **register = value as u8;
+                } else {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
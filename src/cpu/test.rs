@@ -23,6 +23,16 @@ macro_rules! test_instruction_parse {
                     let new_instruction = Z80_PARSER.ins_from_asm_string(&ins_as_string).expect(&format!("Failed to parse instruction: {}", ins_as_string));
                     assert_eq!(ins_as_string, new_instruction.to_string());
                 }
+
+                #[allow(non_snake_case)]
+                #[test]
+                fn [< test_ $instruction _metadata >]() {
+                    let instruction = $instruction::new_with_value($($arg),*);
+                    let metadata = crate::cpu::instruction::BaseInstruction::metadata(&instruction);
+                    assert_eq!(instruction.to_bytes().len(), instruction.common().length as usize, "Length mismatch for {}", instruction);
+                    assert_eq!(metadata.cycles, instruction.common().cycles, "Cycles mismatch for {}", instruction);
+                    assert_eq!(Some(metadata.mnemonic), instruction.to_string().split(' ').next(), "Mnemonic mismatch for {}", instruction);
+                }
             }
     };
     ($instruction:ident) => {
@@ -49,6 +59,16 @@ macro_rules! test_instruction_parse {
                     let new_instruction = Z80_PARSER.ins_from_asm_string(&ins_as_string).unwrap();
                     assert_eq!(ins_as_string, new_instruction.to_string());
                 }
+
+                #[allow(non_snake_case)]
+                #[test]
+                fn [< test_ $instruction _metadata >]() {
+                    let instruction = $instruction::new();
+                    let metadata = crate::cpu::instruction::BaseInstruction::metadata(&instruction);
+                    assert_eq!(instruction.to_bytes().len(), instruction.common().length as usize, "Length mismatch for {}", instruction);
+                    assert_eq!(metadata.cycles, instruction.common().cycles, "Cycles mismatch for {}", instruction);
+                    assert_eq!(Some(metadata.mnemonic), instruction.to_string().split(' ').next(), "Mnemonic mismatch for {}", instruction);
+                }
             }
     };
 }
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use crate::cpu::instruction::{InstructionParser, ParseError};
+use crate::cpu::z80::parser::{is_num, Z80_PARSER};
+use crate::memory::image::MemoryImage;
+
+enum Statement {
+    Org(u16),
+    Data(Vec<u8>),
+    Instruction(String),
+}
+
+struct Line {
+    /// 1-based line number in the source text
+    number: usize,
+    label: Option<String>,
+    statement: Option<Statement>,
+}
+
+fn invalid(message: String) -> ParseError {
+    ParseError::InvalidInstruction(message)
+}
+
+fn parse_value(value: &str) -> Result<u16, ParseError> {
+    is_num(&value.trim().to_lowercase()).map_err(|e| invalid(format!("{}: \"{}\"", e, value)))
+}
+
+fn parse_line(number: usize, line: &str, label_re: &Regex) -> Result<Line, ParseError> {
+    let code = line.split(';').next().unwrap_or("").trim();
+    let (label, rest) = match label_re.captures(code) {
+        Some(caps) => (
+            Some(caps[1].to_string()),
+            code[caps.get(0).map_or(0, |m| m.end())..].trim(),
+        ),
+        None => (None, code),
+    };
+    let statement = if rest.is_empty() {
+        None
+    } else {
+        let (keyword, operands) = rest.split_once(' ').unwrap_or((rest, ""));
+        match keyword.to_lowercase().as_str() {
+            "org" => Some(Statement::Org(parse_value(operands)?)),
+            "db" => {
+                let bytes = operands
+                    .split(',')
+                    .map(|value| {
+                        let value = parse_value(value)?;
+                        u8::try_from(value).map_err(|_| {
+                            invalid(format!("Value does not fit in a byte: {}", value))
+                        })
+                    })
+                    .collect::<Result<Vec<u8>, ParseError>>()?;
+                Some(Statement::Data(bytes))
+            }
+            _ => Some(Statement::Instruction(rest.to_string())),
+        }
+    };
+    Ok(Line {
+        number,
+        label,
+        statement,
+    })
+}
+
+/// Replaces label references with their address, relative jumps get their displacement
+/// computed from the absolute target. On the first pass labels are all zero and
+/// displacements are not range checked.
+fn resolve(
+    instruction: &str,
+    address: u16,
+    labels: &HashMap<String, u16>,
+    first_pass: bool,
+    word_re: &Regex,
+) -> Result<String, ParseError> {
+    let mnemonic = instruction.split(' ').next().unwrap_or("").to_lowercase();
+    if mnemonic == "jr" || mnemonic == "djnz" {
+        let (head, operand) = instruction.rsplit_once(' ').unwrap_or(("", instruction));
+        let target = match labels.get(operand) {
+            Some(target) => Some(*target),
+            None if operand.len() == 6 => Some(parse_value(operand)?),
+            None => None,
+        };
+        return match target {
+            Some(target) => {
+                let displacement = target.wrapping_sub(address.wrapping_add(2)) as i16;
+                if !first_pass && !(-128..=127).contains(&displacement) {
+                    return Err(invalid(format!(
+                        "Relative jump out of range: {}",
+                        instruction
+                    )));
+                }
+                Ok(format!("{} 0x{:02X}", head, displacement as u8))
+            }
+            None => Ok(instruction.to_string()),
+        };
+    }
+    let resolved = word_re.replace_all(instruction, |caps: &regex::Captures| {
+        match labels.get(&caps[0]) {
+            Some(value) => format!("0x{:04X}", value),
+            None => caps[0].to_string(),
+        }
+    });
+    Ok(resolved.to_string())
+}
+
+/// Two pass assembler for the listing produced by the disassembler: `ORG`, `DB`,
+/// `label:` definitions and any instruction understood by the Z80 parser. Errors come with
+/// the 1-based number of their line.
+pub fn assemble(text: &str) -> Result<MemoryImage, Vec<(usize, ParseError)>> {
+    let label_re = Regex::new(r"^([A-Za-z_][A-Za-z0-9_]*):").expect("Error building label regex");
+    let word_re =
+        Regex::new(r"\b[A-Za-z_][A-Za-z0-9_]*\b").expect("Error building label reference regex");
+    let mut errors = Vec::new();
+    let mut lines = Vec::new();
+    for (number, line) in (1..).zip(text.lines()) {
+        match parse_line(number, line, &label_re) {
+            Ok(line) => lines.push(line),
+            Err(e) => errors.push((number, e)),
+        }
+    }
+
+    // First pass, instruction sizes do not depend on label values
+    let placeholders: HashMap<String, u16> = lines
+        .iter()
+        .filter_map(|line| line.label.clone())
+        .map(|label| (label, 0))
+        .collect();
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut address: u16 = 0;
+    for line in &lines {
+        if let Some(Statement::Org(org)) = line.statement {
+            address = org;
+        }
+        if let Some(label) = &line.label {
+            if labels.insert(label.clone(), address).is_some() {
+                errors.push((line.number, invalid(format!("Duplicate label: {}", label))));
+            }
+        }
+        match &line.statement {
+            Some(Statement::Data(bytes)) => address = address.wrapping_add(bytes.len() as u16),
+            Some(Statement::Instruction(instruction)) => {
+                match resolve(instruction, address, &placeholders, true, &word_re)
+                    .and_then(|resolved| Z80_PARSER.ins_from_asm_string(&resolved))
+                {
+                    Ok(ins) => address = address.wrapping_add(ins.common().length),
+                    Err(e) => errors.push((line.number, e)),
+                }
+            }
+            _ => {}
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut image = MemoryImage::new();
+    address = 0;
+    for line in &lines {
+        match &line.statement {
+            Some(Statement::Org(org)) => address = *org,
+            Some(Statement::Data(bytes)) => {
+                image.add_data(address, bytes);
+                address = address.wrapping_add(bytes.len() as u16);
+            }
+            Some(Statement::Instruction(instruction)) => {
+                match resolve(instruction, address, &labels, false, &word_re)
+                    .and_then(|resolved| Z80_PARSER.ins_from_asm_string(&resolved))
+                {
+                    Ok(ins) => {
+                        let bytes = ins.to_bytes();
+                        image.add_data(address, &bytes);
+                        address = address.wrapping_add(bytes.len() as u16);
+                    }
+                    Err(e) => errors.push((line.number, e)),
+                }
+            }
+            None => {}
+        }
+    }
+    if errors.is_empty() {
+        Ok(image)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_with_labels() {
+        let text = "    ORG 0x4000\n\
+                    start:\n\
+                    LD B, 0x03\n\
+                    loop: DEC B ; count down\n\
+                    JR NZ, loop\n\
+                    CALL start\n\
+                    DB 0x01, 0x02\n";
+        let image = assemble(text).unwrap();
+        assert_eq!(
+            image.to_raw(),
+            (
+                0x4000,
+                vec![0x06, 0x03, 0x05, 0x20, 0xFD, 0xCD, 0x00, 0x40, 0x01, 0x02]
+            )
+        );
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let text = "JP nowhere\nJR 0x9000\nFOO A\n";
+        let errors = assemble(text).unwrap_err();
+        assert_eq!(
+            errors.iter().map(|(line, _)| *line).collect::<Vec<usize>>(),
+            vec![1, 3]
+        );
+        let errors = assemble("JR 0x9000\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+    }
+}
@@ -0,0 +1,310 @@
+use crate::bus::{BusCycle, BusCycleKind};
+use crate::cpu::metadata::Operand;
+use crate::cpu::z80::metadata;
+
+const FETCH_CYCLES: u16 = 4;
+const MEMORY_CYCLES: u16 = 3;
+const IO_CYCLES: u16 = 4;
+
+/// T-state the execute cycles of an `(ix+d)` instruction start at, the displacement is added
+/// while the rest of the instruction is read.
+const INDEXED_EXECUTE: u16 = 16;
+
+/// Number of opcode fetch (M1) cycles, the displacement and opcode of DD CB d op are plain reads.
+fn fetch_count(bytes: &[u8]) -> usize {
+    match bytes {
+        [0xDD | 0xFD | 0xED | 0xCB, ..] => 2,
+        _ => 1,
+    }
+}
+
+/// Opcode of the instruction and the ED or CB prefix in front of it, index prefixes skipped.
+fn opcode(bytes: &[u8]) -> (Option<u8>, u8) {
+    let bytes = match bytes {
+        [0xDD | 0xFD, rest @ ..] => rest,
+        _ => bytes,
+    };
+    match bytes {
+        [0xCB, _, op] | [0xCB, op] => (Some(0xCB), *op),
+        [0xED, op, ..] => (Some(0xED), *op),
+        [op, ..] => (None, *op),
+        [] => (None, 0),
+    }
+}
+
+/// The last opcode fetch takes 5 T-states: PUSH, RST, RET cc, DJNZ and INI/OUTI and family.
+fn extended_fetch(bytes: &[u8]) -> bool {
+    match opcode(bytes) {
+        (None, op) => op & 0xCF == 0xC5 || op & 0xC7 == 0xC7 || op & 0xC7 == 0xC0 || op == 0x10,
+        (Some(0xED), op) => op & 0xE6 == 0xA2,
+        _ => false,
+    }
+}
+
+/// Internal T-states between reading memory and writing it back.
+fn modify_states(bytes: &[u8]) -> u16 {
+    match opcode(bytes) {
+        // RRD, RLD
+        (Some(0xED), 0x67 | 0x6F) => 4,
+        // LDI and family write what they read somewhere else
+        (Some(0xED), op) if op & 0xE4 == 0xA0 => 0,
+        _ => 1,
+    }
+}
+
+fn place(cycles: &mut [BusCycle], mut offset: u16) -> u16 {
+    for cycle in cycles {
+        cycle.offset = offset;
+        offset += match cycle.kind {
+            BusCycleKind::OpcodeFetch => FETCH_CYCLES,
+            BusCycleKind::IoRead | BusCycleKind::IoWrite => IO_CYCLES,
+            _ => MEMORY_CYCLES,
+        };
+    }
+    offset
+}
+
+/// What happened during a step, as seen from the outside of the CPU.
+pub(crate) struct StepAccesses<'a> {
+    pub pc: u16,
+    /// Instruction bytes, empty when the instruction came from the interrupting device
+    pub bytes: &'a [u8],
+    pub interrupt: Vec<BusCycle>,
+    pub interrupt_cycles: u16,
+    pub execute: Vec<BusCycle>,
+    pub cycles: u16,
+    /// Registers driving the upper address lines for `(n)` and `(c)` ports
+    pub a: u8,
+    pub b: u8,
+}
+
+/// Builds the machine cycles of a step: interrupt acknowledge, opcode fetches, operand reads
+/// and the accesses made while executing. Internal T-states that do not use the bus go where
+/// the Z80 spends them: in a longer last opcode fetch, before the execute cycles of `(ix+d)`
+/// instructions, between reading memory and writing it back, and the rest at the end of the
+/// instruction, or before its writes when it only writes.
+pub(crate) fn machine_cycles(step: StepAccesses) -> Vec<BusCycle> {
+    let mut cycles = step.interrupt;
+    // The acknowledge cycle comes before the stack writes
+    let writes = cycles.len() as u16 * MEMORY_CYCLES;
+    place(&mut cycles, step.interrupt_cycles.saturating_sub(writes));
+
+    let mut instruction: Vec<BusCycle> = Vec::new();
+    let fetches = fetch_count(step.bytes);
+    for (idx, byte) in step.bytes.iter().enumerate() {
+        instruction.push(BusCycle {
+            kind: if idx < fetches {
+                BusCycleKind::OpcodeFetch
+            } else {
+                BusCycleKind::MemoryRead
+            },
+            address: step.pc.wrapping_add(idx as u16),
+            data: *byte,
+            offset: 0,
+        });
+    }
+    let (fetch, operands) = instruction.split_at_mut(fetches.min(step.bytes.len()));
+    let mut end = place(fetch, step.interrupt_cycles);
+    if extended_fetch(step.bytes) {
+        end += 1;
+    }
+    let end = place(operands, end);
+
+    let meta = metadata::decode(step.bytes);
+    let high = match meta.operands.iter().find_map(|op| match op {
+        Operand::Port(_) => Some(step.a),
+        Operand::PortC => Some(step.b),
+        _ => None,
+    }) {
+        Some(high) => (high as u16) << 8,
+        None => 0,
+    };
+    let mut execute = step.execute;
+    for cycle in execute.iter_mut().filter(|cycle| cycle.kind.is_io()) {
+        cycle.address |= high;
+    }
+
+    let mut start = end;
+    if meta
+        .operands
+        .iter()
+        .any(|op| matches!(op, Operand::Indexed(..)))
+    {
+        start = start.max(step.interrupt_cycles + INDEXED_EXECUTE);
+    }
+    // Memory written back after it was read
+    let modify_at = execute
+        .iter()
+        .position(|cycle| cycle.kind == BusCycleKind::MemoryWrite)
+        .filter(|&idx| {
+            execute[..idx]
+                .iter()
+                .any(|cycle| cycle.kind == BusCycleKind::MemoryRead)
+        });
+    let modify = if modify_at.is_some() {
+        modify_states(step.bytes)
+    } else {
+        0
+    };
+    let used: u16 = execute
+        .iter()
+        .map(|cycle| {
+            if cycle.kind.is_io() {
+                IO_CYCLES
+            } else {
+                MEMORY_CYCLES
+            }
+        })
+        .sum();
+    let spare = (step.interrupt_cycles + step.cycles).saturating_sub(start + used + modify);
+    if !execute.is_empty() && execute.iter().all(|cycle| cycle.kind.is_write()) {
+        start += spare;
+    }
+    let modify_at = modify_at.unwrap_or(execute.len());
+    let (read, write) = execute.split_at_mut(modify_at);
+    let read_end = place(read, start);
+    place(write, read_end + modify);
+
+    cycles.extend(instruction);
+    cycles.extend(execute);
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cycle(kind: BusCycleKind, address: u16, data: u8, offset: u16) -> BusCycle {
+        BusCycle {
+            kind,
+            address,
+            data,
+            offset,
+        }
+    }
+
+    #[test]
+    fn test_call_cycles() {
+        // CALL 0x1234 at 0x8000 with SP = 0x0000
+        let execute = vec![
+            cycle(BusCycleKind::MemoryWrite, 0xFFFF, 0x80, 0),
+            cycle(BusCycleKind::MemoryWrite, 0xFFFE, 0x03, 0),
+        ];
+        let cycles = machine_cycles(StepAccesses {
+            pc: 0x8000,
+            bytes: &[0xCD, 0x34, 0x12],
+            interrupt: vec![],
+            interrupt_cycles: 0,
+            execute,
+            cycles: 17,
+            a: 0,
+            b: 0,
+        });
+        assert_eq!(
+            cycles,
+            vec![
+                cycle(BusCycleKind::OpcodeFetch, 0x8000, 0xCD, 0),
+                cycle(BusCycleKind::MemoryRead, 0x8001, 0x34, 4),
+                cycle(BusCycleKind::MemoryRead, 0x8002, 0x12, 7),
+                cycle(BusCycleKind::MemoryWrite, 0xFFFF, 0x80, 11),
+                cycle(BusCycleKind::MemoryWrite, 0xFFFE, 0x03, 14),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_io_address_and_prefixes() {
+        // IN B, (C) with BC = 0x12FE
+        let cycles = machine_cycles(StepAccesses {
+            pc: 0,
+            bytes: &[0xED, 0x40],
+            interrupt: vec![],
+            interrupt_cycles: 0,
+            execute: vec![cycle(BusCycleKind::IoRead, 0xFE, 0xAA, 0)],
+            cycles: 12,
+            a: 0,
+            b: 0x12,
+        });
+        assert_eq!(cycles[1].kind, BusCycleKind::OpcodeFetch);
+        assert_eq!(cycles[2], cycle(BusCycleKind::IoRead, 0x12FE, 0xAA, 8));
+
+        let cycles = machine_cycles(StepAccesses {
+            pc: 0,
+            bytes: &[0xDD, 0xCB, 0x05, 0x46],
+            interrupt: vec![],
+            interrupt_cycles: 0,
+            execute: vec![],
+            cycles: 20,
+            a: 0,
+            b: 0,
+        });
+        let kinds: Vec<BusCycleKind> = cycles.iter().map(|cycle| cycle.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                BusCycleKind::OpcodeFetch,
+                BusCycleKind::OpcodeFetch,
+                BusCycleKind::MemoryRead,
+                BusCycleKind::MemoryRead
+            ]
+        );
+    }
+
+    fn offsets(bytes: &[u8], execute: &[BusCycleKind], cycles: u16) -> Vec<u16> {
+        let execute = execute.iter().map(|kind| cycle(*kind, 0, 0, 0)).collect();
+        machine_cycles(StepAccesses {
+            pc: 0,
+            bytes,
+            interrupt: vec![],
+            interrupt_cycles: 0,
+            execute,
+            cycles,
+            a: 0,
+            b: 0,
+        })
+        .iter()
+        .map(|cycle| cycle.offset)
+        .collect()
+    }
+
+    #[test]
+    fn test_internal_states() {
+        use BusCycleKind::*;
+        // INC (HL)
+        assert_eq!(offsets(&[0x34], &[MemoryRead, MemoryWrite], 11), [0, 4, 8]);
+        // LDI
+        assert_eq!(
+            offsets(&[0xED, 0xA0], &[MemoryRead, MemoryWrite], 16),
+            [0, 4, 8, 11]
+        );
+        // RLD
+        assert_eq!(
+            offsets(&[0xED, 0x6F], &[MemoryRead, MemoryWrite], 18),
+            [0, 4, 8, 15]
+        );
+        // EX (SP), HL
+        let stack = [MemoryRead, MemoryRead, MemoryWrite, MemoryWrite];
+        assert_eq!(offsets(&[0xE3], &stack, 19), [0, 4, 7, 11, 14]);
+        // PUSH BC
+        assert_eq!(offsets(&[0xC5], &[MemoryWrite, MemoryWrite], 11), [0, 5, 8]);
+        // DJNZ
+        assert_eq!(offsets(&[0x10, 0xFE], &[], 13), [0, 5]);
+        // INI
+        assert_eq!(
+            offsets(&[0xED, 0xA2], &[IoRead, MemoryWrite], 16),
+            [0, 4, 9, 13]
+        );
+        // BIT 0, (HL)
+        assert_eq!(offsets(&[0xCB, 0x46], &[MemoryRead], 12), [0, 4, 8]);
+        // LD A, (IX+5)
+        assert_eq!(
+            offsets(&[0xDD, 0x7E, 0x05], &[MemoryRead], 19),
+            [0, 4, 8, 16]
+        );
+        // SET 0, (IX+5)
+        assert_eq!(
+            offsets(&[0xDD, 0xCB, 0x05, 0xC6], &[MemoryRead, MemoryWrite], 23),
+            [0, 4, 8, 11, 16, 20]
+        );
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Display;
+
+use crate::cpu::instruction::{ExecutableInstruction, InstructionParser};
+use crate::cpu::z80::parser::Z80_PARSER;
+use crate::cpu::z80::Z80;
+use crate::debug::symbols::{label_operands, Symbols};
+use crate::memory::MemoryDevice;
+
+const DATA_BYTES_PER_LINE: usize = 8;
+
+#[derive(Debug)]
+pub enum LineContent {
+    Instruction(Box<dyn ExecutableInstruction<Z80>>),
+    Data,
+    /// Bytes that could not be read, such as unmapped addresses, the line has no bytes
+    Unreadable(usize),
+}
+
+#[derive(Debug)]
+pub struct DisassemblyLine {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub content: LineContent,
+    /// Assembly text with branch targets replaced by labels
+    pub text: String,
+}
+
+impl DisassemblyLine {
+    pub fn is_code(&self) -> bool {
+        matches!(self.content, LineContent::Instruction(_))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Disassembly {
+    pub start: u16,
+    pub lines: Vec<DisassemblyLine>,
+    pub labels: BTreeMap<u16, String>,
+}
+
+impl Disassembly {
+    pub fn line_at(&self, address: u16) -> Option<&DisassemblyLine> {
+        self.lines.iter().find(|line| line.address == address)
+    }
+
+    /// Names the lines that have a symbol and the branch targets outside the range, the
+    /// generated labels are replaced.
+    pub fn apply_symbols(&mut self, symbols: &Symbols) {
+        for line in &self.lines {
+            if let Some(name) = symbols.name(line.address) {
+                self.labels.insert(line.address, name.to_string());
+            }
+        }
+        for line in self.lines.iter_mut() {
+            if let LineContent::Instruction(instruction) = &line.content {
+                line.text = label_operands(instruction.as_ref(), line.address, |target| {
+                    self.labels
+                        .get(&target)
+                        .cloned()
+                        .or_else(|| symbols.name(target).map(str::to_string))
+                });
+            }
+        }
+    }
+}
+
+impl Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "    ORG 0x{:04X}", self.start)?;
+        for line in &self.lines {
+            if let Some(label) = self.labels.get(&line.address) {
+                writeln!(f, "{}:", label)?;
+            }
+            let bytes = match line.content {
+                LineContent::Unreadable(count) => vec!["??"; count].join(" "),
+                _ => line
+                    .bytes
+                    .iter()
+                    .map(|byte| format!("{:02X}", byte))
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            };
+            writeln!(f, "    {:<24}; {:04X}: {}", line.text, line.address, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+fn instruction_text(
+    instruction: &dyn ExecutableInstruction<Z80>,
+    address: u16,
+    labels: &BTreeMap<u16, String>,
+) -> String {
+    label_operands(instruction, address, |target| labels.get(&target).cloned())
+}
+
+fn data_text(bytes: &[u8]) -> String {
+    let values = bytes
+        .iter()
+        .map(|byte| format!("0x{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join(", ");
+    format!("DB {}", values)
+}
+
+/// Disassembles the inclusive range `start..=end` following the control flow from `entry_points`.
+/// Bytes not reached as instructions are emitted as data, branch targets inside the range
+/// receive labels. Memory is read with [`MemoryDevice::peek_8`], bytes that can not be read
+/// are shown as `DB ??`.
+pub fn disassemble(
+    memory: &dyn MemoryDevice,
+    start: u16,
+    end: u16,
+    entry_points: &[u16],
+) -> Disassembly {
+    if end < start {
+        return Disassembly {
+            start,
+            ..Default::default()
+        };
+    }
+    let in_range = |addr: u16| addr >= start && addr <= end;
+    let size = end as usize + 1 - start as usize;
+    let mut covered = vec![false; size];
+    let mut code: BTreeMap<u16, Box<dyn ExecutableInstruction<Z80>>> = BTreeMap::new();
+    let mut targets: BTreeMap<u16, bool> = BTreeMap::new();
+    let mut pending: Vec<u16> = Vec::new();
+    let image: Vec<Option<u8>> = (start..=end).map(|addr| memory.peek_8(addr).ok()).collect();
+    for entry in entry_points
+        .iter()
+        .rev()
+        .copied()
+        .filter(|addr| in_range(*addr))
+    {
+        targets.insert(entry, false);
+        pending.push(entry);
+    }
+
+    while let Some(addr) = pending.pop() {
+        if covered[(addr - start) as usize] {
+            continue;
+        }
+        let offset = (addr - start) as usize;
+        // Parsed from a copy so it stops at the end of the range and at unreadable bytes
+        let bytes: Vec<u8> = image[offset..size.min(offset + 4)]
+            .iter()
+            .map_while(|byte| *byte)
+            .collect();
+        let instruction = match Z80_PARSER.ins_from_machinecode(&bytes, 0) {
+            Ok(instruction) => instruction,
+            Err(_) => continue,
+        };
+        let length = instruction.common().length as usize;
+        if offset + length > size || covered[offset..offset + length].contains(&true) {
+            continue;
+        }
+        covered[offset..offset + length].fill(true);
+        let metadata = instruction.metadata();
+        if let Some(target) = metadata.target_address(addr) {
+            if in_range(target) {
+                let is_call =
+                    targets.get(&target).copied().unwrap_or(false) || metadata.is_subroutine_call();
+                targets.insert(target, is_call);
+                pending.push(target);
+            }
+        }
+        if metadata.falls_through() {
+            if let Some(next) = addr
+                .checked_add(length as u16)
+                .filter(|next| in_range(*next))
+            {
+                pending.push(next);
+            }
+        }
+        code.insert(addr, instruction);
+    }
+
+    // Only addresses that start a line can carry a label
+    let mut labels = BTreeMap::new();
+    for (target, is_call) in &targets {
+        let offset = (target - start) as usize;
+        if code.contains_key(target) || !covered[offset] {
+            let prefix = if *is_call { "SUB" } else { "L" };
+            labels.insert(*target, format!("{}_{:04X}", prefix, target));
+        }
+    }
+
+    let mut disassembly = Disassembly {
+        start,
+        lines: Vec::new(),
+        labels,
+    };
+    let mut addr = start as usize;
+    while addr <= end as usize {
+        if let Some(instruction) = code.remove(&(addr as u16)) {
+            let text = instruction_text(&*instruction, addr as u16, &disassembly.labels);
+            let bytes = instruction.to_bytes();
+            addr += bytes.len();
+            disassembly.lines.push(DisassemblyLine {
+                address: (addr - bytes.len()) as u16,
+                bytes,
+                content: LineContent::Instruction(instruction),
+                text,
+            });
+            continue;
+        }
+        let line_start = addr;
+        let readable = image[addr - start as usize].is_some();
+        let mut bytes = Vec::new();
+        while addr <= end as usize
+            && !covered[addr - start as usize]
+            && image[addr - start as usize].is_some() == readable
+            && addr - line_start < DATA_BYTES_PER_LINE
+            && (addr == line_start || !disassembly.labels.contains_key(&(addr as u16)))
+        {
+            bytes.extend(image[addr - start as usize]);
+            addr += 1;
+        }
+        let (text, content) = match readable {
+            true => (data_text(&bytes), LineContent::Data),
+            false => {
+                let count = addr - line_start;
+                (
+                    format!("DB {}", vec!["??"; count].join(", ")),
+                    LineContent::Unreadable(count),
+                )
+            }
+        };
+        disassembly.lines.push(DisassemblyLine {
+            address: line_start as u16,
+            bytes,
+            content,
+            text,
+        });
+    }
+    disassembly
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::AccessRecorder;
+    use crate::cpu::z80::assembler::assemble;
+    use crate::memory::Memory;
+    use std::sync::Arc;
+
+    fn program() -> Vec<u8> {
+        vec![
+            0x3E, 0x05, // 8000: LD A, 0x05
+            0xCD, 0x0B, 0x80, // 8002: CALL 0x800B
+            0x20, 0xF9, // 8005: JR NZ, 0x8000
+            0xC3, 0x0E, 0x80, // 8007: JP 0x800E
+            0xFF, // 800A: data
+            0x3D, // 800B: DEC A
+            0xC9, // 800C: RET
+            0xED, // 800D: data, not a valid instruction on its own
+            0x18, 0xFE, // 800E: JR 0x800E
+            0x12, 0x34, // 8010: data
+        ]
+    }
+
+    fn disassemble_program() -> Disassembly {
+        let mut memory = vec![0; 0x10000];
+        let program = program();
+        memory[0x8000..0x8000 + program.len()].copy_from_slice(&program);
+        disassemble(
+            &memory,
+            0x8000,
+            0x8000 + program.len() as u16 - 1,
+            &[0x8000],
+        )
+    }
+
+    #[test]
+    fn test_code_and_data_separation() {
+        let disassembly = disassemble_program();
+        let code: Vec<u16> = disassembly
+            .lines
+            .iter()
+            .filter(|line| line.is_code())
+            .map(|line| line.address)
+            .collect();
+        assert_eq!(
+            code,
+            vec![0x8000, 0x8002, 0x8005, 0x8007, 0x800B, 0x800C, 0x800E]
+        );
+        assert_eq!(disassembly.line_at(0x800A).unwrap().text, "DB 0xFF");
+        assert_eq!(disassembly.line_at(0x8010).unwrap().text, "DB 0x12, 0x34");
+    }
+
+    #[test]
+    fn test_labels() {
+        let disassembly = disassemble_program();
+        assert_eq!(disassembly.labels.get(&0x8000).unwrap(), "L_8000");
+        assert_eq!(disassembly.labels.get(&0x800B).unwrap(), "SUB_800B");
+        assert_eq!(disassembly.line_at(0x8002).unwrap().text, "CALL SUB_800B");
+        assert_eq!(disassembly.line_at(0x8005).unwrap().text, "JR NZ, L_8000");
+        assert_eq!(disassembly.line_at(0x800E).unwrap().text, "JR L_800E");
+    }
+
+    #[test]
+    fn test_reassembles_to_identical_bytes() {
+        let disassembly = disassemble_program();
+        let image = assemble(&disassembly.to_string()).expect("Failed to reassemble");
+        assert_eq!(image.to_raw(), (0x8000, program()));
+    }
+
+    #[test]
+    fn test_target_outside_range() {
+        let memory = vec![0xC3, 0x00, 0x90, 0x18, 0xFB];
+        let disassembly = disassemble(&memory, 0, 4, &[0, 3]);
+        assert_eq!(disassembly.line_at(0).unwrap().text, "JP 0x9000");
+        assert_eq!(disassembly.line_at(3).unwrap().text, "JR L_0000");
+        let image = assemble(&disassembly.to_string()).expect("Failed to reassemble");
+        assert_eq!(image.to_raw(), (0, memory));
+    }
+
+    #[test]
+    fn test_unreadable_bytes() {
+        // 0x0000: LD A, 0x05; RET; data, then nothing mapped
+        let memory = vec![0x3E, 0x05, 0xC9, 0xFF];
+        let disassembly = disassemble(&memory, 0, 5, &[0]);
+        assert_eq!(disassembly.line_at(2).unwrap().text, "RET");
+        assert_eq!(disassembly.line_at(3).unwrap().text, "DB 0xFF");
+        let line = disassembly.line_at(4).unwrap();
+        assert_eq!(line.text, "DB ??, ??");
+        assert!(line.bytes.is_empty());
+        assert!(disassembly.to_string().contains("; 0004: ?? ??\n"));
+
+        // The operand of LD A, n is not readable
+        let disassembly = disassemble(&vec![0x3E], 0, 1, &[0]);
+        assert_eq!(disassembly.line_at(0).unwrap().text, "DB 0x3E");
+        assert_eq!(disassembly.line_at(1).unwrap().text, "DB ??");
+    }
+
+    #[test]
+    fn test_no_recorded_reads() {
+        let mut memory = Memory::new_full_ram();
+        memory.write_8(0x8000, 0xC9).unwrap();
+        let recorder = Arc::new(AccessRecorder::new());
+        memory.set_access_recorder(Some(recorder.clone()));
+        let disassembly = disassemble(&memory, 0x8000, 0x8000, &[0x8000]);
+        assert_eq!(disassembly.line_at(0x8000).unwrap().text, "RET");
+        assert!(recorder.take().is_empty());
+    }
+
+    #[test]
+    fn test_apply_symbols() {
+        let memory = vec![0xC3, 0x00, 0x90, 0x18, 0xFB];
+        let mut disassembly = disassemble(&memory, 0, 4, &[0, 3]);
+        let mut symbols = Symbols::new();
+        symbols.insert("start", 0x0000);
+        symbols.insert("far", 0x9000);
+        disassembly.apply_symbols(&symbols);
+        assert_eq!(disassembly.line_at(0).unwrap().text, "JP far");
+        assert_eq!(disassembly.line_at(3).unwrap().text, "JR start");
+        assert!(disassembly.to_string().contains("\nstart:\n"));
+    }
+}
@@ -2,6 +2,8 @@ use std::fmt;
 use std::fmt::Display;
 
 use crate::cpu::instruction::{BaseInstruction, ExecutableInstruction, InstructionCommon};
+use crate::cpu::metadata::InstructionMetadata;
+use crate::cpu::z80::metadata::describe;
 use crate::cpu::z80::instructions::bit::rlc::generics::rlc_r_setf;
 use crate::cpu::z80::Z80;
 use crate::io::IO;
@@ -43,6 +45,9 @@ impl BaseInstruction for RLC_PIXD {
     fn to_bytes(&self) -> Vec<u8> {
         vec![0xdd, 0xcb, self.d as u8, 0x06]
     }
+    fn metadata(&self) -> InstructionMetadata {
+        describe(self)
+    }
 }
 
 impl ExecutableInstruction<Z80> for RLC_PIXD {
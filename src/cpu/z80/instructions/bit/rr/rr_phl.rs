@@ -2,6 +2,8 @@ use std::fmt;
 use std::fmt::Display;
 
 use crate::cpu::instruction::{BaseInstruction, ExecutableInstruction, InstructionCommon};
+use crate::cpu::metadata::InstructionMetadata;
+use crate::cpu::z80::metadata::describe;
 use crate::cpu::z80::instructions::bit::rr::generics::rr_r_setf;
 use crate::cpu::z80::Z80;
 use crate::io::IO;
@@ -33,6 +35,9 @@ impl BaseInstruction for RR_PHL {
     fn to_bytes(&self) -> Vec<u8> {
         vec![0xcb, 0x1e]
     }
+    fn metadata(&self) -> InstructionMetadata {
+        describe(self)
+    }
 }
 
 impl ExecutableInstruction<Z80> for RR_PHL {
@@ -35,6 +35,9 @@ macro_rules! set_b_piyd {
                 fn to_bytes(&self) -> Vec<u8> {
                     vec![0xdd,0xcb,self.d as u8,hex!( $opcode )[0]]
                 }
+                fn metadata(&self) -> crate::cpu::metadata::InstructionMetadata {
+                    crate::cpu::z80::metadata::describe(self)
+                }
             }
 
             impl ExecutableInstruction<Z80> for [<SET_ $bit _PIYD>] {
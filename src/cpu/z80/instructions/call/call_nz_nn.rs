@@ -3,6 +3,8 @@ use std::fmt::Display;
 
 use crate::cpu::instruction::push_16;
 use crate::cpu::instruction::{BaseInstruction, ExecutableInstruction, InstructionCommon};
+use crate::cpu::metadata::InstructionMetadata;
+use crate::cpu::z80::metadata::describe;
 use crate::cpu::z80::Z80;
 use crate::io::IO;
 use crate::memory::errors::MemoryReadError;
@@ -44,6 +46,12 @@ impl BaseInstruction for CALL_NZ_NN {
         let nn_lsb = self.nn.to_le_bytes();
         vec![0xc4, nn_lsb[0], nn_lsb[1]]
     }
+    fn branch_cycles(&self) -> Option<(u16, u16)> {
+        Some((10, 17))
+    }
+    fn metadata(&self) -> InstructionMetadata {
+        describe(self)
+    }
 }
 
 impl ExecutableInstruction<Z80> for CALL_NZ_NN {
@@ -2,6 +2,8 @@ use std::fmt;
 use std::fmt::Display;
 
 use crate::cpu::instruction::{BaseInstruction, ExecutableInstruction, InstructionCommon};
+use crate::cpu::metadata::InstructionMetadata;
+use crate::cpu::z80::metadata::describe;
 use crate::cpu::z80::Z80;
 use crate::io::IO;
 use crate::memory::Memory;
@@ -30,7 +32,10 @@ impl BaseInstruction for CCF {
         &self.common
     }
     fn to_bytes(&self) -> Vec<u8> {
-        vec![0x37]
+        vec![0x3F]
+    }
+    fn metadata(&self) -> InstructionMetadata {
+        describe(self)
     }
 }
 
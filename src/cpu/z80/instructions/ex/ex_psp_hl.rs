@@ -2,6 +2,8 @@ use std::fmt;
 use std::fmt::Display;
 
 use crate::cpu::instruction::{BaseInstruction, ExecutableInstruction, InstructionCommon};
+use crate::cpu::metadata::InstructionMetadata;
+use crate::cpu::z80::metadata::describe;
 use crate::cpu::z80::Z80;
 use crate::io::IO;
 use crate::memory::Memory;
@@ -33,12 +35,17 @@ impl BaseInstruction for EX_PSP_HL {
     fn to_bytes(&self) -> Vec<u8> {
         vec![0xe3]
     }
+    fn metadata(&self) -> InstructionMetadata {
+        describe(self)
+    }
 }
 
 impl ExecutableInstruction<Z80> for EX_PSP_HL {
     fn execute(&mut self, memory: &mut Memory, cpu: &mut Z80, _: &mut IO) -> Result<(), String> {
         let val = memory.read_16(cpu.registers.sp)?;
-        memory.write_16(cpu.registers.sp, cpu.registers.gp.hl)?;
+        let bytes = cpu.registers.gp.hl.to_le_bytes();
+        memory.write_8(cpu.registers.sp.wrapping_add(1), bytes[1])?;
+        memory.write_8(cpu.registers.sp, bytes[0])?;
         cpu.registers.gp.hl = val;
         Ok(())
     }
@@ -3,6 +3,8 @@ use std::fmt;
 use std::fmt::Display;
 
 use crate::cpu::instruction::{pop_16, BaseInstruction, ExecutableInstruction, InstructionCommon};
+use crate::cpu::metadata::InstructionMetadata;
+use crate::cpu::z80::metadata::describe;
 use crate::cpu::z80::Z80;
 use crate::io::IO;
 use crate::memory::Memory;
@@ -33,6 +35,9 @@ impl BaseInstruction for IM0 {
     fn to_bytes(&self) -> Vec<u8> {
         vec![0xed, 0x46]
     }
+    fn metadata(&self) -> InstructionMetadata {
+        describe(self)
+    }
 }
 
 impl ExecutableInstruction<Z80> for IM0 {
@@ -2,6 +2,8 @@ use std::fmt;
 use std::fmt::Display;
 
 use crate::cpu::instruction::{BaseInstruction, ExecutableInstruction, InstructionCommon};
+use crate::cpu::metadata::InstructionMetadata;
+use crate::cpu::z80::metadata::describe;
 use crate::cpu::z80::Z80;
 use crate::io::IO;
 use crate::memory::errors::MemoryReadError;
@@ -42,6 +44,12 @@ impl BaseInstruction for JR_NC_D {
     fn to_bytes(&self) -> Vec<u8> {
         vec![0x30, self.d as u8]
     }
+    fn branch_cycles(&self) -> Option<(u16, u16)> {
+        Some((7, 12))
+    }
+    fn metadata(&self) -> InstructionMetadata {
+        describe(self)
+    }
 }
 
 impl ExecutableInstruction<Z80> for JR_NC_D {
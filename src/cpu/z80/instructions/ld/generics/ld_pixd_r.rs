@@ -37,6 +37,9 @@ macro_rules! ld_pixd_r {
                 fn to_bytes(&self) -> Vec<u8> {
                     vec![0xdd,hex!( $opcode )[0], self.d as u8]
                 }
+                fn metadata(&self) -> crate::cpu::metadata::InstructionMetadata {
+                    crate::cpu::z80::metadata::describe(self)
+                }
             }
 
             impl ExecutableInstruction<Z80> for [<LD_PIXD_ $cdest>] {
@@ -37,6 +37,9 @@ macro_rules! ld_piyd_r {
                 fn to_bytes(&self) -> Vec<u8> {
                     vec![0xfd,hex!( $opcode )[0], self.d as u8]
                 }
+                fn metadata(&self) -> crate::cpu::metadata::InstructionMetadata {
+                    crate::cpu::z80::metadata::describe(self)
+                }
             }
 
             impl ExecutableInstruction<Z80> for [<LD_PIYD_ $cdest>] {
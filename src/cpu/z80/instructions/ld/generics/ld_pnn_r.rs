@@ -38,6 +38,9 @@ macro_rules! ld_pnn_r {
                     let nn_lsb = self.nn.to_le_bytes();
                     vec![hex!( $opcode )[0], nn_lsb[0], nn_lsb[1]]
                 }
+                fn metadata(&self) -> crate::cpu::metadata::InstructionMetadata {
+                    crate::cpu::z80::metadata::describe(self)
+                }
             }
 
             impl ExecutableInstruction<Z80> for [<LD_PNN_ $cdest>] {
@@ -18,7 +18,7 @@ macro_rules! ld_r_pnn {
 
                 pub fn new_with_value(nn: u16) -> [<LD_ $cdest _PNN>] {
                     [<LD_ $cdest _PNN>] {
-                        common: InstructionCommon::new(3, 16, true),
+                        common: InstructionCommon::new(3, 13, true),
                         nn,
                     }
                 }
@@ -38,6 +38,9 @@ macro_rules! ld_r_pnn {
                     let nn_lsb = self.nn.to_le_bytes();
                     vec![hex!( $opcode )[0], nn_lsb[0], nn_lsb[1]]
                 }
+                fn metadata(&self) -> crate::cpu::metadata::InstructionMetadata {
+                    crate::cpu::z80::metadata::describe(self)
+                }
             }
 
             impl ExecutableInstruction<Z80> for [<LD_ $cdest _PNN>] {
@@ -2,6 +2,8 @@ use std::fmt;
 use std::fmt::Display;
 
 use crate::cpu::instruction::{BaseInstruction, ExecutableInstruction, InstructionCommon};
+use crate::cpu::metadata::InstructionMetadata;
+use crate::cpu::z80::metadata::describe;
 use crate::cpu::z80::Z80;
 use crate::io::IO;
 use crate::memory::Memory;
@@ -30,7 +32,10 @@ impl BaseInstruction for LD_SP_IY {
         &self.common
     }
     fn to_bytes(&self) -> Vec<u8> {
-        vec![0xfd, 0xe9]
+        vec![0xfd, 0xf9]
+    }
+    fn metadata(&self) -> InstructionMetadata {
+        describe(self)
     }
 }
 
@@ -2,6 +2,8 @@ use std::fmt;
 use std::fmt::Display;
 
 use crate::cpu::instruction::{BaseInstruction, ExecutableInstruction, InstructionCommon};
+use crate::cpu::metadata::InstructionMetadata;
+use crate::cpu::z80::metadata::describe;
 use crate::cpu::z80::Z80;
 use crate::io::IO;
 use crate::memory::Memory;
@@ -22,7 +24,7 @@ impl ADC_A_PHL {
 
 impl Display for ADC_A_PHL {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "LD A, (HL)",)
+        write!(f, "ADC A, (HL)",)
     }
 }
 
@@ -33,6 +35,9 @@ impl BaseInstruction for ADC_A_PHL {
     fn to_bytes(&self) -> Vec<u8> {
         vec![0x8E]
     }
+    fn metadata(&self) -> InstructionMetadata {
+        describe(self)
+    }
 }
 
 impl ExecutableInstruction<Z80> for ADC_A_PHL {
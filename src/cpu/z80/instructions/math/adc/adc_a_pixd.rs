@@ -2,6 +2,8 @@ use std::fmt;
 use std::fmt::Display;
 
 use crate::cpu::instruction::{BaseInstruction, ExecutableInstruction, InstructionCommon};
+use crate::cpu::metadata::InstructionMetadata;
+use crate::cpu::z80::metadata::describe;
 use crate::cpu::z80::instructions::math::adc::generics::adc_r_r_setf;
 use crate::cpu::z80::Z80;
 use crate::io::IO;
@@ -44,6 +46,9 @@ impl BaseInstruction for ADC_A_PIXD {
     fn to_bytes(&self) -> Vec<u8> {
         vec![0xdd, 0x8e, self.d as u8]
     }
+    fn metadata(&self) -> InstructionMetadata {
+        describe(self)
+    }
 }
 
 impl ExecutableInstruction<Z80> for ADC_A_PIXD {
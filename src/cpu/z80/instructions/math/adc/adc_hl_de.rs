@@ -3,6 +3,8 @@ use std::fmt;
 use std::fmt::Display;
 
 use crate::cpu::instruction::{BaseInstruction, ExecutableInstruction, InstructionCommon};
+use crate::cpu::metadata::InstructionMetadata;
+use crate::cpu::z80::metadata::describe;
 use crate::cpu::z80::instructions::math::adc::generics::adc_rr_rr_setf;
 use crate::cpu::z80::Z80;
 use crate::io::IO;
@@ -36,6 +38,9 @@ impl BaseInstruction for ADC_HL_DE {
     fn to_bytes(&self) -> Vec<u8> {
         vec![0xed, 0x5A]
     }
+    fn metadata(&self) -> InstructionMetadata {
+        describe(self)
+    }
 }
 
 impl ExecutableInstruction<Z80> for ADC_HL_DE {
@@ -28,6 +28,9 @@ macro_rules! adc_r_r {
                 fn to_bytes(&self) -> Vec<u8> {
                     vec![hex!( $opcode )[0]]
                 }
+                fn metadata(&self) -> crate::cpu::metadata::InstructionMetadata {
+                    crate::cpu::z80::metadata::describe(self)
+                }
             }
 
             impl ExecutableInstruction<Z80> for [<ADC_ $cdest _ $csrc>] {
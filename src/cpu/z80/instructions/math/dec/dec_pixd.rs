@@ -2,6 +2,8 @@ use std::fmt;
 use std::fmt::Display;
 
 use crate::cpu::instruction::{BaseInstruction, ExecutableInstruction, InstructionCommon};
+use crate::cpu::metadata::InstructionMetadata;
+use crate::cpu::z80::metadata::describe;
 use crate::cpu::z80::Z80;
 use crate::io::IO;
 use crate::memory::errors::MemoryReadError;
@@ -24,7 +26,7 @@ impl DEC_PIXD {
 
     pub fn new_with_value(d: i8) -> DEC_PIXD {
         DEC_PIXD {
-            common: InstructionCommon::new(2, 23, true),
+            common: InstructionCommon::new(3, 23, true),
             d,
         }
     }
@@ -43,6 +45,9 @@ impl BaseInstruction for DEC_PIXD {
     fn to_bytes(&self) -> Vec<u8> {
         vec![0xdd, 0x35, self.d as u8]
     }
+    fn metadata(&self) -> InstructionMetadata {
+        describe(self)
+    }
 }
 
 impl ExecutableInstruction<Z80> for DEC_PIXD {
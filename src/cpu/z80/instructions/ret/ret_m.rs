@@ -3,6 +3,8 @@ use std::fmt::Display;
 
 use crate::cpu::instruction::pop_16;
 use crate::cpu::instruction::{BaseInstruction, ExecutableInstruction, InstructionCommon};
+use crate::cpu::metadata::InstructionMetadata;
+use crate::cpu::z80::metadata::describe;
 use crate::cpu::z80::Z80;
 use crate::io::IO;
 use crate::memory::{Memory, MemoryDevice};
@@ -33,6 +35,12 @@ impl BaseInstruction for RET_M {
     fn to_bytes(&self) -> Vec<u8> {
         vec![0xf8]
     }
+    fn branch_cycles(&self) -> Option<(u16, u16)> {
+        Some((5, 11))
+    }
+    fn metadata(&self) -> InstructionMetadata {
+        describe(self)
+    }
 }
 
 impl ExecutableInstruction<Z80> for RET_M {
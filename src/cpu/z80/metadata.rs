@@ -0,0 +1,779 @@
+use crate::cpu::instruction::BaseInstruction;
+use crate::cpu::metadata::{AccessKind, BranchTarget, FlowKind, InstructionMetadata, Operand};
+use crate::cpu::registers::Flags;
+
+const FLAG_C: u8 = 0x01;
+const FLAG_N: u8 = 0x02;
+const FLAG_PV: u8 = 0x04;
+const FLAG_H: u8 = 0x10;
+const FLAG_Z: u8 = 0x40;
+const FLAG_S: u8 = 0x80;
+const ALL_FLAGS: u8 = 0xFF;
+/// INC, DEC, BIT, IN r,(C) and friends leave only carry alone
+const ALL_BUT_CARRY: u8 = !FLAG_C;
+/// ADD HL,rr, the accumulator rotates and SCF/CCF keep S, Z and P/V
+const KEEP_SZPV: u8 = !(FLAG_S | FLAG_Z | FLAG_PV);
+/// LDI and friends keep S, Z and C
+const KEEP_SZC: u8 = !(FLAG_S | FLAG_Z | FLAG_C);
+/// CPL sets H and N and copies bits 3 and 5 from A
+const CPL_FLAGS: u8 = 0x3A;
+
+const REG8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "(hl)", "a"];
+const REG16_SP: [&str; 4] = ["bc", "de", "hl", "sp"];
+const REG16_AF: [&str; 4] = ["bc", "de", "hl", "af"];
+const CONDITIONS: [&str; 8] = ["nz", "z", "nc", "c", "po", "pe", "p", "m"];
+const CONDITION_FLAGS: [u8; 4] = [FLAG_Z, FLAG_C, FLAG_PV, FLAG_S];
+const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBC", "AND", "XOR", "OR", "CP"];
+const ROTATIONS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+const INTERRUPT_MODES: [u8; 8] = [0, 0, 1, 2, 0, 0, 1, 2];
+const BLOCK: [[&str; 4]; 4] = [
+    ["LDI", "CPI", "INI", "OUTI"],
+    ["LDD", "CPD", "IND", "OUTD"],
+    ["LDIR", "CPIR", "INIR", "OTIR"],
+    ["LDDR", "CPDR", "INDR", "OTDR"],
+];
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// Set after a DD or FD prefix, replaces HL with IX or IY
+    index: Option<&'static str>,
+    displacement: Option<i8>,
+    meta: InstructionMetadata,
+}
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> u8 {
+        let byte = self.bytes.get(self.pos).copied().unwrap_or(0);
+        self.pos += 1;
+        byte
+    }
+
+    fn word(&mut self) -> u16 {
+        u16::from_le_bytes([self.byte(), self.byte()])
+    }
+
+    fn displacement(&mut self) -> i8 {
+        match self.displacement {
+            Some(d) => d,
+            None => {
+                let d = self.byte() as i8;
+                self.displacement = Some(d);
+                d
+            }
+        }
+    }
+
+    fn hl(&self) -> &'static str {
+        self.index.unwrap_or("hl")
+    }
+
+    fn reg16_sp(&self, p: u8) -> &'static str {
+        match p {
+            2 => self.hl(),
+            _ => REG16_SP[p as usize],
+        }
+    }
+
+    fn reg16_af(&self, p: u8) -> &'static str {
+        match p {
+            2 => self.hl(),
+            _ => REG16_AF[p as usize],
+        }
+    }
+
+    /// 8 bit register operand, `(hl)` becomes `(ix+d)` and, when the instruction has no
+    /// memory operand, H and L become the index register halves.
+    fn reg8(&mut self, z: u8, uses_memory: bool) -> Operand {
+        match (z, self.index) {
+            (6, Some(index)) => Operand::Indexed(index, self.displacement()),
+            (6, None) => Operand::Indirect("hl"),
+            (4, Some("ix")) if !uses_memory => Operand::Register("ixh"),
+            (5, Some("ix")) if !uses_memory => Operand::Register("ixl"),
+            (4, Some("iy")) if !uses_memory => Operand::Register("iyh"),
+            (5, Some("iy")) if !uses_memory => Operand::Register("iyl"),
+            _ => Operand::Register(REG8[z as usize]),
+        }
+    }
+
+    fn read(&mut self, register: &'static str) {
+        if !self.meta.reads.contains(&register) {
+            self.meta.reads.push(register);
+        }
+    }
+
+    fn write(&mut self, register: &'static str) {
+        if !self.meta.writes.contains(&register) {
+            self.meta.writes.push(register);
+        }
+    }
+
+    fn memory(&mut self, access: AccessKind) {
+        self.meta.memory = self.meta.memory.merge(access);
+    }
+
+    fn io(&mut self, access: AccessKind) {
+        self.meta.io = self.meta.io.merge(access);
+    }
+
+    fn flags(&mut self, read: u8, written: u8) {
+        self.meta.flags_read = Flags::from_bits(self.meta.flags_read.into_bits() | read);
+        self.meta.flags_written = Flags::from_bits(self.meta.flags_written.into_bits() | written);
+    }
+
+    /// Records the registers and memory an operand touches.
+    fn access(&mut self, operand: Operand, access: AccessKind) {
+        match operand {
+            Operand::Register(name) => {
+                if access.reads() {
+                    self.read(name);
+                }
+                if access.writes() {
+                    self.write(name);
+                }
+            }
+            Operand::Indirect(name) | Operand::Indexed(name, _) => {
+                self.read(name);
+                self.memory(access);
+            }
+            Operand::Absolute(_) => self.memory(access),
+            Operand::Port(_) => {
+                self.read("a");
+                self.io(access);
+            }
+            Operand::PortC => {
+                self.read("bc");
+                self.io(access);
+            }
+            _ => {}
+        }
+    }
+
+    fn ins(&mut self, mnemonic: &'static str, operands: Vec<Operand>) {
+        self.meta.mnemonic = mnemonic;
+        self.meta.operands = operands;
+    }
+
+    fn condition(&mut self, y: u8) -> Operand {
+        self.meta.conditional = true;
+        self.flags(CONDITION_FLAGS[(y >> 1) as usize], 0);
+        Operand::Condition(CONDITIONS[y as usize])
+    }
+
+    fn branch(&mut self, flow: FlowKind, target: BranchTarget) {
+        self.meta.flow = flow;
+        self.meta.target = Some(target);
+    }
+
+    fn stack(&mut self, access: AccessKind) {
+        self.read("sp");
+        self.write("sp");
+        self.memory(access);
+    }
+
+    fn decode(&mut self) {
+        let op = self.byte();
+        match op {
+            0xDD | 0xFD if self.index.is_none() => {
+                let next = self.bytes.get(self.pos).copied().unwrap_or(0);
+                if matches!(next, 0xDD | 0xED | 0xFD) {
+                    // A prefix followed by another prefix does nothing
+                    self.ins("NOP", vec![]);
+                    return;
+                }
+                self.index = Some(if op == 0xDD { "ix" } else { "iy" });
+                self.decode();
+            }
+            0xCB => self.decode_cb(),
+            0xED if self.index.is_none() => self.decode_ed(),
+            _ => self.decode_main(op),
+        }
+    }
+
+    fn decode_main(&mut self, op: u8) {
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let (p, q) = (y >> 1, y & 1);
+        match (x, z) {
+            (0, 0) => match y {
+                0 => self.ins("NOP", vec![]),
+                1 => {
+                    self.ins(
+                        "EX",
+                        vec![Operand::Register("af"), Operand::Register("af'")],
+                    );
+                    for reg in ["af", "af'"] {
+                        self.read(reg);
+                        self.write(reg);
+                    }
+                    // The flags are swapped with the alternate ones
+                    self.flags(ALL_FLAGS, ALL_FLAGS);
+                }
+                2 => {
+                    let d = self.byte() as i8;
+                    self.ins("DJNZ", vec![Operand::Relative(d)]);
+                    self.meta.conditional = true;
+                    self.read("b");
+                    self.write("b");
+                    self.branch(FlowKind::Jump, BranchTarget::Relative(d));
+                }
+                3 => {
+                    let d = self.byte() as i8;
+                    self.ins("JR", vec![Operand::Relative(d)]);
+                    self.branch(FlowKind::Jump, BranchTarget::Relative(d));
+                }
+                _ => {
+                    let condition = self.condition(y - 4);
+                    let d = self.byte() as i8;
+                    self.ins("JR", vec![condition, Operand::Relative(d)]);
+                    self.branch(FlowKind::Jump, BranchTarget::Relative(d));
+                }
+            },
+            (0, 1) => {
+                let rr = self.reg16_sp(p);
+                if q == 0 {
+                    let nn = self.word();
+                    self.ins("LD", vec![Operand::Register(rr), Operand::Immediate16(nn)]);
+                    self.write(rr);
+                } else {
+                    let hl = self.hl();
+                    self.ins("ADD", vec![Operand::Register(hl), Operand::Register(rr)]);
+                    self.read(hl);
+                    self.read(rr);
+                    self.write(hl);
+                    self.flags(0, KEEP_SZPV);
+                }
+            }
+            (0, 2) => {
+                let (memory, register) = match p {
+                    0 => (Operand::Indirect("bc"), "a"),
+                    1 => (Operand::Indirect("de"), "a"),
+                    2 => (Operand::Absolute(self.word()), self.hl()),
+                    _ => (Operand::Absolute(self.word()), "a"),
+                };
+                if q == 0 {
+                    self.ins("LD", vec![memory, Operand::Register(register)]);
+                    self.access(memory, AccessKind::Write);
+                    self.read(register);
+                } else {
+                    self.ins("LD", vec![Operand::Register(register), memory]);
+                    self.access(memory, AccessKind::Read);
+                    self.write(register);
+                }
+            }
+            (0, 3) => {
+                let rr = self.reg16_sp(p);
+                self.ins(
+                    if q == 0 { "INC" } else { "DEC" },
+                    vec![Operand::Register(rr)],
+                );
+                self.read(rr);
+                self.write(rr);
+            }
+            (0, 4) | (0, 5) => {
+                let r = self.reg8(y, y == 6);
+                self.ins(if z == 4 { "INC" } else { "DEC" }, vec![r]);
+                self.access(r, AccessKind::ReadWrite);
+                self.flags(0, ALL_BUT_CARRY);
+            }
+            (0, 6) => {
+                let r = self.reg8(y, y == 6);
+                let n = self.byte();
+                self.ins("LD", vec![r, Operand::Immediate8(n)]);
+                self.access(r, AccessKind::Write);
+            }
+            (0, 7) => {
+                let (mnemonic, read, written) = match y {
+                    0 => ("RLCA", 0, KEEP_SZPV),
+                    1 => ("RRCA", 0, KEEP_SZPV),
+                    2 => ("RLA", FLAG_C, KEEP_SZPV),
+                    3 => ("RRA", FLAG_C, KEEP_SZPV),
+                    4 => ("DAA", FLAG_C | FLAG_H | FLAG_N, ALL_FLAGS),
+                    5 => ("CPL", 0, CPL_FLAGS),
+                    6 => ("SCF", 0, KEEP_SZPV),
+                    _ => ("CCF", FLAG_C, KEEP_SZPV),
+                };
+                self.ins(mnemonic, vec![]);
+                if y <= 5 {
+                    self.read("a");
+                    self.write("a");
+                }
+                self.flags(read, written);
+            }
+            (1, 6) if y == 6 => {
+                self.ins("HALT", vec![]);
+                self.meta.flow = FlowKind::Halt;
+            }
+            (1, _) => {
+                let uses_memory = y == 6 || z == 6;
+                let dst = self.reg8(y, uses_memory);
+                let src = self.reg8(z, uses_memory);
+                self.ins("LD", vec![dst, src]);
+                self.access(src, AccessKind::Read);
+                self.access(dst, AccessKind::Write);
+            }
+            (2, _) => {
+                let src = self.reg8(z, z == 6);
+                self.alu(y, src);
+            }
+            (3, 0) => {
+                let condition = self.condition(y);
+                self.ins("RET", vec![condition]);
+                self.meta.flow = FlowKind::Return;
+                self.stack(AccessKind::Read);
+            }
+            (3, 1) if q == 0 => {
+                let rr = self.reg16_af(p);
+                self.ins("POP", vec![Operand::Register(rr)]);
+                self.stack(AccessKind::Read);
+                self.write(rr);
+                if rr == "af" {
+                    self.flags(0, ALL_FLAGS);
+                }
+            }
+            (3, 1) => match p {
+                0 => {
+                    self.ins("RET", vec![]);
+                    self.meta.flow = FlowKind::Return;
+                    self.stack(AccessKind::Read);
+                }
+                1 => {
+                    self.ins("EXX", vec![]);
+                    for reg in ["bc", "de", "hl", "bc'", "de'", "hl'"] {
+                        self.read(reg);
+                        self.write(reg);
+                    }
+                }
+                2 => {
+                    let hl = self.hl();
+                    self.ins("JP", vec![Operand::Indirect(hl)]);
+                    self.read(hl);
+                    self.branch(FlowKind::Jump, BranchTarget::Register(hl));
+                }
+                _ => {
+                    let hl = self.hl();
+                    self.ins("LD", vec![Operand::Register("sp"), Operand::Register(hl)]);
+                    self.read(hl);
+                    self.write("sp");
+                }
+            },
+            (3, 2) => {
+                let condition = self.condition(y);
+                let nn = self.word();
+                self.ins("JP", vec![condition, Operand::Immediate16(nn)]);
+                self.branch(FlowKind::Jump, BranchTarget::Absolute(nn));
+            }
+            (3, 3) => match y {
+                0 => {
+                    let nn = self.word();
+                    self.ins("JP", vec![Operand::Immediate16(nn)]);
+                    self.branch(FlowKind::Jump, BranchTarget::Absolute(nn));
+                }
+                2 => {
+                    let port = Operand::Port(self.byte());
+                    self.ins("OUT", vec![port, Operand::Register("a")]);
+                    self.access(port, AccessKind::Write);
+                }
+                3 => {
+                    let port = Operand::Port(self.byte());
+                    self.ins("IN", vec![Operand::Register("a"), port]);
+                    self.access(port, AccessKind::Read);
+                    self.write("a");
+                }
+                4 => {
+                    let hl = self.hl();
+                    self.ins("EX", vec![Operand::Indirect("sp"), Operand::Register(hl)]);
+                    self.access(Operand::Indirect("sp"), AccessKind::ReadWrite);
+                    self.read(hl);
+                    self.write(hl);
+                }
+                5 => {
+                    // Not affected by the index prefixes
+                    self.ins("EX", vec![Operand::Register("de"), Operand::Register("hl")]);
+                    for reg in ["de", "hl"] {
+                        self.read(reg);
+                        self.write(reg);
+                    }
+                }
+                6 => self.ins("DI", vec![]),
+                // y == 1 is the CB prefix, handled before
+                _ => self.ins("EI", vec![]),
+            },
+            (3, 4) => {
+                let condition = self.condition(y);
+                let nn = self.word();
+                self.ins("CALL", vec![condition, Operand::Immediate16(nn)]);
+                self.branch(FlowKind::Call, BranchTarget::Absolute(nn));
+                self.stack(AccessKind::Write);
+            }
+            (3, 5) => {
+                // q == 1 with p != 0 are the prefixes, handled before
+                if q == 0 {
+                    let rr = self.reg16_af(p);
+                    self.ins("PUSH", vec![Operand::Register(rr)]);
+                    self.read(rr);
+                    self.stack(AccessKind::Write);
+                    if rr == "af" {
+                        self.flags(ALL_FLAGS, 0);
+                    }
+                } else {
+                    let nn = self.word();
+                    self.ins("CALL", vec![Operand::Immediate16(nn)]);
+                    self.branch(FlowKind::Call, BranchTarget::Absolute(nn));
+                    self.stack(AccessKind::Write);
+                }
+            }
+            (3, 6) => {
+                let n = self.byte();
+                self.alu(y, Operand::Immediate8(n));
+            }
+            _ => {
+                let target = (y * 8) as u16;
+                self.ins("RST", vec![Operand::Immediate8(y * 8)]);
+                self.branch(FlowKind::Restart, BranchTarget::Absolute(target));
+                self.stack(AccessKind::Write);
+            }
+        }
+    }
+
+    fn alu(&mut self, y: u8, src: Operand) {
+        let mnemonic = ALU[y as usize];
+        let operands = match y {
+            0 | 1 | 3 => vec![Operand::Register("a"), src],
+            _ => vec![src],
+        };
+        self.ins(mnemonic, operands);
+        self.read("a");
+        self.access(src, AccessKind::Read);
+        if y != 7 {
+            self.write("a");
+        }
+        let carry_in = if y == 1 || y == 3 { FLAG_C } else { 0 };
+        self.flags(carry_in, ALL_FLAGS);
+    }
+
+    fn decode_cb(&mut self) {
+        if self.index.is_some() {
+            // DD CB d op, the displacement comes before the opcode
+            self.displacement();
+        }
+        let op = self.byte();
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let target = self.reg8(if self.index.is_some() { 6 } else { z }, true);
+        let bit = Operand::Bit(y);
+        match x {
+            0 => {
+                self.ins(ROTATIONS[y as usize], vec![target]);
+                self.access(target, AccessKind::ReadWrite);
+                let carry_in = if y == 2 || y == 3 { FLAG_C } else { 0 };
+                self.flags(carry_in, ALL_FLAGS);
+            }
+            1 => {
+                self.ins("BIT", vec![bit, target]);
+                self.access(target, AccessKind::Read);
+                self.flags(0, ALL_BUT_CARRY);
+            }
+            _ => {
+                self.ins(if x == 2 { "RES" } else { "SET" }, vec![bit, target]);
+                self.access(target, AccessKind::ReadWrite);
+            }
+        }
+        // Undocumented indexed forms also copy the result to a register
+        if self.index.is_some() && z != 6 && x != 1 {
+            self.write(REG8[z as usize]);
+        }
+    }
+
+    fn decode_ed(&mut self) {
+        let op = self.byte();
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let (p, q) = (y >> 1, y & 1);
+        match (x, z) {
+            (1, 0) => {
+                if y == 6 {
+                    self.ins("IN", vec![Operand::PortC]);
+                } else {
+                    self.ins(
+                        "IN",
+                        vec![Operand::Register(REG8[y as usize]), Operand::PortC],
+                    );
+                    self.write(REG8[y as usize]);
+                }
+                self.access(Operand::PortC, AccessKind::Read);
+                self.flags(0, ALL_BUT_CARRY);
+            }
+            (1, 1) => {
+                if y == 6 {
+                    self.ins("OUT", vec![Operand::PortC, Operand::Immediate8(0)]);
+                } else {
+                    self.ins(
+                        "OUT",
+                        vec![Operand::PortC, Operand::Register(REG8[y as usize])],
+                    );
+                    self.read(REG8[y as usize]);
+                }
+                self.access(Operand::PortC, AccessKind::Write);
+            }
+            (1, 2) => {
+                let rr = REG16_SP[p as usize];
+                self.ins(
+                    if q == 0 { "SBC" } else { "ADC" },
+                    vec![Operand::Register("hl"), Operand::Register(rr)],
+                );
+                self.read("hl");
+                self.read(rr);
+                self.write("hl");
+                self.flags(FLAG_C, ALL_FLAGS);
+            }
+            (1, 3) => {
+                let rr = REG16_SP[p as usize];
+                let memory = Operand::Absolute(self.word());
+                if q == 0 {
+                    self.ins("LD", vec![memory, Operand::Register(rr)]);
+                    self.access(memory, AccessKind::Write);
+                    self.read(rr);
+                } else {
+                    self.ins("LD", vec![Operand::Register(rr), memory]);
+                    self.access(memory, AccessKind::Read);
+                    self.write(rr);
+                }
+            }
+            (1, 4) => {
+                self.ins("NEG", vec![]);
+                self.read("a");
+                self.write("a");
+                self.flags(0, ALL_FLAGS);
+            }
+            (1, 5) => {
+                self.ins(if y == 1 { "RETI" } else { "RETN" }, vec![]);
+                self.meta.flow = FlowKind::Return;
+                self.stack(AccessKind::Read);
+            }
+            (1, 6) => {
+                self.ins(
+                    "IM",
+                    vec![Operand::InterruptMode(INTERRUPT_MODES[y as usize])],
+                );
+            }
+            (1, 7) => match y {
+                0 | 1 => {
+                    let dst = if y == 0 { "i" } else { "r" };
+                    self.ins("LD", vec![Operand::Register(dst), Operand::Register("a")]);
+                    self.read("a");
+                    self.write(dst);
+                }
+                2 | 3 => {
+                    let src = if y == 2 { "i" } else { "r" };
+                    self.ins("LD", vec![Operand::Register("a"), Operand::Register(src)]);
+                    self.read(src);
+                    self.write("a");
+                    self.flags(0, ALL_BUT_CARRY);
+                }
+                4 | 5 => {
+                    self.ins(if y == 4 { "RRD" } else { "RLD" }, vec![]);
+                    self.read("a");
+                    self.write("a");
+                    self.access(Operand::Indirect("hl"), AccessKind::ReadWrite);
+                    self.flags(0, ALL_BUT_CARRY);
+                }
+                _ => self.ins("NOP", vec![]),
+            },
+            (2, 0..=3) if y >= 4 => {
+                let repeats = y >= 6;
+                self.ins(BLOCK[(y - 4) as usize][z as usize], vec![]);
+                self.meta.repeats = repeats;
+                self.meta.conditional = repeats;
+                self.read("hl");
+                self.write("hl");
+                match z {
+                    0 => {
+                        self.read("de");
+                        self.read("bc");
+                        self.write("de");
+                        self.write("bc");
+                        self.memory(AccessKind::ReadWrite);
+                        self.flags(0, KEEP_SZC);
+                    }
+                    1 => {
+                        self.read("a");
+                        self.read("bc");
+                        self.write("bc");
+                        self.memory(AccessKind::Read);
+                        self.flags(FLAG_C, ALL_BUT_CARRY);
+                    }
+                    2 => {
+                        self.read("bc");
+                        self.write("b");
+                        self.memory(AccessKind::Write);
+                        self.io(AccessKind::Read);
+                        self.flags(0, ALL_FLAGS);
+                    }
+                    _ => {
+                        self.read("bc");
+                        self.write("b");
+                        self.memory(AccessKind::Read);
+                        self.io(AccessKind::Write);
+                        self.flags(0, ALL_FLAGS);
+                    }
+                }
+            }
+            // Unassigned ED opcodes behave as two byte NOPs
+            _ => self.ins("NOP", vec![]),
+        }
+    }
+}
+
+/// Decodes the operands, control flow and register, memory and flag usage of the Z80
+/// instruction at the start of `bytes`. Missing trailing bytes read as zero.
+pub(crate) fn decode(bytes: &[u8]) -> InstructionMetadata {
+    let mut decoder = Decoder {
+        bytes,
+        pos: 0,
+        index: None,
+        displacement: None,
+        meta: InstructionMetadata::default(),
+    };
+    decoder.decode();
+    decoder.meta
+}
+
+/// Describes a Z80 instruction from its encoding, with the length and cycles the
+/// instruction itself gives.
+pub fn describe(instruction: &dyn BaseInstruction) -> InstructionMetadata {
+    let common = instruction.common();
+    let mut meta = decode(&instruction.to_bytes());
+    meta.length = common.length;
+    (meta.cycles, meta.cycles_taken) = match instruction.branch_cycles() {
+        Some((cycles, taken)) => (cycles, Some(taken)),
+        None => (common.cycles, None),
+    };
+    meta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::instruction::InstructionParser;
+    use crate::cpu::z80::parser::Z80_PARSER;
+
+    fn parsed(bytes: &[u8]) -> InstructionMetadata {
+        Z80_PARSER
+            .ins_from_machinecode(&bytes.to_vec(), 0)
+            .expect("Failed to parse instruction")
+            .metadata()
+    }
+
+    #[test]
+    fn test_conditional_relative_jump() {
+        let meta = parsed(&[0x20, 0xFE]);
+        assert_eq!(meta.mnemonic, "JR");
+        assert_eq!(meta.length, 2);
+        assert_eq!((meta.cycles, meta.cycles_taken), (7, Some(12)));
+        assert_eq!(meta.flow, FlowKind::Jump);
+        assert!(meta.conditional);
+        assert_eq!(meta.target_address(0x8000), Some(0x8000));
+        assert!(meta.flags_read.zero());
+        assert!(meta.falls_through());
+    }
+
+    #[test]
+    fn test_indexed_operands() {
+        let meta = parsed(&[0xDD, 0x36, 0x05, 0x42]);
+        assert_eq!(meta.mnemonic, "LD");
+        assert_eq!(meta.length, 4);
+        assert_eq!(
+            meta.operands,
+            vec![Operand::Indexed("ix", 5), Operand::Immediate8(0x42)]
+        );
+        assert_eq!(meta.memory, AccessKind::Write);
+        assert!(meta.reads_register("ix"));
+
+        let meta = parsed(&[0xFD, 0xCB, 0xFE, 0x46]);
+        assert_eq!(meta.mnemonic, "BIT");
+        assert_eq!(meta.length, 4);
+        assert_eq!(
+            meta.operands,
+            vec![Operand::Bit(0), Operand::Indexed("iy", -2)]
+        );
+
+        let meta = decode(&[0xDD, 0x7C]);
+        assert_eq!(
+            meta.operands,
+            vec![Operand::Register("a"), Operand::Register("ixh")]
+        );
+    }
+
+    #[test]
+    fn test_calls_and_returns() {
+        let meta = decode(&[0xCD, 0x34, 0x12]);
+        assert!(meta.is_subroutine_call());
+        assert_eq!(meta.target_address(0), Some(0x1234));
+        assert_eq!(meta.memory, AccessKind::Write);
+        assert!(meta.writes_register("sp"));
+
+        let meta = decode(&[0xFF]);
+        assert_eq!(meta.flow, FlowKind::Restart);
+        assert_eq!(meta.target_address(0), Some(0x38));
+
+        let meta = decode(&[0xC9]);
+        assert_eq!(meta.flow, FlowKind::Return);
+        assert!(!meta.falls_through());
+
+        let meta = decode(&[0xED, 0x4D]);
+        assert_eq!(meta.mnemonic, "RETI");
+
+        let meta = decode(&[0xFD, 0xE9]);
+        assert_eq!(meta.target, Some(BranchTarget::Register("iy")));
+        assert_eq!(meta.target_address(0), None);
+    }
+
+    #[test]
+    fn test_block_instructions() {
+        let meta = parsed(&[0xED, 0xB0]);
+        assert_eq!(meta.mnemonic, "LDIR");
+        assert!(meta.repeats);
+        assert_eq!((meta.cycles, meta.cycles_taken), (16, Some(21)));
+        assert_eq!(meta.memory, AccessKind::ReadWrite);
+        assert_eq!(meta.flags_written.into_bits(), KEEP_SZC);
+
+        let meta = decode(&[0xED, 0xB3]);
+        assert_eq!(meta.mnemonic, "OTIR");
+        assert_eq!(meta.io, AccessKind::Write);
+    }
+
+    #[test]
+    fn test_flags() {
+        let meta = decode(&[0x8F]);
+        assert_eq!(meta.mnemonic, "ADC");
+        assert!(meta.flags_read.carry());
+        assert_eq!(meta.flags_written.into_bits(), ALL_FLAGS);
+
+        let meta = decode(&[0x3C]);
+        assert!(!meta.flags_written.carry());
+        assert!(meta.flags_written.zero());
+
+        let meta = decode(&[0x01, 0x00, 0x00]);
+        assert_eq!(meta.flags_written.into_bits(), 0);
+        assert_eq!(meta.writes, vec!["bc"]);
+
+        // PUSH AF reads F, EX AF, AF' swaps it
+        let meta = decode(&[0xF5]);
+        assert_eq!(meta.flags_read.into_bits(), ALL_FLAGS);
+        assert_eq!(meta.flags_written.into_bits(), 0);
+        let meta = decode(&[0x08]);
+        assert_eq!(meta.flags_read.into_bits(), ALL_FLAGS);
+        assert_eq!(meta.flags_written.into_bits(), ALL_FLAGS);
+    }
+
+    #[test]
+    fn test_io() {
+        let meta = decode(&[0xDB, 0xFE]);
+        assert_eq!(
+            meta.operands,
+            vec![Operand::Register("a"), Operand::Port(0xFE)]
+        );
+        assert_eq!(meta.io, AccessKind::Read);
+        let meta = decode(&[0xED, 0x79]);
+        assert_eq!(meta.operands, vec![Operand::PortC, Operand::Register("a")]);
+        assert_eq!(meta.io, AccessKind::Write);
+    }
+}
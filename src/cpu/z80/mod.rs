@@ -1,3 +1,4 @@
+use crate::bus::MemoryAccessKind;
 use crate::cpu::instruction::{push_16, ExecutableInstruction, InstructionParser};
 use crate::cpu::registers::{AllMutRegisters, AllRegisters, GPByteRegisters};
 use crate::cpu::Cpu;
@@ -7,7 +8,11 @@ use std::collections::HashMap;
 
 use super::super::memory::{memdevices::ROM, Memory, MemoryDevice};
 
+pub mod assembler;
+pub mod bus;
+pub mod disassembler;
 pub mod instructions;
+pub mod metadata;
 pub mod parser;
 
 #[cfg(test)]
@@ -47,8 +52,17 @@ impl Default for Z80Registers {
 pub struct Z80 {
     pub registers: Z80Registers,
     halted: bool,
+    /// T-states spent acknowledging an interrupt in the last step
+    #[serde(skip)]
+    interrupt_cycles: u16,
 }
 
+/// Interrupt acknowledge timing, for IM0 on top of the instruction put on the bus
+const NMI_CYCLES: u16 = 11;
+const IM0_CYCLES: u16 = 2;
+const IM1_CYCLES: u16 = 13;
+const IM2_CYCLES: u16 = 19;
+
 impl Z80 {
     fn handle_interrupt(
         &mut self,
@@ -57,6 +71,12 @@ impl Z80 {
     ) -> Result<Option<Box<dyn ExecutableInstruction<Z80>>>, String> {
         match io.get_interrupt() {
             Some((int_vector, id)) => {
+                self.interrupt_cycles = match int_vector {
+                    InterruptType::NMI => NMI_CYCLES,
+                    InterruptType::IM0(_) => IM0_CYCLES,
+                    InterruptType::IM1 => IM1_CYCLES,
+                    InterruptType::IM2(_) => IM2_CYCLES,
+                };
                 let ret_instr: Option<Box<dyn ExecutableInstruction<Z80>>> = match int_vector {
                     InterruptType::NMI => {
                         self.registers.pc = 0x66;
@@ -99,16 +119,69 @@ impl Cpu for Z80 {
         memory: &mut Memory,
         io: &mut IO,
     ) -> Result<Box<(dyn ExecutableInstruction<Self>)>, String> {
+        self.interrupt_cycles = 0;
+        let recorder = memory.bus_recorder().cloned();
+        if let Some(recorder) = &recorder {
+            recorder.take();
+        }
+        // Accesses made between steps, such as a debugger writing memory, are not this step's
+        if let Some(access) = memory.access_recorder() {
+            access.take();
+        }
+        if let Some(access) = io.access_recorder() {
+            access.take();
+        }
         let res = self.handle_interrupt(memory, io)?; // If IM1 interrupt it will be returned and executed
+        let from_device = res.is_some();
         let mut instruction: Box<dyn ExecutableInstruction<Z80>> = match res {
             Some(instruction) => instruction,
             None => parser::Z80_PARSER
                 .ins_from_machinecode(memory, self.registers.pc)
                 .map_err(|e| e.to_string())?,
         };
+        if let Some(access) = memory.access_recorder() {
+            access.discard_reads();
+            if !from_device {
+                let opcode = instruction.to_bytes()[0];
+                access.record(MemoryAccessKind::Execute, self.registers.pc, opcode, opcode);
+            }
+        }
+        // Accesses made by the parser are replaced by the fetches derived from the bytes
+        let interrupt_accesses = match &recorder {
+            Some(recorder) => {
+                let accesses = recorder.take();
+                accesses
+                    .into_iter()
+                    .filter(|cycle| cycle.kind.is_write())
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        let (pc, a, b) = (
+            self.registers.pc,
+            self.registers.gp.a,
+            self.registers.gp.b,
+        );
         // println!("Executing: {:?}", self.registers.gp[0].f);
         // println!("HL: {:X},BC:{:X}", self.registers.gp[0].hl,self.registers.gp[0].bc);
         instruction.execute(memory, self, io)?;
+        if let Some(recorder) = &recorder {
+            let bytes = if from_device {
+                Vec::new()
+            } else {
+                instruction.to_bytes()
+            };
+            recorder.replace(bus::machine_cycles(bus::StepAccesses {
+                pc,
+                bytes: &bytes,
+                interrupt: interrupt_accesses,
+                interrupt_cycles: self.interrupt_cycles,
+                execute: recorder.take(),
+                cycles: instruction.common().cycles,
+                a,
+                b,
+            }));
+        }
         let common = instruction.common();
         self.registers.r = self.registers.r.wrapping_add(1) % 0x80;
         if common.increment_pc {
@@ -163,4 +236,7 @@ impl Cpu for Z80 {
     fn set_halted(&mut self, halted: bool) {
         self.halted = halted;
     }
+    fn interrupt_cycles(&self) -> u16 {
+        self.interrupt_cycles
+    }
 }
@@ -14,7 +14,7 @@ enum ImmediateValue {
     Ptr(u16),
 }
 
-fn is_num(number: &str) -> Result<u16, String> {
+pub(crate) fn is_num(number: &str) -> Result<u16, String> {
     let num = if number.starts_with("0x") && number.len() <= 6 {
         u16::from_str_radix(&number[2..], 16).map_err(|e| e.to_string())?
     } else if number.starts_with("0b") && number.len() <= 18 {
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 
+use crate::bus::{BusCycle, BusCycleKind};
 use crate::cpu::z80::Z80;
 use crate::emulator::Emulator;
 use crate::memory::memdevices::RAM;
@@ -138,6 +139,77 @@ fn assert_z80(emulator: &mut Emulator<Z80>, data: &TestData) {
     }
 }
 
+/// Data transfer in one T-state of the test data as (write, io, data), reads and writes are
+/// the T-states with RD or WR active and a value on the data bus.
+fn transfer(value: Option<u8>, pins: &str) -> Option<(bool, bool, u8)> {
+    let value = value?;
+    let write = pins.contains('w');
+    if !write && !pins.contains('r') {
+        return None;
+    }
+    Some((write, pins.contains('i'), value))
+}
+
+/// Checks every machine cycle against the pins of the T-states it covers. The address stays on
+/// the bus for the whole cycle, except for the refresh in the second half of an opcode fetch,
+/// and every transfer in the test data has to fall in one of the cycles.
+fn assert_bus(bus_cycles: &[BusCycle], data: &TestData) {
+    let mut covered = vec![false; data.cycles.len()];
+    for cycle in bus_cycles {
+        let start = cycle.offset as usize;
+        let len = match cycle.kind {
+            BusCycleKind::OpcodeFetch => 2,
+            BusCycleKind::IoRead | BusCycleKind::IoWrite => 4,
+            BusCycleKind::MemoryRead | BusCycleKind::MemoryWrite => 3,
+        };
+        let states = data
+            .cycles
+            .get(start..start + len)
+            .unwrap_or_else(|| panic!("{:?} ends after the last T-state in {}", cycle, data.name));
+        let mut transferred = false;
+        for (idx, (address, value, pins)) in states.iter().enumerate() {
+            assert_eq!(
+                *address,
+                cycle.address,
+                "Address of {:?} at T-state {} in {}",
+                cycle,
+                start + idx,
+                data.name
+            );
+            if let Some(transfer) = transfer(*value, pins) {
+                assert_eq!(
+                    transfer,
+                    (cycle.kind.is_write(), cycle.kind.is_io(), cycle.data),
+                    "Transfer of {:?} at T-state {} in {}",
+                    cycle,
+                    start + idx,
+                    data.name
+                );
+                covered[start + idx] = true;
+                transferred = true;
+            }
+        }
+        assert!(transferred, "No transfer for {:?} in {}", cycle, data.name);
+        if cycle.kind == BusCycleKind::OpcodeFetch {
+            let refresh = data.cycles.get(start + 2).map(|(_, _, pins)| pins);
+            assert!(
+                refresh.is_some_and(|pins| !pins.contains('r')),
+                "No refresh after {:?} in {}",
+                cycle,
+                data.name
+            );
+        }
+    }
+    for (idx, (_, value, pins)) in data.cycles.iter().enumerate() {
+        assert!(
+            covered[idx] || transfer(*value, pins).is_none(),
+            "Unrecorded transfer at T-state {} in {}",
+            idx,
+            data.name
+        );
+    }
+}
+
 pub fn test_z80_w_data(test_data_vec: Vec<TestData>) {
     for test_data in test_data_vec {
         let mut memory = Memory::new();
@@ -145,8 +217,18 @@ pub fn test_z80_w_data(test_data_vec: Vec<TestData>) {
         memory.add_device(Box::new(rom));
         let mut emulator: Emulator<Z80> = Emulator::new_w_mem(memory);
         setup_z80(&mut emulator, &test_data).expect("Failed to setup Z80");
-        emulator.step().expect("Failed to step");
+        emulator.set_bus_trace(true);
+        let instruction = emulator.step().expect("Failed to step");
+        let bus_cycles = emulator.bus_cycles();
+        emulator.set_bus_trace(false);
+        assert_bus(&bus_cycles, &test_data);
         assert_z80(&mut emulator, &test_data);
+        assert_eq!(
+            instruction.common().cycles as usize,
+            test_data.cycles.len(),
+            "Executed cycles mismatch for {}",
+            test_data.name
+        );
         assert_eq!(emulator.cycles, test_data.cycles.len());
     }
 }
@@ -0,0 +1,269 @@
+use crate::debug::expression::{Expression, ExpressionContext, ExpressionError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum BreakpointAction {
+    /// Stop execution
+    #[default]
+    Stop,
+    /// Keep running and add the message to the log, `{expression}` is replaced by its value
+    Log(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Breakpoint {
+    pub address: u16,
+    /// Only counts as a hit when the expression is true
+    pub condition: Option<Expression>,
+    pub enabled: bool,
+    /// Hits to let pass before the action is taken
+    pub ignore_count: u32,
+    /// Times the address was reached with the condition true
+    pub hit_count: u32,
+    pub action: BreakpointAction,
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Breakpoint {
+        Breakpoint {
+            address,
+            condition: None,
+            enabled: true,
+            ignore_count: 0,
+            hit_count: 0,
+            action: BreakpointAction::Stop,
+        }
+    }
+
+    pub fn conditional(address: u16, condition: &str) -> Result<Breakpoint, ExpressionError> {
+        Ok(Breakpoint {
+            condition: Some(Expression::parse(condition)?),
+            ..Breakpoint::new(address)
+        })
+    }
+
+    pub fn logging(address: u16, message: &str) -> Breakpoint {
+        Breakpoint {
+            action: BreakpointAction::Log(message.to_string()),
+            ..Breakpoint::new(address)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakpointLog {
+    pub id: usize,
+    pub pc: u16,
+    pub message: String,
+}
+
+/// Breakpoints of an emulator, identified by the id returned when adding them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Breakpoints {
+    next_id: usize,
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    #[serde(skip)]
+    log: Vec<BreakpointLog>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints::default()
+    }
+
+    pub fn add(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.insert(id, breakpoint);
+        id
+    }
+
+    /// Adds an unconditional breakpoint
+    pub fn add_address(&mut self, address: u16) -> usize {
+        self.add(Breakpoint::new(address))
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Breakpoint> {
+        self.breakpoints.remove(&id)
+    }
+
+    /// Removes every breakpoint at `address`
+    pub fn remove_address(&mut self, address: u16) {
+        self.breakpoints
+            .retain(|_, breakpoint| breakpoint.address != address);
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Breakpoint> {
+        self.breakpoints.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Breakpoint> {
+        self.breakpoints.get_mut(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.breakpoints
+            .iter()
+            .map(|(id, breakpoint)| (*id, breakpoint))
+    }
+
+    /// There is an enabled breakpoint at `address`
+    pub fn contains(&self, address: u16) -> bool {
+        self.breakpoints
+            .values()
+            .any(|breakpoint| breakpoint.enabled && breakpoint.address == address)
+    }
+
+    pub fn len(&self) -> usize {
+        self.breakpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Messages of the logging breakpoints hit since the last call
+    pub fn take_log(&mut self) -> Vec<BreakpointLog> {
+        std::mem::take(&mut self.log)
+    }
+
+    /// First enabled breakpoint at `pc` that stops with its condition true, without counting
+    /// the hit or logging.
+    pub fn find(
+        &self,
+        pc: u16,
+        context: &dyn ExpressionContext,
+    ) -> Result<Option<usize>, ExpressionError> {
+        for (id, breakpoint) in self.breakpoints.iter() {
+            if !breakpoint.enabled
+                || breakpoint.address != pc
+                || breakpoint.action != BreakpointAction::Stop
+            {
+                continue;
+            }
+            match &breakpoint.condition {
+                Some(condition) if !condition.is_true(context)? => continue,
+                _ => return Ok(Some(*id)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Evaluates the breakpoints at `pc`, counting hits and logging. Returns the id of the first
+    /// breakpoint that stops execution.
+    pub fn check(
+        &mut self,
+        pc: u16,
+        context: &dyn ExpressionContext,
+    ) -> Result<Option<usize>, ExpressionError> {
+        let mut stop = None;
+        for (id, breakpoint) in self.breakpoints.iter_mut() {
+            if !breakpoint.enabled || breakpoint.address != pc {
+                continue;
+            }
+            if let Some(condition) = &breakpoint.condition {
+                if !condition.is_true(context)? {
+                    continue;
+                }
+            }
+            breakpoint.hit_count += 1;
+            if breakpoint.hit_count <= breakpoint.ignore_count {
+                continue;
+            }
+            match &breakpoint.action {
+                BreakpointAction::Stop => {
+                    stop.get_or_insert(*id);
+                }
+                BreakpointAction::Log(message) => self.log.push(BreakpointLog {
+                    id: *id,
+                    pc,
+                    message: interpolate(message, context)?,
+                }),
+            }
+        }
+        Ok(stop)
+    }
+}
+
+/// Replaces each `{expression}` in `message` with its value in hexadecimal.
+pub fn interpolate(
+    message: &str,
+    context: &dyn ExpressionContext,
+) -> Result<String, ExpressionError> {
+    let mut result = String::new();
+    let mut rest = message;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        let value = Expression::parse(&rest[start + 1..start + end])?.evaluate(context)?;
+        result.push_str(&format!("{:#x}", value));
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Registers(u16, u8);
+
+    impl ExpressionContext for Registers {
+        fn register(&self, name: &str) -> Option<u16> {
+            match name {
+                "hl" => Some(self.0),
+                "a" => Some(self.1 as u16),
+                _ => None,
+            }
+        }
+
+        fn memory(&self, addr: u16) -> Option<u8> {
+            Some(addr as u8)
+        }
+    }
+
+    #[test]
+    fn test_condition_and_ignore_count() {
+        let mut breakpoints = Breakpoints::new();
+        let mut breakpoint = Breakpoint::conditional(0x100, "a == 0x3f && (hl) != 0").unwrap();
+        breakpoint.ignore_count = 1;
+        let id = breakpoints.add(breakpoint);
+        assert_eq!(breakpoints.check(0x100, &Registers(0x1000, 0x3F)), Ok(None));
+        assert_eq!(breakpoints.check(0x100, &Registers(0x1001, 0x3F)), Ok(None));
+        assert_eq!(breakpoints.check(0x100, &Registers(0x1001, 0x3E)), Ok(None));
+        assert_eq!(breakpoints.check(0x101, &Registers(0x1001, 0x3F)), Ok(None));
+        assert_eq!(
+            breakpoints.check(0x100, &Registers(0x1001, 0x3F)),
+            Ok(Some(id))
+        );
+        assert_eq!(breakpoints.get(id).unwrap().hit_count, 2);
+
+        breakpoints.get_mut(id).unwrap().enabled = false;
+        assert_eq!(breakpoints.check(0x100, &Registers(0x1001, 0x3F)), Ok(None));
+        assert!(!breakpoints.contains(0x100));
+    }
+
+    #[test]
+    fn test_log_action() {
+        let mut breakpoints = Breakpoints::new();
+        let id = breakpoints.add(Breakpoint::logging(0x200, "hl={hl} a={a + 1}"));
+        assert_eq!(breakpoints.check(0x200, &Registers(0x1234, 0x0F)), Ok(None));
+        assert_eq!(
+            breakpoints.take_log(),
+            vec![BreakpointLog {
+                id,
+                pc: 0x200,
+                message: "hl=0x1234 a=0x10".to_string()
+            }]
+        );
+        assert!(breakpoints.take_log().is_empty());
+    }
+}
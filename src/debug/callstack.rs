@@ -0,0 +1,295 @@
+use crate::cpu::metadata::FlowKind;
+use crate::memory::Memory;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FrameKind {
+    Call,
+    Restart,
+    /// An accepted interrupt, the caller is the interrupted instruction
+    Interrupt,
+}
+
+/// An active subroutine call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// Address of the call instruction, or of the instruction the interrupt came before
+    pub caller: u16,
+    /// Address that was called
+    pub target: u16,
+    pub return_address: u16,
+    /// Where the return address is stored
+    pub sp: u16,
+}
+
+/// What an instruction did to the flow of control, see [`CallStack::track`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallStep {
+    /// Address of the instruction
+    pub pc: u16,
+    pub length: u16,
+    pub flow: FlowKind,
+    /// The instruction loads SP rather than moving it, like `LD SP, HL`
+    pub loads_sp: bool,
+    /// SP before the instruction, and before the interrupt if one was taken
+    pub sp: u16,
+    /// PC and SP after the instruction
+    pub next: u16,
+    pub sp_after: u16,
+    /// Address the CPU was at if an interrupt was taken before the instruction
+    pub interrupted: Option<u16>,
+}
+
+/// Shadow of the calls the CPU is in, innermost last. Frames are pushed when a call,
+/// restart or interrupt pushes a return address and dropped once SP moves above the
+/// return address, which covers returns as well as code that pops it or reloads SP.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack::default()
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn push(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    /// Updates the frames after an instruction ran.
+    pub fn track(&mut self, step: &CallStep, memory: &Memory) {
+        let mut sp = step.sp;
+        if let Some(return_address) = step.interrupted {
+            sp = sp.wrapping_sub(2);
+            self.push(Frame {
+                kind: FrameKind::Interrupt,
+                caller: return_address,
+                target: step.pc,
+                return_address,
+                sp,
+            });
+        }
+        let kind = match step.flow {
+            FlowKind::Call => Some(FrameKind::Call),
+            FlowKind::Restart => Some(FrameKind::Restart),
+            _ => None,
+        };
+        match kind {
+            // A conditional call that is not taken leaves SP alone
+            Some(kind) if step.sp_after == sp.wrapping_sub(2) => self.push(Frame {
+                kind,
+                caller: step.pc,
+                target: step.next,
+                return_address: step.pc.wrapping_add(step.length),
+                sp: step.sp_after,
+            }),
+            _ if step.loads_sp => self.resync(step.sp_after, memory),
+            _ => self.unwind(step.sp_after),
+        }
+    }
+
+    /// Drops the frames whose return address is below `sp`, they have been returned from.
+    pub fn unwind(&mut self, sp: u16) {
+        while self.frames.last().is_some_and(|frame| frame.sp < sp) {
+            self.frames.pop();
+        }
+    }
+
+    /// Drops the frames whose return address is no longer in memory, used when SP is loaded
+    /// with a new value and the stack may have been switched or rewritten.
+    pub fn resync(&mut self, sp: u16, memory: &Memory) {
+        self.unwind(sp);
+        let valid = self
+            .frames
+            .iter()
+            .position(|frame| read_word(memory, frame.sp) != Some(frame.return_address))
+            .unwrap_or(self.frames.len());
+        self.frames.truncate(valid);
+    }
+
+    /// Frames from the innermost, the first one is where the CPU is at `pc`.
+    pub fn backtrace(
+        &self,
+        pc: u16,
+        sp: u16,
+        symbol: impl Fn(u16) -> Option<String>,
+    ) -> Vec<BacktraceFrame> {
+        let mut backtrace = Vec::with_capacity(self.frames.len() + 1);
+        let mut current = (pc, sp, None);
+        for frame in self.frames.iter().rev() {
+            let (pc, sp, kind) = current;
+            backtrace.push(BacktraceFrame {
+                pc,
+                function: Some(frame.target),
+                symbol: symbol(pc),
+                sp,
+                kind,
+            });
+            current = (frame.caller, frame.sp, Some(frame.kind));
+        }
+        let (pc, sp, kind) = current;
+        backtrace.push(BacktraceFrame {
+            pc,
+            function: None,
+            symbol: symbol(pc),
+            sp,
+            kind,
+        });
+        backtrace
+    }
+
+    /// Describes `count` words of stack from `sp` up, the ones holding the return address
+    /// of an active frame are marked as such, the others are pushed data.
+    pub fn annotate(&self, memory: &Memory, sp: u16, count: usize) -> Vec<StackWord> {
+        (0..count)
+            .filter_map(|idx| {
+                let address = sp.wrapping_add(2 * idx as u16);
+                let value = read_word(memory, address)?;
+                let frame = self
+                    .frames
+                    .iter()
+                    .rev()
+                    .find(|frame| frame.sp == address && frame.return_address == value);
+                Some(StackWord {
+                    address,
+                    value,
+                    frame: frame.copied(),
+                })
+            })
+            .collect()
+    }
+}
+
+fn read_word(memory: &Memory, address: u16) -> Option<u16> {
+    let low = memory.peek_8(address).ok()?;
+    let high = memory.peek_8(address.wrapping_add(1)).ok()?;
+    Some(u16::from_le_bytes([low, high]))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BacktraceFrame {
+    /// Where execution is in this frame, the call instruction for the outer frames
+    pub pc: u16,
+    /// Address that was called to enter the frame, unknown for the outermost one
+    pub function: Option<u16>,
+    pub symbol: Option<String>,
+    pub sp: u16,
+    /// How the inner frame was entered from here
+    pub kind: Option<FrameKind>,
+}
+
+impl Display for BacktraceFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04X}", self.pc)?;
+        if let Some(symbol) = &self.symbol {
+            write!(f, " <{}>", symbol)?;
+        }
+        if let Some(function) = self.function {
+            write!(f, " in {:04X}", function)?;
+        }
+        write!(f, " sp={:04X}", self.sp)?;
+        if self.kind == Some(FrameKind::Interrupt) {
+            write!(f, " (interrupted)")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackWord {
+    pub address: u16,
+    pub value: u16,
+    /// The frame whose return address this is, `None` for pushed data
+    pub frame: Option<Frame>,
+}
+
+impl StackWord {
+    pub fn is_return_address(&self) -> bool {
+        self.frame.is_some()
+    }
+}
+
+impl Display for StackWord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04X}: {:04X}", self.address, self.value)?;
+        match self.frame {
+            Some(frame) => write!(
+                f,
+                "  return to {:04X}, called {:04X}",
+                frame.return_address, frame.target
+            ),
+            None => write!(f, "  data"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryDevice;
+
+    fn frame(caller: u16, target: u16, sp: u16) -> Frame {
+        Frame {
+            kind: FrameKind::Call,
+            caller,
+            target,
+            return_address: caller + 3,
+            sp,
+        }
+    }
+
+    #[test]
+    fn test_unwind_and_resync() {
+        let mut memory = Memory::new_full_ram();
+        let mut stack = CallStack::new();
+        for (caller, target, sp) in [
+            (0x100, 0x200, 0xFFFE),
+            (0x210, 0x300, 0xFFFA),
+            (0x310, 0x400, 0xFFF6),
+        ] {
+            stack.push(frame(caller, target, sp));
+            memory.write_16(sp, caller + 3).unwrap();
+        }
+        // Pushed data between the frames
+        memory.write_16(0xFFFC, 0x1234).unwrap();
+        let words = stack.annotate(&memory, 0xFFF6, 5);
+        let marks: Vec<bool> = words.iter().map(StackWord::is_return_address).collect();
+        assert_eq!(marks, vec![true, false, true, false, true]);
+
+        let backtrace = stack.backtrace(0x405, 0xFFF4, |addr| {
+            (addr == 0x210).then(|| "main".to_string())
+        });
+        let lines: Vec<String> = backtrace.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "0405 in 0400 sp=FFF4",
+                "0310 in 0300 sp=FFF6",
+                "0210 <main> in 0200 sp=FFFA",
+                "0100 sp=FFFE"
+            ]
+        );
+
+        stack.unwind(0xFFF8);
+        assert_eq!(stack.depth(), 2);
+        // The outer return address was overwritten, so only the first frame is still valid
+        memory.write_16(0xFFFA, 0).unwrap();
+        stack.resync(0xFFF0, &memory);
+        assert_eq!(stack.frames(), &[frame(0x100, 0x200, 0xFFFE)]);
+    }
+}
@@ -0,0 +1,311 @@
+use crate::bus::{MemoryAccess, MemoryAccessKind};
+use crate::cpu::instruction::InstructionParser;
+use crate::cpu::metadata::{FlowKind, InstructionMetadata};
+use crate::cpu::Cpu;
+use crate::debug::dap::SourceMap;
+use crate::memory::Memory;
+use bitfield_struct::bitfield;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::ops::RangeInclusive;
+
+/// Execution count of a source line and the branches on it
+type LineCoverage = (u64, Vec<(u16, BranchCoverage)>);
+
+/// How an address was used.
+#[bitfield(u8)]
+#[derive(PartialEq, Eq)]
+pub struct AddressCoverage {
+    /// First byte of an executed instruction
+    pub opcode: bool,
+    /// Other byte of an executed instruction
+    pub operand: bool,
+    pub read: bool,
+    pub written: bool,
+    #[bits(4)]
+    __: u8,
+}
+
+impl AddressCoverage {
+    pub fn executed(&self) -> bool {
+        self.opcode() || self.operand()
+    }
+}
+
+/// Outcomes of a conditional branch, call or return.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl BranchCoverage {
+    /// Both outcomes were seen
+    pub fn covered(&self) -> bool {
+        self.taken > 0 && self.not_taken > 0
+    }
+}
+
+/// Which addresses the program executed, read and wrote, and which ways its conditional
+/// branches went.
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    addresses: Vec<AddressCoverage>,
+    /// Times each address was executed as the start of an instruction
+    executions: Vec<u32>,
+    branches: BTreeMap<u16, BranchCoverage>,
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            addresses: vec![AddressCoverage::new(); 0x10000],
+            executions: vec![0; 0x10000],
+            branches: BTreeMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.addresses.fill(AddressCoverage::new());
+        self.executions.fill(0);
+        self.branches.clear();
+    }
+
+    /// Counts an instruction from the memory accesses it made, `next` is the PC after it.
+    pub fn record(&mut self, metadata: &InstructionMetadata, next: u16, accesses: &[MemoryAccess]) {
+        for access in accesses {
+            let coverage = &mut self.addresses[access.address as usize];
+            match access.kind {
+                MemoryAccessKind::Execute => coverage.set_opcode(true),
+                MemoryAccessKind::Read => coverage.set_read(true),
+                MemoryAccessKind::Write => coverage.set_written(true),
+            }
+        }
+        // Instructions put on the bus by a device during an interrupt are not in memory
+        let Some(pc) = accesses
+            .iter()
+            .find(|access| access.kind == MemoryAccessKind::Execute)
+            .map(|access| access.address)
+        else {
+            return;
+        };
+        let executions = &mut self.executions[pc as usize];
+        *executions = executions.saturating_add(1);
+        for offset in 1..metadata.length {
+            self.addresses[pc.wrapping_add(offset) as usize].set_operand(true);
+        }
+        let branches = matches!(
+            metadata.flow,
+            FlowKind::Jump | FlowKind::Call | FlowKind::Return
+        );
+        if metadata.conditional && branches {
+            let branch = self.branches.entry(pc).or_default();
+            if next == pc.wrapping_add(metadata.length) {
+                branch.not_taken += 1;
+            } else {
+                branch.taken += 1;
+            }
+        }
+    }
+
+    pub fn address(&self, address: u16) -> AddressCoverage {
+        self.addresses[address as usize]
+    }
+
+    /// Times an instruction was executed at `address`
+    pub fn executions(&self, address: u16) -> u32 {
+        self.executions[address as usize]
+    }
+
+    pub fn branch(&self, address: u16) -> Option<BranchCoverage> {
+        self.branches.get(&address).copied()
+    }
+
+    /// Conditional instructions that were executed, by address.
+    pub fn branches(&self) -> &BTreeMap<u16, BranchCoverage> {
+        &self.branches
+    }
+
+    /// Writes an LCOV tracefile for the lines of `source`. Every address the map has a line
+    /// for is counted, so lines that were assembled but never executed show as missed.
+    pub fn write_lcov(&self, out: &mut impl Write, source: &dyn SourceMap) -> std::io::Result<()> {
+        let mut files: BTreeMap<String, BTreeMap<u32, LineCoverage>> = BTreeMap::new();
+        for address in 0..=u16::MAX {
+            let Some((path, line)) = source.location(address) else {
+                continue;
+            };
+            let (hits, branches) = files.entry(path).or_default().entry(line).or_default();
+            *hits += self.executions(address) as u64;
+            if let Some(branch) = self.branch(address) {
+                branches.push((address, branch));
+            }
+        }
+        writeln!(out, "TN:")?;
+        for (path, lines) in files {
+            writeln!(out, "SF:{}", path)?;
+            let (mut branches_found, mut branches_hit) = (0, 0);
+            for (line, (_, branches)) in lines.iter() {
+                for (block, (_, branch)) in branches.iter().enumerate() {
+                    for (idx, count) in [branch.taken, branch.not_taken].into_iter().enumerate() {
+                        writeln!(out, "BRDA:{},{},{},{}", line, block, idx, count)?;
+                        branches_found += 1;
+                        if count > 0 {
+                            branches_hit += 1;
+                        }
+                    }
+                }
+            }
+            if branches_found > 0 {
+                writeln!(out, "BRF:{}", branches_found)?;
+                writeln!(out, "BRH:{}", branches_hit)?;
+            }
+            for (line, (hits, _)) in lines.iter() {
+                writeln!(out, "DA:{},{}", line, hits)?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            let hit = lines.values().filter(|(hits, _)| *hits > 0).count();
+            writeln!(out, "LH:{}", hit)?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+
+    /// Writes a disassembly of `range` with the execution count of every instruction, `-`
+    /// for the ones never executed. Bytes that were only used as data are shown as such,
+    /// and the disassembly follows the instructions that were executed when they overlap.
+    pub fn write_annotated<T: Cpu + 'static>(
+        &self,
+        out: &mut impl Write,
+        parser: &dyn InstructionParser<T>,
+        memory: &Memory,
+        range: RangeInclusive<u16>,
+    ) -> std::io::Result<()> {
+        let (start, end) = (*range.start() as u32, *range.end() as u32);
+        let mut address = start;
+        while address <= end {
+            let pc = address as u16;
+            let coverage = self.address(pc);
+            let data = !coverage.executed() && (coverage.read() || coverage.written());
+            let instruction = match data {
+                true => None,
+                false => parser.ins_from_machinecode(memory, pc).ok(),
+            };
+            // An instruction starting inside this one ran, so this one is not code
+            let instruction = instruction.filter(|instruction| {
+                (1..instruction.to_bytes().len() as u16)
+                    .all(|offset| !self.address(pc.wrapping_add(offset)).opcode())
+            });
+            let (count, bytes, text) = match instruction {
+                Some(instruction) => {
+                    let count = match self.executions(pc) {
+                        0 => "-".to_string(),
+                        count => count.to_string(),
+                    };
+                    (count, instruction.to_bytes(), instruction.to_string())
+                }
+                None => {
+                    let byte = memory.peek_8(pc).unwrap_or(0);
+                    (String::new(), vec![byte], format!("DB 0x{:02X}", byte))
+                }
+            };
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let mut notes = Vec::new();
+            if let Some(branch) = self.branch(pc) {
+                notes.push(format!(
+                    "taken {}, not taken {}",
+                    branch.taken, branch.not_taken
+                ));
+            }
+            if coverage.read() {
+                notes.push("read".to_string());
+            }
+            if coverage.written() {
+                notes.push("written".to_string());
+            }
+            let line = format!("{:>8}  {:04X}  {:<12} {}", count, pc, hex.join(" "), text);
+            match notes.is_empty() {
+                true => writeln!(out, "{}", line)?,
+                false => writeln!(out, "{:<48} ; {}", line, notes.join(", "))?,
+            }
+            address += bytes.len() as u32;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::emulator_with;
+
+    struct Lines;
+
+    impl SourceMap for Lines {
+        fn address(&self, _path: &str, _line: u32) -> Option<u16> {
+            None
+        }
+
+        fn location(&self, address: u16) -> Option<(String, u32)> {
+            let line = [0x00, 0x03, 0x05, 0x06, 0x08, 0x0A]
+                .iter()
+                .position(|start| *start == address)?;
+            Some(("test.asm".to_string(), line as u32 + 1))
+        }
+    }
+
+    #[test]
+    fn test_coverage() {
+        // 0x00: LD HL, 0x20; LD B, 2
+        // 0x05: INC (HL); DJNZ 0x05; JR Z, 0x0B; HALT
+        // 0x0A: NOP
+        let program = [
+            0x21, 0x20, 0x00, 0x06, 0x02, 0x34, 0x10, 0xFD, 0x28, 0x01, 0x76, 0x00,
+        ];
+        let mut emulator = emulator_with(&program);
+        emulator.set_coverage(true);
+        while emulator.step().is_ok() {}
+        let coverage = emulator.coverage().unwrap();
+        assert!(coverage.address(0x05).opcode());
+        assert!(coverage.address(0x07).operand());
+        assert!(!coverage.address(0x0B).executed());
+        let data = coverage.address(0x20);
+        assert!(data.read() && data.written() && !data.executed());
+        assert_eq!(coverage.executions(0x05), 2);
+        assert!(coverage.branch(0x06).unwrap().covered());
+        assert_eq!(
+            coverage.branch(0x08),
+            Some(BranchCoverage {
+                taken: 0,
+                not_taken: 1
+            })
+        );
+
+        let mut out = Vec::new();
+        coverage.write_lcov(&mut out, &Lines).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "TN:\nSF:test.asm\n\
+             BRDA:4,0,0,1\nBRDA:4,0,1,1\nBRDA:5,0,0,0\nBRDA:5,0,1,1\nBRF:4\nBRH:3\n\
+             DA:1,1\nDA:2,1\nDA:3,2\nDA:4,2\nDA:5,1\nDA:6,1\n\
+             LF:6\nLH:6\nend_of_record\n"
+        );
+
+        let mut out = Vec::new();
+        let parser = emulator.cpu.parser();
+        coverage
+            .write_annotated(&mut out, parser, &emulator.memory, 0x05..=0x0B)
+            .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().map(str::trim_end).collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("       2  0005  34"));
+        assert!(lines[1].ends_with("; taken 1, not taken 1"));
+        assert!(lines[4].starts_with("       -  000B  00"));
+    }
+}
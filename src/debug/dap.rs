@@ -0,0 +1,891 @@
+//! Debug Adapter Protocol server, lets editors drive an [`Emulator`] over stdio or TCP.
+//!
+//! The machine is built by the host and handed to [`DapServer::new`], the `launch` request
+//! loads a program into it and sets its registers. Source breakpoints and source locations
+//! in stack traces need a [`SourceMap`].
+
+use crate::cpu::instruction::ExecutableInstruction;
+use crate::cpu::Cpu;
+use crate::debug::breakpoint::Breakpoint;
+use crate::debug::expression::{Expression, MachineContext};
+use crate::emulator::{Emulator, StopReason};
+use crate::memory::MemoryDevice;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener};
+use std::sync::mpsc::{channel, TryRecvError};
+
+/// T-states run between checks for new requests while the machine is running
+const CHUNK_TICKS: f64 = 10_000.0;
+
+const REGISTERS_REFERENCE: u64 = 1;
+const FLAGS_REFERENCE: u64 = 2;
+
+/// Registers shown in the registers scope, the ones the CPU does not have are left out.
+const REGISTERS: [&str; 22] = [
+    "a", "f", "b", "c", "d", "e", "h", "l", "af", "bc", "de", "hl", "af'", "bc'", "de'", "hl'",
+    "ix", "iy", "sp", "pc", "i", "r",
+];
+
+const FLAGS: [&str; 8] = ["S", "Z", "5", "H", "3", "P/V", "N", "C"];
+
+/// Maps between addresses and source lines.
+pub trait SourceMap {
+    /// Address of the first instruction generated for `line` of `path`
+    fn address(&self, path: &str, line: u32) -> Option<u16>;
+    /// Source file and line `address` was generated from
+    fn location(&self, address: u16) -> Option<(String, u32)>;
+}
+
+pub struct DapServer<T: Cpu> {
+    emulator: Emulator<T>,
+    source_map: Option<Box<dyn SourceMap>>,
+    seq: u64,
+    outbox: Vec<Value>,
+    /// Emulator breakpoint ids set through `setBreakpoints`, by source path
+    source_breakpoints: HashMap<String, Vec<usize>>,
+    instruction_breakpoints: Vec<usize>,
+    stop_on_entry: bool,
+    running: bool,
+}
+
+impl<T: Cpu + 'static> DapServer<T> {
+    pub fn new(emulator: Emulator<T>) -> DapServer<T> {
+        DapServer {
+            emulator,
+            source_map: None,
+            seq: 0,
+            outbox: Vec::new(),
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: Vec::new(),
+            stop_on_entry: false,
+            running: false,
+        }
+    }
+
+    pub fn set_source_map(&mut self, source_map: Option<Box<dyn SourceMap>>) {
+        self.source_map = source_map;
+    }
+
+    pub fn emulator(&self) -> &Emulator<T> {
+        &self.emulator
+    }
+
+    pub fn emulator_mut(&mut self) -> &mut Emulator<T> {
+        &mut self.emulator
+    }
+
+    pub fn into_emulator(self) -> Emulator<T> {
+        self.emulator
+    }
+
+    pub fn serve_stdio(&mut self) -> std::io::Result<()> {
+        self.serve(std::io::stdin(), std::io::stdout().lock())
+    }
+
+    /// Waits for one client on `port` on localhost and serves it.
+    pub fn serve_tcp(&mut self, port: u16) -> std::io::Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        let (stream, _) = listener.accept()?;
+        self.serve(stream.try_clone()?, stream)
+    }
+
+    /// Serves requests from `reader` until the client disconnects or closes the stream.
+    /// Requests are read on their own thread so the machine can be paused while running.
+    pub fn serve(
+        &mut self,
+        reader: impl Read + Send + 'static,
+        mut writer: impl Write,
+    ) -> std::io::Result<()> {
+        let (sender, receiver) = channel();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            while let Ok(Some(message)) = read_message(&mut reader) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        loop {
+            let message = match self.running {
+                true => match receiver.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                },
+                false => match receiver.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => return Ok(()),
+                },
+            };
+            let done = match message {
+                Some(message) => self.handle(&message),
+                None => {
+                    self.run_chunk();
+                    false
+                }
+            };
+            for message in std::mem::take(&mut self.outbox) {
+                write_message(&mut writer, &message)?;
+            }
+            writer.flush()?;
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) {
+        self.seq += 1;
+        self.outbox.push(json!({
+            "seq": self.seq,
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": true,
+            "command": request["command"],
+            "body": body,
+        }));
+    }
+
+    fn fail(&mut self, request: &Value, message: &str) {
+        self.seq += 1;
+        self.outbox.push(json!({
+            "seq": self.seq,
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": false,
+            "command": request["command"],
+            "message": message,
+        }));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.seq += 1;
+        self.outbox.push(json!({
+            "seq": self.seq,
+            "type": "event",
+            "event": event,
+            "body": body,
+        }));
+    }
+
+    /// Handles one request, returns true when the session is over.
+    fn handle(&mut self, request: &Value) -> bool {
+        let arguments = &request["arguments"];
+        let result = match request["command"].as_str().unwrap_or("") {
+            "initialize" => {
+                self.respond(
+                    request,
+                    json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsConditionalBreakpoints": true,
+                        "supportsInstructionBreakpoints": true,
+                        "supportsReadMemoryRequest": true,
+                        "supportsWriteMemoryRequest": true,
+                        "supportsSetVariable": true,
+                    }),
+                );
+                self.event("initialized", json!({}));
+                Ok(())
+            }
+            "launch" => self.launch(request),
+            "configurationDone" => {
+                self.respond(request, json!({}));
+                match self.stop_on_entry {
+                    true => self.stopped("entry", None, None),
+                    false => self.running = true,
+                }
+                Ok(())
+            }
+            "setBreakpoints" => self.set_breakpoints(request),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(request),
+            "threads" => {
+                self.respond(request, json!({"threads": [{"id": 1, "name": "CPU"}]}));
+                Ok(())
+            }
+            "stackTrace" => {
+                self.stack_trace(request);
+                Ok(())
+            }
+            "scopes" => {
+                self.respond(
+                    request,
+                    json!({"scopes": [
+                        {"name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false},
+                        {"name": "Flags", "variablesReference": FLAGS_REFERENCE, "expensive": false},
+                    ]}),
+                );
+                Ok(())
+            }
+            "variables" => {
+                let variables = self.variables(arguments["variablesReference"].as_u64());
+                self.respond(request, json!({ "variables": variables }));
+                Ok(())
+            }
+            "setVariable" => self.set_variable(request),
+            "evaluate" => self.evaluate(request),
+            "readMemory" => self.read_memory(request),
+            "writeMemory" => self.write_memory(request),
+            "continue" => {
+                self.respond(request, json!({"allThreadsContinued": true}));
+                self.running = true;
+                Ok(())
+            }
+            "pause" => {
+                self.respond(request, json!({}));
+                if self.running {
+                    self.running = false;
+                    self.stopped("pause", None, None);
+                }
+                Ok(())
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.respond(request, json!({}));
+                self.step(request["command"].as_str().unwrap_or(""));
+                Ok(())
+            }
+            "disconnect" => {
+                self.running = false;
+                self.respond(request, json!({}));
+                return true;
+            }
+            command => Err(format!("Unsupported request {}", command)),
+        };
+        if let Err(message) = result {
+            self.fail(request, &message);
+        }
+        false
+    }
+
+    fn launch(&mut self, request: &Value) -> Result<(), String> {
+        let arguments = &request["arguments"];
+        let load_address = u16_argument(arguments, "loadAddress")?.unwrap_or(0);
+        if let Some(program) = arguments["program"].as_str() {
+            let data = std::fs::read(program).map_err(|e| format!("{}: {}", program, e))?;
+            for (offset, byte) in data.iter().enumerate() {
+                self.emulator
+                    .memory
+                    .write_8_force(load_address.wrapping_add(offset as u16), *byte)
+                    .map_err(|e| format!("Failed to load {}: {:?}", program, e))?;
+            }
+        }
+        let pc = u16_argument(arguments, "pc")?;
+        let sp = u16_argument(arguments, "sp")?;
+        let mut registers = self.emulator.cpu.registers_mut();
+        for (name, value) in [("pc", pc), ("sp", sp)] {
+            if let Some(value) = value {
+                registers.set(name, value);
+            }
+        }
+        if arguments["pc"].is_null() && arguments["program"].is_string() {
+            registers.set("pc", load_address);
+        }
+        self.emulator.set_call_stack(true);
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.respond(request, json!({}));
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, request: &Value) -> Result<(), String> {
+        let arguments = &request["arguments"];
+        let path = arguments["source"]["path"]
+            .as_str()
+            .ok_or("Only sources with a path are supported")?
+            .to_string();
+        for id in self.source_breakpoints.remove(&path).unwrap_or_default() {
+            self.emulator.breakpoints.remove(id);
+        }
+        let mut ids = Vec::new();
+        let mut breakpoints = Vec::new();
+        for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as u32;
+            let address = self
+                .source_map
+                .as_ref()
+                .and_then(|map| map.address(&path, line));
+            let result = match address {
+                Some(address) => self.add_breakpoint(address, &breakpoint["condition"]),
+                None => Err("No code at this line".to_string()),
+            };
+            breakpoints.push(match result {
+                Ok(id) => {
+                    ids.push(id);
+                    json!({"id": id, "verified": true, "line": line})
+                }
+                Err(message) => json!({"verified": false, "line": line, "message": message}),
+            });
+        }
+        self.source_breakpoints.insert(path, ids);
+        self.respond(request, json!({ "breakpoints": breakpoints }));
+        Ok(())
+    }
+
+    fn set_instruction_breakpoints(&mut self, request: &Value) -> Result<(), String> {
+        for id in std::mem::take(&mut self.instruction_breakpoints) {
+            self.emulator.breakpoints.remove(id);
+        }
+        let mut breakpoints = Vec::new();
+        let requested = request["arguments"]["breakpoints"].as_array();
+        for breakpoint in requested.into_iter().flatten() {
+            let address = breakpoint["instructionReference"]
+                .as_str()
+                .and_then(parse_address)
+                .map(|address| {
+                    let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+                    address.wrapping_add(offset as u16)
+                });
+            let result = match address {
+                Some(address) => self.add_breakpoint(address, &breakpoint["condition"]),
+                None => Err("Invalid instruction reference".to_string()),
+            };
+            breakpoints.push(match result {
+                Ok(id) => {
+                    self.instruction_breakpoints.push(id);
+                    json!({"id": id, "verified": true, "instructionReference": format_address(address.unwrap_or(0))})
+                }
+                Err(message) => json!({"verified": false, "message": message}),
+            });
+        }
+        self.respond(request, json!({ "breakpoints": breakpoints }));
+        Ok(())
+    }
+
+    fn add_breakpoint(&mut self, address: u16, condition: &Value) -> Result<usize, String> {
+        let breakpoint = match condition.as_str().filter(|condition| !condition.is_empty()) {
+            Some(condition) => {
+                Breakpoint::conditional(address, condition).map_err(|e| e.to_string())?
+            }
+            None => Breakpoint::new(address),
+        };
+        Ok(self.emulator.breakpoints.add(breakpoint))
+    }
+
+    fn stack_trace(&mut self, request: &Value) {
+        let backtrace = self.emulator.backtrace();
+        let frames: Vec<Value> = backtrace
+            .iter()
+            .enumerate()
+            .map(|(idx, frame)| {
+                let name = match (&frame.symbol, frame.function) {
+                    (Some(symbol), _) => symbol.clone(),
+                    (None, Some(function)) => format_address(function),
+                    (None, None) => "<top>".to_string(),
+                };
+                let mut value = json!({
+                    "id": idx,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format_address(frame.pc),
+                });
+                let location = self
+                    .source_map
+                    .as_ref()
+                    .and_then(|map| map.location(frame.pc));
+                if let Some((path, line)) = location {
+                    value["source"] = json!({ "path": path });
+                    value["line"] = json!(line);
+                    value["column"] = json!(1);
+                }
+                value
+            })
+            .collect();
+        let total = frames.len();
+        self.respond(
+            request,
+            json!({"stackFrames": frames, "totalFrames": total}),
+        );
+    }
+
+    fn variables(&self, reference: Option<u64>) -> Vec<Value> {
+        let registers = self.emulator.cpu.registers();
+        match reference {
+            Some(REGISTERS_REFERENCE) => REGISTERS
+                .iter()
+                .filter_map(|name| {
+                    let value = registers.get(name)?;
+                    let value = match name.trim_end_matches('\'').len() {
+                        1 => format!("0x{:02X}", value),
+                        _ => format!("0x{:04X}", value),
+                    };
+                    Some(json!({"name": name, "value": value, "variablesReference": 0}))
+                })
+                .collect(),
+            Some(FLAGS_REFERENCE) => {
+                let f = registers.get("f").unwrap_or(0);
+                FLAGS
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, name)| {
+                        let set = (f >> (7 - idx)) & 1;
+                        json!({"name": name, "value": set.to_string(), "variablesReference": 0})
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn set_variable(&mut self, request: &Value) -> Result<(), String> {
+        let arguments = &request["arguments"];
+        let name = arguments["name"].as_str().ok_or("Missing name")?;
+        let value = {
+            let text = arguments["value"].as_str().ok_or("Missing value")?;
+            let context = MachineContext::new(&self.emulator.cpu, &self.emulator.memory)
+                .with_symbols(&self.emulator.symbols);
+            Expression::parse(text)
+                .and_then(|expression| expression.evaluate(&context))
+                .map_err(|e| e.to_string())? as u16
+        };
+        let (name, value) = match arguments["variablesReference"].as_u64() {
+            Some(REGISTERS_REFERENCE) => (name.to_string(), value),
+            Some(FLAGS_REFERENCE) => {
+                let idx = FLAGS
+                    .iter()
+                    .position(|flag| *flag == name)
+                    .ok_or("Unknown flag")?;
+                let f = self.emulator.cpu.registers().get("f").unwrap_or(0);
+                let bit = 0x80 >> idx;
+                let f = if value != 0 { f | bit } else { f & !bit };
+                ("f".to_string(), f)
+            }
+            _ => return Err("Unknown variables reference".to_string()),
+        };
+        if !self.emulator.cpu.registers_mut().set(&name, value) {
+            return Err(format!("Unknown register {}", name));
+        }
+        let shown = self
+            .variables(arguments["variablesReference"].as_u64())
+            .into_iter()
+            .find(|variable| variable["name"] == arguments["name"])
+            .map(|variable| variable["value"].clone())
+            .unwrap_or_default();
+        self.respond(request, json!({ "value": shown }));
+        Ok(())
+    }
+
+    fn evaluate(&mut self, request: &Value) -> Result<(), String> {
+        let text = request["arguments"]["expression"]
+            .as_str()
+            .ok_or("Missing expression")?;
+        let context = MachineContext::new(&self.emulator.cpu, &self.emulator.memory)
+            .with_symbols(&self.emulator.symbols);
+        let value = Expression::parse(text)
+            .and_then(|expression| expression.evaluate(&context))
+            .map_err(|e| e.to_string())?;
+        self.respond(
+            request,
+            json!({"result": format!("{:#X} ({})", value, value), "variablesReference": 0}),
+        );
+        Ok(())
+    }
+
+    fn read_memory(&mut self, request: &Value) -> Result<(), String> {
+        let arguments = &request["arguments"];
+        let address = arguments["memoryReference"]
+            .as_str()
+            .and_then(parse_address)
+            .ok_or("Invalid memory reference")?
+            .wrapping_add(arguments["offset"].as_i64().unwrap_or(0) as u16);
+        let count = arguments["count"].as_u64().unwrap_or(0) as usize;
+        let data: Vec<u8> = (0..count.min(0x10000))
+            .map_while(|offset| {
+                self.emulator
+                    .memory
+                    .peek_8(address.wrapping_add(offset as u16))
+                    .ok()
+            })
+            .collect();
+        let unreadable = count - data.len();
+        self.respond(
+            request,
+            json!({
+                "address": format_address(address),
+                "data": base64_encode(&data),
+                "unreadableBytes": unreadable,
+            }),
+        );
+        Ok(())
+    }
+
+    fn write_memory(&mut self, request: &Value) -> Result<(), String> {
+        let arguments = &request["arguments"];
+        let address = arguments["memoryReference"]
+            .as_str()
+            .and_then(parse_address)
+            .ok_or("Invalid memory reference")?
+            .wrapping_add(arguments["offset"].as_i64().unwrap_or(0) as u16);
+        let data = arguments["data"]
+            .as_str()
+            .and_then(base64_decode)
+            .ok_or("Invalid data")?;
+        for (offset, byte) in data.iter().enumerate() {
+            self.emulator
+                .memory
+                .write_8(address.wrapping_add(offset as u16), *byte)
+                .map_err(|e| format!("{:?}", e))?;
+        }
+        self.respond(request, json!({ "bytesWritten": data.len() }));
+        Ok(())
+    }
+
+    fn step(&mut self, command: &str) {
+        let result = match command {
+            "next" => self.emulator.step_over(),
+            "stepOut" => self.emulator.step_out(),
+            _ => self
+                .emulator
+                .step()
+                .map(|_| ())
+                .map_err(|e| self.emulator.step_error(e)),
+        };
+        match result {
+            Ok(()) => self.stopped("step", None, None),
+            Err(reason) => self.stop(reason),
+        }
+    }
+
+    fn run_chunk(&mut self) {
+        let result = self.emulator.run_ticks(
+            CHUNK_TICKS,
+            &None::<fn(&mut Emulator<T>, &dyn ExecutableInstruction<T>)>,
+        );
+        if let Err(reason) = result {
+            self.running = false;
+            self.stop(reason);
+        }
+    }
+
+    fn stop(&mut self, reason: StopReason) {
+        match reason {
+            StopReason::Breakpoint(id) => {
+                let reason = match self.instruction_breakpoints.contains(&id) {
+                    true => "instruction breakpoint",
+                    false => "breakpoint",
+                };
+                self.stopped(reason, None, Some(id))
+            }
+            StopReason::Watchpoint(hit) => self.stopped(
+                "data breakpoint",
+                Some(format!(
+                    "Watchpoint {} at {}",
+                    hit.id,
+                    format_address(hit.address)
+                )),
+                None,
+            ),
+            StopReason::IoBreakpoint(hit) => self.stopped(
+                "breakpoint",
+                Some(format!(
+                    "IO breakpoint {} on port {:#04X}",
+                    hit.id, hit.port
+                )),
+                None,
+            ),
+            StopReason::Halt => self.stopped("pause", Some("CPU halted".to_string()), None),
+            StopReason::CycleLimit | StopReason::InstructionLimit | StopReason::Condition => {
+                self.stopped("step", None, None)
+            }
+            StopReason::Pause => self.stopped("pause", None, None),
+            StopReason::IllegalOpcode { address, message } => self.stopped(
+                "exception",
+                Some(format!("Illegal opcode at {}: {}", format_address(address), message)),
+                None,
+            ),
+            StopReason::Error(message) => self.stopped("exception", Some(message), None),
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>, breakpoint: Option<usize>) {
+        let mut body = json!({"reason": reason, "threadId": 1, "allThreadsStopped": true});
+        if let Some(description) = description {
+            body["description"] = json!(description);
+        }
+        if let Some(id) = breakpoint {
+            body["hitBreakpointIds"] = json!([id]);
+        }
+        self.event("stopped", body);
+    }
+}
+
+/// Reads one `Content-Length` framed message, `None` at the end of the stream.
+fn read_message(reader: &mut impl BufRead) -> std::io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0u8; length.unwrap_or(0)];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> std::io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+/// Reads an optional address argument, numbers that do not fit in 16 bits are an error.
+fn u16_argument(arguments: &Value, name: &str) -> Result<Option<u16>, String> {
+    match &arguments[name] {
+        Value::Null => Ok(None),
+        value => value
+            .as_u64()
+            .and_then(|value| u16::try_from(value).ok())
+            .map(Some)
+            .ok_or_else(|| format!("{} must be a number from 0 to 0xFFFF", name)),
+    }
+}
+
+fn format_address(address: u16) -> String {
+    format!("0x{:04X}", address)
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut text = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (idx, byte)| {
+            bits | (*byte as u32) << (16 - 8 * idx)
+        });
+        for idx in 0..4 {
+            match idx <= chunk.len() {
+                true => text.push(BASE64[(bits >> (18 - 6 * idx) & 0x3F) as usize] as char),
+                false => text.push('='),
+            }
+        }
+    }
+    text
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(text.len() / 4 * 3);
+    let (mut bits, mut count) = (0u32, 0);
+    for byte in text.bytes().filter(|byte| *byte != b'=') {
+        let value = BASE64.iter().position(|c| *c == byte)? as u32;
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::z80::Z80;
+    use crate::emulator::emulator_with;
+
+    struct Lines(Vec<(u32, u16)>);
+
+    impl SourceMap for Lines {
+        fn address(&self, path: &str, line: u32) -> Option<u16> {
+            let (_, address) = self
+                .0
+                .iter()
+                .find(|(l, _)| *l == line && path == "main.asm")?;
+            Some(*address)
+        }
+
+        fn location(&self, address: u16) -> Option<(String, u32)> {
+            let (line, _) = self.0.iter().find(|(_, a)| *a == address)?;
+            Some(("main.asm".to_string(), *line))
+        }
+    }
+
+    fn server() -> DapServer<Z80> {
+        // 0x00: LD SP, 0xFF00; CALL 0x10; HALT
+        // 0x10: LD A, 0x42; LD (0x8000), A; RET
+        let mut program = vec![0x31, 0x00, 0xFF, 0xCD, 0x10, 0x00, 0x76];
+        program.resize(0x10, 0);
+        program.extend([0x3E, 0x42, 0x32, 0x00, 0x80, 0xC9]);
+        let mut server = DapServer::new(emulator_with(&program));
+        server.set_source_map(Some(Box::new(Lines(vec![
+            (1, 0x00),
+            (2, 0x03),
+            (3, 0x06),
+            (10, 0x10),
+            (11, 0x12),
+            (12, 0x15),
+        ]))));
+        server
+    }
+
+    /// Feeds the requests of a transcript to the server and checks every other message is
+    /// what the server sent back, running the machine to completion after each request.
+    fn replay(server: &mut DapServer<Z80>, transcript: &str) {
+        let transcript: Vec<Value> = serde_json::from_str(transcript).unwrap();
+        let mut expected = Vec::new();
+        let mut sent = Vec::new();
+        for message in transcript {
+            if message["type"] == "request" {
+                server.handle(&message);
+                while server.running {
+                    server.run_chunk();
+                }
+                sent.append(&mut server.outbox);
+            } else {
+                expected.push(message);
+            }
+        }
+        for (sent, expected) in sent.iter().zip(expected.iter()) {
+            assert_eq!(sent, expected);
+        }
+        assert_eq!(sent.len(), expected.len());
+    }
+
+    #[test]
+    fn test_session_transcript() {
+        let mut server = server();
+        replay(
+            &mut server,
+            r#"[
+            {"seq": 1, "type": "request", "command": "initialize", "arguments": {"adapterID": "emu"}},
+            {"seq": 1, "type": "response", "request_seq": 1, "success": true, "command": "initialize", "body": {
+                "supportsConfigurationDoneRequest": true, "supportsConditionalBreakpoints": true,
+                "supportsInstructionBreakpoints": true, "supportsReadMemoryRequest": true,
+                "supportsWriteMemoryRequest": true, "supportsSetVariable": true}},
+            {"seq": 2, "type": "event", "event": "initialized", "body": {}},
+            {"seq": 2, "type": "request", "command": "launch", "arguments": {"stopOnEntry": true}},
+            {"seq": 3, "type": "response", "request_seq": 2, "success": true, "command": "launch", "body": {}},
+            {"seq": 3, "type": "request", "command": "setBreakpoints", "arguments": {
+                "source": {"path": "main.asm"}, "breakpoints": [{"line": 11}, {"line": 5}]}},
+            {"seq": 4, "type": "response", "request_seq": 3, "success": true, "command": "setBreakpoints", "body": {
+                "breakpoints": [{"id": 0, "verified": true, "line": 11},
+                                {"verified": false, "line": 5, "message": "No code at this line"}]}},
+            {"seq": 4, "type": "request", "command": "configurationDone"},
+            {"seq": 5, "type": "response", "request_seq": 4, "success": true, "command": "configurationDone", "body": {}},
+            {"seq": 6, "type": "event", "event": "stopped", "body": {"reason": "entry", "threadId": 1, "allThreadsStopped": true}},
+            {"seq": 5, "type": "request", "command": "continue", "arguments": {"threadId": 1}},
+            {"seq": 7, "type": "response", "request_seq": 5, "success": true, "command": "continue", "body": {"allThreadsContinued": true}},
+            {"seq": 8, "type": "event", "event": "stopped", "body": {"reason": "breakpoint", "threadId": 1, "allThreadsStopped": true, "hitBreakpointIds": [0]}},
+            {"seq": 6, "type": "request", "command": "stackTrace", "arguments": {"threadId": 1}},
+            {"seq": 9, "type": "response", "request_seq": 6, "success": true, "command": "stackTrace", "body": {
+                "stackFrames": [
+                    {"id": 0, "name": "0x0010", "line": 11, "column": 1, "source": {"path": "main.asm"}, "instructionPointerReference": "0x0012"},
+                    {"id": 1, "name": "<top>", "line": 2, "column": 1, "source": {"path": "main.asm"}, "instructionPointerReference": "0x0003"}],
+                "totalFrames": 2}},
+            {"seq": 7, "type": "request", "command": "evaluate", "arguments": {"expression": "a + 1"}},
+            {"seq": 10, "type": "response", "request_seq": 7, "success": true, "command": "evaluate", "body": {"result": "0x43 (67)", "variablesReference": 0}},
+            {"seq": 8, "type": "request", "command": "variables", "arguments": {"variablesReference": 2}},
+            {"seq": 11, "type": "response", "request_seq": 8, "success": true, "command": "variables", "body": {"variables": [
+                {"name": "S", "value": "0", "variablesReference": 0}, {"name": "Z", "value": "0", "variablesReference": 0},
+                {"name": "5", "value": "0", "variablesReference": 0}, {"name": "H", "value": "0", "variablesReference": 0},
+                {"name": "3", "value": "0", "variablesReference": 0}, {"name": "P/V", "value": "0", "variablesReference": 0},
+                {"name": "N", "value": "0", "variablesReference": 0}, {"name": "C", "value": "0", "variablesReference": 0}]}},
+            {"seq": 9, "type": "request", "command": "next", "arguments": {"threadId": 1}},
+            {"seq": 12, "type": "response", "request_seq": 9, "success": true, "command": "next", "body": {}},
+            {"seq": 13, "type": "event", "event": "stopped", "body": {"reason": "step", "threadId": 1, "allThreadsStopped": true}},
+            {"seq": 10, "type": "request", "command": "readMemory", "arguments": {"memoryReference": "0x8000", "count": 2}},
+            {"seq": 14, "type": "response", "request_seq": 10, "success": true, "command": "readMemory", "body": {"address": "0x8000", "data": "QgA=", "unreadableBytes": 0}},
+            {"seq": 11, "type": "request", "command": "setVariable", "arguments": {"variablesReference": 1, "name": "hl", "value": "0x1234"}},
+            {"seq": 15, "type": "response", "request_seq": 11, "success": true, "command": "setVariable", "body": {"value": "0x1234"}},
+            {"seq": 12, "type": "request", "command": "stepOut", "arguments": {"threadId": 1}},
+            {"seq": 16, "type": "response", "request_seq": 12, "success": true, "command": "stepOut", "body": {}},
+            {"seq": 17, "type": "event", "event": "stopped", "body": {"reason": "step", "threadId": 1, "allThreadsStopped": true}},
+            {"seq": 13, "type": "request", "command": "continue", "arguments": {"threadId": 1}},
+            {"seq": 18, "type": "response", "request_seq": 13, "success": true, "command": "continue", "body": {"allThreadsContinued": true}},
+            {"seq": 19, "type": "event", "event": "stopped", "body": {"reason": "pause", "threadId": 1, "allThreadsStopped": true, "description": "CPU halted"}},
+            {"seq": 14, "type": "request", "command": "disconnect"},
+            {"seq": 20, "type": "response", "request_seq": 14, "success": true, "command": "disconnect", "body": {}}
+        ]"#,
+        );
+        let emulator = server.emulator();
+        assert_eq!(emulator.cpu.pc(), 0x07);
+        assert_eq!(emulator.cpu.registers.gp.hl, 0x1234);
+    }
+
+    #[test]
+    fn test_launch_arguments() {
+        let mut server = server();
+        server.handle(&json!({"seq": 1, "type": "request", "command": "launch",
+            "arguments": {"pc": 0x10000}}));
+        let reply = server.outbox.pop().unwrap();
+        assert_eq!(reply["success"], false);
+        assert_eq!(reply["message"], "pc must be a number from 0 to 0xFFFF");
+        assert_eq!(server.emulator().cpu.pc(), 0);
+
+        server.handle(&json!({"seq": 2, "type": "request", "command": "launch",
+            "arguments": {"pc": 0x10, "sp": 0xFFFF}}));
+        assert_eq!(server.outbox.pop().unwrap()["success"], true);
+        assert_eq!(server.emulator().cpu.pc(), 0x10);
+        assert_eq!(server.emulator().cpu.registers.sp, 0xFFFF);
+    }
+
+    #[test]
+    fn test_step_illegal_opcode() {
+        // ED FF is not decoded
+        let mut server = DapServer::new(emulator_with(&[0xED, 0xFF]));
+        server.handle(&json!({"seq": 1, "type": "request", "command": "stepIn",
+            "arguments": {"threadId": 1}}));
+        let stopped = server.outbox.pop().unwrap();
+        assert_eq!(stopped["body"]["reason"], "exception");
+        let description = stopped["body"]["description"].as_str().unwrap();
+        assert!(description.starts_with("Illegal opcode at 0x0000"));
+    }
+
+    #[test]
+    fn test_framing() {
+        let mut input = Vec::new();
+        for request in [
+            json!({"seq": 1, "type": "request", "command": "threads"}),
+            json!({"seq": 2, "type": "request", "command": "writeMemory", "arguments": {"memoryReference": "0x8000", "offset": 1, "data": "AQID"}}),
+            json!({"seq": 3, "type": "request", "command": "setInstructionBreakpoints", "arguments": {"breakpoints": [{"instructionReference": "0x0010"}]}}),
+            json!({"seq": 4, "type": "request", "command": "unknown"}),
+        ] {
+            write_message(&mut input, &request).unwrap();
+        }
+        let mut server = server();
+        let mut output = Vec::new();
+        server
+            .serve(std::io::Cursor::new(input), &mut output)
+            .unwrap();
+        let mut reader = BufReader::new(output.as_slice());
+        let mut replies = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            replies.push(message);
+        }
+        assert_eq!(replies.len(), 4);
+        assert_eq!(replies[0]["body"]["threads"][0]["name"], "CPU");
+        assert_eq!(replies[1]["body"]["bytesWritten"], 3);
+        assert_eq!(
+            replies[2]["body"]["breakpoints"][0]["instructionReference"],
+            "0x0010"
+        );
+        assert_eq!(replies[3]["success"], false);
+        assert_eq!(server.emulator().memory.peek_8(0x8003).unwrap(), 3);
+        assert!(server.emulator().breakpoints.contains(0x10));
+        assert_eq!(
+            base64_decode(&base64_encode(b"emulator")).unwrap(),
+            b"emulator"
+        );
+    }
+}
@@ -0,0 +1,515 @@
+use crate::cpu::registers::AllRegisters;
+use crate::cpu::Cpu;
+use crate::debug::symbols::Symbols;
+use crate::memory::Memory;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ExpressionError {
+    #[error("Syntax error at {0}: {1}")]
+    Syntax(usize, String),
+    #[error("Unknown register or symbol: {0}")]
+    UnknownName(String),
+    #[error("Unmapped memory at {0:#06x}")]
+    UnmappedMemory(u16),
+    #[error("Division by zero")]
+    DivisionByZero,
+}
+
+/// Values an expression can look at, registers by lowercase name, memory and symbols.
+pub trait ExpressionContext {
+    fn register(&self, name: &str) -> Option<u16>;
+    fn memory(&self, addr: u16) -> Option<u8>;
+    fn symbol(&self, _name: &str) -> Option<u16> {
+        None
+    }
+}
+
+/// Context over the registers of a CPU and the memory it is attached to, memory is read
+/// with [`Memory::peek_8`] so evaluating an expression has no side effects.
+pub struct MachineContext<'a> {
+    registers: AllRegisters<'a>,
+    memory: &'a Memory,
+    symbols: Option<&'a Symbols>,
+}
+
+impl<'a> MachineContext<'a> {
+    pub fn new<T: Cpu>(cpu: &'a T, memory: &'a Memory) -> MachineContext<'a> {
+        MachineContext {
+            registers: cpu.registers(),
+            memory,
+            symbols: None,
+        }
+    }
+
+    /// Resolves the names that are not registers with `symbols`.
+    pub fn with_symbols(mut self, symbols: &'a Symbols) -> MachineContext<'a> {
+        self.symbols = Some(symbols);
+        self
+    }
+}
+
+impl ExpressionContext for MachineContext<'_> {
+    fn register(&self, name: &str) -> Option<u16> {
+        self.registers.get(name)
+    }
+
+    fn memory(&self, addr: u16) -> Option<u8> {
+        self.memory.peek_8(addr).ok()
+    }
+
+    fn symbol(&self, name: &str) -> Option<u16> {
+        self.symbols?.address(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UnaryOp {
+    Not,
+    Complement,
+    Negate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BinaryOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinaryOp {
+    /// Binding strength, higher binds tighter
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::BitOr => 3,
+            BinaryOp::BitXor => 4,
+            BinaryOp::BitAnd => 5,
+            BinaryOp::Equal | BinaryOp::NotEqual => 6,
+            BinaryOp::Less | BinaryOp::LessEqual | BinaryOp::Greater | BinaryOp::GreaterEqual => 7,
+            BinaryOp::ShiftLeft | BinaryOp::ShiftRight => 8,
+            BinaryOp::Add | BinaryOp::Sub => 9,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Node {
+    Number(i64),
+    /// Register or symbol, registers take precedence
+    Name(String),
+    /// Byte in memory, `(hl)`
+    Byte(Box<Node>),
+    /// Little endian word in memory, `word(sp)`
+    Word(Box<Node>),
+    Unary(UnaryOp, Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn evaluate(&self, context: &dyn ExpressionContext) -> Result<i64, ExpressionError> {
+        match self {
+            Node::Number(value) => Ok(*value),
+            Node::Name(name) => context
+                .register(&name.to_ascii_lowercase())
+                .or_else(|| context.symbol(name))
+                .map(|value| value as i64)
+                .ok_or_else(|| ExpressionError::UnknownName(name.clone())),
+            Node::Byte(addr) => read_8(context, addr.evaluate(context)? as u16).map(|v| v as i64),
+            Node::Word(addr) => {
+                let addr = addr.evaluate(context)? as u16;
+                let lsb = read_8(context, addr)?;
+                let msb = read_8(context, addr.wrapping_add(1))?;
+                Ok(u16::from_le_bytes([lsb, msb]) as i64)
+            }
+            Node::Unary(op, value) => {
+                let value = value.evaluate(context)?;
+                Ok(match op {
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::Complement => !value,
+                    UnaryOp::Negate => value.wrapping_neg(),
+                })
+            }
+            Node::Binary(BinaryOp::Or, lhs, rhs) => {
+                Ok((lhs.evaluate(context)? != 0 || rhs.evaluate(context)? != 0) as i64)
+            }
+            Node::Binary(BinaryOp::And, lhs, rhs) => {
+                Ok((lhs.evaluate(context)? != 0 && rhs.evaluate(context)? != 0) as i64)
+            }
+            Node::Binary(op, lhs, rhs) => {
+                let lhs = lhs.evaluate(context)?;
+                let rhs = rhs.evaluate(context)?;
+                Ok(match op {
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::BitXor => lhs ^ rhs,
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::Equal => (lhs == rhs) as i64,
+                    BinaryOp::NotEqual => (lhs != rhs) as i64,
+                    BinaryOp::Less => (lhs < rhs) as i64,
+                    BinaryOp::LessEqual => (lhs <= rhs) as i64,
+                    BinaryOp::Greater => (lhs > rhs) as i64,
+                    BinaryOp::GreaterEqual => (lhs >= rhs) as i64,
+                    BinaryOp::ShiftLeft => lhs.wrapping_shl(rhs as u32),
+                    BinaryOp::ShiftRight => lhs.wrapping_shr(rhs as u32),
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOp::Div | BinaryOp::Rem if rhs == 0 => {
+                        return Err(ExpressionError::DivisionByZero)
+                    }
+                    BinaryOp::Div => lhs.wrapping_div(rhs),
+                    BinaryOp::Rem => lhs.wrapping_rem(rhs),
+                    BinaryOp::Or | BinaryOp::And => unreachable!(),
+                })
+            }
+        }
+    }
+}
+
+fn read_8(context: &dyn ExpressionContext, addr: u16) -> Result<u8, ExpressionError> {
+    context
+        .memory(addr)
+        .ok_or(ExpressionError::UnmappedMemory(addr))
+}
+
+/// Parsed expression over registers, memory and symbols, shared by breakpoint conditions,
+/// watch filters and the debugger front ends.
+///
+/// Numbers are decimal, `0x`/`$` hexadecimal or `0b` binary. Parentheses dereference a byte
+/// like Z80 assembly does, `(hl)`, `word(addr)` reads a little endian word, and square
+/// brackets group. Operators follow C precedence, comparisons and logic give 0 or 1 and any
+/// nonzero value is true.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, ExpressionError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+            end: source.len(),
+        };
+        let root = parser.expression(0)?;
+        if let Some((position, token)) = parser.tokens.get(parser.position) {
+            return Err(ExpressionError::Syntax(
+                *position,
+                format!("Unexpected {:?}", token),
+            ));
+        }
+        Ok(Expression {
+            source: source.trim().to_string(),
+            root,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+
+    pub fn evaluate(&self, context: &dyn ExpressionContext) -> Result<i64, ExpressionError> {
+        self.root.evaluate(context)
+    }
+
+    pub fn is_true(&self, context: &dyn ExpressionContext) -> Result<bool, ExpressionError> {
+        Ok(self.evaluate(context)? != 0)
+    }
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Unary(UnaryOp),
+    Binary(BinaryOp),
+    /// `-` is both negation and subtraction
+    Minus,
+    Open,
+    Close,
+    OpenGroup,
+    CloseGroup,
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ExpressionError> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let start = idx;
+        let current = bytes[idx] as char;
+        let next = bytes.get(idx + 1).map(|b| *b as char);
+        if current.is_ascii_whitespace() {
+            idx += 1;
+            continue;
+        }
+        if current.is_ascii_digit() || current == '$' {
+            let (radix, skip) = match (current, next) {
+                ('$', _) => (16, 1),
+                ('0', Some('x' | 'X')) => (16, 2),
+                ('0', Some('b' | 'B')) => (2, 2),
+                _ => (10, 0),
+            };
+            idx += skip;
+            let digits_start = idx;
+            while idx < bytes.len() && (bytes[idx] as char).is_ascii_alphanumeric() {
+                idx += 1;
+            }
+            let digits = &source[digits_start..idx];
+            let value = i64::from_str_radix(digits, radix).map_err(|_| {
+                ExpressionError::Syntax(start, format!("Invalid number {}", &source[start..idx]))
+            })?;
+            tokens.push((start, Token::Number(value)));
+            continue;
+        }
+        if current.is_ascii_alphabetic() || current == '_' {
+            while idx < bytes.len()
+                && ((bytes[idx] as char).is_ascii_alphanumeric()
+                    || matches!(bytes[idx], b'_' | b'.'))
+            {
+                idx += 1;
+            }
+            if bytes.get(idx) == Some(&b'\'') {
+                idx += 1;
+            }
+            tokens.push((start, Token::Name(source[start..idx].to_string())));
+            continue;
+        }
+        let (token, length) = match (current, next) {
+            ('|', Some('|')) => (Token::Binary(BinaryOp::Or), 2),
+            ('&', Some('&')) => (Token::Binary(BinaryOp::And), 2),
+            ('=', Some('=')) => (Token::Binary(BinaryOp::Equal), 2),
+            ('!', Some('=')) => (Token::Binary(BinaryOp::NotEqual), 2),
+            ('<', Some('=')) => (Token::Binary(BinaryOp::LessEqual), 2),
+            ('>', Some('=')) => (Token::Binary(BinaryOp::GreaterEqual), 2),
+            ('<', Some('<')) => (Token::Binary(BinaryOp::ShiftLeft), 2),
+            ('>', Some('>')) => (Token::Binary(BinaryOp::ShiftRight), 2),
+            ('|', _) => (Token::Binary(BinaryOp::BitOr), 1),
+            ('^', _) => (Token::Binary(BinaryOp::BitXor), 1),
+            ('&', _) => (Token::Binary(BinaryOp::BitAnd), 1),
+            ('<', _) => (Token::Binary(BinaryOp::Less), 1),
+            ('>', _) => (Token::Binary(BinaryOp::Greater), 1),
+            ('+', _) => (Token::Binary(BinaryOp::Add), 1),
+            ('*', _) => (Token::Binary(BinaryOp::Mul), 1),
+            ('/', _) => (Token::Binary(BinaryOp::Div), 1),
+            ('%', _) => (Token::Binary(BinaryOp::Rem), 1),
+            ('-', _) => (Token::Minus, 1),
+            ('!', _) => (Token::Unary(UnaryOp::Not), 1),
+            ('~', _) => (Token::Unary(UnaryOp::Complement), 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            ('[', _) => (Token::OpenGroup, 1),
+            (']', _) => (Token::CloseGroup, 1),
+            _ => {
+                return Err(ExpressionError::Syntax(
+                    start,
+                    format!("Unexpected character '{}'", current),
+                ))
+            }
+        };
+        tokens.push((start, token));
+        idx += length;
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [(usize, Token)],
+    position: usize,
+    end: usize,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Result<(usize, Token), ExpressionError> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| ExpressionError::Syntax(self.end, "Unexpected end".to_string()))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn peek_binary(&self) -> Option<BinaryOp> {
+        match self.tokens.get(self.position) {
+            Some((_, Token::Binary(op))) => Some(*op),
+            Some((_, Token::Minus)) => Some(BinaryOp::Sub),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), ExpressionError> {
+        let (position, token) = self.next()?;
+        if token != expected {
+            return Err(ExpressionError::Syntax(
+                position,
+                format!("Expected {:?}, found {:?}", expected, token),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Precedence climbing, parses operators binding tighter than `min_precedence`
+    fn expression(&mut self, min_precedence: u8) -> Result<Node, ExpressionError> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.peek_binary() {
+            if op.precedence() <= min_precedence {
+                break;
+            }
+            self.position += 1;
+            let rhs = self.expression(op.precedence())?;
+            lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Node, ExpressionError> {
+        let (position, token) = self.next()?;
+        match token {
+            Token::Number(value) => Ok(Node::Number(value)),
+            Token::Name(name) => {
+                let lower = name.to_ascii_lowercase();
+                if lower == "word"
+                    && self.tokens.get(self.position).map(|t| &t.1) == Some(&Token::Open)
+                {
+                    self.position += 1;
+                    let addr = self.expression(0)?;
+                    self.expect(Token::Close)?;
+                    return Ok(Node::Word(Box::new(addr)));
+                }
+                Ok(Node::Name(name))
+            }
+            Token::Unary(op) => Ok(Node::Unary(op, Box::new(self.unary()?))),
+            Token::Minus => Ok(Node::Unary(UnaryOp::Negate, Box::new(self.unary()?))),
+            Token::Open => {
+                let addr = self.expression(0)?;
+                self.expect(Token::Close)?;
+                Ok(Node::Byte(Box::new(addr)))
+            }
+            Token::OpenGroup => {
+                let inner = self.expression(0)?;
+                self.expect(Token::CloseGroup)?;
+                Ok(inner)
+            }
+            token => Err(ExpressionError::Syntax(
+                position,
+                format!("Unexpected {:?}", token),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct TestContext {
+        registers: HashMap<&'static str, u16>,
+        memory: Vec<u8>,
+    }
+
+    impl ExpressionContext for TestContext {
+        fn register(&self, name: &str) -> Option<u16> {
+            self.registers.get(name).copied()
+        }
+
+        fn memory(&self, addr: u16) -> Option<u8> {
+            self.memory.get(addr as usize).copied()
+        }
+
+        fn symbol(&self, name: &str) -> Option<u16> {
+            (name == "buffer").then_some(2)
+        }
+    }
+
+    fn context() -> TestContext {
+        TestContext {
+            registers: HashMap::from([("a", 0x3F), ("hl", 0x0001), ("sp", 0x0002)]),
+            memory: vec![0x00, 0x12, 0x34, 0x56],
+        }
+    }
+
+    fn eval(source: &str) -> Result<i64, ExpressionError> {
+        Expression::parse(source)?.evaluate(&context())
+    }
+
+    #[test]
+    fn test_arithmetic_and_precedence() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), 7);
+        assert_eq!(eval("[1 + 2] * 3").unwrap(), 9);
+        assert_eq!(eval("0x10 - $8 - 0b11").unwrap(), 5);
+        assert_eq!(eval("-2 + ~0").unwrap(), -3);
+        assert_eq!(eval("1 << 4 | 1").unwrap(), 17);
+        assert_eq!(eval("7 % 4 == 3 && !0").unwrap(), 1);
+        assert_eq!(eval("1 / 0"), Err(ExpressionError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        assert_eq!(eval("a == 0x3f && (hl) != 0").unwrap(), 1);
+        assert_eq!(eval("(hl + 1)").unwrap(), 0x34);
+        assert_eq!(eval("word(sp)").unwrap(), 0x5634);
+        assert_eq!(eval("(buffer)").unwrap(), 0x34);
+        assert_eq!(
+            eval("bc"),
+            Err(ExpressionError::UnknownName("bc".to_string()))
+        );
+        assert_eq!(eval("(100)"), Err(ExpressionError::UnmappedMemory(100)));
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert!(matches!(
+            Expression::parse("a =="),
+            Err(ExpressionError::Syntax(4, _))
+        ));
+        assert!(matches!(
+            Expression::parse("(hl"),
+            Err(ExpressionError::Syntax(_, _))
+        ));
+        assert!(matches!(
+            Expression::parse("a # 1"),
+            Err(ExpressionError::Syntax(2, _))
+        ));
+        assert!(matches!(
+            Expression::parse("1 2"),
+            Err(ExpressionError::Syntax(2, _))
+        ));
+        assert!(matches!(
+            Expression::parse("0xZZ"),
+            Err(ExpressionError::Syntax(0, _))
+        ));
+    }
+}
@@ -0,0 +1,667 @@
+//! GDB remote serial protocol stub, lets GDB and other front ends speaking RSP drive an
+//! [`Emulator`] over TCP.
+//!
+//! Registers are exposed in the order GDB uses for the Z80, registers a CPU does not have
+//! are reported as unavailable. Software and hardware breakpoints both map to
+//! [`Emulator::breakpoints`], watchpoints to [`Emulator::watchpoints`].
+
+use crate::bus::MemoryAccessKind;
+use crate::cpu::instruction::ExecutableInstruction;
+use crate::cpu::Cpu;
+use crate::debug::watchpoint::Watchpoint;
+use crate::emulator::{Emulator, StopReason};
+use crate::memory::MemoryDevice;
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+
+/// Registers in GDB order, `ir` is `I` in the high byte and `R` in the low one.
+pub const REGISTERS: [&str; 13] = [
+    "af", "bc", "de", "hl", "sp", "pc", "ix", "iy", "af'", "bc'", "de'", "hl'", "ir",
+];
+
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>z80</architecture>
+  <feature name="org.gnu.gdb.z80.cpu">
+    <flags id="af_flags" size="2">
+      <field name="C" start="0" end="0"/>
+      <field name="N" start="1" end="1"/>
+      <field name="P/V" start="2" end="2"/>
+      <field name="X" start="3" end="3"/>
+      <field name="H" start="4" end="4"/>
+      <field name="Y" start="5" end="5"/>
+      <field name="Z" start="6" end="6"/>
+      <field name="S" start="7" end="7"/>
+    </flags>
+    <reg name="af" bitsize="16" type="af_flags"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="data_ptr"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="ix" bitsize="16" type="data_ptr"/>
+    <reg name="iy" bitsize="16" type="data_ptr"/>
+    <reg name="af'" bitsize="16" type="af_flags"/>
+    <reg name="bc'" bitsize="16" type="uint16"/>
+    <reg name="de'" bitsize="16" type="uint16"/>
+    <reg name="hl'" bitsize="16" type="uint16"/>
+    <reg name="ir" bitsize="16" type="uint16"/>
+  </feature>
+</target>
+"#;
+
+/// Instructions run between checks for an interrupt request from the debugger
+const CHUNK_TICKS: f64 = 10_000.0;
+
+/// Largest packet the stub accepts or sends, advertised in `qSupported`
+const PACKET_SIZE: u32 = 0x4000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// Listens on localhost for debugger connections.
+pub struct GdbServer {
+    listener: TcpListener,
+}
+
+impl GdbServer {
+    /// Binds to `port` on localhost, 0 picks a free port.
+    pub fn bind(port: u16) -> std::io::Result<GdbServer> {
+        Ok(GdbServer {
+            listener: TcpListener::bind((Ipv4Addr::LOCALHOST, port))?,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Waits for a debugger and serves it until it detaches, kills the target or
+    /// disconnects. Breakpoints and watchpoints it set are removed at the end.
+    pub fn serve<T: Cpu + 'static>(&self, emulator: &mut Emulator<T>) -> std::io::Result<()> {
+        let (stream, _) = self.listener.accept()?;
+        stream.set_nodelay(true)?;
+        let mut session = Session {
+            emulator,
+            stream,
+            no_ack: false,
+            breakpoints: BTreeMap::new(),
+            watchpoints: BTreeMap::new(),
+        };
+        let result = session.run();
+        session.remove_points();
+        result
+    }
+}
+
+enum Packet {
+    Data(Vec<u8>),
+    /// Ctrl-C sent outside of a packet
+    Interrupt,
+    Closed,
+}
+
+struct Session<'a, T: Cpu> {
+    emulator: &'a mut Emulator<T>,
+    stream: TcpStream,
+    no_ack: bool,
+    /// Ids in the emulator breakpoints, by address
+    breakpoints: BTreeMap<u16, usize>,
+    /// Ids in the emulator watchpoints, by `Z` type, address and length
+    watchpoints: BTreeMap<(u8, u16, u16), usize>,
+}
+
+impl<T: Cpu + 'static> Session<'_, T> {
+    fn run(&mut self) -> std::io::Result<()> {
+        loop {
+            let packet = match self.read_packet()? {
+                Packet::Data(packet) => packet,
+                Packet::Interrupt => {
+                    self.send(&format!("S{:02x}", SIGINT))?;
+                    continue;
+                }
+                Packet::Closed => return Ok(()),
+            };
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            match packet.as_str() {
+                "k" => return Ok(()),
+                _ if packet.starts_with('D') => return self.send("OK"),
+                "QStartNoAckMode" => {
+                    // This reply is still acknowledged
+                    self.send("OK")?;
+                    self.no_ack = true;
+                    continue;
+                }
+                _ => {}
+            }
+            let reply = self.handle(&packet)?;
+            self.send(&reply)?;
+        }
+    }
+
+    fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        let mut byte = [0u8];
+        loop {
+            match self.stream.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn read_packet(&mut self) -> std::io::Result<Packet> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(Packet::Closed),
+                Some(0x03) => return Ok(Packet::Interrupt),
+                Some(b'$') => break,
+                // Acknowledgements and noise between packets
+                Some(_) => {}
+            }
+        }
+        let mut data = Vec::new();
+        let mut checksum = 0u8;
+        loop {
+            let Some(byte) = self.read_byte()? else {
+                return Ok(Packet::Closed);
+            };
+            if byte == b'#' {
+                break;
+            }
+            checksum = checksum.wrapping_add(byte);
+            data.push(byte);
+        }
+        let mut expected = [0u8; 2];
+        self.stream.read_exact(&mut expected)?;
+        let valid = std::str::from_utf8(&expected)
+            .ok()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            == Some(checksum);
+        if !self.no_ack {
+            self.stream.write_all(if valid { b"+" } else { b"-" })?;
+        }
+        if !valid {
+            return self.read_packet();
+        }
+        Ok(Packet::Data(unescape(&data)))
+    }
+
+    fn send(&mut self, reply: &str) -> std::io::Result<()> {
+        let checksum = reply.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        let packet = format!("${}#{:02x}", reply, checksum);
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str) -> std::io::Result<String> {
+        let (command, args) = packet.split_at(1.min(packet.len()));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "c" => self.resume(args, false)?,
+            "s" => self.resume(args, true)?,
+            "Z" => self.insert_point(args),
+            "z" => self.remove_point(args),
+            "H" | "T" => "OK".to_string(),
+            "q" | "Q" | "v" => self.query(packet)?,
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> std::io::Result<String> {
+        let reply = match packet {
+            _ if packet.starts_with("qSupported") => format!(
+                "PacketSize={PACKET_SIZE:x};qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+;vContSupported+"
+            ),
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "vCont?" => "vCont;c;C;s;S".to_string(),
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                let range = &packet["qXfer:features:read:target.xml:".len()..];
+                match parse_pair(range, ',') {
+                    Some((offset, length)) => xfer(TARGET_XML, offset as usize, length as usize),
+                    None => "E01".to_string(),
+                }
+            }
+            _ if packet.starts_with("vCont;") => {
+                let action = packet["vCont;".len()..].split([';', ':']).next().unwrap_or("");
+                match action.chars().next() {
+                    Some('c') | Some('C') => self.resume("", false)?,
+                    Some('s') | Some('S') => self.resume("", true)?,
+                    _ => "E01".to_string(),
+                }
+            }
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn register(&self, name: &str) -> Option<u16> {
+        let registers = self.emulator.cpu.registers();
+        match name {
+            "ir" => Some(registers.get("i")? << 8 | registers.get("r")?),
+            _ => registers.get(name),
+        }
+    }
+
+    fn set_register(&mut self, name: &str, value: u16) -> bool {
+        let mut registers = self.emulator.cpu.registers_mut();
+        match name {
+            "ir" => registers.set("i", value >> 8) && registers.set("r", value),
+            _ => registers.set(name, value),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        REGISTERS
+            .iter()
+            .map(|name| match self.register(name) {
+                Some(value) => hex(&value.to_le_bytes()),
+                None => "xxxx".to_string(),
+            })
+            .collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        let Some(bytes) = unhex(args) else {
+            return "E01".to_string();
+        };
+        for (name, value) in REGISTERS.iter().zip(bytes.chunks_exact(2)) {
+            self.set_register(name, u16::from_le_bytes([value[0], value[1]]));
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let value = usize::from_str_radix(args, 16)
+            .ok()
+            .and_then(|idx| REGISTERS.get(idx))
+            .and_then(|name| self.register(name));
+        match value {
+            Some(value) => hex(&value.to_le_bytes()),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let Some((idx, value)) = args.split_once('=') else {
+            return "E01".to_string();
+        };
+        let name = usize::from_str_radix(idx, 16)
+            .ok()
+            .and_then(|idx| REGISTERS.get(idx));
+        let value = unhex(value).filter(|bytes| bytes.len() == 2);
+        match (name, value) {
+            (Some(name), Some(value))
+                if self.set_register(name, u16::from_le_bytes([value[0], value[1]])) =>
+            {
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((address, length)) = parse_pair(args, ',') else {
+            return "E01".to_string();
+        };
+        // Each byte is sent as two hex digits, so longer reads are cut short
+        let length = length.min(PACKET_SIZE / 2);
+        let bytes: Vec<u8> = (0..length)
+            .map_while(|offset| {
+                self.emulator
+                    .memory
+                    .peek_8((address as u16).wrapping_add(offset as u16))
+                    .ok()
+            })
+            .collect();
+        match bytes.is_empty() && length > 0 {
+            true => "E01".to_string(),
+            false => hex(&bytes),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        let (Some((address, length)), Some(bytes)) = (parse_pair(range, ','), unhex(data)) else {
+            return "E01".to_string();
+        };
+        if bytes.len() != length as usize {
+            return "E01".to_string();
+        }
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            let address = (address as u16).wrapping_add(offset as u16);
+            if self.emulator.memory.write_8(address, byte).is_err() {
+                return "E01".to_string();
+            }
+        }
+        "OK".to_string()
+    }
+
+    /// Continues or steps, optionally from a new address, and returns the stop reply.
+    fn resume(&mut self, args: &str, step: bool) -> std::io::Result<String> {
+        if let Ok(address) = u16::from_str_radix(args, 16) {
+            self.emulator.cpu.registers_mut().set("pc", address);
+        }
+        if self.emulator.cpu.halted() {
+            return Ok(format!("S{:02x}", SIGTRAP));
+        }
+        if step {
+            return Ok(match self.emulator.step() {
+                Ok(_) => format!("S{:02x}", SIGTRAP),
+                Err(_) => format!("S{:02x}", SIGILL),
+            });
+        }
+        loop {
+            let result = self.emulator.run_ticks(
+                CHUNK_TICKS,
+                &None::<fn(&mut Emulator<T>, &dyn ExecutableInstruction<T>)>,
+            );
+            if let Err(reason) = result {
+                return Ok(self.stop_reply(reason));
+            }
+            if self.interrupt_requested()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    /// Checks for a Ctrl-C from the debugger without blocking
+    fn interrupt_requested(&mut self) -> std::io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let result = self.stream.peek(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => Ok(true),
+            Ok(_) if byte[0] == 0x03 => {
+                self.stream.read_exact(&mut byte)?;
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn stop_reply(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Breakpoint(_) => format!("T{:02x}swbreak:;", SIGTRAP),
+            StopReason::Watchpoint(hit) => {
+                let kind = self
+                    .watchpoints
+                    .iter()
+                    .find(|(_, id)| **id == hit.id)
+                    .map(|((kind, _, _), _)| *kind);
+                let name = match (kind, hit.kind) {
+                    (Some(4), _) => "awatch",
+                    (_, MemoryAccessKind::Read) => "rwatch",
+                    _ => "watch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, hit.address)
+            }
+            StopReason::Pause => format!("S{:02x}", SIGINT),
+            StopReason::IllegalOpcode { .. } | StopReason::Error(_) => format!("S{:02x}", SIGILL),
+            _ => format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    /// `Z`/`z` arguments, type, address and length
+    fn parse_point(args: &str) -> Option<(u8, u16, u16)> {
+        let mut parts = args.split(',');
+        let kind = parts.next()?.parse().ok()?;
+        let address = u16::from_str_radix(parts.next()?, 16).ok()?;
+        let length = u16::from_str_radix(parts.next()?.split(';').next()?, 16).ok()?;
+        Some((kind, address, length))
+    }
+
+    fn insert_point(&mut self, args: &str) -> String {
+        let Some((kind, address, length)) = Self::parse_point(args) else {
+            return "E01".to_string();
+        };
+        let end = address.wrapping_add(length.max(1) - 1);
+        let watchpoint = match kind {
+            0 | 1 => {
+                if !self.breakpoints.contains_key(&address) {
+                    let id = self.emulator.breakpoints.add_address(address);
+                    self.breakpoints.insert(address, id);
+                }
+                return "OK".to_string();
+            }
+            2 => Watchpoint::write(address, end),
+            3 => Watchpoint::read(address, end),
+            4 => Watchpoint::new(address, end, true, true, false),
+            _ => return String::new(),
+        };
+        if !self.watchpoints.contains_key(&(kind, address, length)) {
+            let id = self.emulator.watchpoints.add(watchpoint);
+            self.watchpoints.insert((kind, address, length), id);
+        }
+        "OK".to_string()
+    }
+
+    fn remove_point(&mut self, args: &str) -> String {
+        let Some((kind, address, length)) = Self::parse_point(args) else {
+            return "E01".to_string();
+        };
+        match kind {
+            0 | 1 => {
+                if let Some(id) = self.breakpoints.remove(&address) {
+                    self.emulator.breakpoints.remove(id);
+                }
+            }
+            2..=4 => {
+                if let Some(id) = self.watchpoints.remove(&(kind, address, length)) {
+                    self.emulator.watchpoints.remove(id);
+                }
+            }
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+
+    fn remove_points(&mut self) {
+        for (_, id) in std::mem::take(&mut self.breakpoints) {
+            self.emulator.breakpoints.remove(id);
+        }
+        for (_, id) in std::mem::take(&mut self.watchpoints) {
+            self.emulator.watchpoints.remove(id);
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(text.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_pair(text: &str, separator: char) -> Option<(u32, u32)> {
+    let (first, second) = text.split_once(separator)?;
+    Some((
+        u32::from_str_radix(first, 16).ok()?,
+        u32::from_str_radix(second, 16).ok()?,
+    ))
+}
+
+/// Bytes escaped with `}` are xored with 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'}' => result.extend(bytes.next().map(|byte| byte ^ 0x20)),
+            _ => result.push(*byte),
+        }
+    }
+    result
+}
+
+/// Reply to a `qXfer` read of `length` bytes at `offset`
+fn xfer(document: &str, offset: usize, length: usize) -> String {
+    let rest = document.get(offset..).unwrap_or("");
+    match rest.len() > length {
+        true => format!("m{}", &rest[..length]),
+        false => format!("l{}", rest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::emulator_with;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, packet: &str) {
+            let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            write!(self.stream, "${}#{:02x}", packet, checksum).unwrap();
+            let mut ack = [0u8];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+        }
+
+        fn reply(&mut self) -> String {
+            let mut reply = Vec::new();
+            let mut byte = [0u8];
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                match byte[0] {
+                    b'$' => reply.clear(),
+                    b'#' => break,
+                    byte => reply.push(byte),
+                }
+            }
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+
+        fn request(&mut self, packet: &str) -> String {
+            self.send(packet);
+            self.reply()
+        }
+    }
+
+    #[test]
+    fn test_session() {
+        // 0x00: LD HL, 0x8000; LD A, 5; NOP; LD (HL), A; JR 0x07
+        let program = [0x21, 0x00, 0x80, 0x3E, 0x05, 0x00, 0x77, 0x18, 0xFE];
+        let mut emulator = emulator_with(&program);
+        emulator.cpu.registers.gp.hl = 0x1234;
+        let server = GdbServer::bind(0).unwrap();
+        let address = server.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut client = Client {
+                stream: TcpStream::connect(address).unwrap(),
+            };
+            let mut transcript = Vec::new();
+            for packet in [
+                "qSupported:swbreak+",
+                "qXfer:features:read:target.xml:0,f",
+                "?",
+                "p3",
+                "P3=cdab",
+                "p3",
+                "m0,4",
+                "M8000,2:aa55",
+                "m8000,3",
+                "m0,ffffffff",
+                "Z0,6,1",
+                "c",
+                "p5",
+                "z0,6,1",
+                "Z2,8000,1",
+                "c",
+                "s",
+                "p5",
+            ] {
+                transcript.push(client.request(packet));
+            }
+            client.send("c");
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            client.stream.write_all(&[0x03]).unwrap();
+            transcript.push(client.reply());
+            transcript.push(client.request("D"));
+            transcript
+        });
+        server.serve(&mut emulator).unwrap();
+        let mut transcript = client.join().unwrap();
+        let large = transcript.remove(9);
+
+        assert!(transcript[0].contains("PacketSize=4000;"));
+        assert!(transcript[0].contains("qXfer:features:read+"));
+        assert_eq!(large.len(), PACKET_SIZE as usize);
+        assert!(large.starts_with("2100803e"));
+        assert_eq!(transcript[1], "m<?xml version=\"");
+        assert_eq!(
+            transcript[2..],
+            [
+                "S05",
+                "3412",
+                "OK",
+                "cdab",
+                "2100803e",
+                "OK",
+                "aa5500",
+                "OK",
+                "T05swbreak:;",
+                "0600",
+                "OK",
+                "OK",
+                "T05watch:8000;",
+                "S05",
+                "0700",
+                "S02",
+                "OK",
+            ]
+        );
+        assert_eq!(emulator.memory.peek_8(0x8000).unwrap(), 0x05);
+        assert!(emulator.breakpoints.is_empty() && emulator.watchpoints.is_empty());
+    }
+
+    #[test]
+    fn test_encoding() {
+        assert_eq!(xfer(TARGET_XML, TARGET_XML.len() - 3, 10), "lt>\n");
+        assert_eq!(xfer("abcdef", 1, 2), "mbc");
+        assert_eq!(unescape(b"a}\x03b"), b"a#b");
+        assert_eq!(unhex("0aFf"), Some(vec![0x0A, 0xFF]));
+        assert_eq!(unhex("0a0"), None);
+    }
+}
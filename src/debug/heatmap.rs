@@ -0,0 +1,216 @@
+use crate::bus::{MemoryAccess, MemoryAccessKind};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::{AddAssign, RangeInclusive};
+use std::path::Path;
+
+/// Accesses to an address, or the sum over a range.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccessCounts {
+    pub reads: u64,
+    pub writes: u64,
+    /// Bytes fetched as part of an instruction, opcodes and operands
+    pub fetches: u64,
+}
+
+impl AccessCounts {
+    pub fn is_empty(&self) -> bool {
+        self.reads == 0 && self.writes == 0 && self.fetches == 0
+    }
+}
+
+impl AddAssign for AccessCounts {
+    fn add_assign(&mut self, other: AccessCounts) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.fetches += other.fetches;
+    }
+}
+
+/// Counts the reads, writes and instruction fetches of every address. Reads only count
+/// data, the bytes of the instructions are counted as fetches.
+#[derive(Debug, Clone)]
+pub struct Heatmap {
+    reads: Vec<u32>,
+    writes: Vec<u32>,
+    fetches: Vec<u32>,
+}
+
+impl Default for Heatmap {
+    fn default() -> Heatmap {
+        Heatmap::new()
+    }
+}
+
+impl Heatmap {
+    pub fn new() -> Heatmap {
+        Heatmap {
+            reads: vec![0; 0x10000],
+            writes: vec![0; 0x10000],
+            fetches: vec![0; 0x10000],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.reads.fill(0);
+        self.writes.fill(0);
+        self.fetches.fill(0);
+    }
+
+    /// Counts the accesses of an instruction `length` bytes long.
+    pub fn record(&mut self, length: u16, accesses: &[MemoryAccess]) {
+        for access in accesses {
+            let address = access.address as usize;
+            match access.kind {
+                MemoryAccessKind::Read => increment(&mut self.reads[address]),
+                MemoryAccessKind::Write => increment(&mut self.writes[address]),
+                MemoryAccessKind::Execute => {
+                    for offset in 0..length {
+                        let address = access.address.wrapping_add(offset) as usize;
+                        increment(&mut self.fetches[address]);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, address: u16) -> AccessCounts {
+        let address = address as usize;
+        AccessCounts {
+            reads: self.reads[address] as u64,
+            writes: self.writes[address] as u64,
+            fetches: self.fetches[address] as u64,
+        }
+    }
+
+    /// Counts of every address in `range`, in order.
+    pub fn range(&self, range: RangeInclusive<u16>) -> Vec<AccessCounts> {
+        range.map(|address| self.get(address)).collect()
+    }
+
+    /// Sum of the counts of the addresses in `range`.
+    pub fn total(&self, range: RangeInclusive<u16>) -> AccessCounts {
+        let mut total = AccessCounts::default();
+        for address in range {
+            total += self.get(address);
+        }
+        total
+    }
+
+    /// Runs of consecutive addresses whose counts match `filter`, for example the data
+    /// read but never executed with `|counts| counts.reads > 0 && counts.fetches == 0`.
+    pub fn regions(&self, filter: impl Fn(AccessCounts) -> bool) -> Vec<RangeInclusive<u16>> {
+        let mut regions = Vec::new();
+        let mut start = None;
+        for address in 0..=u16::MAX {
+            match (filter(self.get(address)), start) {
+                (true, None) => start = Some(address),
+                (false, Some(first)) => {
+                    regions.push(first..=address - 1);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(first) = start {
+            regions.push(first..=u16::MAX);
+        }
+        regions
+    }
+
+    /// Writes the 64K as a 256x256 binary PPM image, one pixel per address with the high
+    /// byte as the row. Writes are red, reads green and fetches blue, on a log scale up to
+    /// the most accessed address of each kind.
+    pub fn write_ppm(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let scale = |counts: &[u32]| {
+            let max = counts.iter().copied().max().unwrap_or(0) as f64;
+            move |count: u32| match count {
+                0 => 0,
+                // Anything accessed stays visible next to the hottest addresses
+                count => (64.0 + 191.0 * (count as f64).ln_1p() / max.ln_1p()) as u8,
+            }
+        };
+        let (red, green, blue) = (
+            scale(&self.writes),
+            scale(&self.reads),
+            scale(&self.fetches),
+        );
+        write!(out, "P6\n256 256\n255\n")?;
+        let mut pixels = Vec::with_capacity(3 * 0x10000);
+        for address in 0..0x10000 {
+            pixels.push(red(self.writes[address]));
+            pixels.push(green(self.reads[address]));
+            pixels.push(blue(self.fetches[address]));
+        }
+        out.write_all(&pixels)
+    }
+
+    pub fn save_ppm(&self, path: &Path) -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_ppm(&mut out)?;
+        out.flush()
+    }
+}
+
+fn increment(count: &mut u32) {
+    *count = count.saturating_add(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::emulator_with;
+
+    #[test]
+    fn test_heatmap() {
+        // LD HL, 0x8000; LD B, 3; loop: LD A, (HL); INC HL; LD (HL), A; DJNZ loop; HALT
+        let program = [
+            0x21, 0x00, 0x80, 0x06, 0x03, 0x7E, 0x23, 0x77, 0x10, 0xFB, 0x76,
+        ];
+        let mut emulator = emulator_with(&program);
+        emulator.set_heatmap(true);
+        while emulator.step().is_ok() {}
+        let heatmap = emulator.heatmap().unwrap();
+        assert_eq!(
+            heatmap.get(0x0009),
+            AccessCounts {
+                reads: 0,
+                writes: 0,
+                fetches: 3
+            }
+        );
+        assert_eq!(
+            heatmap.total(0x8000..=0x8003),
+            AccessCounts {
+                reads: 3,
+                writes: 3,
+                fetches: 0
+            }
+        );
+        let writes: Vec<u64> = heatmap
+            .range(0x8000..=0x8003)
+            .iter()
+            .map(|counts| counts.writes)
+            .collect();
+        assert_eq!(writes, vec![0, 1, 1, 1]);
+        assert_eq!(
+            heatmap.regions(|counts| counts.fetches > 0),
+            vec![0x0000..=0x000A]
+        );
+        assert_eq!(
+            heatmap.regions(|counts| counts.reads > 0),
+            vec![0x8000..=0x8002]
+        );
+
+        let mut out = Vec::new();
+        heatmap.write_ppm(&mut out).unwrap();
+        assert!(out.starts_with(b"P6\n256 256\n255\n"));
+        let pixels = &out[15..];
+        assert_eq!(pixels.len(), 3 * 0x10000);
+        assert_eq!(&pixels[3 * 0x8000..3 * 0x8002], &[0, 255, 0, 255, 255, 0]);
+        assert_eq!(&pixels[3 * 0x0009..3 * 0x000A], &[0, 0, 255]);
+
+        emulator.heatmap_mut().unwrap().clear();
+        assert!(emulator.heatmap().unwrap().total(0..=u16::MAX).is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+use crate::bus::{IoAccess, IoDirection};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// One entry of the IO access log kept by the emulator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IoLogEntry {
+    pub port: u8,
+    pub value: u8,
+    pub direction: IoDirection,
+    /// Address of the instruction that made the access
+    pub pc: u16,
+    /// Cycle count when the instruction started
+    pub cycle: usize,
+}
+
+/// Stops after an instruction accessed a port.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IoBreakpoint {
+    pub port: u8,
+    pub read: bool,
+    pub write: bool,
+    /// Bits of the value that must match `value`, 0 matches anything
+    pub mask: u8,
+    pub value: u8,
+    pub enabled: bool,
+}
+
+impl IoBreakpoint {
+    pub fn new(port: u8, read: bool, write: bool) -> IoBreakpoint {
+        IoBreakpoint {
+            port,
+            read,
+            write,
+            mask: 0,
+            value: 0,
+            enabled: true,
+        }
+    }
+
+    pub fn read(port: u8) -> IoBreakpoint {
+        IoBreakpoint::new(port, true, false)
+    }
+
+    pub fn write(port: u8) -> IoBreakpoint {
+        IoBreakpoint::new(port, false, true)
+    }
+
+    pub fn with_value(self, mask: u8, value: u8) -> IoBreakpoint {
+        IoBreakpoint {
+            mask,
+            value: value & mask,
+            ..self
+        }
+    }
+
+    pub fn matches(&self, access: &IoAccess) -> bool {
+        let direction = match access.direction {
+            IoDirection::Read => self.read,
+            IoDirection::Write => self.write,
+        };
+        self.enabled
+            && direction
+            && self.port == access.port
+            && access.value & self.mask == self.value
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoHit {
+    pub id: usize,
+    pub direction: IoDirection,
+    pub port: u8,
+    pub value: u8,
+    /// Address of the instruction that made the access
+    pub pc: u16,
+}
+
+/// IO breakpoints of an emulator, identified by the id returned when adding them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IoBreakpoints {
+    next_id: usize,
+    breakpoints: BTreeMap<usize, IoBreakpoint>,
+}
+
+impl IoBreakpoints {
+    pub fn new() -> IoBreakpoints {
+        IoBreakpoints::default()
+    }
+
+    pub fn add(&mut self, breakpoint: IoBreakpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.insert(id, breakpoint);
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<IoBreakpoint> {
+        self.breakpoints.remove(&id)
+    }
+
+    pub fn get(&self, id: usize) -> Option<&IoBreakpoint> {
+        self.breakpoints.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut IoBreakpoint> {
+        self.breakpoints.get_mut(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &IoBreakpoint)> {
+        self.breakpoints
+            .iter()
+            .map(|(id, breakpoint)| (*id, breakpoint))
+    }
+
+    pub fn len(&self) -> usize {
+        self.breakpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// First access of an instruction at `pc` that triggers a breakpoint
+    pub fn check(&self, pc: u16, accesses: &[IoAccess]) -> Option<IoHit> {
+        accesses.iter().find_map(|access| {
+            let (id, _) = self
+                .breakpoints
+                .iter()
+                .find(|(_, breakpoint)| breakpoint.matches(access))?;
+            Some(IoHit {
+                id: *id,
+                direction: access.direction,
+                port: access.port,
+                value: access.value,
+                pc,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let mut breakpoints = IoBreakpoints::new();
+        let border = breakpoints.add(IoBreakpoint::write(0xFE).with_value(0x07, 0x02));
+        let keyboard = breakpoints.add(IoBreakpoint::read(0xFE));
+        let out = |value| IoAccess {
+            direction: IoDirection::Write,
+            port: 0xFE,
+            value,
+        };
+        assert_eq!(breakpoints.check(0, &[out(0x01), out(0x11)]), None);
+        assert_eq!(
+            breakpoints.check(0x8000, &[out(0x01), out(0x1A)]),
+            Some(IoHit {
+                id: border,
+                direction: IoDirection::Write,
+                port: 0xFE,
+                value: 0x1A,
+                pc: 0x8000
+            })
+        );
+        let input = IoAccess {
+            direction: IoDirection::Read,
+            port: 0xFE,
+            value: 0xFF,
+        };
+        assert_eq!(
+            breakpoints.check(0, &[input]).map(|hit| hit.id),
+            Some(keyboard)
+        );
+        breakpoints.get_mut(keyboard).unwrap().enabled = false;
+        assert_eq!(breakpoints.check(0, &[input]), None);
+    }
+}
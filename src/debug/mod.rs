@@ -0,0 +1,16 @@
+pub mod breakpoint;
+pub mod callstack;
+pub mod coverage;
+pub mod dap;
+pub mod expression;
+pub mod gdb;
+pub mod heatmap;
+pub mod io;
+pub mod profile;
+pub mod rewind;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod source;
+pub mod symbols;
+pub mod trace;
+pub mod watchpoint;
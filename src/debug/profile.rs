@@ -0,0 +1,484 @@
+use crate::debug::callstack::{CallStack, CallStep, Frame, FrameKind};
+use crate::memory::Memory;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::ops::{AddAssign, Sub};
+
+/// Instructions executed and the T-states they took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cost {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl AddAssign for Cost {
+    fn add_assign(&mut self, other: Cost) {
+        self.instructions += other.instructions;
+        self.cycles += other.cycles;
+    }
+}
+
+impl Sub for Cost {
+    type Output = Cost;
+
+    fn sub(self, other: Cost) -> Cost {
+        Cost {
+            instructions: self.instructions - other.instructions,
+            cycles: self.cycles - other.cycles,
+        }
+    }
+}
+
+/// Cost of a function, identified by the address it was called at. `None` is the code
+/// that runs outside any call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionProfile {
+    pub function: Option<u16>,
+    pub calls: u64,
+    /// Cost of the instructions of the function itself
+    pub exclusive: Cost,
+    /// Cost including the functions it called, recursive calls are only counted once
+    pub inclusive: Cost,
+}
+
+impl FunctionProfile {
+    fn new(function: Option<u16>) -> FunctionProfile {
+        FunctionProfile {
+            function,
+            calls: 0,
+            exclusive: Cost::default(),
+            inclusive: Cost::default(),
+        }
+    }
+}
+
+/// Calls from one call site to a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallProfile {
+    pub caller: Option<u16>,
+    /// Address of the call instruction, or of the interrupted instruction
+    pub call_site: u16,
+    pub callee: u16,
+    pub calls: u64,
+    pub inclusive: Cost,
+}
+
+#[derive(Debug, Clone)]
+struct Activation {
+    frame: Frame,
+    /// Total cost when the function was entered
+    entry: Cost,
+}
+
+type CallKey = (Option<u16>, u16, u16);
+
+/// Counts the hits and T-states of every address and attributes them to functions by
+/// following calls, restarts and interrupts the same way as [`CallStack`]. The costs of
+/// the calls still running are included in what it reports.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    total: Cost,
+    addresses: Vec<Cost>,
+    /// Cost per function and address, code shared by functions is counted in each
+    lines: HashMap<(Option<u16>, u16), Cost>,
+    functions: HashMap<Option<u16>, FunctionProfile>,
+    calls: HashMap<CallKey, CallProfile>,
+    stack: CallStack,
+    activations: Vec<Activation>,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            total: Cost::default(),
+            addresses: vec![Cost::default(); 0x10000],
+            lines: HashMap::new(),
+            functions: HashMap::new(),
+            calls: HashMap::new(),
+            stack: CallStack::new(),
+            activations: Vec::new(),
+        }
+    }
+
+    /// Forgets the counts, the calls that are running are kept and count from here.
+    pub fn clear(&mut self) {
+        self.total = Cost::default();
+        self.addresses.fill(Cost::default());
+        self.lines.clear();
+        self.functions.clear();
+        self.calls.clear();
+        for activation in self.activations.iter_mut() {
+            activation.entry = Cost::default();
+        }
+    }
+
+    /// Counts an instruction that took `cycles` T-states, including the interrupt
+    /// acknowledge if one was taken.
+    pub fn record(&mut self, step: &CallStep, cycles: u64, memory: &Memory) {
+        let cost = Cost {
+            instructions: 1,
+            cycles,
+        };
+        let before = self.total;
+        self.total += cost;
+        let function = match step.interrupted {
+            Some(_) => Some(step.pc),
+            None => self
+                .activations
+                .last()
+                .map(|activation| activation.frame.target),
+        };
+        self.addresses[step.pc as usize] += cost;
+        *self.lines.entry((function, step.pc)).or_default() += cost;
+
+        self.stack.track(step, memory);
+        let frames = self.stack.frames();
+        if frames.len() == self.activations.len()
+            && frames.last() == self.activations.last().map(|activation| &activation.frame)
+        {
+            return;
+        }
+        let kept = self
+            .activations
+            .iter()
+            .zip(frames)
+            .take_while(|(activation, frame)| activation.frame == **frame)
+            .count();
+        while self.activations.len() > kept {
+            let activation = self.activations.pop().unwrap();
+            close(
+                &self.activations,
+                &activation,
+                self.total,
+                &mut self.functions,
+                &mut self.calls,
+            );
+        }
+        for frame in &frames[kept..] {
+            // The interrupt is acknowledged in the same step as the first instruction
+            // of the handler, which belongs to the handler
+            let entry = match frame.kind {
+                FrameKind::Interrupt => before,
+                _ => self.total,
+            };
+            self.activations.push(Activation {
+                frame: *frame,
+                entry,
+            });
+        }
+    }
+
+    pub fn total(&self) -> Cost {
+        self.total
+    }
+
+    pub fn address(&self, address: u16) -> Cost {
+        self.addresses[address as usize]
+    }
+
+    /// The `count` addresses that took the most T-states.
+    pub fn hottest(&self, count: usize) -> Vec<(u16, Cost)> {
+        let mut addresses: Vec<(u16, Cost)> = self
+            .addresses
+            .iter()
+            .enumerate()
+            .filter(|(_, cost)| cost.instructions > 0)
+            .map(|(address, cost)| (address as u16, *cost))
+            .collect();
+        addresses.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        addresses.truncate(count);
+        addresses
+    }
+
+    /// Closes the calls still running, as if they returned now.
+    fn settled(
+        &self,
+    ) -> (
+        BTreeMap<Option<u16>, FunctionProfile>,
+        BTreeMap<CallKey, CallProfile>,
+    ) {
+        let mut functions = self.functions.clone();
+        let mut calls = self.calls.clone();
+        for (idx, activation) in self.activations.iter().enumerate().rev() {
+            close(
+                &self.activations[..idx],
+                activation,
+                self.total,
+                &mut functions,
+                &mut calls,
+            );
+        }
+        let mut functions: BTreeMap<_, _> = functions.into_iter().collect();
+        for (&(function, _), cost) in self.lines.iter() {
+            functions
+                .entry(function)
+                .or_insert_with(|| FunctionProfile::new(function))
+                .exclusive += *cost;
+        }
+        if let Some(top) = functions.get_mut(&None) {
+            top.inclusive = self.total;
+        }
+        (functions, calls.into_iter().collect())
+    }
+
+    /// Functions by inclusive T-states, highest first.
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions: Vec<FunctionProfile> = self.settled().0.into_values().collect();
+        functions.sort_by_key(|function| std::cmp::Reverse(function.inclusive.cycles));
+        functions
+    }
+
+    /// Calls by caller, call site and callee.
+    pub fn calls(&self) -> Vec<CallProfile> {
+        self.settled().1.into_values().collect()
+    }
+
+    /// Writes the profile in the format of callgrind, to be read by KCachegrind and
+    /// `callgrind_annotate`. Functions are named with `symbol` or their address.
+    pub fn write_callgrind(
+        &self,
+        out: &mut impl Write,
+        symbol: impl Fn(u16) -> Option<String>,
+    ) -> std::io::Result<()> {
+        let (functions, calls) = self.settled();
+        let mut lines: Vec<_> = self.lines.iter().collect();
+        lines.sort_by_key(|(key, _)| **key);
+        writeln!(out, "# callgrind format")?;
+        writeln!(out, "version: 1")?;
+        writeln!(out, "creator: emu_lib")?;
+        writeln!(out, "positions: instr")?;
+        writeln!(out, "events: Instructions Tstates")?;
+        writeln!(
+            out,
+            "summary: {} {}",
+            self.total.instructions, self.total.cycles
+        )?;
+        for function in functions.keys() {
+            writeln!(out)?;
+            writeln!(out, "fn={}", function_name(*function, &symbol))?;
+            for ((_, address), cost) in lines.iter().filter(|((f, _), _)| f == function) {
+                writeln!(
+                    out,
+                    "0x{:04X} {} {}",
+                    address, cost.instructions, cost.cycles
+                )?;
+            }
+            for call in calls.values().filter(|call| call.caller == *function) {
+                writeln!(out, "cfn={}", function_name(Some(call.callee), &symbol))?;
+                writeln!(out, "calls={} 0x{:04X}", call.calls, call.callee)?;
+                writeln!(
+                    out,
+                    "0x{:04X} {} {}",
+                    call.call_site, call.inclusive.instructions, call.inclusive.cycles
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the `count` most expensive functions and addresses.
+    pub fn write_report(
+        &self,
+        out: &mut impl Write,
+        count: usize,
+        symbol: impl Fn(u16) -> Option<String>,
+    ) -> std::io::Result<()> {
+        let percent = |cycles: u64| match self.total.cycles {
+            0 => 0.0,
+            total => cycles as f64 * 100.0 / total as f64,
+        };
+        writeln!(
+            out,
+            "{} T-states in {} instructions",
+            self.total.cycles, self.total.instructions
+        )?;
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:>12} {:>6} {:>12} {:>6} {:>8}  function",
+            "inclusive", "%", "exclusive", "%", "calls"
+        )?;
+        for function in self.functions().iter().take(count) {
+            writeln!(
+                out,
+                "{:>12} {:>6.2} {:>12} {:>6.2} {:>8}  {}",
+                function.inclusive.cycles,
+                percent(function.inclusive.cycles),
+                function.exclusive.cycles,
+                percent(function.exclusive.cycles),
+                function.calls,
+                function_name(function.function, &symbol)
+            )?;
+        }
+        writeln!(out)?;
+        writeln!(out, "{:>12} {:>6} {:>10}  address", "T-states", "%", "hits")?;
+        for (address, cost) in self.hottest(count) {
+            write!(
+                out,
+                "{:>12} {:>6.2} {:>10}  {:04X}",
+                cost.cycles,
+                percent(cost.cycles),
+                cost.instructions,
+                address
+            )?;
+            match symbol(address) {
+                Some(symbol) => writeln!(out, " <{}>", symbol)?,
+                None => writeln!(out)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adds a call that returned to the totals, `outer` are the calls it was made in.
+fn close(
+    outer: &[Activation],
+    activation: &Activation,
+    total: Cost,
+    functions: &mut HashMap<Option<u16>, FunctionProfile>,
+    calls: &mut HashMap<CallKey, CallProfile>,
+) {
+    let inclusive = total - activation.entry;
+    let callee = activation.frame.target;
+    let caller = outer.last().map(|activation| activation.frame.target);
+    let function = functions
+        .entry(Some(callee))
+        .or_insert_with(|| FunctionProfile::new(Some(callee)));
+    function.calls += 1;
+    if !outer
+        .iter()
+        .any(|activation| activation.frame.target == callee)
+    {
+        function.inclusive += inclusive;
+    }
+    let call_site = activation.frame.caller;
+    let call = calls
+        .entry((caller, call_site, callee))
+        .or_insert(CallProfile {
+            caller,
+            call_site,
+            callee,
+            calls: 0,
+            inclusive: Cost::default(),
+        });
+    call.calls += 1;
+    call.inclusive += inclusive;
+}
+
+fn function_name(function: Option<u16>, symbol: &impl Fn(u16) -> Option<String>) -> String {
+    match function {
+        Some(address) => symbol(address).unwrap_or_else(|| format!("{:04X}", address)),
+        None => "(top)".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::emulator_with;
+
+    #[test]
+    fn test_profile() {
+        // 0x00: LD SP, 0xFF00; CALL 0x10; CALL 0x10; HALT
+        // 0x10: CALL 0x20; RET
+        // 0x20: NOP; RET
+        let mut program = vec![0x31, 0x00, 0xFF, 0xCD, 0x10, 0x00, 0xCD, 0x10, 0x00, 0x76];
+        program.resize(0x10, 0);
+        program.extend([0xCD, 0x20, 0x00, 0xC9]);
+        program.resize(0x20, 0);
+        program.extend([0x00, 0xC9]);
+        let mut emulator = emulator_with(&program);
+        emulator.set_profiler(true);
+        // Stop inside the second call to see the running calls counted
+        emulator.run_to_address(0x21).unwrap();
+        emulator.run_to_address(0x21).unwrap();
+        let profiler = emulator.profiler().unwrap();
+        let inner = profiler.functions()[2];
+        assert_eq!(
+            (inner.function, inner.calls, inner.inclusive.cycles),
+            (Some(0x20), 2, 18)
+        );
+
+        while emulator.step().is_ok() {}
+        let profiler = emulator.profiler().unwrap();
+        assert_eq!(
+            profiler.total(),
+            Cost {
+                instructions: 12,
+                cycles: 130
+            }
+        );
+        let functions: Vec<_> = profiler
+            .functions()
+            .iter()
+            .map(|f| (f.function, f.calls, f.exclusive.cycles, f.inclusive.cycles))
+            .collect();
+        assert_eq!(
+            functions,
+            vec![
+                (None, 0, 48, 130),
+                (Some(0x10), 2, 54, 82),
+                (Some(0x20), 2, 28, 28)
+            ]
+        );
+        let hottest: Vec<(u16, u64)> = profiler
+            .hottest(2)
+            .iter()
+            .map(|(address, cost)| (*address, cost.cycles))
+            .collect();
+        assert_eq!(hottest, vec![(0x10, 34), (0x13, 20)]);
+
+        let mut out = Vec::new();
+        let symbol = |address| (address == 0x10).then(|| "outer".to_string());
+        profiler.write_callgrind(&mut out, symbol).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# callgrind format\n\
+             version: 1\n\
+             creator: emu_lib\n\
+             positions: instr\n\
+             events: Instructions Tstates\n\
+             summary: 12 130\n\
+             \n\
+             fn=(top)\n\
+             0x0000 1 10\n\
+             0x0003 1 17\n\
+             0x0006 1 17\n\
+             0x0009 1 4\n\
+             cfn=outer\n\
+             calls=1 0x0010\n\
+             0x0003 4 41\n\
+             cfn=outer\n\
+             calls=1 0x0010\n\
+             0x0006 4 41\n\
+             \n\
+             fn=outer\n\
+             0x0010 2 34\n\
+             0x0013 2 20\n\
+             cfn=0020\n\
+             calls=2 0x0020\n\
+             0x0010 4 28\n\
+             \n\
+             fn=0020\n\
+             0x0020 2 8\n\
+             0x0021 2 20\n"
+        );
+
+        let mut out = Vec::new();
+        profiler.write_report(&mut out, 1, symbol).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "130 T-states in 12 instructions");
+        assert_eq!(
+            lines[3],
+            "         130 100.00           48  36.92        0  (top)"
+        );
+        assert_eq!(lines[6], "          34  26.15          2  0010 <outer>");
+    }
+}
@@ -0,0 +1,235 @@
+use crate::io::InterruptType;
+use std::collections::{BTreeMap, VecDeque};
+use std::mem::size_of;
+
+/// State of the machine before an instruction, made with
+/// [`Emulator::save`](crate::emulator::Emulator::save).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Instructions executed when the snapshot was taken
+    pub instructions: usize,
+    pub cycles: usize,
+    pub iff1: bool,
+    pub iff2: bool,
+    pub state: Vec<u8>,
+}
+
+impl Snapshot {
+    fn size(&self) -> usize {
+        size_of::<Snapshot>() + self.state.len()
+    }
+}
+
+/// Execution history used to go backwards: snapshots taken every `interval` instructions and
+/// the inputs that do not come from the machine itself, port reads and interrupts. Any state
+/// in the history is rebuilt by loading the snapshot before it and replaying the inputs.
+///
+/// The oldest snapshots are dropped when the history grows over its budget in bytes, at least
+/// one snapshot is always kept.
+#[derive(Debug, Clone)]
+pub struct Rewind {
+    interval: usize,
+    budget: usize,
+    snapshots: VecDeque<Snapshot>,
+    /// Port reads, with the index of the instruction that made them
+    reads: VecDeque<(usize, u8)>,
+    /// Interrupts, by the index of the instruction they were taken before
+    interrupts: BTreeMap<usize, InterruptType>,
+    /// Instructions executed at the end of the history
+    end: usize,
+    size: usize,
+    pub(crate) replaying: bool,
+}
+
+impl Rewind {
+    pub fn new(interval: usize, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            snapshots: VecDeque::new(),
+            reads: VecDeque::new(),
+            interrupts: BTreeMap::new(),
+            end: 0,
+            size: 0,
+            replaying: false,
+        }
+    }
+
+    /// Oldest instruction count that can be rewound to
+    pub fn start(&self) -> Option<usize> {
+        self.snapshots.front().map(|snapshot| snapshot.instructions)
+    }
+
+    /// Newest instruction count in the history
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn snapshots(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Approximate bytes used by the history
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.reads.clear();
+        self.interrupts.clear();
+        self.end = 0;
+        self.size = 0;
+    }
+
+    pub(crate) fn needs_snapshot(&self, instructions: usize) -> bool {
+        self.snapshots
+            .back()
+            .is_none_or(|snapshot| instructions >= snapshot.instructions + self.interval)
+    }
+
+    pub(crate) fn push_snapshot(&mut self, snapshot: Snapshot) {
+        self.size += snapshot.size();
+        self.end = snapshot.instructions;
+        self.snapshots.push_back(snapshot);
+        self.trim();
+    }
+
+    pub(crate) fn record_read(&mut self, instruction: usize, value: u8) {
+        self.size += size_of::<(usize, u8)>();
+        self.reads.push_back((instruction, value));
+    }
+
+    pub(crate) fn record_interrupt(&mut self, instruction: usize, interrupt: InterruptType) {
+        self.size += size_of::<(usize, InterruptType)>();
+        self.interrupts.insert(instruction, interrupt);
+    }
+
+    pub(crate) fn set_end(&mut self, instructions: usize) {
+        self.end = instructions;
+    }
+
+    /// Forgets everything after `instructions`, execution went another way from there.
+    pub(crate) fn truncate(&mut self, instructions: usize) {
+        if instructions >= self.end {
+            return;
+        }
+        while self
+            .snapshots
+            .back()
+            .is_some_and(|snapshot| snapshot.instructions > instructions)
+        {
+            let snapshot = self.snapshots.pop_back().expect("Snapshot checked above");
+            self.size -= snapshot.size();
+        }
+        while self
+            .reads
+            .back()
+            .is_some_and(|(instruction, _)| *instruction >= instructions)
+        {
+            self.reads.pop_back();
+            self.size -= size_of::<(usize, u8)>();
+        }
+        let later = self.interrupts.split_off(&instructions);
+        self.size -= later.len() * size_of::<(usize, InterruptType)>();
+        self.end = instructions;
+    }
+
+    fn trim(&mut self) {
+        while self.size > self.budget && self.snapshots.len() > 1 {
+            let snapshot = self.snapshots.pop_front().expect("Snapshot checked above");
+            self.size -= snapshot.size();
+            let start = self.start().expect("At least one snapshot is kept");
+            while self
+                .reads
+                .front()
+                .is_some_and(|(instruction, _)| *instruction < start)
+            {
+                self.reads.pop_front();
+                self.size -= size_of::<(usize, u8)>();
+            }
+            let kept = self.interrupts.split_off(&start);
+            self.size -= self.interrupts.len() * size_of::<(usize, InterruptType)>();
+            self.interrupts = kept;
+        }
+    }
+
+    /// Newest snapshot taken at or before `instructions`
+    pub(crate) fn snapshot_before(&self, instructions: usize) -> Option<&Snapshot> {
+        self.snapshots
+            .iter()
+            .rev()
+            .find(|snapshot| snapshot.instructions <= instructions)
+    }
+
+    /// Instruction counts of the snapshots taken before `instructions`, newest first
+    pub(crate) fn snapshots_before(&self, instructions: usize) -> Vec<usize> {
+        self.snapshots
+            .iter()
+            .rev()
+            .map(|snapshot| snapshot.instructions)
+            .filter(|start| *start < instructions)
+            .collect()
+    }
+
+    /// Port reads made from instruction `instruction` on, in order
+    pub(crate) fn reads_from(&self, instruction: usize) -> Vec<u8> {
+        self.reads
+            .iter()
+            .filter(|(index, _)| *index >= instruction)
+            .map(|(_, value)| *value)
+            .collect()
+    }
+
+    pub(crate) fn interrupt_at(&self, instruction: usize) -> Option<InterruptType> {
+        self.interrupts.get(&instruction).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(instructions: usize) -> Snapshot {
+        Snapshot {
+            instructions,
+            cycles: 0,
+            iff1: false,
+            iff2: false,
+            state: vec![0; 1000],
+        }
+    }
+
+    #[test]
+    fn test_budget_and_truncate() {
+        let mut rewind = Rewind::new(10, 2500 + 3 * size_of::<Snapshot>());
+        for start in [0, 10, 20] {
+            assert!(rewind.needs_snapshot(start));
+            rewind.push_snapshot(snapshot(start));
+            assert!(!rewind.needs_snapshot(start + 9));
+            rewind.record_read(start + 5, start as u8);
+            rewind.record_interrupt(start + 6, InterruptType::IM1);
+        }
+        rewind.set_end(25);
+        // The first snapshot and its inputs are gone
+        assert_eq!((rewind.start(), rewind.snapshots()), (Some(10), 2));
+        assert_eq!(rewind.reads_from(0), vec![10, 20]);
+        assert_eq!(rewind.interrupt_at(6), None);
+        assert_eq!(rewind.interrupt_at(16), Some(InterruptType::IM1));
+        assert_eq!(
+            rewind
+                .snapshot_before(19)
+                .map(|snapshot| snapshot.instructions),
+            Some(10)
+        );
+        assert_eq!(rewind.snapshots_before(20), vec![10]);
+
+        rewind.truncate(16);
+        assert_eq!((rewind.end(), rewind.snapshots()), (16, 1));
+        assert_eq!(rewind.reads_from(0), vec![10]);
+        assert_eq!(rewind.interrupt_at(16), None);
+        rewind.truncate(12);
+        assert!(rewind.reads_from(0).is_empty());
+        assert_eq!(rewind.size(), snapshot(0).size());
+    }
+}
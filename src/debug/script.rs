@@ -0,0 +1,469 @@
+//! Scripts driving an emulator, written in [Rhai](https://rhai.rs).
+//!
+//! A script sees the emulator as `emu`, its memory as `mem` and the CPU registers as
+//! `regs`:
+//!
+//! ```text
+//! emu.add_breakpoint(emu.symbol("draw"));
+//! let stop = emu.run();
+//! assert(stop.reason == "breakpoint", "draw was not reached");
+//! regs.hl = 0x4000;
+//! mem[0x8000] = 0xFF;
+//! emu.step_out();
+//! assert_eq(mem.read16(0x8001), 0x1234);
+//! ```
+//!
+//! Stops are maps with a `reason` of `breakpoint`, `halt`, `watchpoint`, `io_breakpoint`,
+//! `cycle_limit`, `instruction_limit`, `pause` or `illegal_opcode` and the fields of the
+//! hit, the runs also give the `cycles` and `instructions` they executed. Emulator errors
+//! are thrown.
+
+use crate::cpu::Cpu;
+use crate::debug::breakpoint::Breakpoint;
+use crate::emulator::{Emulator, RunResult, StopReason};
+use crate::memory::MemoryDevice;
+use rhai::{Blob, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::rc::Rc;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ScriptError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Parse error: {0}")]
+    Parse(#[from] rhai::ParseError),
+    #[error("{0}")]
+    Eval(#[from] Box<EvalAltResult>),
+}
+
+type Shared<T> = Rc<RefCell<Emulator<T>>>;
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// The emulator as seen by a script, `emu`.
+struct ScriptEmulator<T: Cpu>(Shared<T>);
+
+/// Memory of the emulator, `mem`.
+struct ScriptMemory<T: Cpu>(Shared<T>);
+
+/// CPU registers by name, `regs`.
+struct ScriptRegisters<T: Cpu>(Shared<T>);
+
+macro_rules! impl_clone {
+    ($($handle:ident),*) => {
+        $(impl<T: Cpu> Clone for $handle<T> {
+            fn clone(&self) -> Self {
+                $handle(self.0.clone())
+            }
+        })*
+    };
+}
+
+impl_clone!(ScriptEmulator, ScriptMemory, ScriptRegisters);
+
+/// Rhai engine with the emulator bindings registered, more functions can be added through
+/// [`ScriptEngine::engine_mut`].
+pub struct ScriptEngine<T: Cpu> {
+    engine: Engine,
+    cpu: PhantomData<T>,
+}
+
+impl<T: Cpu + 'static> Default for ScriptEngine<T> {
+    fn default() -> ScriptEngine<T> {
+        ScriptEngine::new()
+    }
+}
+
+impl<T: Cpu + 'static> ScriptEngine<T> {
+    pub fn new() -> ScriptEngine<T> {
+        let mut engine = Engine::new();
+        register_emulator::<T>(&mut engine);
+        register_memory::<T>(&mut engine);
+        register_registers::<T>(&mut engine);
+        register_asserts(&mut engine);
+        ScriptEngine {
+            engine,
+            cpu: PhantomData,
+        }
+    }
+
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    pub fn engine_mut(&mut self) -> &mut Engine {
+        &mut self.engine
+    }
+
+    pub fn compile(&self, source: &str) -> Result<AST, ScriptError> {
+        Ok(self.engine.compile(source)?)
+    }
+
+    /// Runs a script on `emulator` and returns the value of its last statement.
+    pub fn run(&self, emulator: &mut Emulator<T>, source: &str) -> Result<Dynamic, ScriptError> {
+        let ast = self.compile(source)?;
+        self.run_ast(emulator, &ast)
+    }
+
+    pub fn run_file(
+        &self,
+        emulator: &mut Emulator<T>,
+        path: &Path,
+    ) -> Result<Dynamic, ScriptError> {
+        let source = std::fs::read_to_string(path)?;
+        self.run(emulator, &source)
+    }
+
+    pub fn run_ast(&self, emulator: &mut Emulator<T>, ast: &AST) -> Result<Dynamic, ScriptError> {
+        // The bindings share the emulator, it is moved back out once the script is done
+        let shared = Rc::new(RefCell::new(std::mem::take(emulator)));
+        let mut scope = Scope::new();
+        scope.push("emu", ScriptEmulator(shared.clone()));
+        scope.push("mem", ScriptMemory(shared.clone()));
+        scope.push("regs", ScriptRegisters(shared.clone()));
+        let result = self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, ast);
+        *emulator = std::mem::take(&mut *shared.borrow_mut());
+        Ok(result?)
+    }
+}
+
+fn to_u16(value: i64) -> ScriptResult<u16> {
+    u16::try_from(value).map_err(|_| format!("{} is not a 16 bit value", value).into())
+}
+
+fn to_u8(value: i64) -> ScriptResult<u8> {
+    u8::try_from(value).map_err(|_| format!("{} is not an 8 bit value", value).into())
+}
+
+fn stop_map(reason: StopReason) -> ScriptResult<Map> {
+    let mut map = Map::new();
+    let mut insert = |key: &str, value: Dynamic| {
+        map.insert(key.into(), value);
+    };
+    match reason {
+        StopReason::Breakpoint(id) => {
+            insert("reason", "breakpoint".into());
+            insert("id", (id as i64).into());
+        }
+        StopReason::Halt => insert("reason", "halt".into()),
+        StopReason::Watchpoint(hit) => {
+            insert("reason", "watchpoint".into());
+            insert("id", (hit.id as i64).into());
+            insert("address", (hit.address as i64).into());
+            insert("old", (hit.old as i64).into());
+            insert("new", (hit.new as i64).into());
+            insert("pc", (hit.pc as i64).into());
+        }
+        StopReason::IoBreakpoint(hit) => {
+            insert("reason", "io_breakpoint".into());
+            insert("id", (hit.id as i64).into());
+            insert("port", (hit.port as i64).into());
+            insert("value", (hit.value as i64).into());
+            insert("pc", (hit.pc as i64).into());
+        }
+        StopReason::CycleLimit => insert("reason", "cycle_limit".into()),
+        StopReason::InstructionLimit => insert("reason", "instruction_limit".into()),
+        StopReason::Condition => insert("reason", "condition".into()),
+        StopReason::Pause => insert("reason", "pause".into()),
+        StopReason::IllegalOpcode { address, message } => {
+            insert("reason", "illegal_opcode".into());
+            insert("address", (address as i64).into());
+            insert("message", message.into());
+        }
+        StopReason::Error(e) => return Err(e.into()),
+    }
+    Ok(map)
+}
+
+/// `()` when a run finished, the stop otherwise.
+fn run_result(result: Result<(), StopReason>) -> ScriptResult<Dynamic> {
+    match result {
+        Ok(()) => Ok(Dynamic::UNIT),
+        Err(reason) => Ok(stop_map(reason)?.into()),
+    }
+}
+
+/// The stop of a run with the T-states and instructions it executed.
+fn result_map(result: RunResult) -> ScriptResult<Map> {
+    let mut map = stop_map(result.reason)?;
+    map.insert("cycles".into(), (result.cycles as i64).into());
+    map.insert("instructions".into(), (result.instructions as i64).into());
+    Ok(map)
+}
+
+fn register_emulator<T: Cpu + 'static>(engine: &mut Engine) {
+    engine
+        .register_type_with_name::<ScriptEmulator<T>>("Emulator")
+        .register_fn(
+            "step",
+            |emu: &mut ScriptEmulator<T>| -> ScriptResult<String> {
+                let instruction = emu.0.borrow_mut().step()?;
+                Ok(instruction.to_string())
+            },
+        )
+        .register_fn("step_over", |emu: &mut ScriptEmulator<T>| {
+            run_result(emu.0.borrow_mut().step_over())
+        })
+        .register_fn("step_out", |emu: &mut ScriptEmulator<T>| {
+            run_result(emu.0.borrow_mut().step_out())
+        })
+        .register_fn("step_line", |emu: &mut ScriptEmulator<T>| {
+            run_result(emu.0.borrow_mut().step_line())
+        })
+        .register_fn("run_to", |emu: &mut ScriptEmulator<T>, address: i64| {
+            run_result(emu.0.borrow_mut().run_to_address(to_u16(address)?))
+        })
+        .register_fn("run", |emu: &mut ScriptEmulator<T>| {
+            result_map(emu.0.borrow_mut().run_until(|_| false))
+        })
+        .register_fn("run", |emu: &mut ScriptEmulator<T>, cycles: i64| {
+            let cycles = usize::try_from(cycles).map_err(|_| "Negative cycle count")?;
+            result_map(emu.0.borrow_mut().run_for_cycles(cycles))
+        })
+        .register_fn(
+            "run_instructions",
+            |emu: &mut ScriptEmulator<T>, instructions: i64| {
+                let instructions =
+                    usize::try_from(instructions).map_err(|_| "Negative instruction count")?;
+                result_map(emu.0.borrow_mut().run_for_instructions(instructions))
+            },
+        )
+        .register_fn(
+            "add_breakpoint",
+            |emu: &mut ScriptEmulator<T>, address: i64| -> ScriptResult<i64> {
+                let breakpoint = Breakpoint::new(to_u16(address)?);
+                Ok(emu.0.borrow_mut().breakpoints.add(breakpoint) as i64)
+            },
+        )
+        .register_fn(
+            "add_breakpoint",
+            |emu: &mut ScriptEmulator<T>, address: i64, condition: &str| -> ScriptResult<i64> {
+                let breakpoint = Breakpoint::conditional(to_u16(address)?, condition)
+                    .map_err(|e| e.to_string())?;
+                Ok(emu.0.borrow_mut().breakpoints.add(breakpoint) as i64)
+            },
+        )
+        .register_fn(
+            "remove_breakpoint",
+            |emu: &mut ScriptEmulator<T>, id: i64| {
+                usize::try_from(id)
+                    .is_ok_and(|id| emu.0.borrow_mut().breakpoints.remove(id).is_some())
+            },
+        )
+        .register_fn("clear_breakpoints", |emu: &mut ScriptEmulator<T>| {
+            emu.0.borrow_mut().breakpoints.clear()
+        })
+        .register_fn(
+            "symbol",
+            |emu: &mut ScriptEmulator<T>, name: &str| -> ScriptResult<i64> {
+                match emu.0.borrow().symbols.address(name) {
+                    Some(address) => Ok(address as i64),
+                    None => Err(format!("Unknown symbol {}", name).into()),
+                }
+            },
+        )
+        .register_fn(
+            "save",
+            |emu: &mut ScriptEmulator<T>| -> ScriptResult<Blob> { Ok(emu.0.borrow().save()?) },
+        )
+        .register_fn(
+            "load",
+            |emu: &mut ScriptEmulator<T>, state: Blob| -> ScriptResult<()> {
+                Ok(emu.0.borrow_mut().load(state, false, true)?)
+            },
+        )
+        .register_fn("reset_counters", |emu: &mut ScriptEmulator<T>| {
+            emu.0.borrow_mut().reset_counters()
+        })
+        .register_get_set(
+            "pc",
+            |emu: &mut ScriptEmulator<T>| emu.0.borrow().cpu.pc() as i64,
+            |emu: &mut ScriptEmulator<T>, pc: i64| -> ScriptResult<()> {
+                let pc = to_u16(pc)?;
+                emu.0.borrow_mut().cpu.registers_mut().set("pc", pc);
+                Ok(())
+            },
+        )
+        .register_get("cycles", |emu: &mut ScriptEmulator<T>| {
+            emu.0.borrow().cycles as i64
+        })
+        .register_get("instructions", |emu: &mut ScriptEmulator<T>| {
+            emu.0.borrow().instructions as i64
+        })
+        .register_get("halted", |emu: &mut ScriptEmulator<T>| {
+            emu.0.borrow().cpu.halted()
+        });
+}
+
+fn register_memory<T: Cpu + 'static>(engine: &mut Engine) {
+    engine
+        .register_type_with_name::<ScriptMemory<T>>("Memory")
+        .register_indexer_get(
+            |mem: &mut ScriptMemory<T>, address: i64| -> ScriptResult<i64> {
+                let value = mem.0.borrow().memory.peek_8(to_u16(address)?);
+                Ok(value.map_err(|e| format!("{:?}", e))? as i64)
+            },
+        )
+        .register_indexer_set(
+            |mem: &mut ScriptMemory<T>, address: i64, value: i64| -> ScriptResult<()> {
+                let (address, value) = (to_u16(address)?, to_u8(value)?);
+                let result = mem.0.borrow_mut().memory.write_8_force(address, value);
+                Ok(result.map_err(|e| format!("{:?}", e))?)
+            },
+        )
+        .register_fn(
+            "read16",
+            |mem: &mut ScriptMemory<T>, address: i64| -> ScriptResult<i64> {
+                let address = to_u16(address)?;
+                let memory = &mem.0.borrow().memory;
+                let low = memory.peek_8(address).map_err(|e| format!("{:?}", e))?;
+                let high = memory
+                    .peek_8(address.wrapping_add(1))
+                    .map_err(|e| format!("{:?}", e))?;
+                Ok(u16::from_le_bytes([low, high]) as i64)
+            },
+        )
+        .register_fn(
+            "write16",
+            |mem: &mut ScriptMemory<T>, address: i64, value: i64| -> ScriptResult<()> {
+                let (address, value) = (to_u16(address)?, to_u16(value)?);
+                let result = mem.0.borrow_mut().memory.write_16_force(address, value);
+                Ok(result.map_err(|e| format!("{:?}", e))?)
+            },
+        )
+        .register_fn(
+            "read",
+            |mem: &mut ScriptMemory<T>, address: i64, length: i64| -> ScriptResult<Blob> {
+                let address = to_u16(address)?;
+                let memory = &mem.0.borrow().memory;
+                (0..length.max(0) as u16)
+                    .map(|offset| {
+                        let value = memory.peek_8(address.wrapping_add(offset));
+                        value.map_err(|e| format!("{:?}", e).into())
+                    })
+                    .collect()
+            },
+        )
+        .register_fn(
+            "write",
+            |mem: &mut ScriptMemory<T>, address: i64, data: Blob| -> ScriptResult<()> {
+                let address = to_u16(address)?;
+                let memory = &mut mem.0.borrow_mut().memory;
+                for (offset, value) in data.into_iter().enumerate() {
+                    memory
+                        .write_8_force(address.wrapping_add(offset as u16), value)
+                        .map_err(|e| format!("{:?}", e))?;
+                }
+                Ok(())
+            },
+        );
+}
+
+fn register_registers<T: Cpu + 'static>(engine: &mut Engine) {
+    // Properties that are not registered fall back to the indexer, `regs.hl` is `regs["hl"]`
+    engine
+        .register_type_with_name::<ScriptRegisters<T>>("Registers")
+        .register_indexer_get(
+            |regs: &mut ScriptRegisters<T>, name: &str| -> ScriptResult<i64> {
+                match regs.0.borrow().cpu.registers().get(name) {
+                    Some(value) => Ok(value as i64),
+                    None => Err(format!("Unknown register {}", name).into()),
+                }
+            },
+        )
+        .register_indexer_set(
+            |regs: &mut ScriptRegisters<T>, name: &str, value: i64| -> ScriptResult<()> {
+                let value = to_u16(value)?;
+                match regs.0.borrow_mut().cpu.registers_mut().set(name, value) {
+                    true => Ok(()),
+                    false => Err(format!("Unknown register {}", name).into()),
+                }
+            },
+        );
+}
+
+fn register_asserts(engine: &mut Engine) {
+    engine
+        .register_fn("assert", |condition: bool| -> ScriptResult<()> {
+            match condition {
+                true => Ok(()),
+                false => Err("Assertion failed".into()),
+            }
+        })
+        .register_fn(
+            "assert",
+            |condition: bool, message: &str| -> ScriptResult<()> {
+                match condition {
+                    true => Ok(()),
+                    false => Err(format!("Assertion failed: {}", message).into()),
+                }
+            },
+        )
+        .register_fn(
+            "assert_eq",
+            |left: Dynamic, right: Dynamic| -> ScriptResult<()> {
+                match left.to_string() == right.to_string() && left.type_id() == right.type_id() {
+                    true => Ok(()),
+                    false => Err(format!("Assertion failed: {} != {}", left, right).into()),
+                }
+            },
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::z80::Z80;
+    use crate::emulator::emulator_with;
+
+    #[test]
+    fn test_script() {
+        // LD HL, 0x8000; loop: LD A, (HL); INC A; LD (HL), A; CP 3; JR NZ, loop; HALT
+        let mut emulator = emulator_with(&[
+            0x21, 0x00, 0x80, 0x7E, 0x3C, 0x77, 0xFE, 0x03, 0x20, 0xF9, 0x76,
+        ]);
+        emulator.symbols.insert("store", 0x0005);
+        let script = ScriptEngine::<Z80>::new();
+        let result = script
+            .run(
+                &mut emulator,
+                r#"
+                let id = emu.add_breakpoint(emu.symbol("store"));
+                let stop = emu.run();
+                assert(stop.reason == "breakpoint" && stop.id == id, "breakpoint");
+                assert_eq(regs.hl, 0x8000);
+                assert_eq(regs["a"], 1);
+                let state = emu.save();
+                mem[0x8000] = 1;
+                regs.a = 2;
+                emu.remove_breakpoint(id);
+                assert_eq(emu.run().reason, "halt");
+                assert_eq(mem[0x8000], 3);
+                emu.load(state);
+                assert_eq(emu.pc, 5);
+                emu.step();
+                let stop = emu.run_instructions(2);
+                assert(stop.reason == "instruction_limit" && stop.instructions == 2);
+                mem.read16(0x8000)
+                "#,
+            )
+            .unwrap();
+        assert_eq!(result.as_int(), Ok(1));
+        assert_eq!(emulator.cpu.pc(), 0x0003);
+        assert_eq!(emulator.breakpoints.len(), 1);
+
+        let error = script
+            .run(&mut emulator, "assert_eq(regs.a, 7)")
+            .unwrap_err();
+        assert!(error.to_string().contains("1 != 7"), "{}", error);
+        let error = script.run(&mut emulator, "regs.xyz").unwrap_err();
+        assert!(
+            error.to_string().contains("Unknown register xyz"),
+            "{}",
+            error
+        );
+        assert_eq!(emulator.cpu.pc(), 0x0003);
+    }
+}
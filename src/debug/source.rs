@@ -0,0 +1,236 @@
+//! Line tables mapping addresses to the source lines they were assembled from.
+//!
+//! They are read from sjasmplus source level debugging data (`--sld`), or from a listing
+//! in the format of [`crate::cpu::z80::disassembler`] and accepted by
+//! [`crate::cpu::z80::assembler`], where every line carries its address in a comment.
+
+use crate::debug::dap::SourceMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SourceError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Line {line}: {message}")]
+    Parse { line: usize, message: String },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineTable {
+    files: Vec<String>,
+    file_ids: HashMap<String, usize>,
+    /// File and line of the instruction starting at each address
+    lines: BTreeMap<u16, (usize, u32)>,
+    /// First address generated for each line
+    addresses: BTreeMap<(usize, u32), u16>,
+    /// Directory relative source paths are read from
+    base: Option<PathBuf>,
+}
+
+impl LineTable {
+    pub fn new() -> LineTable {
+        LineTable::default()
+    }
+
+    /// Reads an SLD file by its `.sld` extension, a listing otherwise. Source files are
+    /// looked for relative to the directory of `path`.
+    pub fn load(path: &Path) -> Result<LineTable, SourceError> {
+        let text = std::fs::read_to_string(path)?;
+        let is_sld = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("sld"));
+        let mut table = match is_sld {
+            true => LineTable::parse_sld(&text)?,
+            false => {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                LineTable::parse_listing(&name, &text)?
+            }
+        };
+        table.base = path.parent().map(Path::to_path_buf);
+        Ok(table)
+    }
+
+    /// Instructions of an SLD file, the lines of macro bodies are attributed to the line
+    /// the macro is used on.
+    pub fn parse_sld(text: &str) -> Result<LineTable, SourceError> {
+        let mut table = LineTable::new();
+        for (idx, line) in text.lines().enumerate() {
+            if line.starts_with('|') || line.trim().is_empty() {
+                continue;
+            }
+            let error = |message: &str| SourceError::Parse {
+                line: idx + 1,
+                message: message.to_string(),
+            };
+            // file|line|definition file|definition line|page|value|type|data
+            let fields: Vec<&str> = line.split('|').collect();
+            if fields.len() < 8 {
+                return Err(error("Expected 8 fields"));
+            }
+            if fields[6] != "T" {
+                continue;
+            }
+            let number = fields[1]
+                .parse()
+                .map_err(|_| error("Invalid line number"))?;
+            let value: i64 = fields[5].parse().map_err(|_| error("Invalid address"))?;
+            if let Ok(address) = u16::try_from(value) {
+                table.insert(fields[0], number, address);
+            }
+        }
+        Ok(table)
+    }
+
+    /// Lines of a listing that have an address comment, `    LD A, 0x05  ; 8000: 3E 05`.
+    pub fn parse_listing(path: &str, text: &str) -> Result<LineTable, SourceError> {
+        let mut table = LineTable::new();
+        for (idx, line) in text.lines().enumerate() {
+            let Some((_, comment)) = line.split_once(';') else {
+                continue;
+            };
+            let Some((address, _)) = comment.trim().split_once(':') else {
+                continue;
+            };
+            let Ok(address) = u16::from_str_radix(address, 16) else {
+                continue;
+            };
+            table.insert(path, idx as u32 + 1, address);
+        }
+        Ok(table)
+    }
+
+    pub fn insert(&mut self, path: &str, line: u32, address: u16) {
+        let file = match self.file_ids.get(path) {
+            Some(file) => *file,
+            None => {
+                self.files.push(path.to_string());
+                self.file_ids.insert(path.to_string(), self.files.len() - 1);
+                self.files.len() - 1
+            }
+        };
+        self.lines.insert(address, (file, line));
+        let first = self.addresses.entry((file, line)).or_insert(address);
+        *first = (*first).min(address);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// File and line of the instruction starting at `address`.
+    pub fn line_at(&self, address: u16) -> Option<(&str, u32)> {
+        let (file, line) = self.lines.get(&address)?;
+        Some((&self.files[*file], *line))
+    }
+
+    /// First address of `line`, `path` can leave out leading directories.
+    pub fn address_of(&self, path: &str, line: u32) -> Option<u16> {
+        let file = self.file_id(path)?;
+        self.addresses.get(&(file, line)).copied()
+    }
+
+    /// Address of a location written as `file.asm:123`.
+    pub fn resolve(&self, location: &str) -> Option<u16> {
+        let (path, line) = location.rsplit_once(':')?;
+        self.address_of(path, line.parse().ok()?)
+    }
+
+    fn file_id(&self, path: &str) -> Option<usize> {
+        self.file_ids.get(path).copied().or_else(|| {
+            self.files
+                .iter()
+                .position(|file| Path::new(file).ends_with(path))
+        })
+    }
+
+    /// The source around the line of `address`, `context` lines before and after it with
+    /// the line itself marked, or `None` when the address has no line or the file cannot
+    /// be read.
+    pub fn source_context(&self, address: u16, context: u32) -> Option<String> {
+        let (path, line) = self.line_at(address)?;
+        let path = match &self.base {
+            Some(base) => base.join(path),
+            None => PathBuf::from(path),
+        };
+        let text = std::fs::read_to_string(&path).ok()?;
+        let first = line.saturating_sub(context).max(1);
+        let mut out = format!("{}:{}\n", path.display(), line);
+        for (number, source) in text
+            .lines()
+            .enumerate()
+            .map(|(idx, source)| (idx as u32 + 1, source))
+            .skip(first as usize - 1)
+            .take_while(|(number, _)| *number <= line + context)
+        {
+            let marker = if number == line { '>' } else { ' ' };
+            out.push_str(&format!("{}{:>5}  {}\n", marker, number, source));
+        }
+        Some(out)
+    }
+}
+
+impl SourceMap for LineTable {
+    fn address(&self, path: &str, line: u32) -> Option<u16> {
+        self.address_of(path, line)
+    }
+
+    fn location(&self, address: u16) -> Option<(String, u32)> {
+        self.line_at(address)
+            .map(|(path, line)| (path.to_string(), line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sld() {
+        let sld = "|SLD.data.version|1\n\
+                   src/main.asm|1||0|-1|-1|Z|pages.size:65536,pages.count:1,slots.count:1,slots.adr:0\n\
+                   src/main.asm|3||0|0|0|F|start\n\
+                   src/main.asm|3||0|0|0|T|\n\
+                   src/main.asm|4||0|0|2|T|\n\
+                   src/main.asm|5|src/macros.asm|2|0|5|T|\n\
+                   src/main.asm|5|src/macros.asm|3|0|4|T|\n";
+        let table = LineTable::parse_sld(sld).unwrap();
+        assert_eq!(table.line_at(2), Some(("src/main.asm", 4)));
+        assert_eq!(table.line_at(1), None);
+        assert_eq!(table.address_of("src/main.asm", 5), Some(4));
+        assert_eq!(table.resolve("main.asm:3"), Some(0));
+        assert_eq!(table.resolve("other.asm:3"), None);
+        assert_eq!(table.location(5), Some(("src/main.asm".to_string(), 5)));
+    }
+
+    #[test]
+    fn test_listing_and_context() {
+        let listing = "    ORG 0x8000\n\
+                       start:\n    \
+                       LD A, 0x05              ; 8000: 3E 05\n    \
+                       DEC A                   ; 8002: 3D\n    \
+                       JR NZ, start            ; 8003: 20 FB\n";
+        let path = std::env::temp_dir().join(format!("emu_listing_{}.asm", std::process::id()));
+        std::fs::write(&path, listing).unwrap();
+        let table = LineTable::load(&path).unwrap();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        assert_eq!(table.line_at(0x8002), Some((name.as_str(), 4)));
+        let context = table.source_context(0x8002, 1).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = context.lines().collect();
+        assert_eq!(lines[0], format!("{}:4", path.display()));
+        assert_eq!(
+            &lines[1..],
+            &[
+                "     3      LD A, 0x05              ; 8000: 3E 05",
+                ">    4      DEC A                   ; 8002: 3D",
+                "     5      JR NZ, start            ; 8003: 20 FB",
+            ]
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! Symbol tables from Z80 toolchains, to show and accept names instead of addresses.
+//!
+//! Supported formats:
+//! - z88dk map files, `name = $8000 ; addr, public, ...`
+//! - `EQU` listings as written by sjasmplus `--sym` and pasmo, `name: EQU 0x8000` or
+//!   `name EQU 08000H`
+//! - sjasmplus source level debugging data `--sld`, its labels
+//! - NoICE command files, `DEF name 8000`
+
+use crate::cpu::instruction::ExecutableInstruction;
+use crate::cpu::metadata::BranchTarget;
+use crate::cpu::Cpu;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SymbolError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unknown symbol file format")]
+    UnknownFormat,
+    #[error("Line {line}: {message}")]
+    Parse { line: usize, message: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolFormat {
+    Z88dkMap,
+    /// sjasmplus `--sym` and pasmo symbol files
+    Equ,
+    Sld,
+    NoIce,
+}
+
+impl SymbolFormat {
+    /// Guesses the format from the extension of `path`, or the contents for `.sym` files
+    /// and unknown extensions.
+    pub fn detect(path: &Path, text: &str) -> Option<SymbolFormat> {
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        match extension.map(str::to_ascii_lowercase).as_deref() {
+            Some("map") => return Some(SymbolFormat::Z88dkMap),
+            Some("sld") => return Some(SymbolFormat::Sld),
+            Some("noi") => return Some(SymbolFormat::NoIce),
+            _ => {}
+        }
+        let mut lines = text.lines().map(str::trim);
+        let first_word = |line: &str| line.split_whitespace().next().map(str::to_ascii_lowercase);
+        if text.starts_with("|SLD.data.version") {
+            Some(SymbolFormat::Sld)
+        } else if lines
+            .clone()
+            .any(|line| first_word(line).as_deref() == Some("def"))
+        {
+            Some(SymbolFormat::NoIce)
+        } else if lines.clone().any(|line| line.contains(" = $")) {
+            Some(SymbolFormat::Z88dkMap)
+        } else if lines.any(|line| {
+            line.split_whitespace()
+                .nth(1)
+                .is_some_and(|word| word.eq_ignore_ascii_case("equ"))
+        }) {
+            Some(SymbolFormat::Equ)
+        } else {
+            None
+        }
+    }
+}
+
+/// Names of addresses, loaded from symbol files or added by hand. An address can have
+/// several names, the first one defined is used to show it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+    /// Address of each name and when it was defined
+    addresses: HashMap<String, (u16, usize)>,
+    names: BTreeMap<u16, String>,
+    defined: usize,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    /// Reads a symbol file, see [`SymbolFormat::detect`].
+    pub fn load(path: &Path) -> Result<Symbols, SymbolError> {
+        let mut symbols = Symbols::new();
+        symbols.import(path)?;
+        Ok(symbols)
+    }
+
+    /// Adds the symbols of a file, returns how many it had.
+    pub fn import(&mut self, path: &Path) -> Result<usize, SymbolError> {
+        let text = std::fs::read_to_string(path)?;
+        let format = SymbolFormat::detect(path, &text).ok_or(SymbolError::UnknownFormat)?;
+        self.import_str(&text, format)
+    }
+
+    pub fn import_str(&mut self, text: &str, format: SymbolFormat) -> Result<usize, SymbolError> {
+        let mut count = 0;
+        for (idx, line) in text.lines().enumerate() {
+            let parsed = match format {
+                SymbolFormat::Z88dkMap => parse_z88dk(line),
+                SymbolFormat::Equ => parse_equ(line),
+                SymbolFormat::Sld => parse_sld(line),
+                SymbolFormat::NoIce => parse_noice(line),
+            };
+            let symbol = parsed.map_err(|message| SymbolError::Parse {
+                line: idx + 1,
+                message,
+            })?;
+            if let Some((name, address)) = symbol {
+                self.insert(name, address);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn insert(&mut self, name: &str, address: u16) {
+        self.defined += 1;
+        if let Some((old, _)) = self
+            .addresses
+            .insert(name.to_string(), (address, self.defined))
+        {
+            if self.names.get(&old).is_some_and(|shown| shown == name) {
+                self.names.remove(&old);
+                if let Some(other) = self
+                    .addresses
+                    .iter()
+                    .filter(|(_, (address, _))| *address == old)
+                    .min_by_key(|(_, (_, defined))| *defined)
+                    .map(|(name, _)| name)
+                {
+                    self.names.insert(old, other.clone());
+                }
+            }
+        }
+        self.names
+            .entry(address)
+            .or_insert_with(|| name.to_string());
+    }
+
+    pub fn clear(&mut self) {
+        self.addresses.clear();
+        self.names.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).map(|(address, _)| *address)
+    }
+
+    /// Name of exactly `address`.
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.names.get(&address).map(String::as_str)
+    }
+
+    /// Closest symbol at or below `address` and the offset from it.
+    pub fn locate(&self, address: u16) -> Option<(&str, u16)> {
+        self.names
+            .range(..=address)
+            .next_back()
+            .map(|(start, name)| (name.as_str(), address - start))
+    }
+
+    /// `name` or `name+offset` for `address`, like `main+3`.
+    pub fn describe(&self, address: u16) -> Option<String> {
+        self.locate(address).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            offset => format!("{}+{}", name, offset),
+        })
+    }
+
+    /// Symbols by address.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.names
+            .iter()
+            .map(|(address, name)| (*address, name.as_str()))
+    }
+
+    /// Assembly text of the instruction at `address` with its branch target named.
+    pub fn instruction_text<T: Cpu + 'static>(
+        &self,
+        instruction: &dyn ExecutableInstruction<T>,
+        address: u16,
+    ) -> String {
+        label_operands(instruction, address, |target| {
+            self.name(target).map(str::to_string)
+        })
+    }
+}
+
+/// Assembly text of the instruction at `address` with the branch target replaced by its
+/// label, relative jumps are written with the absolute target.
+pub fn label_operands<T: Cpu + 'static>(
+    instruction: &dyn ExecutableInstruction<T>,
+    address: u16,
+    label: impl Fn(u16) -> Option<String>,
+) -> String {
+    let text = instruction.to_string();
+    let metadata = instruction.metadata();
+    match metadata.target_address(address) {
+        Some(target) if matches!(metadata.target, Some(BranchTarget::Relative(_))) => {
+            let operand = label(target).unwrap_or_else(|| format!("0x{:04X}", target));
+            match text.rsplit_once(' ') {
+                Some((head, _)) => format!("{} {}", head, operand),
+                None => text,
+            }
+        }
+        Some(target) => match label(target) {
+            Some(label) => text.replace(&format!("0x{:04X}", target), &label),
+            None => text,
+        },
+        None => text,
+    }
+}
+
+/// Number in any of the notations of the assemblers: `0x8000`, `$8000`, `#8000`,
+/// `08000H` or decimal.
+fn parse_number(text: &str) -> Result<i64, String> {
+    let lower = text.to_ascii_lowercase();
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_prefix('$').or(lower.strip_prefix('#')) {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        (hex, 16)
+    } else {
+        (lower.as_str(), 10)
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| format!("Invalid number {}", text))
+}
+
+/// Values that do not fit in 16 bits are constants rather than addresses.
+fn address(name: &str, value: i64) -> Option<(&str, u16)> {
+    u16::try_from(value).ok().map(|address| (name, address))
+}
+
+fn parse_z88dk(line: &str) -> Result<Option<(&str, u16)>, String> {
+    let (definition, comment) = line.split_once(';').unwrap_or((line, ""));
+    let Some((name, value)) = definition.split_once('=') else {
+        return Ok(None);
+    };
+    // Equates are listed with the labels
+    if comment
+        .split(',')
+        .next()
+        .is_some_and(|kind| kind.trim() == "const")
+    {
+        return Ok(None);
+    }
+    let value = parse_number(value.trim())?;
+    Ok(address(name.trim(), value))
+}
+
+fn parse_equ(line: &str) -> Result<Option<(&str, u16)>, String> {
+    let line = line.split(';').next().unwrap_or("");
+    let mut words = line.split_whitespace();
+    let (Some(name), Some(keyword), Some(value)) = (words.next(), words.next(), words.next())
+    else {
+        return Ok(None);
+    };
+    if !keyword.eq_ignore_ascii_case("equ") {
+        return Ok(None);
+    }
+    let value = parse_number(value)?;
+    Ok(address(name.trim_end_matches(':'), value))
+}
+
+fn parse_sld(line: &str) -> Result<Option<(&str, u16)>, String> {
+    if line.starts_with('|') || line.trim().is_empty() {
+        return Ok(None);
+    }
+    // file|line|definition file|definition line|page|value|type|data
+    let fields: Vec<&str> = line.split('|').collect();
+    if fields.len() < 8 {
+        return Err("Expected 8 fields".to_string());
+    }
+    if !matches!(fields[6], "F" | "L") {
+        return Ok(None);
+    }
+    let value = parse_number(fields[5])?;
+    let name = fields[7].split(',').next().unwrap_or("");
+    Ok(address(name, value))
+}
+
+fn parse_noice(line: &str) -> Result<Option<(&str, u16)>, String> {
+    let mut words = line.split_whitespace();
+    let (Some(command), Some(name), Some(value)) = (words.next(), words.next(), words.next())
+    else {
+        return Ok(None);
+    };
+    if !command.eq_ignore_ascii_case("def") {
+        return Ok(None);
+    }
+    let value = match value.to_ascii_lowercase().starts_with("0x") {
+        true => parse_number(value)?,
+        false => i64::from_str_radix(value.trim_end_matches(['h', 'H']), 16)
+            .map_err(|_| format!("Invalid number {}", value))?,
+    };
+    Ok(address(name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str, format: SymbolFormat) -> Vec<(u16, String)> {
+        let mut symbols = Symbols::new();
+        symbols.import_str(text, format).unwrap();
+        symbols
+            .iter()
+            .map(|(address, name)| (address, name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_formats() {
+        let expected = vec![
+            (0x0000, "start".to_string()),
+            (0x8000, "buffer".to_string()),
+        ];
+        let map = "start                           = $0000 ; addr, public, , main, code_compiler, main.c:3\n\
+                   buffer                          = $8000 ; addr, public, , main, bss_compiler, main.c:1\n\
+                   SIZE                            = $0100 ; const, public, , main, , main.c:2\n";
+        assert_eq!(parse(map, SymbolFormat::Z88dkMap), expected);
+        let sjasmplus =
+            "; File test.asm\nstart: EQU 0x00000000\nbuffer: EQU 0x00008000\nBIG: EQU 0x00012345\n";
+        assert_eq!(parse(sjasmplus, SymbolFormat::Equ), expected);
+        let pasmo = "start\tEQU 0000H\nbuffer\tEQU 08000H\n";
+        assert_eq!(parse(pasmo, SymbolFormat::Equ), expected);
+        let sld = "|SLD.data.version|1\n\
+                   test.asm|1||0|-1|-1|Z|pages.size:65536,pages.count:1,slots.count:1,slots.adr:0\n\
+                   test.asm|3||0|0|0|F|start\n\
+                   test.asm|3||0|0|0|T|\n\
+                   test.asm|9||0|0|32768|L|buffer\n\
+                   test.asm|10||0|-1|256|E|SIZE\n";
+        assert_eq!(parse(sld, SymbolFormat::Sld), expected);
+        let noice = "LOAD test.bin\nDEF start 0000\nDEF buffer 8000\n";
+        assert_eq!(parse(noice, SymbolFormat::NoIce), expected);
+
+        assert_eq!(
+            SymbolFormat::detect(Path::new("test.sym"), sjasmplus),
+            Some(SymbolFormat::Equ)
+        );
+        assert_eq!(
+            SymbolFormat::detect(Path::new("test.txt"), noice),
+            Some(SymbolFormat::NoIce)
+        );
+        let error = Symbols::new()
+            .import_str("start EQU zz\n", SymbolFormat::Equ)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Line 1: Invalid number zz");
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut symbols = Symbols::new();
+        symbols.insert("main", 0x100);
+        symbols.insert("entry", 0x100);
+        symbols.insert("loop", 0x110);
+        assert_eq!(symbols.address("entry"), Some(0x100));
+        assert_eq!(symbols.name(0x100), Some("main"));
+        assert_eq!(symbols.describe(0x10F).as_deref(), Some("main+15"));
+        assert_eq!(symbols.describe(0x110).as_deref(), Some("loop"));
+        assert_eq!(symbols.describe(0xFF), None);
+        // Moving a name hands the address over to its other name
+        symbols.insert("main", 0x200);
+        assert_eq!(symbols.name(0x100), Some("entry"));
+        assert_eq!(symbols.len(), 3);
+        // The next name in definition order takes over, not the first alphabetically
+        symbols.insert("zeta", 0x300);
+        symbols.insert("omega", 0x300);
+        symbols.insert("alpha", 0x300);
+        assert_eq!(symbols.name(0x300), Some("zeta"));
+        symbols.insert("zeta", 0x400);
+        assert_eq!(symbols.name(0x300), Some("omega"));
+    }
+}
@@ -0,0 +1,536 @@
+//! Compact binary execution traces, one record per instruction.
+//!
+//! A trace starts with [`MAGIC`] and a version byte, each record is then
+//! - PC of the instruction, u16 little endian
+//! - number of instruction bytes, u8, and the bytes
+//! - u16 mask of the registers that changed since the previous record, followed by their values
+//! - cycles since the previous record, LEB128
+//! - number of memory writes, LEB128, each an u16 address and the value
+
+use crate::cpu::registers::{AllRegisters, Flags};
+use crate::debug::symbols::Symbols;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::Path;
+use thiserror::Error;
+
+pub const MAGIC: &[u8; 8] = b"EMUTRACE";
+pub const VERSION: u8 = 1;
+
+/// Registers stored in each record, in order.
+pub const REGISTERS: [&str; 13] = [
+    "af", "bc", "de", "hl", "af'", "bc'", "de'", "hl'", "ix", "iy", "sp", "i", "r",
+];
+
+#[derive(Debug, Error)]
+pub enum TraceError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Not a trace file")]
+    BadHeader,
+    #[error("Unsupported trace version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Trace ends in the middle of a record")]
+    Truncated,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Address the instruction was executed from
+    pub pc: u16,
+    pub bytes: Vec<u8>,
+    /// Registers after the instruction, in the order of [`REGISTERS`]
+    pub registers: [u16; REGISTERS.len()],
+    /// Cycle count after the instruction
+    pub cycles: u64,
+    /// Memory written by the instruction, in order
+    pub writes: Vec<(u16, u8)>,
+}
+
+impl TraceRecord {
+    pub fn new(
+        pc: u16,
+        bytes: Vec<u8>,
+        registers: &AllRegisters,
+        cycles: u64,
+        writes: Vec<(u16, u8)>,
+    ) -> TraceRecord {
+        TraceRecord {
+            pc,
+            bytes,
+            registers: REGISTERS.map(|name| registers.get(name).unwrap_or(0)),
+            cycles,
+            writes,
+        }
+    }
+
+    pub fn register(&self, name: &str) -> Option<u16> {
+        let idx = REGISTERS.iter().position(|register| *register == name)?;
+        Some(self.registers[idx])
+    }
+
+    pub fn flags(&self) -> Flags {
+        Flags::from_bits(self.registers[0] as u8)
+    }
+}
+
+fn flag_string(flags: Flags) -> String {
+    "SZ5H3PNC"
+        .chars()
+        .enumerate()
+        .map(|(idx, name)| match flags.into_bits() & (0x80 >> idx) {
+            0 => '-',
+            _ => name,
+        })
+        .collect()
+}
+
+impl Display for TraceRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(
+            f,
+            "{:>10} {:04X}  {:<11}",
+            self.cycles,
+            self.pc,
+            bytes.join(" ")
+        )?;
+        for (name, value) in REGISTERS.iter().zip(self.registers) {
+            match *name {
+                "i" | "r" => write!(f, " {}={:02X}", name.to_uppercase(), value)?,
+                _ => write!(f, " {}={:04X}", name.to_uppercase(), value)?,
+            }
+        }
+        write!(f, " F={}", flag_string(self.flags()))?;
+        for (addr, value) in &self.writes {
+            write!(f, " [{:04X}]={:02X}", addr, value)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_varint(writer: &mut impl Write, mut value: u64) -> std::io::Result<()> {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            return writer.write_all(&[byte]);
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+pub struct TraceWriter<W: Write> {
+    writer: W,
+    registers: Option<[u16; REGISTERS.len()]>,
+    cycles: u64,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(mut writer: W) -> std::io::Result<TraceWriter<W>> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(TraceWriter {
+            writer,
+            registers: None,
+            cycles: 0,
+        })
+    }
+
+    pub fn write(&mut self, record: &TraceRecord) -> std::io::Result<()> {
+        let mut data = Vec::with_capacity(32);
+        data.extend(record.pc.to_le_bytes());
+        data.push(record.bytes.len() as u8);
+        data.extend(&record.bytes);
+        let mut mask: u16 = 0;
+        let mut values = Vec::new();
+        for (idx, value) in record.registers.iter().enumerate() {
+            if self
+                .registers
+                .is_none_or(|previous| previous[idx] != *value)
+            {
+                mask |= 1 << idx;
+                values.extend(value.to_le_bytes());
+            }
+        }
+        data.extend(mask.to_le_bytes());
+        data.extend(values);
+        write_varint(&mut data, record.cycles.wrapping_sub(self.cycles))?;
+        write_varint(&mut data, record.writes.len() as u64)?;
+        for (addr, value) in &record.writes {
+            data.extend(addr.to_le_bytes());
+            data.push(*value);
+        }
+        self.writer.write_all(&data)?;
+        self.registers = Some(record.registers);
+        self.cycles = record.cycles;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+pub struct TraceReader<R: Read> {
+    reader: R,
+    registers: [u16; REGISTERS.len()],
+    cycles: u64,
+}
+
+impl TraceReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<TraceReader<BufReader<File>>, TraceError> {
+        TraceReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut reader: R) -> Result<TraceReader<R>, TraceError> {
+        let mut header = [0u8; MAGIC.len() + 1];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => TraceError::BadHeader,
+            _ => TraceError::Io(e),
+        })?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(TraceError::BadHeader);
+        }
+        if header[MAGIC.len()] != VERSION {
+            return Err(TraceError::UnsupportedVersion(header[MAGIC.len()]));
+        }
+        Ok(TraceReader {
+            reader,
+            registers: [0; REGISTERS.len()],
+            cycles: 0,
+        })
+    }
+
+    fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N], TraceError> {
+        let mut bytes = [0u8; N];
+        self.reader
+            .read_exact(&mut bytes)
+            .map_err(|e| match e.kind() {
+                ErrorKind::UnexpectedEof => TraceError::Truncated,
+                _ => TraceError::Io(e),
+            })?;
+        Ok(bytes)
+    }
+
+    fn read_u16(&mut self) -> Result<u16, TraceError> {
+        Ok(u16::from_le_bytes(self.read_bytes()?))
+    }
+
+    fn read_varint(&mut self) -> Result<u64, TraceError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let [byte] = self.read_bytes()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok(value)
+    }
+
+    /// Next record, `None` at the end of the trace
+    pub fn read_record(&mut self) -> Result<Option<TraceRecord>, TraceError> {
+        let mut first = [0u8; 1];
+        loop {
+            match self.reader.read(&mut first) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let [high] = self.read_bytes()?;
+        let pc = u16::from_le_bytes([first[0], high]);
+        let [length] = self.read_bytes()?;
+        let mut bytes = vec![0u8; length as usize];
+        for byte in bytes.iter_mut() {
+            [*byte] = self.read_bytes()?;
+        }
+        let mask = self.read_u16()?;
+        for idx in 0..REGISTERS.len() {
+            if mask & (1 << idx) != 0 {
+                self.registers[idx] = self.read_u16()?;
+            }
+        }
+        self.cycles = self.cycles.wrapping_add(self.read_varint()?);
+        let count = self.read_varint()?;
+        let mut writes = Vec::new();
+        for _ in 0..count {
+            let addr = self.read_u16()?;
+            let [value] = self.read_bytes()?;
+            writes.push((addr, value));
+        }
+        Ok(Some(TraceRecord {
+            pc,
+            bytes,
+            registers: self.registers,
+            cycles: self.cycles,
+            writes,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceRecord, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Writes one line of text per record, returns the number of records. Records at an
+/// address that has a symbol are preceded by the symbol as a label.
+pub fn export_text(
+    records: impl IntoIterator<Item = Result<TraceRecord, TraceError>>,
+    writer: &mut impl Write,
+    symbols: &Symbols,
+) -> Result<usize, TraceError> {
+    let mut count = 0;
+    for record in records {
+        let record = record?;
+        if let Some(name) = symbols.name(record.pc) {
+            writeln!(writer, "{}:", name)?;
+        }
+        writeln!(writer, "{}", record)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    /// Records shown before and after the divergence
+    pub context: usize,
+    /// Compare cycle counts, traces from emulators with different timings never match
+    pub ignore_cycles: bool,
+    /// Registers left out of the comparison, `r` is often not emulated the same way
+    pub ignore_registers: Vec<String>,
+}
+
+/// First place where two traces differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the first record that differs
+    pub index: usize,
+    /// What differs, like `hl: 1234 != 1235`
+    pub differences: Vec<String>,
+    /// Matching records before the divergence
+    pub before: Vec<TraceRecord>,
+    /// The differing record and the ones after it, from each trace
+    pub left: Vec<TraceRecord>,
+    pub right: Vec<TraceRecord>,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Traces diverge at record {}: {}",
+            self.index,
+            self.differences.join(", ")
+        )?;
+        for record in &self.before {
+            writeln!(f, "  {}", record)?;
+        }
+        for record in &self.left {
+            writeln!(f, "< {}", record)?;
+        }
+        for record in &self.right {
+            writeln!(f, "> {}", record)?;
+        }
+        Ok(())
+    }
+}
+
+/// Fields that differ between two records.
+pub fn differences(left: &TraceRecord, right: &TraceRecord, options: &DiffOptions) -> Vec<String> {
+    let mut differences = Vec::new();
+    if left.pc != right.pc {
+        differences.push(format!("pc: {:04X} != {:04X}", left.pc, right.pc));
+    }
+    if left.bytes != right.bytes {
+        differences.push(format!(
+            "bytes: {:02X?} != {:02X?}",
+            left.bytes, right.bytes
+        ));
+    }
+    for (idx, name) in REGISTERS.iter().enumerate() {
+        if left.registers[idx] != right.registers[idx]
+            && !options
+                .ignore_registers
+                .iter()
+                .any(|ignored| ignored == name)
+        {
+            differences.push(format!(
+                "{}: {:04X} != {:04X}",
+                name, left.registers[idx], right.registers[idx]
+            ));
+        }
+    }
+    if !options.ignore_cycles && left.cycles != right.cycles {
+        differences.push(format!("cycles: {} != {}", left.cycles, right.cycles));
+    }
+    if left.writes != right.writes {
+        differences.push(format!(
+            "writes: {:04X?} != {:04X?}",
+            left.writes, right.writes
+        ));
+    }
+    differences
+}
+
+/// Finds the first record where the traces differ, or where one of them ends first.
+pub fn diff(
+    left: impl IntoIterator<Item = Result<TraceRecord, TraceError>>,
+    right: impl IntoIterator<Item = Result<TraceRecord, TraceError>>,
+    options: &DiffOptions,
+) -> Result<Option<Divergence>, TraceError> {
+    let mut left = left.into_iter();
+    let mut right = right.into_iter();
+    let mut before = std::collections::VecDeque::new();
+    let mut index = 0;
+    loop {
+        let (l, r) = (left.next().transpose()?, right.next().transpose()?);
+        let differences = match (&l, &r) {
+            (None, None) => return Ok(None),
+            (Some(_), None) => vec!["right trace ends".to_string()],
+            (None, Some(_)) => vec!["left trace ends".to_string()],
+            (Some(l), Some(r)) => differences(l, r, options),
+        };
+        if differences.is_empty() {
+            before.push_back(l.expect("Both records exist"));
+            if before.len() > options.context {
+                before.pop_front();
+            }
+            index += 1;
+            continue;
+        }
+        let after = |first: Option<TraceRecord>,
+                     rest: &mut dyn Iterator<Item = Result<TraceRecord, TraceError>>|
+         -> Result<Vec<TraceRecord>, TraceError> {
+            let mut records: Vec<TraceRecord> = first.into_iter().collect();
+            if !records.is_empty() {
+                for record in rest.take(options.context) {
+                    records.push(record?);
+                }
+            }
+            Ok(records)
+        };
+        return Ok(Some(Divergence {
+            index,
+            differences,
+            before: before.into(),
+            left: after(l, &mut left)?,
+            right: after(r, &mut right)?,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(pc: u16, hl: u16, cycles: u64, writes: Vec<(u16, u8)>) -> TraceRecord {
+        let mut registers = [0u16; REGISTERS.len()];
+        registers[0] = 0x00C1;
+        registers[3] = hl;
+        registers[10] = 0xFFFE;
+        TraceRecord {
+            pc,
+            bytes: vec![0x23],
+            registers,
+            cycles,
+            writes,
+        }
+    }
+
+    fn encode(records: &[TraceRecord]) -> Vec<u8> {
+        let mut writer = TraceWriter::new(Vec::new()).unwrap();
+        for record in records {
+            writer.write(record).unwrap();
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let records = vec![
+            record(0x8000, 0x0001, 6, vec![]),
+            record(0x8001, 0x0002, 12, vec![(0x4000, 0xAA), (0x4001, 0x55)]),
+            record(0x8002, 0x0002, 100_000, vec![]),
+        ];
+        let data = encode(&records);
+        // Header, the first record with every register, then only HL changes
+        assert_eq!(
+            data.len(),
+            9 + (6 + 26 + 2) + (6 + 2 + 1 + 1 + 6) + (6 + 3 + 1)
+        );
+        let read: Vec<TraceRecord> = TraceReader::new(data.as_slice())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, records);
+
+        assert!(matches!(
+            TraceReader::new(&data[..data.len() - 1]).unwrap().nth(2),
+            Some(Err(TraceError::Truncated))
+        ));
+        assert!(matches!(
+            TraceReader::new(&b"NOTATRACE"[..]),
+            Err(TraceError::BadHeader)
+        ));
+    }
+
+    #[test]
+    fn test_text_export() {
+        let mut text = Vec::new();
+        let records = vec![Ok(record(0x8001, 0x1234, 12, vec![(0x4000, 0xAA)]))];
+        let mut symbols = Symbols::new();
+        symbols.insert("print", 0x8001);
+        assert_eq!(export_text(records, &mut text, &symbols).unwrap(), 1);
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with("print:\n        12 8001  23          AF=00C1 BC=0000 DE=0000 HL=1234"));
+        assert!(text.ends_with("SP=FFFE I=00 R=00 F=SZ-----C [4000]=AA\n"));
+    }
+
+    #[test]
+    fn test_diff() {
+        let left: Vec<TraceRecord> = (0..10)
+            .map(|i| record(i, i, i as u64 * 4, vec![]))
+            .collect();
+        let mut right = left.clone();
+        right[6].registers[3] = 0x1234;
+        right[6].registers[12] = 0x55;
+        let ok = |records: &Vec<TraceRecord>| -> Vec<Result<TraceRecord, TraceError>> {
+            records.iter().cloned().map(Ok).collect()
+        };
+        let options = DiffOptions {
+            context: 2,
+            ignore_cycles: false,
+            ignore_registers: vec!["r".to_string()],
+        };
+        let divergence = diff(ok(&left), ok(&right), &options).unwrap().unwrap();
+        assert_eq!(divergence.index, 6);
+        assert_eq!(divergence.differences, vec!["hl: 0006 != 1234"]);
+        assert_eq!(divergence.before, left[4..6]);
+        assert_eq!(divergence.left, left[6..9]);
+        assert_eq!(divergence.right, right[6..9]);
+        assert!(divergence
+            .to_string()
+            .starts_with("Traces diverge at record 6: hl"));
+
+        assert_eq!(diff(ok(&left), ok(&left), &options).unwrap(), None);
+        let divergence = diff(ok(&left), ok(&left[..8].to_vec()), &options)
+            .unwrap()
+            .unwrap();
+        assert_eq!((divergence.index, divergence.right.len()), (8, 0));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::bus::{MemoryAccess, MemoryAccessKind};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Watches a range of memory, checked after every instruction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Watchpoint {
+    pub start: u16,
+    /// Last watched address, inclusive
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    /// Only trigger when the value read, written or executed is this one
+    pub value: Option<u8>,
+    pub enabled: bool,
+}
+
+impl Watchpoint {
+    pub fn new(start: u16, end: u16, read: bool, write: bool, execute: bool) -> Watchpoint {
+        Watchpoint {
+            start,
+            end,
+            read,
+            write,
+            execute,
+            value: None,
+            enabled: true,
+        }
+    }
+
+    pub fn read(start: u16, end: u16) -> Watchpoint {
+        Watchpoint::new(start, end, true, false, false)
+    }
+
+    pub fn write(start: u16, end: u16) -> Watchpoint {
+        Watchpoint::new(start, end, false, true, false)
+    }
+
+    pub fn execute(start: u16, end: u16) -> Watchpoint {
+        Watchpoint::new(start, end, false, false, true)
+    }
+
+    pub fn with_value(self, value: u8) -> Watchpoint {
+        Watchpoint {
+            value: Some(value),
+            ..self
+        }
+    }
+
+    pub fn matches(&self, access: &MemoryAccess) -> bool {
+        let kind = match access.kind {
+            MemoryAccessKind::Read => self.read,
+            MemoryAccessKind::Write => self.write,
+            MemoryAccessKind::Execute => self.execute,
+        };
+        self.enabled
+            && kind
+            && (self.start..=self.end).contains(&access.address)
+            && self.value.is_none_or(|value| value == access.new)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub kind: MemoryAccessKind,
+    pub address: u16,
+    pub old: u8,
+    pub new: u8,
+    /// Address of the instruction that made the access
+    pub pc: u16,
+}
+
+/// Watchpoints of an emulator, identified by the id returned when adding them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Watchpoints {
+    next_id: usize,
+    watchpoints: BTreeMap<usize, Watchpoint>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Watchpoints {
+        Watchpoints::default()
+    }
+
+    pub fn add(&mut self, watchpoint: Watchpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watchpoints.insert(id, watchpoint);
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Watchpoint> {
+        self.watchpoints.remove(&id)
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Watchpoint> {
+        self.watchpoints.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Watchpoint> {
+        self.watchpoints.get_mut(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.watchpoints
+            .iter()
+            .map(|(id, watchpoint)| (*id, watchpoint))
+    }
+
+    pub fn len(&self) -> usize {
+        self.watchpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watchpoints.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    /// First access of an instruction at `pc` that triggers a watchpoint
+    pub fn check(&self, pc: u16, accesses: &[MemoryAccess]) -> Option<WatchHit> {
+        accesses.iter().find_map(|access| {
+            let (id, _) = self
+                .watchpoints
+                .iter()
+                .find(|(_, watchpoint)| watchpoint.matches(access))?;
+            Some(WatchHit {
+                id: *id,
+                kind: access.kind,
+                address: access.address,
+                old: access.old,
+                new: access.new,
+                pc,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(kind: MemoryAccessKind, address: u16, old: u8, new: u8) -> MemoryAccess {
+        MemoryAccess {
+            kind,
+            address,
+            old,
+            new,
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let mut watchpoints = Watchpoints::new();
+        let write = watchpoints.add(Watchpoint::write(0x4000, 0x40FF).with_value(0x55));
+        let read = watchpoints.add(Watchpoint::read(0x8000, 0x8000));
+        let accesses = [
+            access(MemoryAccessKind::Execute, 0x4000, 0x77, 0x77),
+            access(MemoryAccessKind::Write, 0x4010, 0x00, 0x54),
+            access(MemoryAccessKind::Write, 0x4011, 0x00, 0x55),
+        ];
+        assert_eq!(
+            watchpoints.check(0x1234, &accesses),
+            Some(WatchHit {
+                id: write,
+                kind: MemoryAccessKind::Write,
+                address: 0x4011,
+                old: 0x00,
+                new: 0x55,
+                pc: 0x1234
+            })
+        );
+        let accesses = [
+            access(MemoryAccessKind::Write, 0x8000, 0x00, 0x01),
+            access(MemoryAccessKind::Read, 0x8000, 0x01, 0x01),
+        ];
+        assert_eq!(
+            watchpoints.check(0, &accesses).map(|hit| hit.id),
+            Some(read)
+        );
+        watchpoints.get_mut(read).unwrap().enabled = false;
+        assert_eq!(watchpoints.check(0, &accesses), None);
+    }
+}
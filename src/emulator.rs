@@ -1,32 +1,134 @@
-use crate::cpu::instruction::ExecutableInstruction;
+use crate::bus::{
+    AccessRecorder, BusCycle, BusRecorder, IoDirection, IoRecorder, MemoryAccessKind,
+};
+use crate::cpu::instruction::{ExecutableInstruction, ParseError};
+use crate::cpu::metadata::{FlowKind, InstructionMetadata};
 use crate::cpu::Cpu;
-use crate::io::IO;
+use crate::debug::breakpoint::Breakpoints;
+use crate::debug::callstack::{BacktraceFrame, CallStack, CallStep, StackWord};
+use crate::debug::coverage::Coverage;
+use crate::debug::expression::MachineContext;
+use crate::debug::heatmap::Heatmap;
+use crate::debug::io::{IoBreakpoints, IoHit, IoLogEntry};
+use crate::debug::profile::Profiler;
+use crate::debug::rewind::{Rewind, Snapshot};
+use crate::debug::source::LineTable;
+use crate::debug::symbols::Symbols;
+use crate::debug::trace::{TraceRecord, TraceWriter};
+use crate::debug::watchpoint::{WatchHit, Watchpoint, Watchpoints};
+use crate::io::{InterruptType, IO};
 use crate::memory::{Memory, MemoryDevice};
+use crate::scheduler::Scheduler;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 #[derive(Debug)]
 pub enum StopReason {
-    Breakpoint,
+    /// Id of the breakpoint in [`Emulator::breakpoints`]
+    Breakpoint(usize),
     Halt,
+    /// An instruction accessed watched memory, execution stops after it
+    Watchpoint(WatchHit),
+    /// An instruction accessed a port with a breakpoint, execution stops after it
+    IoBreakpoint(IoHit),
+    /// The run executed the T-states it was given
+    CycleLimit,
+    /// The run executed the instructions it was given
+    InstructionLimit,
+    /// The condition of [`Emulator::run_until`] became true
+    Condition,
+    /// Requested through a [`PauseHandle`]
+    Pause,
+    /// The instruction at `address` cannot be decoded
+    IllegalOpcode { address: u16, message: String },
     Error(String),
 }
 
+/// Result of a headless run, the counters are what the run itself executed.
+#[derive(Debug)]
+pub struct RunResult {
+    pub reason: StopReason,
+    pub cycles: usize,
+    pub instructions: usize,
+}
+
+/// Stops a running emulator from another thread, execution stops after the current
+/// instruction with [`StopReason::Pause`].
+#[derive(Debug, Clone, Default)]
+pub struct PauseHandle(Arc<AtomicBool>);
+
+impl PauseHandle {
+    pub fn pause(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+const SNAPSHOT_MAGIC: [u8; 4] = *b"EMUS";
+/// Changes whenever [`EmuState`] does, older snapshots are refused
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Start of a snapshot, read before the [`EmuState`] that follows it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    pub magic: [u8; 4],
+    pub version: u32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct EmuState {
     pub cpu: Vec<u8>,
     pub memory: Vec<u8>,
-    pub breakpoints: Vec<u16>,
+    /// Size of each memory device, in address order
+    pub memory_map: Vec<usize>,
+    pub memory_devices: Vec<Vec<u8>>,
+    /// Ports of each IO device
+    pub io_map: Vec<Vec<u8>>,
+    pub io_devices: Vec<Vec<u8>>,
+    pub iff1: bool,
+    pub iff2: bool,
+    pub cycles: usize,
+    pub instructions: usize,
+    pub breakpoints: Breakpoints,
+    pub watchpoints: Watchpoints,
+    pub io_breakpoints: IoBreakpoints,
+    pub call_stack: Option<CallStack>,
 }
 
 pub struct Emulator<T: Cpu> {
     pub memory: Memory,
     pub cpu: T,
-    pub breakpoints: Vec<u16>,
+    pub breakpoints: Breakpoints,
+    pub watchpoints: Watchpoints,
+    pub io_breakpoints: IoBreakpoints,
+    /// Names for addresses, used by breakpoint conditions and backtraces
+    pub symbols: Symbols,
+    /// Source lines of the program, for stepping by line
+    pub lines: LineTable,
     pub io: IO,
     pub cycles: usize,
     pub instructions: usize,
+    /// Events at T-states of [`Emulator::cycles`], they are not rewound and do not fire
+    /// while replaying
+    pub scheduler: Scheduler,
+    bus: Option<Arc<BusRecorder>>,
+    access: Option<Arc<AccessRecorder>>,
+    watch_hit: Option<WatchHit>,
+    io_access: Option<Arc<IoRecorder>>,
+    io_hit: Option<IoHit>,
+    io_log: Option<Vec<IoLogEntry>>,
+    rewind: Option<Rewind>,
+    trace: Option<TraceWriter<Box<dyn Write + Send>>>,
+    call_stack: Option<CallStack>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    heatmap: Option<Heatmap>,
+    pause: PauseHandle,
 }
 
 impl<T: Cpu+'static> Default for Emulator<T> {
@@ -34,10 +136,28 @@ impl<T: Cpu+'static> Default for Emulator<T> {
         Emulator {
             memory: Memory::default(),
             cpu: T::default(),
-            breakpoints: Vec::new(),
+            breakpoints: Breakpoints::new(),
+            watchpoints: Watchpoints::new(),
+            io_breakpoints: IoBreakpoints::new(),
+            symbols: Symbols::new(),
+            lines: LineTable::new(),
             io: IO::default(),
             cycles: 0,
             instructions: 0,
+            scheduler: Scheduler::new(),
+            bus: None,
+            access: None,
+            watch_hit: None,
+            io_access: None,
+            io_hit: None,
+            io_log: None,
+            rewind: None,
+            trace: None,
+            call_stack: None,
+            profiler: None,
+            coverage: None,
+            heatmap: None,
+            pause: PauseHandle::default(),
         }
     }
 }
@@ -47,26 +167,493 @@ impl<T: Cpu +'static> Emulator<T> {
         Emulator {
             memory,
             cpu: T::default(),
-            breakpoints: Vec::new(),
+            breakpoints: Breakpoints::new(),
+            watchpoints: Watchpoints::new(),
+            io_breakpoints: IoBreakpoints::new(),
+            symbols: Symbols::new(),
+            lines: LineTable::new(),
             io: IO::default(),
             cycles: 0,
             instructions: 0,
+            scheduler: Scheduler::new(),
+            bus: None,
+            access: None,
+            watch_hit: None,
+            io_access: None,
+            io_hit: None,
+            io_log: None,
+            rewind: None,
+            trace: None,
+            call_stack: None,
+            profiler: None,
+            coverage: None,
+            heatmap: None,
+            pause: PauseHandle::default(),
+        }
+    }
+
+    /// Records the machine cycles of every step, see [`Emulator::bus_cycles`].
+    pub fn set_bus_trace(&mut self, enabled: bool) {
+        self.bus = enabled.then(|| Arc::new(BusRecorder::new()));
+        self.memory.set_bus_recorder(self.bus.clone());
+        self.io.set_bus_recorder(self.bus.clone());
+    }
+
+    /// Machine cycles of the last step, empty when bus tracing is disabled.
+    pub fn bus_cycles(&self) -> Vec<BusCycle> {
+        match &self.bus {
+            Some(bus) => bus.cycles(),
+            None => Vec::new(),
         }
     }
+
+    /// Records every port access with the PC and cycle count of the instruction, see
+    /// [`Emulator::io_log`].
+    pub fn set_io_log(&mut self, enabled: bool) {
+        self.io_log = enabled.then(Vec::new);
+    }
+
+    /// Port accesses since the log was enabled or last taken.
+    pub fn io_log(&self) -> &[IoLogEntry] {
+        self.io_log.as_deref().unwrap_or_default()
+    }
+
+    pub fn take_io_log(&mut self) -> Vec<IoLogEntry> {
+        match &mut self.io_log {
+            Some(log) => std::mem::take(log),
+            None => Vec::new(),
+        }
+    }
+
+    /// Writes a record of every executed instruction to `writer`, see
+    /// [`crate::debug::trace`]. A trace that was already running is stopped.
+    pub fn start_trace(&mut self, writer: Box<dyn Write + Send>) -> std::io::Result<()> {
+        self.stop_trace()?;
+        self.trace = Some(TraceWriter::new(writer)?);
+        Ok(())
+    }
+
+    pub fn start_trace_file(&mut self, path: &Path) -> std::io::Result<()> {
+        self.start_trace(Box::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Flushes and closes the trace.
+    pub fn stop_trace(&mut self) -> std::io::Result<()> {
+        match self.trace.take() {
+            Some(mut trace) => trace.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    /// Keeps a shadow call stack, see [`Emulator::backtrace`].
+    pub fn set_call_stack(&mut self, enabled: bool) {
+        self.call_stack = enabled.then(CallStack::new);
+    }
+
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.call_stack.as_ref()
+    }
+
+    /// Active calls from the innermost, starting with the current PC. Only the current frame
+    /// is known when the call stack is not kept.
+    pub fn backtrace(&self) -> Vec<BacktraceFrame> {
+        let stack = self.call_stack.clone().unwrap_or_default();
+        stack.backtrace(self.cpu.pc(), self.sp(), |pc| self.symbols.describe(pc))
+    }
+
+    /// Counts where the time goes, see [`crate::debug::profile`]. Enabling it again starts over.
+    pub fn set_profiler(&mut self, enabled: bool) {
+        self.profiler = enabled.then(Profiler::new);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Records which addresses are executed, read and written, see [`crate::debug::coverage`].
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.coverage = enabled.then(Coverage::new);
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Counts the accesses to every address, see [`crate::debug::heatmap`].
+    pub fn set_heatmap(&mut self, enabled: bool) {
+        self.heatmap = enabled.then(Heatmap::new);
+    }
+
+    pub fn heatmap(&self) -> Option<&Heatmap> {
+        self.heatmap.as_ref()
+    }
+
+    pub fn heatmap_mut(&mut self) -> Option<&mut Heatmap> {
+        self.heatmap.as_mut()
+    }
+
+    /// `count` words of stack from SP, with the return addresses of the active calls marked.
+    pub fn annotate_stack(&self, count: usize) -> Vec<StackWord> {
+        let stack = self.call_stack.clone().unwrap_or_default();
+        stack.annotate(&self.memory, self.sp(), count)
+    }
+
+    /// Describes the flow of the instruction at `pc` that just ran, `sp` is the stack pointer
+    /// before it and `interrupted` the address the CPU was at if an interrupt was taken.
+    fn call_step(
+        &self,
+        instruction: &dyn ExecutableInstruction<T>,
+        pc: u16,
+        sp: u16,
+        interrupted: Option<u16>,
+    ) -> CallStep {
+        let metadata = instruction.metadata();
+        CallStep {
+            pc,
+            length: metadata.length,
+            flow: metadata.flow,
+            loads_sp: metadata.writes_register("sp") && !metadata.reads_register("sp"),
+            sp,
+            next: self.cpu.pc(),
+            sp_after: self.sp(),
+            interrupted,
+        }
+    }
+
+    /// Attaches the access recorders while something needs them, so running without
+    /// watchpoints, IO breakpoints, the IO log, a trace, the call stack, the profiler,
+    /// coverage or the heatmap costs nothing.
+    fn attach_recorders(&mut self) {
+        let io = !self.io_breakpoints.is_empty() || self.io_log.is_some() || self.rewind.is_some();
+        // Memory accesses also tell which instruction made the IO accesses
+        let memory = io
+            || !self.watchpoints.is_empty()
+            || self.trace.is_some()
+            || self.call_stack.is_some()
+            || self.profiler.is_some()
+            || self.coverage.is_some()
+            || self.heatmap.is_some();
+        if memory != self.access.is_some() {
+            self.access = memory.then(|| Arc::new(AccessRecorder::new()));
+            self.memory.set_access_recorder(self.access.clone());
+        }
+        if io != self.io_access.is_some() {
+            self.io_access = io.then(|| Arc::new(IoRecorder::new()));
+            self.io.set_access_recorder(self.io_access.clone());
+        }
+    }
+
     pub fn step(&mut self) -> Result<Box<dyn ExecutableInstruction<T>>, String> {
         if self.cpu.halted() {
             return Err("CPU is halted".to_string());
         }
         self.memory.clear_changes();
+        self.attach_recorders();
+        let (pc, cycle, index) = (self.cpu.pc(), self.cycles, self.instructions);
+        let sp = self.sp();
+        let interrupt = self.prepare_rewind()?;
         let instruction = self.cpu.step(&mut self.memory, &mut self.io);
-        self.io.step();
+        let replaying = self.io.is_replaying();
+        let mut writes = Vec::new();
+        let start = pc;
+        let mut pc = pc;
+        if let Some(access) = &self.access {
+            let accesses = access.take();
+            // The executed instruction is not at the old PC when an interrupt was taken
+            pc = accesses
+                .iter()
+                .find(|access| access.kind == MemoryAccessKind::Execute)
+                .map_or(pc, |access| access.address);
+            if self.trace.is_some() {
+                writes = accesses
+                    .iter()
+                    .filter(|access| access.kind == MemoryAccessKind::Write)
+                    .map(|access| (access.address, access.new))
+                    .collect();
+            }
+            self.watch_hit = self.watchpoints.check(pc, &accesses);
+            let usage = self.coverage.is_some() || self.heatmap.is_some();
+            if let Some(instruction) = instruction.as_ref().ok().filter(|_| usage && !replaying) {
+                let metadata = instruction.metadata();
+                if let Some(coverage) = self.coverage.as_mut() {
+                    coverage.record(&metadata, self.cpu.pc(), &accesses);
+                }
+                if let Some(heatmap) = self.heatmap.as_mut() {
+                    heatmap.record(metadata.length, &accesses);
+                }
+            }
+            if let Some(io_access) = &self.io_access {
+                let accesses = io_access.take();
+                self.io_hit = self.io_breakpoints.check(pc, &accesses);
+                if let Some(rewind) = self.rewind.as_mut().filter(|_| !replaying) {
+                    for access in accesses.iter() {
+                        if access.direction == IoDirection::Read {
+                            rewind.record_read(index, access.value);
+                        }
+                    }
+                }
+                if let Some(log) = self.io_log.as_mut().filter(|_| !replaying) {
+                    log.extend(accesses.iter().map(|access| IoLogEntry {
+                        port: access.port,
+                        value: access.value,
+                        direction: access.direction,
+                        pc,
+                        cycle,
+                    }));
+                }
+            }
+        }
         if let Ok(instruction) = &instruction {
-            self.cycles += instruction.common().cycles as usize;
+            self.cycles +=
+                instruction.common().cycles as usize + self.cpu.interrupt_cycles() as usize;
             self.instructions += 1;
+            self.io.step(self.cycles);
+            if let Some(rewind) = self.rewind.as_mut().filter(|_| !replaying) {
+                if let Some(interrupt) = interrupt {
+                    rewind.record_interrupt(index, interrupt);
+                }
+                rewind.set_end(self.instructions);
+            }
+            if !replaying {
+                self.scheduler.run_due(self.cycles);
+            }
+            if self.call_stack.is_some() || self.profiler.is_some() {
+                let interrupted = (pc != start).then_some(start);
+                let step = self.call_step(instruction.as_ref(), pc, sp, interrupted);
+                if let Some(stack) = self.call_stack.as_mut() {
+                    stack.track(&step, &self.memory);
+                }
+                if let Some(profiler) = self.profiler.as_mut().filter(|_| !replaying) {
+                    profiler.record(&step, (self.cycles - cycle) as u64, &self.memory);
+                }
+            }
+            if let Some(trace) = self.trace.as_mut().filter(|_| !replaying) {
+                let record = TraceRecord::new(
+                    pc,
+                    instruction.to_bytes(),
+                    &self.cpu.registers(),
+                    self.cycles as u64,
+                    writes,
+                );
+                if let Err(e) = trace.write(&record) {
+                    self.trace = None;
+                    return Err(format!("Failed to write trace: {}", e));
+                }
+            }
         }
         instruction
     }
 
+    /// Keeps an execution history to go backwards with [`Emulator::step_back`],
+    /// [`Emulator::reverse_continue`] and [`Emulator::run_back_to_write`]. A snapshot is taken
+    /// every `interval` instructions and the oldest ones are dropped to stay under `budget`
+    /// bytes.
+    ///
+    /// Port reads and interrupts are recorded and given back to the CPU when replaying, the
+    /// devices themselves are not rewound. Changes made from outside between steps are not
+    /// recorded, and executing after going back forgets the history after that point.
+    pub fn enable_rewind(&mut self, interval: usize, budget: usize) {
+        self.rewind = Some(Rewind::new(interval, budget));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    pub fn rewind(&self) -> Option<&Rewind> {
+        self.rewind.as_ref()
+    }
+
+    /// Takes snapshots while recording and feeds the recorded interrupt while replaying.
+    /// Returns the interrupt the CPU is about to take when recording.
+    fn prepare_rewind(&mut self) -> Result<Option<InterruptType>, String> {
+        let Some(rewind) = &mut self.rewind else {
+            return Ok(None);
+        };
+        if rewind.replaying {
+            self.io
+                .set_replay_interrupt(rewind.interrupt_at(self.instructions));
+            return Ok(None);
+        }
+        rewind.truncate(self.instructions);
+        if rewind.needs_snapshot(self.instructions) {
+            let snapshot = Snapshot {
+                instructions: self.instructions,
+                cycles: self.cycles,
+                iff1: self.io.iff1,
+                iff2: self.io.iff2,
+                state: self.save()?,
+            };
+            if let Some(rewind) = &mut self.rewind {
+                rewind.push_snapshot(snapshot);
+            }
+        }
+        Ok(self.io.get_interrupt().map(|(interrupt, _)| interrupt))
+    }
+
+    /// Loads the newest snapshot at or before `instructions` and starts replaying from it
+    fn start_replay(&mut self, instructions: usize) -> Result<(), String> {
+        let rewind = self.rewind.as_ref().ok_or("Rewind is not enabled")?;
+        let snapshot = rewind
+            .snapshot_before(instructions)
+            .cloned()
+            .ok_or_else(|| format!("Instruction {} is not in the rewind history", instructions))?;
+        let reads = rewind.reads_from(snapshot.instructions);
+        // Loading a snapshot keeps the debugger settings and the history itself
+        let breakpoints = std::mem::take(&mut self.breakpoints);
+        let watchpoints = std::mem::take(&mut self.watchpoints);
+        let io_breakpoints = std::mem::take(&mut self.io_breakpoints);
+        let mut rewind = self.rewind.take();
+        let result = Self::read_snapshot(&snapshot.state).and_then(|state| {
+            let cpu = bincode::deserialize::<T>(&state.cpu).map_err(|e| format!("{:?}", e))?;
+            self.restore(state, cpu, false, true)
+        });
+        self.breakpoints = breakpoints;
+        self.watchpoints = watchpoints;
+        self.io_breakpoints = io_breakpoints;
+        if let Some(rewind) = &mut rewind {
+            rewind.replaying = true;
+        }
+        self.rewind = rewind;
+        self.cycles = snapshot.cycles;
+        self.instructions = snapshot.instructions;
+        self.io.iff1 = snapshot.iff1;
+        self.io.iff2 = snapshot.iff2;
+        self.io.start_replay(reads);
+        result
+    }
+
+    fn stop_replay(&mut self) {
+        self.io.stop_replay();
+        if let Some(rewind) = &mut self.rewind {
+            rewind.replaying = false;
+        }
+        self.watch_hit = None;
+        self.io_hit = None;
+    }
+
+    /// Replays until `instructions` have been executed, calling `each` after every step
+    fn replay_until(
+        &mut self,
+        instructions: usize,
+        mut each: impl FnMut(&mut Self),
+    ) -> Result<(), String> {
+        while self.instructions < instructions {
+            self.step()?;
+            each(self);
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the state after `instructions` instructions from the rewind history.
+    pub fn rewind_to(&mut self, instructions: usize) -> Result<(), String> {
+        let end = self.rewind.as_ref().ok_or("Rewind is not enabled")?.end();
+        if instructions > end {
+            return Err(format!("Instruction {} has not been executed yet", instructions));
+        }
+        self.start_replay(instructions)?;
+        let result = self.replay_until(instructions, |_| {});
+        self.stop_replay();
+        result
+    }
+
+    pub fn step_back(&mut self) -> Result<(), String> {
+        let instructions = self
+            .instructions
+            .checked_sub(1)
+            .ok_or("No instruction to step back over")?;
+        self.rewind_to(instructions)
+    }
+
+    /// Goes back to the newest earlier state for which `stop` gives a reason, replaying one
+    /// snapshot interval at a time from the newest. The state is undefined when nothing is
+    /// found.
+    fn reverse_search(
+        &mut self,
+        mut stop: impl FnMut(&mut Self) -> Option<StopReason>,
+    ) -> Result<Option<StopReason>, String> {
+        let current = self.instructions;
+        let starts = self
+            .rewind
+            .as_ref()
+            .ok_or("Rewind is not enabled")?
+            .snapshots_before(current);
+        let mut end = current;
+        for start in starts {
+            self.start_replay(start)?;
+            let mut found = None;
+            let result = self.replay_until(end, |emulator| {
+                if emulator.instructions < current {
+                    if let Some(reason) = stop(emulator) {
+                        found = Some((emulator.instructions, reason));
+                    }
+                }
+            });
+            self.stop_replay();
+            result?;
+            if let Some((instructions, reason)) = found {
+                self.rewind_to(instructions)?;
+                return Ok(Some(reason));
+            }
+            end = start;
+        }
+        Ok(None)
+    }
+
+    /// Runs backwards to the newest earlier state where a breakpoint, watchpoint or IO
+    /// breakpoint would have stopped execution, ignoring hit and ignore counts. Returns `Ok`
+    /// at the start of the history when there is none.
+    pub fn reverse_continue(&mut self) -> Result<(), StopReason> {
+        let found = self
+            .reverse_search(|emulator| {
+                if let Some(hit) = emulator.watch_hit.take() {
+                    return Some(StopReason::Watchpoint(hit));
+                }
+                if let Some(hit) = emulator.io_hit.take() {
+                    return Some(StopReason::IoBreakpoint(hit));
+                }
+                let context = MachineContext::new(&emulator.cpu, &emulator.memory)
+                    .with_symbols(&emulator.symbols);
+                let id = emulator.breakpoints.find(emulator.cpu.pc(), &context);
+                id.ok().flatten().map(StopReason::Breakpoint)
+            })
+            .map_err(StopReason::Error)?;
+        if let Some(reason) = found {
+            return Err(reason);
+        }
+        let start = self
+            .rewind
+            .as_ref()
+            .and_then(Rewind::start)
+            .ok_or_else(|| StopReason::Error("Rewind history is empty".to_string()))?;
+        self.rewind_to(start).map_err(StopReason::Error)
+    }
+
+    /// Goes back to just after the newest instruction that wrote `address` and returns the
+    /// write, its id does not refer to [`Emulator::watchpoints`]. Stays at the current state
+    /// when the history has no such write.
+    pub fn run_back_to_write(&mut self, address: u16) -> Result<Option<WatchHit>, String> {
+        let current = self.instructions;
+        let mut write = Watchpoints::new();
+        write.add(Watchpoint::write(address, address));
+        let watchpoints = std::mem::replace(&mut self.watchpoints, write);
+        let found = self.reverse_search(|emulator| {
+            emulator.watch_hit.take().map(StopReason::Watchpoint)
+        });
+        self.watchpoints = watchpoints;
+        match found? {
+            Some(StopReason::Watchpoint(hit)) => Ok(Some(hit)),
+            _ => {
+                self.rewind_to(current)?;
+                Ok(None)
+            }
+        }
+    }
+
     pub fn run_ticks<CB: Fn(&mut Self, &dyn ExecutableInstruction<T>)>(
         &mut self,
         ticks: f64,
@@ -74,19 +661,202 @@ impl<T: Cpu +'static> Emulator<T> {
     ) -> Result<f64, StopReason> {
         let mut current_ticks = 0.0;
         while current_ticks < ticks {
-            let instruction = self.step().map_err(|e| StopReason::Error(e))?;
-            current_ticks += instruction.common().cycles as f64;
+            let cycles_before = self.cycles;
+            let instruction = self.step().map_err(|e| self.step_error(e))?;
+            current_ticks += (self.cycles - cycles_before) as f64;
             if let Some(callback) = &callback {
                 callback(self, &*instruction);
             }
-            if self.cpu.halted() {
-                return Err(StopReason::Halt);
+            self.check_stop()?;
+        }
+        Ok(current_ticks)
+    }
+
+    /// Runs until `condition` is true after an instruction, or something else stops
+    /// execution.
+    pub fn run_until(&mut self, condition: impl FnMut(&Self) -> bool) -> RunResult {
+        self.run_limited(None, None, condition)
+    }
+
+    /// Runs until PC reaches `address`, at least one instruction is executed.
+    pub fn run_until_pc(&mut self, address: u16) -> RunResult {
+        self.run_limited(None, None, |emulator| emulator.cpu.pc() == address)
+    }
+
+    /// Runs instructions until at least `cycles` T-states were executed.
+    pub fn run_for_cycles(&mut self, cycles: usize) -> RunResult {
+        self.run_limited(Some(cycles), None, |_| false)
+    }
+
+    pub fn run_for_instructions(&mut self, instructions: usize) -> RunResult {
+        self.run_limited(None, Some(instructions), |_| false)
+    }
+
+    /// Handle to stop the emulator from another thread while it runs.
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.pause.clone()
+    }
+
+    /// Runs as fast as possible until a limit is reached, `condition` is true after an
+    /// instruction, or something else stops execution.
+    fn run_limited(
+        &mut self,
+        cycles: Option<usize>,
+        instructions: Option<usize>,
+        mut condition: impl FnMut(&Self) -> bool,
+    ) -> RunResult {
+        let (start_cycles, start_instructions) = (self.cycles, self.instructions);
+        let reason = loop {
+            if cycles.is_some_and(|cycles| self.cycles - start_cycles >= cycles) {
+                break StopReason::CycleLimit;
+            }
+            if instructions.is_some_and(|count| self.instructions - start_instructions >= count) {
+                break StopReason::InstructionLimit;
+            }
+            if let Err(e) = self.step() {
+                break self.step_error(e);
+            }
+            if let Err(reason) = self.check_stop() {
+                break reason;
             }
-            if self.breakpoints.contains(&self.cpu.pc()) {
-                return Err(StopReason::Breakpoint);
+            if condition(self) {
+                break StopReason::Condition;
             }
+        };
+        RunResult {
+            reason,
+            cycles: self.cycles - start_cycles,
+            instructions: self.instructions - start_instructions,
         }
-        Ok(current_ticks)
+    }
+
+    /// Reason to stop for a step that failed, bytes that do not decode to an instruction
+    /// are an illegal opcode.
+    pub fn step_error(&self, error: String) -> StopReason {
+        let address = self.cpu.pc();
+        let bytes: Vec<u8> = (0..4)
+            .map(|offset| {
+                self.memory
+                    .peek_8(address.wrapping_add(offset))
+                    .unwrap_or(0)
+            })
+            .collect();
+        match self.cpu.parser().ins_from_machinecode(&bytes, 0) {
+            Err(ParseError::InvalidInstruction(message)) => {
+                StopReason::IllegalOpcode { address, message }
+            }
+            _ => StopReason::Error(error),
+        }
+    }
+
+    /// Reasons to stop after the step that just happened, in order: watchpoints and IO
+    /// breakpoints hit by the instruction, halting, breakpoints at the new PC and pause
+    /// requests.
+    fn check_stop(&mut self) -> Result<(), StopReason> {
+        if let Some(hit) = self.watch_hit.take() {
+            return Err(StopReason::Watchpoint(hit));
+        }
+        if let Some(hit) = self.io_hit.take() {
+            return Err(StopReason::IoBreakpoint(hit));
+        }
+        if self.cpu.halted() {
+            return Err(StopReason::Halt);
+        }
+        if !self.breakpoints.is_empty() {
+            let context = MachineContext::new(&self.cpu, &self.memory).with_symbols(&self.symbols);
+            match self.breakpoints.check(self.cpu.pc(), &context) {
+                Ok(Some(id)) => return Err(StopReason::Breakpoint(id)),
+                Ok(None) => {}
+                Err(e) => return Err(StopReason::Error(format!("Breakpoint condition: {}", e))),
+            }
+        }
+        if self.pause.0.swap(false, Ordering::Relaxed) {
+            return Err(StopReason::Pause);
+        }
+        Ok(())
+    }
+
+    /// Steps until `done` is true after an instruction, or something else stops execution.
+    fn step_until(&mut self, mut done: impl FnMut(&mut Self) -> bool) -> Result<(), StopReason> {
+        loop {
+            self.step().map_err(|e| self.step_error(e))?;
+            if done(self) {
+                return Ok(());
+            }
+            self.check_stop()?;
+        }
+    }
+
+    /// Static description of the instruction at `address`, unmapped bytes read as 0 and bytes
+    /// that are not an instruction give the default description.
+    pub fn metadata_at(&self, address: u16) -> InstructionMetadata {
+        let bytes: Vec<u8> = (0..4)
+            .map(|offset| {
+                self.memory
+                    .peek_8(address.wrapping_add(offset))
+                    .unwrap_or(0)
+            })
+            .collect();
+        self.cpu
+            .parser()
+            .ins_from_machinecode(&bytes, 0)
+            .map(|instruction| instruction.metadata())
+            .unwrap_or_default()
+    }
+
+    fn sp(&self) -> u16 {
+        *self.cpu.registers().sp
+    }
+
+    /// Executes one instruction, calls, restarts and repeating block instructions run until
+    /// execution comes back to the next instruction in the same stack frame.
+    pub fn step_over(&mut self) -> Result<(), StopReason> {
+        let pc = self.cpu.pc();
+        let metadata = self.metadata_at(pc);
+        if !metadata.is_subroutine_call() && !metadata.repeats {
+            return self.step_until(|_| true);
+        }
+        let (next, sp) = (pc.wrapping_add(metadata.length), self.sp());
+        self.step_until(|emulator| emulator.cpu.pc() == next && emulator.sp() >= sp)
+    }
+
+    /// Runs until execution reaches an instruction of another source line in
+    /// [`Emulator::lines`], code without a line is run through.
+    pub fn step_line(&mut self) -> Result<(), StopReason> {
+        if self.lines.is_empty() {
+            return Err(StopReason::Error("No line table".to_string()));
+        }
+        let start = self
+            .lines
+            .line_at(self.cpu.pc())
+            .map(|(path, line)| (path.to_string(), line));
+        self.step_until(|emulator| {
+            let current = emulator.lines.line_at(emulator.cpu.pc());
+            current.is_some() && current != start.as_ref().map(|(path, line)| (path.as_str(), *line))
+        })
+    }
+
+    /// Runs until the current subroutine returns. The return is the first return instruction
+    /// that leaves SP above its value at the start, so nested calls and returns that are not
+    /// taken are run through.
+    pub fn step_out(&mut self) -> Result<(), StopReason> {
+        let sp = self.sp();
+        let is_return = |emulator: &Self| {
+            emulator.metadata_at(emulator.cpu.pc()).flow == FlowKind::Return
+        };
+        // Whether the instruction being executed is a return
+        let mut returning = is_return(self);
+        self.step_until(|emulator| {
+            let done = returning && emulator.sp() > sp;
+            returning = is_return(emulator);
+            done
+        })
+    }
+
+    /// Runs until PC reaches `address`, as if there was a temporary breakpoint on it. At least
+    /// one instruction is executed.
+    pub fn run_to_address(&mut self, address: u16) -> Result<(), StopReason> {
+        self.step_until(|emulator| emulator.cpu.pc() == address)
     }
 
     pub fn run_with_callback<CB: Fn(&mut Self, &dyn ExecutableInstruction<T>)>(
@@ -126,19 +896,138 @@ impl<T: Cpu +'static> Emulator<T> {
         )
     }
 
+    /// Snapshot of the whole machine: CPU, memory and device states, interrupt flip-flops,
+    /// counters and debugger settings. Events of [`Emulator::scheduler`] are not included.
     pub fn save(&self) -> Result<Vec<u8>, String> {
+        let state = self.state()?;
+        let header = SnapshotHeader {
+            magic: SNAPSHOT_MAGIC,
+            version: SNAPSHOT_VERSION,
+        };
+        let mut data = bincode::serialize(&header).map_err(|e| format!("{:?}", e))?;
+        bincode::serialize_into(&mut data, &state).map_err(|e| format!("{:?}", e))?;
+        Ok(data)
+    }
+
+    fn state(&self) -> Result<EmuState, String> {
         let memory = self.memory.save().map_err(|e| format!("{:?}", e))?;
         let cpu = bincode::serialize(&self.cpu).map_err(|e| format!("{:?}", e))?;
-        let state = EmuState {
+        Ok(EmuState {
             cpu,
             memory,
+            memory_map: self.memory.layout(),
+            memory_devices: self.memory.device_states(),
+            io_map: self.io.layout(),
+            io_devices: self.io.device_states(),
+            iff1: self.io.iff1,
+            iff2: self.io.iff2,
+            cycles: self.cycles,
+            instructions: self.instructions,
             breakpoints: self.breakpoints.clone(),
-        };
-        bincode::serialize(&state).map_err(|e| format!("{:?}", e))
+            watchpoints: self.watchpoints.clone(),
+            io_breakpoints: self.io_breakpoints.clone(),
+            call_stack: self.call_stack.clone(),
+        })
     }
+
+    /// Loads a snapshot from [`Emulator::save`] taken on a machine with the same memory map
+    /// and IO devices. Scheduled events stay as many T-states away as they were. The
+    /// emulator is left as it was when loading fails, unless rolling back fails as well,
+    /// which is reported in the error.
     pub fn load(&mut self, data: Vec<u8>, clear_mem: bool, force: bool) -> Result<(), String> {
-        let state = bincode::deserialize::<EmuState>(&data).map_err(|e| format!("{:?}", e))?;
-        self.cpu = bincode::deserialize::<T>(&state.cpu).map_err(|e| format!("{:?}", e))?;
+        let state = Self::read_snapshot(&data)?;
+        let cpu = self.check_snapshot(&state)?;
+        // Devices and memory can still refuse their part, such as a ROM without `force`
+        let backup = self.state()?;
+        let from = self.cycles;
+        if let Err(e) = self.apply(state, cpu, clear_mem, force) {
+            let restored = bincode::deserialize(&backup.cpu)
+                .map_err(|e| format!("{:?}", e))
+                .and_then(|cpu| self.apply(backup, cpu, false, true));
+            return Err(match restored {
+                Ok(()) => e,
+                Err(rollback) => format!(
+                    "{}, rolling back failed and the emulator state is inconsistent: {}",
+                    e, rollback
+                ),
+            });
+        }
+        self.scheduler.rebase(from, self.cycles);
+        Ok(())
+    }
+
+    fn read_snapshot(data: &[u8]) -> Result<EmuState, String> {
+        let mut reader = data;
+        let header: SnapshotHeader =
+            bincode::deserialize_from(&mut reader).map_err(|_| "Not an emulator snapshot")?;
+        if header.magic != SNAPSHOT_MAGIC {
+            return Err("Not an emulator snapshot".to_string());
+        }
+        if header.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "Snapshot version {} is not supported, expected {}",
+                header.version, SNAPSHOT_VERSION
+            ));
+        }
+        bincode::deserialize_from(reader).map_err(|e| format!("{:?}", e))
+    }
+
+    /// Checks that a snapshot fits the emulator and decodes its CPU.
+    fn check_snapshot(&self, state: &EmuState) -> Result<T, String> {
+        if state.memory_map != self.memory.layout() {
+            return Err(format!(
+                "Snapshot memory map {:?} does not match the emulator's {:?}",
+                state.memory_map,
+                self.memory.layout()
+            ));
+        }
+        if state.memory.len() != self.memory.size() {
+            return Err(format!(
+                "Snapshot has {} bytes of memory, expected {}",
+                state.memory.len(),
+                self.memory.size()
+            ));
+        }
+        if state.memory_devices.len() != state.memory_map.len() {
+            return Err(format!(
+                "Snapshot has {} memory device states for {} devices",
+                state.memory_devices.len(),
+                state.memory_map.len()
+            ));
+        }
+        if state.io_map != self.io.layout() {
+            return Err("Snapshot IO devices do not match the emulator's".to_string());
+        }
+        if state.io_devices.len() != state.io_map.len() {
+            return Err(format!(
+                "Snapshot has {} IO device states for {} devices",
+                state.io_devices.len(),
+                state.io_map.len()
+            ));
+        }
+        bincode::deserialize::<T>(&state.cpu).map_err(|e| format!("{:?}", e))
+    }
+
+    fn apply(
+        &mut self,
+        state: EmuState,
+        cpu: T,
+        clear_mem: bool,
+        force: bool,
+    ) -> Result<(), String> {
+        self.memory.load_device_states(&state.memory_devices)?;
+        self.io.load_device_states(&state.io_devices, state.cycles)?;
+        self.restore(state, cpu, clear_mem, force)
+    }
+
+    /// Machine state and debugger settings of a snapshot, device states are left alone.
+    fn restore(
+        &mut self,
+        state: EmuState,
+        cpu: T,
+        clear_mem: bool,
+        force: bool,
+    ) -> Result<(), String> {
         self.memory
             .load(&state.memory, force)
             .map_err(|e| format!("{:?}", e))?;
@@ -149,11 +1038,733 @@ impl<T: Cpu +'static> Emulator<T> {
                     .map_err(|e| format!("{:?}", e))?;
             }
         }
+        self.cpu = cpu;
+        self.io.iff1 = state.iff1;
+        self.io.iff2 = state.iff2;
+        self.cycles = state.cycles;
+        self.instructions = state.instructions;
         self.breakpoints = state.breakpoints;
+        self.watchpoints = state.watchpoints;
+        self.io_breakpoints = state.io_breakpoints;
+        if self.call_stack.is_some() {
+            self.call_stack = Some(state.call_stack.unwrap_or_default());
+        }
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
         Ok(())
     }
+
     pub fn reset_counters(&mut self) {
+        self.scheduler.rebase(self.cycles, 0);
+        self.io.rebase(self.cycles, 0);
         self.cycles=0;
         self.instructions=0;
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
+    }
+}
+
+/// Z80 with 64K of RAM holding `program` from address 0, for the tests.
+#[cfg(test)]
+pub(crate) fn emulator_with(program: &[u8]) -> Emulator<crate::cpu::z80::Z80> {
+    let mut memory = Memory::new_full_ram();
+    for (address, byte) in program.iter().enumerate() {
+        memory.write_8(address as u16, *byte).unwrap();
+    }
+    Emulator::new_w_mem(memory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::z80::Z80;
+    use crate::debug::breakpoint::Breakpoint;
+    use crate::debug::callstack::FrameKind;
+    use crate::bus::IoDirection;
+    use crate::debug::io::IoBreakpoint;
+    use crate::debug::watchpoint::Watchpoint;
+    use crate::io::iodevice::IODevice;
+    use crate::memory::memdevices::{RAM, ROM};
+
+    #[test]
+    fn test_conditional_branch_cycles() {
+        // JR NZ, -2 with Z clear loops, then with Z set falls through
+        let mut emulator = emulator_with(&[0x20, 0xFE]);
+        let instruction = emulator.step().unwrap();
+        let metadata = instruction.metadata();
+        assert_eq!(Some(instruction.common().cycles), metadata.cycles_taken);
+        assert_eq!(emulator.cpu.registers.pc, 0);
+        emulator.cpu.registers.gp.f.set_zero(true);
+        let instruction = emulator.step().unwrap();
+        assert_eq!(instruction.common().cycles, metadata.cycles);
+        assert_eq!(emulator.cpu.registers.pc, 2);
+        assert_eq!(emulator.cycles, 12 + 7);
+    }
+
+    #[test]
+    fn test_repeat_cycles() {
+        // LD BC, 2 then LDIR copies two bytes
+        let mut emulator = emulator_with(&[0x01, 0x02, 0x00, 0xED, 0xB0]);
+        emulator.cpu.registers.gp.hl = 0x8000;
+        emulator.cpu.registers.gp.de = 0x9000;
+        let cycles: Vec<u16> = (0..3)
+            .map(|_| emulator.step().unwrap().common().cycles)
+            .collect();
+        assert_eq!(cycles, vec![10, 21, 16]);
+        assert_eq!(emulator.cpu.registers.pc, 5);
+        assert_eq!(emulator.cycles, 47);
+        assert_eq!(emulator.instructions, 3);
+    }
+
+    #[test]
+    fn test_run_ticks_counts_actual_cycles() {
+        // DJNZ to itself with B = 3: taken twice, then falls through to HALT
+        let mut emulator = emulator_with(&[0x06, 0x03, 0x10, 0xFE, 0x76]);
+        let result = emulator.run_ticks(
+            1000.0,
+            &None::<fn(&mut Emulator<Z80>, &dyn ExecutableInstruction<Z80>)>,
+        );
+        assert!(matches!(result, Err(StopReason::Halt)));
+        assert_eq!(emulator.cycles, 7 + 13 + 13 + 8 + 4);
+    }
+
+    #[test]
+    fn test_run_limits() {
+        // LD B, 3; loop: INC A; DJNZ loop; then ED 00, which is not an instruction
+        let mut emulator = emulator_with(&[0x06, 0x03, 0x3C, 0x10, 0xFD, 0xED, 0x00]);
+        let result = emulator.run_for_instructions(2);
+        assert!(matches!(result.reason, StopReason::InstructionLimit));
+        assert_eq!((result.cycles, result.instructions), (7 + 4, 2));
+        // Whole instructions run until the limit is passed
+        let result = emulator.run_for_cycles(20);
+        assert!(matches!(result.reason, StopReason::CycleLimit));
+        assert_eq!((result.cycles, result.instructions), (13 + 4 + 13, 3));
+        let result = emulator.run_until(|emulator| emulator.cpu.registers.gp.a == 3);
+        assert!(matches!(result.reason, StopReason::Condition));
+        assert_eq!(result.instructions, 1);
+        let result = emulator.run_until(|_| false);
+        assert!(matches!(
+            result.reason,
+            StopReason::IllegalOpcode { address: 0x0005, .. }
+        ));
+        assert_eq!(emulator.cycles, 7 + 3 * 4 + 2 * 13 + 8);
+
+        emulator.cpu.registers.pc = 0;
+        let result = emulator.run_until_pc(0x0003);
+        assert!(matches!(result.reason, StopReason::Condition));
+        assert_eq!(result.instructions, 2);
+        emulator.pause_handle().pause();
+        let result = emulator.run_for_instructions(100);
+        assert!(matches!(result.reason, StopReason::Pause));
+        assert_eq!(result.instructions, 1);
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        // INC A; JR -3 loops forever, stop the third time A is 2 at the JR
+        let mut emulator = emulator_with(&[0x3C, 0x18, 0xFD]);
+        let mut breakpoint = Breakpoint::conditional(0x0001, "[a & 3] == 2").unwrap();
+        breakpoint.ignore_count = 2;
+        let id = emulator.breakpoints.add(breakpoint);
+        emulator.breakpoints.add(Breakpoint::logging(0x0000, "a={a}"));
+        let result = emulator.run_ticks(
+            1_000_000.0,
+            &None::<fn(&mut Emulator<Z80>, &dyn ExecutableInstruction<Z80>)>,
+        );
+        assert!(matches!(result, Err(StopReason::Breakpoint(hit)) if hit == id));
+        assert_eq!(emulator.cpu.registers.gp.a, 10);
+        assert_eq!(emulator.breakpoints.take_log().len(), 9);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // LD A, 0x55; LD (0x8000), A; LD A, (0x8000); HALT
+        let mut emulator =
+            emulator_with(&[0x3E, 0x55, 0x32, 0x00, 0x80, 0x3A, 0x00, 0x80, 0x76]);
+        let no_callback = &None::<fn(&mut Emulator<Z80>, &dyn ExecutableInstruction<Z80>)>;
+        // Fetching the code is not a read
+        emulator.watchpoints.add(Watchpoint::read(0x0000, 0x0008));
+        let write = emulator.watchpoints.add(Watchpoint::write(0x8000, 0x80FF));
+        let read = emulator
+            .watchpoints
+            .add(Watchpoint::read(0x8000, 0x8000).with_value(0x55));
+        let execute = emulator.watchpoints.add(Watchpoint::execute(0x0008, 0x0008));
+
+        let result = emulator.run_ticks(1000.0, no_callback);
+        let Err(StopReason::Watchpoint(hit)) = result else {
+            panic!("Expected watchpoint, got {:?}", result);
+        };
+        assert_eq!(
+            (hit.id, hit.kind, hit.address, hit.old, hit.new, hit.pc),
+            (write, MemoryAccessKind::Write, 0x8000, 0x00, 0x55, 0x0002)
+        );
+        assert_eq!(emulator.cpu.pc(), 0x0005);
+
+        let result = emulator.run_ticks(1000.0, no_callback);
+        assert!(matches!(result, Err(StopReason::Watchpoint(hit)) if hit.id == read && hit.pc == 5));
+        let result = emulator.run_ticks(1000.0, no_callback);
+        assert!(matches!(result, Err(StopReason::Watchpoint(hit)) if hit.id == execute));
+    }
+
+    #[test]
+    fn test_io_breakpoints_and_log() {
+        // LD A, 0x12; OUT (0xFE), A; IN A, (0x10); HALT
+        let mut emulator = emulator_with(&[0x3E, 0x12, 0xD3, 0xFE, 0xDB, 0x10, 0x76]);
+        let no_callback = &None::<fn(&mut Emulator<Z80>, &dyn ExecutableInstruction<Z80>)>;
+        emulator.set_io_log(true);
+        let id = emulator.io_breakpoints.add(IoBreakpoint::write(0xFE));
+        emulator.io_breakpoints.add(IoBreakpoint::read(0x10).with_value(0xFF, 0x01));
+
+        let result = emulator.run_ticks(1000.0, no_callback);
+        assert!(
+            matches!(result, Err(StopReason::IoBreakpoint(hit)) if hit.id == id && hit.value == 0x12 && hit.pc == 2)
+        );
+        assert!(matches!(emulator.run_ticks(1000.0, no_callback), Err(StopReason::Halt)));
+        assert_eq!(
+            emulator.io_log(),
+            &[
+                IoLogEntry {
+                    port: 0xFE,
+                    value: 0x12,
+                    direction: IoDirection::Write,
+                    pc: 2,
+                    cycle: 7
+                },
+                IoLogEntry {
+                    port: 0x10,
+                    value: 0x00,
+                    direction: IoDirection::Read,
+                    pc: 4,
+                    cycle: 18
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        // 0x00: CALL 0x10; LD B, 2; HALT
+        // 0x10: INC A; CALL 0x20; RET Z; RET
+        // 0x20: INC A; RET
+        let mut program = vec![0u8; 0x22];
+        program[0x00..0x06].copy_from_slice(&[0xCD, 0x10, 0x00, 0x06, 0x02, 0x76]);
+        program[0x10..0x16].copy_from_slice(&[0x3C, 0xCD, 0x20, 0x00, 0xC8, 0xC9]);
+        program[0x20..0x22].copy_from_slice(&[0x3C, 0xC9]);
+        let mut emulator = emulator_with(&program);
+        emulator.cpu.registers.sp = 0xFF00;
+
+        emulator.step_over().unwrap();
+        assert_eq!((emulator.cpu.pc(), emulator.cpu.registers.gp.a), (0x03, 2));
+        assert_eq!(emulator.cpu.registers.sp, 0xFF00);
+        emulator.step_over().unwrap();
+        assert_eq!(emulator.cpu.pc(), 0x05);
+
+        emulator.cpu.registers.pc = 0;
+        emulator.step().unwrap();
+        emulator.run_to_address(0x20).unwrap();
+        assert_eq!(emulator.cpu.registers.sp, 0xFEFC);
+        // Returns from the inner call and skips the RET Z that is not taken
+        emulator.step_out().unwrap();
+        assert_eq!((emulator.cpu.pc(), emulator.cpu.registers.sp), (0x14, 0xFEFE));
+        emulator.step_out().unwrap();
+        assert_eq!((emulator.cpu.pc(), emulator.cpu.registers.sp), (0x03, 0xFF00));
+
+        emulator.cpu.registers.pc = 0;
+        let id = emulator.breakpoints.add_address(0x20);
+        assert!(matches!(emulator.step_over(), Err(StopReason::Breakpoint(hit)) if hit == id));
+    }
+
+    #[test]
+    fn test_step_line() {
+        // 1: LD B, 2
+        // 2: DEC B; NOP
+        // 3: JR NZ, 2
+        // 4: CALL 0x10, which has no line
+        // 5: HALT
+        let mut program = vec![0u8; 0x11];
+        program[..0x0A].copy_from_slice(&[0x06, 0x02, 0x05, 0x00, 0x20, 0xFC, 0xCD, 0x10, 0x00, 0x76]);
+        program[0x10] = 0xC9;
+        let mut emulator = emulator_with(&program);
+        assert!(emulator.step_line().is_err());
+        for (line, address) in [(1, 0x00), (2, 0x02), (2, 0x03), (3, 0x04), (4, 0x06), (5, 0x09)] {
+            emulator.lines.insert("test.asm", line, address);
+        }
+        let mut lines = Vec::new();
+        while emulator.step_line().is_ok() {
+            lines.push(emulator.lines.line_at(emulator.cpu.pc()).unwrap().1);
+        }
+        assert_eq!(lines, vec![2, 3, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_step_over_block_instruction() {
+        // LD BC, 3; LDIR; HALT
+        let mut emulator = emulator_with(&[0x01, 0x03, 0x00, 0xED, 0xB0, 0x76]);
+        emulator.cpu.registers.gp.hl = 0x8000;
+        emulator.cpu.registers.gp.de = 0x9000;
+        emulator.step_over().unwrap();
+        emulator.step_over().unwrap();
+        assert_eq!(emulator.cpu.pc(), 0x05);
+        assert_eq!(emulator.cpu.registers.gp.bc, 0);
+    }
+
+    /// Port 0x10 reads a counter that moves on every read, and an IM1 interrupt is raised
+    /// every 7 steps, so replaying has to use the recorded values.
+    #[derive(Default)]
+    struct Noisy {
+        reads: std::sync::atomic::AtomicU8,
+        steps: usize,
+        pending: bool,
+    }
+
+    impl IODevice for Noisy {
+        fn ports(&self) -> Vec<u8> {
+            vec![0x10]
+        }
+        fn read(&self, _port: u8) -> Result<u8, &'static str> {
+            Ok(self
+                .reads
+                .fetch_add(3, std::sync::atomic::Ordering::Relaxed))
+        }
+        fn write(&mut self, _port: u8, _data: u8) -> Result<(), &'static str> {
+            Ok(())
+        }
+        fn step(&mut self, _elapsed: usize, _cycle: usize) {
+            self.steps += 1;
+            self.pending |= self.steps.is_multiple_of(7);
+        }
+        fn will_interrupt(&self) -> Option<InterruptType> {
+            self.pending.then_some(InterruptType::IM1)
+        }
+        fn ack_int(&mut self) -> Result<(), &'static str> {
+            self.pending = false;
+            Ok(())
+        }
+    }
+
+    fn rewind_emulator() -> Emulator<Z80> {
+        // 0x00: EI; IN A, (0x10); LD (0x8000), A; JR 0x01
+        // 0x38: INC B; EI; RET
+        let mut program = vec![0u8; 0x3B];
+        program[0x00..0x08].copy_from_slice(&[0xFB, 0xDB, 0x10, 0x32, 0x00, 0x80, 0x18, 0xF9]);
+        program[0x38..0x3B].copy_from_slice(&[0x04, 0xFB, 0xC9]);
+        let mut emulator = emulator_with(&program);
+        emulator.io = IO::new();
+        emulator.io.add_device(Box::new(Noisy::default())).unwrap();
+        emulator.cpu.registers.sp = 0xFF00;
+        emulator
+    }
+
+    /// Raises an IM1 interrupt when something else sets `pending`.
+    struct InterruptLine {
+        pending: Arc<AtomicBool>,
+    }
+
+    impl IODevice for InterruptLine {
+        fn ports(&self) -> Vec<u8> {
+            vec![]
+        }
+        fn read(&self, _port: u8) -> Result<u8, &'static str> {
+            Err("No ports")
+        }
+        fn write(&mut self, _port: u8, _data: u8) -> Result<(), &'static str> {
+            Err("No ports")
+        }
+        fn step(&mut self, _elapsed: usize, _cycle: usize) {}
+        fn next_step(&self) -> Option<usize> {
+            None
+        }
+        fn will_interrupt(&self) -> Option<InterruptType> {
+            self.pending.load(Ordering::Relaxed).then_some(InterruptType::IM1)
+        }
+        fn ack_int(&mut self) -> Result<(), &'static str> {
+            self.pending.store(false, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_scheduled_interrupts() {
+        let run = || {
+            // 0x00: EI; JR 0x01
+            // 0x38: INC B; EI; RET
+            let mut program = vec![0u8; 0x3B];
+            program[0x00..0x03].copy_from_slice(&[0xFB, 0x18, 0xFE]);
+            program[0x38..0x3B].copy_from_slice(&[0x04, 0xFB, 0xC9]);
+            let mut emulator = emulator_with(&program);
+            emulator.cpu.registers.sp = 0xFF00;
+            let pending = Arc::new(AtomicBool::new(false));
+            emulator.io = IO::new();
+            emulator
+                .io
+                .add_device(Box::new(InterruptLine { pending: pending.clone() }))
+                .unwrap();
+            emulator.scheduler.schedule_periodic(1000, 1000, move |_| {
+                pending.store(true, Ordering::Relaxed)
+            });
+            let result = emulator.run_for_cycles(10_500);
+            assert!(matches!(result.reason, StopReason::CycleLimit));
+            (emulator.cpu.registers.gp.b, emulator.cycles, emulator.scheduler.next_event())
+        };
+        let first = run();
+        assert_eq!(first.0, 10);
+        assert_eq!(first.2, Some(11_000));
+        assert_eq!(run(), first);
+    }
+
+    /// Wants a step every `period` T-states once written, `period` is the value written to
+    /// port 0x20.
+    struct Timer {
+        period: Option<usize>,
+        due: Option<usize>,
+        steps: Arc<std::sync::Mutex<Vec<(usize, usize)>>>,
+    }
+
+    impl IODevice for Timer {
+        fn ports(&self) -> Vec<u8> {
+            vec![0x20]
+        }
+        fn read(&self, _port: u8) -> Result<u8, &'static str> {
+            Ok(0)
+        }
+        fn write(&mut self, _port: u8, data: u8) -> Result<(), &'static str> {
+            self.period = Some(data as usize);
+            self.due = None;
+            Ok(())
+        }
+        fn step(&mut self, elapsed: usize, cycle: usize) {
+            self.steps.lock().unwrap().push((elapsed, cycle));
+            self.due = self.period.map(|period| cycle + period);
+        }
+        fn next_step(&self) -> Option<usize> {
+            self.period.map(|_| self.due.unwrap_or(0))
+        }
+        fn rebase(&mut self, from: usize, to: usize) {
+            self.due = self.due.map(|due| (due + to).saturating_sub(from));
+        }
+        fn will_interrupt(&self) -> Option<InterruptType> {
+            None
+        }
+        fn ack_int(&mut self) -> Result<(), &'static str> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_device_steps() {
+        // LD A, 20; OUT (0x20), A; 20 NOPs; HALT
+        let mut program = vec![0x3E, 0x14, 0xD3, 0x20];
+        program.extend([0x00; 20]);
+        program.push(0x76);
+        let with_timer = || {
+            let mut emulator = emulator_with(&program);
+            let steps = Arc::new(std::sync::Mutex::new(Vec::new()));
+            emulator.io = IO::new();
+            let timer = Timer {
+                period: None,
+                due: None,
+                steps: steps.clone(),
+            };
+            emulator.io.add_device(Box::new(timer)).unwrap();
+            (emulator, steps)
+        };
+        let (mut emulator, steps) = with_timer();
+        let result = emulator.run_until(|_| false);
+        assert!(matches!(result.reason, StopReason::Halt));
+        // Stepped right after the write, then only when due
+        assert_eq!(
+            *steps.lock().unwrap(),
+            vec![(18, 18), (20, 38), (20, 58), (20, 78), (20, 98)]
+        );
+
+        // Resetting the counters keeps the device on its period
+        let (mut emulator, steps) = with_timer();
+        emulator.run_for_instructions(7);
+        assert_eq!(emulator.cycles, 38);
+        emulator.reset_counters();
+        emulator.run_until(|_| false);
+        assert_eq!(
+            *steps.lock().unwrap(),
+            vec![(18, 18), (20, 38), (20, 20), (20, 40), (20, 60)]
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        // LD A, 0x42; OUT (0x10), A; EI; LD B, 5; HALT
+        let program = [0x3E, 0x42, 0xD3, 0x10, 0xFB, 0x06, 0x05, 0x76];
+        let mut emulator = emulator_with(&program);
+        let result = emulator.run_until(|_| false);
+        assert!(matches!(result.reason, StopReason::Halt));
+        let data = emulator.save().unwrap();
+
+        let mut other = emulator_with(&[]);
+        other.scheduler.schedule(100, |_| None);
+        other.load(data.clone(), false, false).unwrap();
+        assert_eq!(other.io.read(0x10), Ok(0x42));
+        assert_eq!(
+            (other.cycles, other.instructions),
+            (emulator.cycles, emulator.instructions)
+        );
+        assert_eq!((other.io.iff1, other.io.iff2), (emulator.io.iff1, emulator.io.iff2));
+        assert!(other.io.iff1);
+        assert_eq!(other.cpu.registers.gp.b, 5);
+        assert!(other.cpu.halted());
+        assert_eq!(other.scheduler.next_event(), Some(emulator.cycles + 100));
+
+        let mut memory = Memory::new();
+        memory.add_device(Box::new(RAM::new(0x8000)));
+        memory.add_device(Box::new(RAM::new(0x8000)));
+        let mut split = Emulator::<Z80>::new_w_mem(memory);
+        let error = split.load(data.clone(), false, false).unwrap_err();
+        assert!(error.contains("memory map"), "{}", error);
+        let mut newer = data;
+        newer[4] += 1;
+        let error = other.load(newer, false, false).unwrap_err();
+        assert!(error.contains("version 2"), "{}", error);
+        assert!(other.load(vec![1, 2, 3], false, false).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_load_fails() {
+        let with_rom = || {
+            let mut memory = Memory::new();
+            memory.add_device(Box::new(ROM::new(0x4000)));
+            memory.add_device(Box::new(RAM::new(0xC000)));
+            Emulator::<Z80>::new_w_mem(memory)
+        };
+        let mut emulator = with_rom();
+        emulator.memory.write_8(0x8000, 0xAA).unwrap();
+        emulator.io.write(0x10, 0x42).unwrap();
+        emulator.cycles = 1000;
+        let data = emulator.save().unwrap();
+
+        let mut other = with_rom();
+        other.memory.write_8(0x8000, 0xBB).unwrap();
+        other.io.write(0x10, 0x24).unwrap();
+        other.cpu.registers.pc = 0x1234;
+        other.cycles = 100;
+        other.scheduler.schedule(150, |_| None);
+        // Writing the ROM needs force, nothing of the snapshot is applied
+        let error = other.load(data.clone(), false, false).unwrap_err();
+        assert!(error.contains("ReadOnly"), "{}", error);
+        assert_eq!(other.memory.read_8(0x8000).unwrap(), 0xBB);
+        assert_eq!(other.io.read(0x10), Ok(0x24));
+        assert_eq!(other.cpu.registers.pc, 0x1234);
+        assert_eq!(other.cycles, 100);
+        assert_eq!(other.scheduler.next_event(), Some(150));
+
+        other.load(data, false, true).unwrap();
+        assert_eq!(other.memory.read_8(0x8000).unwrap(), 0xAA);
+        assert_eq!(other.io.read(0x10), Ok(0x42));
+        assert_eq!(other.scheduler.next_event(), Some(1050));
+    }
+
+    #[test]
+    fn test_accesses_between_steps() {
+        let mut emulator = emulator_with(&[0x00, 0x00, 0x00]);
+        emulator.watchpoints.add(Watchpoint::write(0x8000, 0x8000));
+        emulator.io_breakpoints.add(IoBreakpoint::write(0xFE));
+        emulator.step().unwrap();
+        // A debugger poking memory and ports is not the next instruction's doing
+        emulator.memory.write_8(0x8000, 0x12).unwrap();
+        emulator.io.write(0xFE, 0x34).unwrap();
+        let result = emulator.run_for_instructions(1);
+        assert!(
+            matches!(result.reason, StopReason::InstructionLimit),
+            "{:?}",
+            result.reason
+        );
+    }
+
+    fn state(emulator: &Emulator<Z80>) -> (Vec<u8>, u8, usize, usize, bool) {
+        (
+            bincode::serialize(&emulator.cpu).unwrap(),
+            emulator.memory.peek_8(0x8000).unwrap(),
+            emulator.cycles,
+            emulator.instructions,
+            emulator.io.iff1,
+        )
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut emulator = rewind_emulator();
+        emulator.enable_rewind(5, usize::MAX);
+        let mut states = vec![state(&emulator)];
+        for _ in 0..60 {
+            emulator.step().unwrap();
+            states.push(state(&emulator));
+        }
+        assert!(emulator.cpu.registers.gp.b > 0);
+        for expected in states.iter().rev().skip(1) {
+            emulator.step_back().unwrap();
+            assert_eq!(&state(&emulator), expected);
+        }
+        assert!(emulator.step_back().is_err());
+        emulator.rewind_to(33).unwrap();
+        assert_eq!(state(&emulator), states[33]);
+        assert!(emulator.rewind_to(61).is_err());
+
+        // Running again forgets the recorded future
+        emulator.step().unwrap();
+        assert_eq!(emulator.rewind().unwrap().end(), 34);
+    }
+
+    #[test]
+    fn test_reverse_continue_and_last_write() {
+        let mut emulator = rewind_emulator();
+        emulator.enable_rewind(4, usize::MAX);
+        let mut states = vec![state(&emulator)];
+        for _ in 0..40 {
+            emulator.step().unwrap();
+            states.push(state(&emulator));
+        }
+        let last_write = (0..=40)
+            .rev()
+            .find(|idx| states[*idx].1 != states[idx - 1].1)
+            .unwrap();
+        let hit = emulator.run_back_to_write(0x8000).unwrap().unwrap();
+        assert_eq!(state(&emulator), states[last_write]);
+        assert_eq!((hit.pc, hit.new), (0x0003, states[last_write].1));
+
+        // The first instruction of the handler runs in the same step as the interrupt
+        let id = emulator.breakpoints.add_address(0x0039);
+        let ignored = emulator.breakpoints.add_address(0x0006);
+        emulator.breakpoints.get_mut(ignored).unwrap().enabled = false;
+        let result = emulator.reverse_continue();
+        assert!(matches!(result, Err(StopReason::Breakpoint(hit)) if hit == id));
+        assert_eq!(emulator.cpu.pc(), 0x0039);
+        let at_interrupt = emulator.instructions;
+        assert_eq!(state(&emulator), states[at_interrupt]);
+        assert!(matches!(emulator.reverse_continue(), Err(StopReason::Breakpoint(_))));
+        assert!(emulator.instructions < at_interrupt);
+        assert_eq!(emulator.breakpoints.get(id).unwrap().hit_count, 0);
+
+        emulator.breakpoints.clear();
+        assert!(emulator.reverse_continue().is_ok());
+        assert_eq!(state(&emulator), states[0]);
+    }
+
+    #[test]
+    fn test_rewind_budget() {
+        let mut emulator = rewind_emulator();
+        let snapshot_size = emulator.save().unwrap().len();
+        emulator.enable_rewind(10, snapshot_size * 3);
+        for _ in 0..100 {
+            emulator.step().unwrap();
+        }
+        let rewind = emulator.rewind().unwrap();
+        assert!(rewind.size() <= snapshot_size * 3);
+        assert_eq!(rewind.start(), Some(80));
+        assert!(emulator.rewind_to(79).is_err());
+        emulator.rewind_to(80).unwrap();
+    }
+
+    #[test]
+    fn test_trace() {
+        // LD HL, 0x8000; LD (HL), 0x55; INC HL; HALT
+        let mut emulator = emulator_with(&[0x21, 0x00, 0x80, 0x36, 0x55, 0x23, 0x76]);
+        let path = std::env::temp_dir().join(format!("emu_trace_{}.bin", std::process::id()));
+        emulator.start_trace_file(&path).unwrap();
+        while emulator.step().is_ok() {}
+        emulator.stop_trace().unwrap();
+        let records: Vec<TraceRecord> = crate::debug::trace::TraceReader::open(&path)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|record| (record.pc, record.register("hl").unwrap(), record.cycles))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0x0000, 0x8000, 10),
+                (0x0003, 0x8000, 20),
+                (0x0005, 0x8001, 26),
+                (0x0006, 0x8001, 30)
+            ]
+        );
+        assert_eq!(records[0].bytes, vec![0x21, 0x00, 0x80]);
+        assert_eq!(records[1].writes, vec![(0x8000, 0x55)]);
+    }
+
+    #[test]
+    fn test_profiler_rewind() {
+        // 0x00: LD SP, 0xFF00; CALL 0x10; HALT
+        // 0x10: NOP; RET
+        let mut program = vec![0u8; 0x12];
+        program[0x00..0x07].copy_from_slice(&[0x31, 0x00, 0xFF, 0xCD, 0x10, 0x00, 0x76]);
+        program[0x10..0x12].copy_from_slice(&[0x00, 0xC9]);
+        let mut emulator = emulator_with(&program);
+        emulator.enable_rewind(2, 1 << 20);
+        emulator.set_profiler(true);
+        for _ in 0..4 {
+            emulator.step().unwrap();
+        }
+        let total = emulator.profiler().unwrap().total();
+        let functions = emulator.profiler().unwrap().functions();
+        emulator.step_back().unwrap();
+        emulator.step_back().unwrap();
+        assert_eq!(emulator.instructions, 2);
+        // The replayed instructions were already counted
+        let profiler = emulator.profiler().unwrap();
+        assert_eq!(profiler.total(), total);
+        assert_eq!(profiler.functions(), functions);
+    }
+
+    #[test]
+    fn test_call_stack() {
+        // 0x00: LD SP, 0xFF00; CALL 0x10; HALT
+        // 0x10: PUSH BC; CALL 0x20; POP BC; RET
+        // 0x20: RST 0x28; RET
+        // 0x28: RET
+        let mut program = vec![0u8; 0x29];
+        program[0x00..0x07].copy_from_slice(&[0x31, 0x00, 0xFF, 0xCD, 0x10, 0x00, 0x76]);
+        program[0x10..0x16].copy_from_slice(&[0xC5, 0xCD, 0x20, 0x00, 0xC1, 0xC9]);
+        program[0x20..0x22].copy_from_slice(&[0xEF, 0xC9]);
+        program[0x28] = 0xC9;
+        let mut emulator = emulator_with(&program);
+        emulator.set_call_stack(true);
+        emulator.run_to_address(0x28).unwrap();
+        let backtrace: Vec<String> = emulator.backtrace().iter().map(ToString::to_string).collect();
+        assert_eq!(
+            backtrace,
+            vec![
+                "0028 in 0028 sp=FEF8",
+                "0020 in 0020 sp=FEF8",
+                "0011 in 0010 sp=FEFA",
+                "0003 sp=FEFE"
+            ]
+        );
+        let stack: Vec<(u16, bool)> = emulator
+            .annotate_stack(4)
+            .iter()
+            .map(|word| (word.value, word.is_return_address()))
+            .collect();
+        assert_eq!(
+            stack,
+            vec![(0x0021, true), (0x0014, true), (0x0000, false), (0x0006, true)]
+        );
+        emulator.step().unwrap();
+        assert_eq!(emulator.call_stack().unwrap().depth(), 2);
+        while emulator.step().is_ok() {}
+        assert_eq!(emulator.call_stack().unwrap().depth(), 0);
+
+        // Interrupts push a frame, reloading SP drops the frames above it
+        let mut emulator = rewind_emulator();
+        emulator.set_call_stack(true);
+        emulator.step_until(|emulator| emulator.cpu.pc() == 0x39).unwrap();
+        let frame = emulator.call_stack().unwrap().frames()[0];
+        assert_eq!((frame.kind, frame.target, frame.sp), (FrameKind::Interrupt, 0x38, 0xFEFE));
+        emulator.cpu.registers.gp.h = 0xFF;
+        emulator.cpu.registers.gp.l = 0x00;
+        emulator.memory.write_8(0x39, 0xF9).unwrap();
+        emulator.step().unwrap();
+        assert_eq!(emulator.call_stack().unwrap().depth(), 0);
     }
 }
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use super::InterruptType;
 
@@ -6,9 +6,27 @@ pub trait IODevice: Send + Sync {
     fn ports(&self) -> Vec<u8>;
     fn read(&self, port: u8) -> Result<u8, &'static str>;
     fn write(&mut self, pin: u8, data: u8) -> Result<(), &'static str>;
-    fn step(&mut self);
+    /// Brings the device to T-state `cycle`, `elapsed` T-states after its last step.
+    fn step(&mut self, elapsed: usize, cycle: usize);
+    /// T-state the device needs its next step at, `None` when it only changes through port
+    /// accesses. It is asked again after every step, port write and acknowledged
+    /// interrupt, by default the device is stepped after every instruction.
+    fn next_step(&self) -> Option<usize> {
+        Some(0)
+    }
+    /// Moves the T-states the device keeps for a cycle counter that jumps from `from` to `to`.
+    fn rebase(&mut self, _from: usize, _to: usize) {}
     fn will_interrupt(&self) -> Option<InterruptType>;
     fn ack_int(&mut self) -> Result<(), &'static str>;
+    /// State saved in snapshots, devices that keep nothing between port accesses can leave
+    /// it empty.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    /// Restores a state from [`IODevice::save_state`].
+    fn load_state(&mut self, _state: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
 }
 
 pub struct IORegister {
@@ -40,13 +58,32 @@ impl IODevice for IORegister {
         Ok(())
     }
 
-    fn step(&mut self) {}
+    fn step(&mut self, _elapsed: usize, _cycle: usize) {}
+    fn next_step(&self) -> Option<usize> {
+        None
+    }
     fn will_interrupt(&self) -> Option<InterruptType> {
         None
     }
     fn ack_int(&mut self) -> Result<(), &'static str> {
         Ok(())
     }
+    fn save_state(&self) -> Vec<u8> {
+        let registers: BTreeMap<u8, u8> = self.registers.iter().map(|(k, v)| (*k, *v)).collect();
+        // A map of bytes always has a size and bincode writes it to a Vec, neither can fail
+        bincode::serialize(&registers).expect("Serializing registers can not fail")
+    }
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let registers: BTreeMap<u8, u8> =
+            bincode::deserialize(state).map_err(|e| format!("{:?}", e))?;
+        for (port, value) in registers {
+            match self.registers.get_mut(&port) {
+                Some(register) => *register = value,
+                None => return Err(format!("Port {:#04X} is not mapped to this device", port)),
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Default for IORegister {
@@ -1,10 +1,14 @@
 use iodevice::IODevice;
 use iodevice::IORegister;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex, Weak};
 
+use crate::bus::{BusCycleKind, BusRecorder, IoDirection, IoRecorder};
+
 pub mod iodevice;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum InterruptType {
     NMI,
     // non-maskable interrupt
@@ -18,8 +22,23 @@ pub enum InterruptType {
 pub struct IO {
     pub port_map: HashMap<u8, Weak<Mutex<Box<dyn IODevice>>>>,
     devices: Vec<Arc<Mutex<Box<dyn IODevice>>>>,
+    /// T-state of the last step of each device
+    stepped: Vec<usize>,
+    /// Earliest T-state a device needs a step at
+    next_step: usize,
+    /// T-state of the last step
+    cycle: usize,
     pub iff1: bool,
     pub iff2: bool,
+    bus: Option<Arc<BusRecorder>>,
+    access: Option<Arc<IoRecorder>>,
+    replay: Option<Replay>,
+}
+
+/// Inputs given to the CPU instead of the devices while replaying recorded execution
+struct Replay {
+    reads: Mutex<VecDeque<u8>>,
+    interrupt: Option<InterruptType>,
 }
 
 impl Default for IO {
@@ -33,8 +52,14 @@ impl Default for IO {
         IO {
             port_map,
             devices: vec![registers],
+            stepped: vec![0],
+            next_step: 0,
+            cycle: 0,
             iff1: false,
             iff2: false,
+            bus: None,
+            access: None,
+            replay: None,
         }
     }
 }
@@ -44,8 +69,14 @@ impl IO {
         IO{
             port_map: HashMap::new(),
             devices: Vec::new(),
+            stepped: Vec::new(),
+            next_step: 0,
+            cycle: 0,
             iff1: false,
             iff2: false,
+            bus: None,
+            access: None,
+            replay: None,
         }
     }
     pub fn read(&self, port: u8) -> Result<u8, &str> {
@@ -60,38 +91,133 @@ impl IO {
         //     .lock()
         //     .expect("Failed to get IO lock")
         //     .read(port)
-        let device: Weak<Mutex<Box<dyn IODevice>>> = self
-            .port_map
-            .get(&port)
-            .ok_or("Attempting to read from unconnected port")?
-            .clone();
-        device
-            .upgrade()
-            .ok_or("Attempting to read from removed device")?
-            .lock()
-            .expect("Failed to get IO lock")
-            .read(port)
+        let data = match &self.replay {
+            Some(replay) => replay
+                .reads
+                .lock()
+                .expect("Failed to get replay lock")
+                .pop_front()
+                .ok_or("Replay has no more recorded port reads")?,
+            None => {
+                let device: Weak<Mutex<Box<dyn IODevice>>> = self
+                    .port_map
+                    .get(&port)
+                    .ok_or("Attempting to read from unconnected port")?
+                    .clone();
+                device
+                    .upgrade()
+                    .ok_or("Attempting to read from removed device")?
+                    .lock()
+                    .expect("Failed to get IO lock")
+                    .read(port)?
+            }
+        };
+        if let Some(bus) = &self.bus {
+            bus.record(BusCycleKind::IoRead, port as u16, data);
+        }
+        if let Some(access) = &self.access {
+            access.record(IoDirection::Read, port, data);
+        }
+        Ok(data)
     }
 
     pub fn write(&mut self, port: u8, data: u8) -> Result<(), &str> {
-        let device = self
-            .port_map
-            .get(&port)
-            .ok_or("Attempting to write to unconnected port")?;
-        device
-            .upgrade()
-            .ok_or("Attempting to write to removed device")?
-            .lock()
-            .expect("Failed to get IO lock")
-            .write(port, data)
+        if self.replay.is_none() {
+            let device = self
+                .port_map
+                .get(&port)
+                .ok_or("Attempting to write to unconnected port")?;
+            device
+                .upgrade()
+                .ok_or("Attempting to write to removed device")?
+                .lock()
+                .expect("Failed to get IO lock")
+                .write(port, data)?;
+            self.next_step = 0;
+        }
+        if let Some(bus) = &self.bus {
+            bus.record(BusCycleKind::IoWrite, port as u16, data);
+        }
+        if let Some(access) = &self.access {
+            access.record(IoDirection::Write, port, data);
+        }
+        Ok(())
+    }
+
+    /// Only the low byte of the address is known here, the CPU fills in the rest.
+    pub fn set_bus_recorder(&mut self, recorder: Option<Arc<BusRecorder>>) {
+        self.bus = recorder;
+    }
+
+    pub fn set_access_recorder(&mut self, recorder: Option<Arc<IoRecorder>>) {
+        self.access = recorder;
+    }
+
+    pub fn access_recorder(&self) -> Option<&Arc<IoRecorder>> {
+        self.access.as_ref()
+    }
+
+    /// Gives `reads` to the CPU in order instead of reading the devices, drops writes and stops
+    /// stepping devices until [`IO::stop_replay`]. Interrupts come from
+    /// [`IO::set_replay_interrupt`].
+    pub fn start_replay(&mut self, reads: impl IntoIterator<Item = u8>) {
+        self.replay = Some(Replay {
+            reads: Mutex::new(reads.into_iter().collect()),
+            interrupt: None,
+        });
     }
 
-    pub fn step(&mut self) {
-        for device in self.devices.iter() {
-            device.lock().expect("Failed to get IO lock").step();
+    /// Interrupt the CPU takes on its next step while replaying
+    pub fn set_replay_interrupt(&mut self, interrupt: Option<InterruptType>) {
+        if let Some(replay) = &mut self.replay {
+            replay.interrupt = interrupt;
         }
     }
 
+    pub fn stop_replay(&mut self) {
+        self.replay = None;
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    /// Steps the devices that need it at T-state `cycle`.
+    pub fn step(&mut self, cycle: usize) {
+        if self.replay.is_some() {
+            return;
+        }
+        self.cycle = cycle;
+        if cycle < self.next_step {
+            return;
+        }
+        let mut next_step = usize::MAX;
+        for (device, stepped) in self.devices.iter().zip(self.stepped.iter_mut()) {
+            let mut device = device.lock().expect("Failed to get IO lock");
+            if device.next_step().is_some_and(|next| next <= cycle) {
+                // Going back in the rewind history moves the cycle count backwards
+                device.step(cycle.saturating_sub(*stepped), cycle);
+                *stepped = cycle;
+            }
+            if let Some(next) = device.next_step() {
+                next_step = next_step.min(next);
+            }
+        }
+        self.next_step = next_step;
+    }
+
+    /// Moves the step times for a cycle counter that jumps from `from` to `to`, the devices
+    /// keep the time since their last step.
+    pub fn rebase(&mut self, from: usize, to: usize) {
+        let shift = |time: usize| (time + to).saturating_sub(from);
+        for (device, stepped) in self.devices.iter().zip(self.stepped.iter_mut()) {
+            device.lock().expect("Failed to get IO lock").rebase(from, to);
+            *stepped = shift(*stepped);
+        }
+        self.cycle = shift(self.cycle);
+        self.next_step = 0;
+    }
+
     pub fn add_device(&mut self, device: Box<dyn IODevice>) -> Result<(), &'static str> {
         let dev: Arc<Mutex<Box<dyn IODevice>>> = Arc::new(Mutex::new(device));
         let ports = dev.lock().expect("Failed to get IO lock").ports();
@@ -104,11 +230,56 @@ impl IO {
             self.port_map.insert(port, Arc::downgrade(&dev));
         }
         self.devices.push(dev);
+        self.stepped.push(self.cycle);
+        self.next_step = 0;
+        Ok(())
+    }
+
+    /// Ports of each device in the order they were added.
+    pub fn layout(&self) -> Vec<Vec<u8>> {
+        self.devices
+            .iter()
+            .map(|device| {
+                let mut ports = device.lock().expect("Failed to get IO lock").ports();
+                ports.sort_unstable();
+                ports
+            })
+            .collect()
+    }
+
+    pub fn device_states(&self) -> Vec<Vec<u8>> {
+        self.devices
+            .iter()
+            .map(|device| device.lock().expect("Failed to get IO lock").save_state())
+            .collect()
+    }
+
+    /// Restores the states of [`IO::device_states`], the devices have to be the same. They
+    /// continue from T-state `cycle`.
+    pub fn load_device_states(&mut self, states: &[Vec<u8>], cycle: usize) -> Result<(), String> {
+        if states.len() != self.devices.len() {
+            return Err(format!(
+                "{} IO device states for {} devices",
+                states.len(),
+                self.devices.len()
+            ));
+        }
+        for (idx, (device, state)) in self.devices.iter().zip(states).enumerate() {
+            device
+                .lock()
+                .expect("Failed to get IO lock")
+                .load_state(state)
+                .map_err(|e| format!("IO device {}: {}", idx, e))?;
+        }
+        self.stepped.fill(cycle);
+        self.cycle = cycle;
+        self.next_step = 0;
         Ok(())
     }
 
     pub fn remove_dev_by_id(&mut self, device_id: usize) -> Result<(), &str> {
         self.devices.remove(device_id);
+        self.stepped.remove(device_id);
         Ok(())
     }
 
@@ -121,6 +292,7 @@ impl IO {
         for (i, dev) in self.devices.iter().enumerate() {
             if Arc::ptr_eq(&device.upgrade().unwrap(), dev) {
                 self.devices.remove(i);
+                self.stepped.remove(i);
                 found = true;
                 break;
             }
@@ -133,6 +305,9 @@ impl IO {
     }
 
     pub fn get_interrupt(&self) -> Option<(InterruptType, usize)> {
+        if let Some(replay) = &self.replay {
+            return replay.interrupt.map(|interrupt| (interrupt, usize::MAX));
+        }
         let mut min_im = None;
         for (i, device) in self.devices.iter().enumerate() {
             match (
@@ -155,6 +330,11 @@ impl IO {
     }
 
     pub fn ack_int(&mut self, device_id: usize) -> Result<(), &str> {
+        if let Some(replay) = &mut self.replay {
+            replay.interrupt = None;
+            return Ok(());
+        }
+        self.next_step = 0;
         let devopt = self
             .devices
             .get_mut(device_id)
@@ -1,7 +1,10 @@
 #![feature(trait_upcasting)]
 #![allow(dead_code)]
 
+pub mod bus;
 pub mod cpu;
+pub mod debug;
 pub mod emulator;
 pub mod io;
 pub mod memory;
+pub mod scheduler;
@@ -55,10 +55,24 @@ pub enum MemoryRWError {
     MemRead(#[from] MemoryReadError),
 }
 
+#[derive(Debug, Error, Clone)]
+pub enum ImageError {
+    #[error("Invalid record at line {0}: {1}")]
+    InvalidRecord(usize, String),
+    #[error("Checksum mismatch at line {0}")]
+    ChecksumMismatch(usize),
+    #[error("Address out of the 16 bit range at line {0}: h:{1:x}")]
+    AddressOutOfRange(usize, u32),
+    #[error("Missing end of file record")]
+    MissingEof,
+}
+
 #[derive(Debug, Error, Clone)]
 pub enum MemorySaveLoadError {
     #[error("Memory error: {0}")]
     MemoryRWError(#[from] MemoryRWError),
     #[error("File error: {0}")]
     FileError(#[from] FileError),
+    #[error("Image error: {0}")]
+    ImageError(#[from] ImageError),
 }
@@ -0,0 +1,183 @@
+use std::fmt::Write;
+
+use crate::memory::errors::ImageError;
+use crate::memory::image::{check_range, parse_hex_bytes, MemoryImage};
+
+const RECORD_DATA: u8 = 0x00;
+const RECORD_EOF: u8 = 0x01;
+const RECORD_EXT_SEGMENT: u8 = 0x02;
+const RECORD_START_SEGMENT: u8 = 0x03;
+const RECORD_EXT_LINEAR: u8 = 0x04;
+const RECORD_START_LINEAR: u8 = 0x05;
+
+const BYTES_PER_RECORD: usize = 16;
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |acc, byte| acc.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+fn write_record(out: &mut String, record_type: u8, address: u16, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend_from_slice(data);
+    bytes.push(checksum(&bytes));
+    out.push(':');
+    for byte in bytes {
+        write!(out, "{:02X}", byte).expect("Writing to a String can not fail");
+    }
+    out.push('\n');
+}
+
+pub fn decode(text: &str) -> Result<MemoryImage, ImageError> {
+    let mut image = MemoryImage::new();
+    let mut base: u32 = 0;
+    for (idx, line) in text.lines().enumerate() {
+        let line_nr = idx + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = line.strip_prefix(':').ok_or(ImageError::InvalidRecord(
+            line_nr,
+            "Record does not start with ':'".to_string(),
+        ))?;
+        let bytes = parse_hex_bytes(line_nr, record)?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(ImageError::InvalidRecord(
+                line_nr,
+                "Record length does not match its byte count".to_string(),
+            ));
+        }
+        if bytes.iter().fold(0u8, |acc, byte| acc.wrapping_add(*byte)) != 0 {
+            return Err(ImageError::ChecksumMismatch(line_nr));
+        }
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]);
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            RECORD_DATA => {
+                let address = check_range(line_nr, base.saturating_add(offset as u32), data.len())?;
+                image.add_data(address, data);
+            }
+            RECORD_EOF => return Ok(image),
+            RECORD_EXT_SEGMENT if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
+            }
+            RECORD_EXT_LINEAR if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+            }
+            RECORD_START_SEGMENT if data.len() == 4 => {
+                let cs = u16::from_be_bytes([data[0], data[1]]) as u32;
+                let ip = u16::from_be_bytes([data[2], data[3]]) as u32;
+                let address = (cs << 4) + ip;
+                image.start_address = Some(
+                    u16::try_from(address)
+                        .map_err(|_| ImageError::AddressOutOfRange(line_nr, address))?,
+                );
+            }
+            RECORD_START_LINEAR if data.len() == 4 => {
+                let address = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                image.start_address = Some(
+                    u16::try_from(address)
+                        .map_err(|_| ImageError::AddressOutOfRange(line_nr, address))?,
+                );
+            }
+            record_type => {
+                return Err(ImageError::InvalidRecord(
+                    line_nr,
+                    format!("Unsupported record type: 0x{:02x}", record_type),
+                ))
+            }
+        }
+    }
+    Err(ImageError::MissingEof)
+}
+
+pub fn encode(image: &MemoryImage) -> String {
+    let mut out = String::new();
+    for segment in image.segments() {
+        for (idx, chunk) in segment.data.chunks(BYTES_PER_RECORD).enumerate() {
+            let address = segment
+                .address
+                .wrapping_add((idx * BYTES_PER_RECORD) as u16);
+            write_record(&mut out, RECORD_DATA, address, chunk);
+        }
+    }
+    if let Some(start) = image.start_address {
+        let start = start.to_be_bytes();
+        write_record(
+            &mut out,
+            RECORD_START_SEGMENT,
+            0,
+            &[0, 0, start[0], start[1]],
+        );
+    }
+    write_record(&mut out, RECORD_EOF, 0, &[]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_known_file() {
+        let text = ":0300300002337A1E\n\
+                    :10010000214601360121470136007EFE09D2190140\n\
+                    :00000001FF\n";
+        let image = decode(text).unwrap();
+        assert_eq!(image.read_8(0x30), Some(0x02));
+        assert_eq!(image.read_8(0x32), Some(0x7A));
+        assert_eq!(image.read_8(0x100), Some(0x21));
+        assert_eq!(image.read_8(0x10F), Some(0x01));
+        assert_eq!(image.read_8(0x110), None);
+    }
+
+    #[test]
+    fn test_encode_and_back() {
+        let mut image = MemoryImage::from_bytes(0x8000, &(0..40).collect::<Vec<u8>>());
+        image.add_data(0xFFFE, &[0xAA, 0xBB]);
+        image.start_address = Some(0x8000);
+        let text = encode(&image);
+        assert!(text.ends_with(":00000001FF\n"));
+        assert_eq!(decode(&text).unwrap(), image);
+    }
+
+    #[test]
+    fn test_decode_bad_checksum() {
+        let text = ":0300300002337A1F\n:00000001FF\n";
+        assert!(matches!(decode(text), Err(ImageError::ChecksumMismatch(1))));
+    }
+
+    #[test]
+    fn test_decode_out_of_range() {
+        let text = ":020000040001F9\n:0100000000FF\n:00000001FF\n";
+        assert!(matches!(
+            decode(text),
+            Err(ImageError::AddressOutOfRange(2, 0x10000))
+        ));
+    }
+
+    #[test]
+    fn test_decode_address_overflow() {
+        let text = ":02000004FFFFFC
+:02FFFF00AABB9B
+:00000001FF
+";
+        assert!(matches!(
+            decode(text),
+            Err(ImageError::AddressOutOfRange(2, 0xFFFFFFFF))
+        ));
+    }
+
+    #[test]
+    fn test_decode_non_ascii() {
+        assert!(matches!(
+            decode(":01\u{fffd}0\n"),
+            Err(ImageError::InvalidRecord(1, _))
+        ));
+    }
+}
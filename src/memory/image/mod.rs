@@ -0,0 +1,257 @@
+use std::path::Path;
+
+use crate::cpu::instruction::ExecutableInstruction;
+use crate::cpu::Cpu;
+use crate::memory::errors::ImageError;
+
+pub mod ihex;
+pub mod srec;
+
+/// Byte used to fill the gaps between segments when flattening an image to raw bytes,
+/// matches the erased state of an EPROM.
+pub const RAW_FILL: u8 = 0xFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Raw,
+    IntelHex,
+    SRecord,
+}
+
+impl ImageFormat {
+    /// Guesses the format from the file extension, anything unknown is treated as raw.
+    pub fn from_path(path: &Path) -> ImageFormat {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+        match extension.as_deref() {
+            Some("hex" | "ihx" | "ihex") => ImageFormat::IntelHex,
+            Some("srec" | "s19" | "s28" | "s37" | "mot") => ImageFormat::SRecord,
+            _ => ImageFormat::Raw,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageSegment {
+    pub address: u16,
+    pub data: Vec<u8>,
+}
+
+impl ImageSegment {
+    fn end(&self) -> usize {
+        self.address as usize + self.data.len()
+    }
+}
+
+/// Sparse memory contents, a list of non overlapping segments sorted by address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryImage {
+    segments: Vec<ImageSegment>,
+    pub start_address: Option<u16>,
+}
+
+impl MemoryImage {
+    pub fn new() -> MemoryImage {
+        MemoryImage::default()
+    }
+
+    pub fn from_bytes(address: u16, data: &[u8]) -> MemoryImage {
+        let mut image = MemoryImage::new();
+        image.add_data(address, data);
+        image
+    }
+
+    /// Places assembled instructions one after another starting at `address`.
+    pub fn from_instructions<T: Cpu + 'static>(
+        address: u16,
+        instructions: &[Box<dyn ExecutableInstruction<T>>],
+    ) -> MemoryImage {
+        let data: Vec<u8> = instructions.iter().flat_map(|ins| ins.to_bytes()).collect();
+        MemoryImage::from_bytes(address, &data)
+    }
+
+    pub fn segments(&self) -> &[ImageSegment] {
+        &self.segments
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.iter().all(|segment| segment.data.is_empty())
+    }
+
+    /// Adds data at the given address, overwriting overlapping bytes and merging adjacent segments.
+    /// Data running past 0xFFFF is truncated.
+    pub fn add_data(&mut self, address: u16, data: &[u8]) {
+        let len = data.len().min(0x10000 - address as usize);
+        if len == 0 {
+            return;
+        }
+        let start = address as usize;
+        let end = start + len;
+        let mut merged = ImageSegment {
+            address,
+            data: data[..len].to_vec(),
+        };
+        let mut remaining = Vec::with_capacity(self.segments.len() + 1);
+        for segment in self.segments.drain(..) {
+            if segment.end() < start || segment.address as usize > end {
+                remaining.push(segment);
+                continue;
+            }
+            // Touching or overlapping, old bytes survive only outside the new range
+            let new_start = merged.address.min(segment.address) as usize;
+            let new_end = merged.end().max(segment.end());
+            let mut bytes = vec![0; new_end - new_start];
+            for (idx, byte) in segment.data.iter().enumerate() {
+                bytes[segment.address as usize - new_start + idx] = *byte;
+            }
+            for (idx, byte) in merged.data.iter().enumerate() {
+                bytes[merged.address as usize - new_start + idx] = *byte;
+            }
+            merged = ImageSegment {
+                address: new_start as u16,
+                data: bytes,
+            };
+        }
+        remaining.push(merged);
+        remaining.sort_by_key(|segment| segment.address);
+        self.segments = remaining;
+    }
+
+    pub fn read_8(&self, addr: u16) -> Option<u8> {
+        self.segments
+            .iter()
+            .find(|segment| addr >= segment.address && (addr as usize) < segment.end())
+            .map(|segment| segment.data[(addr - segment.address) as usize])
+    }
+
+    /// Lowest address covered by the image and the flattened bytes up to the highest one,
+    /// gaps are filled with [`RAW_FILL`].
+    pub fn to_raw(&self) -> (u16, Vec<u8>) {
+        let (first, last) = match (self.segments.first(), self.segments.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return (0, Vec::new()),
+        };
+        let base = first.address as usize;
+        let mut data = vec![RAW_FILL; last.end() - base];
+        for segment in &self.segments {
+            let offset = segment.address as usize - base;
+            data[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        (first.address, data)
+    }
+
+    /// Parses an image, `base` is the load address used for raw data and ignored otherwise.
+    pub fn decode(format: ImageFormat, data: &[u8], base: u16) -> Result<MemoryImage, ImageError> {
+        match format {
+            ImageFormat::Raw => {
+                if base as usize + data.len() > 0x10000 {
+                    return Err(ImageError::AddressOutOfRange(
+                        0,
+                        (base as usize + data.len() - 1) as u32,
+                    ));
+                }
+                Ok(MemoryImage::from_bytes(base, data))
+            }
+            ImageFormat::IntelHex => ihex::decode(&String::from_utf8_lossy(data)),
+            ImageFormat::SRecord => srec::decode(&String::from_utf8_lossy(data)),
+        }
+    }
+
+    pub fn encode(&self, format: ImageFormat) -> Vec<u8> {
+        match format {
+            ImageFormat::Raw => self.to_raw().1,
+            ImageFormat::IntelHex => ihex::encode(self).into_bytes(),
+            ImageFormat::SRecord => srec::encode(self).into_bytes(),
+        }
+    }
+}
+
+/// Converts the hex digits of a record into bytes.
+pub(crate) fn parse_hex_bytes(line: usize, text: &str) -> Result<Vec<u8>, ImageError> {
+    if text.len() % 2 == 1 {
+        return Err(ImageError::InvalidRecord(
+            line,
+            "Odd number of hex digits".to_string(),
+        ));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|idx| {
+            let digits = text.get(idx..idx + 2).ok_or(ImageError::InvalidRecord(
+                line,
+                "Record contains non hex characters".to_string(),
+            ))?;
+            u8::from_str_radix(digits, 16)
+                .map_err(|e| ImageError::InvalidRecord(line, e.to_string()))
+        })
+        .collect()
+}
+
+/// Checks that `len` bytes at `address` fit in the 16 bit address space and returns the
+/// address.
+pub(crate) fn check_range(line: usize, address: u32, len: usize) -> Result<u16, ImageError> {
+    match address.checked_add(len as u32) {
+        Some(end) if end <= 0x10000 => Ok(address as u16),
+        _ => Err(ImageError::AddressOutOfRange(
+            line,
+            address.saturating_add(len.saturating_sub(1) as u32),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_data_merges_segments() {
+        let mut image = MemoryImage::new();
+        image.add_data(0x10, &[1, 2, 3]);
+        image.add_data(0x20, &[4]);
+        image.add_data(0x13, &[5, 6]);
+        image.add_data(0x11, &[7]);
+        assert_eq!(
+            image.segments(),
+            &[
+                ImageSegment {
+                    address: 0x10,
+                    data: vec![1, 7, 3, 5, 6]
+                },
+                ImageSegment {
+                    address: 0x20,
+                    data: vec![4]
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_instructions() {
+        use crate::cpu::instruction::InstructionParser;
+        use crate::cpu::z80::parser::Z80_PARSER;
+        use crate::cpu::z80::Z80;
+
+        let lines = vec!["ld a, 0x12".to_string(), "ret".to_string()];
+        let instructions = Z80_PARSER.ins_vec_from_asm_lines(&lines).unwrap();
+        let image = MemoryImage::from_instructions::<Z80>(0x8000, &instructions);
+        assert_eq!(image.to_raw(), (0x8000, vec![0x3E, 0x12, 0xC9]));
+    }
+
+    #[test]
+    fn test_to_raw_fills_gaps() {
+        let mut image = MemoryImage::from_bytes(0x8000, &[0xAA]);
+        image.add_data(0x8003, &[0xBB]);
+        assert_eq!(
+            image.to_raw(),
+            (0x8000, vec![0xAA, RAW_FILL, RAW_FILL, 0xBB])
+        );
+    }
+
+    #[test]
+    fn test_add_data_truncates_at_top() {
+        let image = MemoryImage::from_bytes(0xFFFE, &[1, 2, 3, 4]);
+        assert_eq!(image.to_raw(), (0xFFFE, vec![1, 2]));
+    }
+}
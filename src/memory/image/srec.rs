@@ -0,0 +1,160 @@
+use std::fmt::Write;
+
+use crate::memory::errors::ImageError;
+use crate::memory::image::{check_range, parse_hex_bytes, MemoryImage};
+
+const BYTES_PER_RECORD: usize = 32;
+const HEADER: &[u8] = b"emu_lib";
+
+fn checksum(bytes: &[u8]) -> u8 {
+    !bytes.iter().fold(0u8, |acc, byte| acc.wrapping_add(*byte))
+}
+
+fn write_record(out: &mut String, record_type: char, address: &[u8], data: &[u8]) {
+    let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+    bytes.extend_from_slice(address);
+    bytes.extend_from_slice(data);
+    bytes.push(checksum(&bytes));
+    out.push('S');
+    out.push(record_type);
+    for byte in bytes {
+        write!(out, "{:02X}", byte).expect("Writing to a String can not fail");
+    }
+    out.push('\n');
+}
+
+fn address_len(record_type: char) -> Option<usize> {
+    match record_type {
+        '0' | '1' | '5' | '9' => Some(2),
+        '2' | '6' | '8' => Some(3),
+        '3' | '7' => Some(4),
+        _ => None,
+    }
+}
+
+pub fn decode(text: &str) -> Result<MemoryImage, ImageError> {
+    let mut image = MemoryImage::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line_nr = idx + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut chars = line.chars();
+        let record_type = match (chars.next(), chars.next()) {
+            (Some('S'), Some(record_type)) => record_type,
+            _ => {
+                return Err(ImageError::InvalidRecord(
+                    line_nr,
+                    "Record does not start with 'S'".to_string(),
+                ))
+            }
+        };
+        let addr_len = address_len(record_type).ok_or(ImageError::InvalidRecord(
+            line_nr,
+            format!("Unsupported record type: S{}", record_type),
+        ))?;
+        let bytes = parse_hex_bytes(line_nr, &line[2..])?;
+        if bytes.len() < addr_len + 2 || bytes.len() != bytes[0] as usize + 1 {
+            return Err(ImageError::InvalidRecord(
+                line_nr,
+                "Record length does not match its byte count".to_string(),
+            ));
+        }
+        let (body, check) = bytes.split_at(bytes.len() - 1);
+        if checksum(body) != check[0] {
+            return Err(ImageError::ChecksumMismatch(line_nr));
+        }
+        let address = body[1..=addr_len]
+            .iter()
+            .fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+        let data = &body[addr_len + 1..];
+        match record_type {
+            '1' | '2' | '3' => {
+                image.add_data(check_range(line_nr, address, data.len())?, data);
+            }
+            '7' | '8' | '9' => {
+                image.start_address = Some(
+                    u16::try_from(address)
+                        .map_err(|_| ImageError::AddressOutOfRange(line_nr, address))?,
+                );
+                return Ok(image);
+            }
+            // Header and record counts carry nothing to load
+            _ => {}
+        }
+    }
+    Ok(image)
+}
+
+pub fn encode(image: &MemoryImage) -> String {
+    let mut out = String::new();
+    write_record(&mut out, '0', &[0, 0], HEADER);
+    let mut count: usize = 0;
+    for segment in image.segments() {
+        for (idx, chunk) in segment.data.chunks(BYTES_PER_RECORD).enumerate() {
+            let address = segment
+                .address
+                .wrapping_add((idx * BYTES_PER_RECORD) as u16);
+            write_record(&mut out, '1', &address.to_be_bytes(), chunk);
+            count += 1;
+        }
+    }
+    if count <= 0xFFFF {
+        write_record(&mut out, '5', &(count as u16).to_be_bytes(), &[]);
+    }
+    let start = image.start_address.unwrap_or(0);
+    write_record(&mut out, '9', &start.to_be_bytes(), &[]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_known_file() {
+        let text = "S00F000068656C6C6F202020202000003C\n\
+                    S11F00007C0802A6900100049421FFF07C6C1B787C8C23783C6000003863000026\n\
+                    S5030001FB\n\
+                    S9030000FC\n";
+        let image = decode(text).unwrap();
+        assert_eq!(image.read_8(0x0000), Some(0x7C));
+        assert_eq!(image.read_8(0x001B), Some(0x00));
+        assert_eq!(image.read_8(0x001C), None);
+        assert_eq!(image.start_address, Some(0));
+    }
+
+    #[test]
+    fn test_encode_and_back() {
+        let mut image = MemoryImage::from_bytes(0x4000, &(0..100).collect::<Vec<u8>>());
+        image.add_data(0x0038, &[0xC9]);
+        image.start_address = Some(0x4000);
+        let text = encode(&image);
+        assert!(text.starts_with("S0"));
+        assert!(text.ends_with("S9034000BC\n"));
+        assert_eq!(decode(&text).unwrap(), image);
+    }
+
+    #[test]
+    fn test_decode_bad_checksum() {
+        let text = "S1040000C936\n";
+        assert!(matches!(decode(text), Err(ImageError::ChecksumMismatch(1))));
+    }
+
+    #[test]
+    fn test_decode_24bit_address() {
+        let text = "S2050010000AE0\nS804000000FB\n";
+        let image = decode(text).unwrap();
+        assert_eq!(image.read_8(0x1000), Some(0x0A));
+    }
+
+    #[test]
+    fn test_decode_address_overflow() {
+        let text = "S307FFFFFFFFAABB97\n";
+        assert!(matches!(
+            decode(text),
+            Err(ImageError::AddressOutOfRange(1, 0xFFFFFFFF))
+        ));
+    }
+}
@@ -2,13 +2,17 @@ use std::fs;
 use std::fs::File;
 use std::io::{BufReader, Read};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use bincode::Options;
+use crate::bus::{AccessRecorder, BusCycleKind, BusRecorder, MemoryAccessKind};
 use crate::memory::errors::{
     MemoryRWCommonError, MemoryReadError, MemorySaveLoadError, MemoryWriteError,
 };
 use errors::{FileError, MemoryRWError};
+use image::{ImageFormat, MemoryImage};
 
 pub mod errors;
+pub mod image;
 pub mod memdevices;
 
 pub struct Memory {
@@ -16,11 +20,18 @@ pub struct Memory {
     changes: Option<Vec<u16>>,
     readcallback: Option<fn(u16, u8)>,
     writecallback: Option<fn(u16, u8)>,
+    bus: Option<Arc<BusRecorder>>,
+    access: Option<Arc<AccessRecorder>>,
 }
 
 pub trait MemoryDevice: Send + Sync {
     fn size(&self) -> usize;
     fn read_8(&self, addr: u16) -> Result<u8, MemoryReadError>;
+    /// Reads a byte without the side effects of [`MemoryDevice::read_8`], for devices that
+    /// have any.
+    fn peek_8(&self, addr: u16) -> Result<u8, MemoryReadError> {
+        self.read_8(addr)
+    }
     fn read_16(&self, addr: u16) -> Result<u16, MemoryReadError> {
         let lsb = self.read_8(addr)?;
         let msb = self.read_8(addr.wrapping_add(1))?;
@@ -48,6 +59,15 @@ pub trait MemoryDevice: Send + Sync {
         }
         Ok(())
     }
+
+    /// State saved in snapshots besides the contents, such as bank registers.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    /// Restores a state from [`MemoryDevice::save_state`].
+    fn load_state(&mut self, _state: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
 }
 
 impl Memory {
@@ -57,6 +77,8 @@ impl Memory {
             changes: None,
             writecallback: None,
             readcallback: None,
+            bus: None,
+            access: None,
         }
     }
 
@@ -104,6 +126,29 @@ impl Memory {
         self.readcallback = callback;
     }
 
+    pub fn set_bus_recorder(&mut self, recorder: Option<Arc<BusRecorder>>) {
+        self.bus = recorder;
+    }
+
+    pub fn bus_recorder(&self) -> Option<&Arc<BusRecorder>> {
+        self.bus.as_ref()
+    }
+
+    pub fn set_access_recorder(&mut self, recorder: Option<Arc<AccessRecorder>>) {
+        self.access = recorder;
+    }
+
+    pub fn access_recorder(&self) -> Option<&Arc<AccessRecorder>> {
+        self.access.as_ref()
+    }
+
+    /// Reads a byte without calling the read callback or recording a bus cycle, for debuggers
+    /// looking at memory without disturbing the machine.
+    pub fn peek_8(&self, addr: u16) -> Result<u8, MemoryReadError> {
+        let (device_idx, offset) = self.get_elem_idx(addr)?;
+        self.data[device_idx].peek_8(offset as u16)
+    }
+
     fn get_elem_idx(&self, addr: u16) -> Result<(usize, usize), MemoryRWCommonError> {
         let mut offset = 0;
         for (index, device) in self.data.iter().enumerate() {
@@ -117,6 +162,32 @@ impl Memory {
         Err(MemoryRWCommonError::UnmappedAddress(addr))
     }
 
+    /// Size of each device, in address order.
+    pub fn layout(&self) -> Vec<usize> {
+        self.data.iter().map(|device| device.size()).collect()
+    }
+
+    pub fn device_states(&self) -> Vec<Vec<u8>> {
+        self.data.iter().map(|device| device.save_state()).collect()
+    }
+
+    /// Restores the states of [`Memory::device_states`], the devices have to be the same.
+    pub fn load_device_states(&mut self, states: &[Vec<u8>]) -> Result<(), String> {
+        if states.len() != self.data.len() {
+            return Err(format!(
+                "{} memory device states for {} devices",
+                states.len(),
+                self.data.len()
+            ));
+        }
+        for (idx, (device, state)) in self.data.iter_mut().zip(states).enumerate() {
+            device
+                .load_state(state)
+                .map_err(|e| format!("Memory device {}: {}", idx, e))?;
+        }
+        Ok(())
+    }
+
     pub fn save(&self) -> Result<Vec<u8>, MemoryReadError> {
         let mut data = Vec::new();
         for device in &self.data {
@@ -200,6 +271,83 @@ impl Memory {
             Err(_) => Err(vec![FileError::FileCreate(filename.to_path_buf()).into()]),
         }
     }
+
+    pub fn load_image(
+        &mut self,
+        image: &MemoryImage,
+        force: bool,
+    ) -> Result<(), Vec<MemoryWriteError>> {
+        let mut result: Vec<MemoryWriteError> = vec![];
+        for segment in image.segments() {
+            for (idx, byte) in segment.data.iter().enumerate() {
+                let addr = segment.address.wrapping_add(idx as u16);
+                let res = match force {
+                    true => self.write_8_force(addr, *byte),
+                    false => self.write_8(addr, *byte),
+                };
+                if let Err(err) = res {
+                    result.push(err);
+                }
+            }
+        }
+        if result.is_empty() {
+            Ok(())
+        } else {
+            Err(result)
+        }
+    }
+
+    /// Reads the inclusive address range `start..=end` into an image.
+    pub fn dump_image(&self, start: u16, end: u16) -> Result<MemoryImage, MemoryReadError> {
+        let mut data = Vec::with_capacity((end as usize + 1).saturating_sub(start as usize));
+        for addr in start..=end {
+            data.push(self.peek_8(addr)?);
+        }
+        Ok(MemoryImage::from_bytes(start, &data))
+    }
+
+    /// Loads an image file, `base` is the load address for raw files.
+    pub fn load_image_file(
+        &mut self,
+        filename: &Path,
+        format: ImageFormat,
+        base: u16,
+        force: bool,
+    ) -> Result<MemoryImage, Vec<MemorySaveLoadError>> {
+        if fs::metadata(filename).is_err() {
+            return Err(vec![
+                FileError::FileDoesNotExist(filename.to_path_buf()).into()
+            ]);
+        }
+        let data = fs::read(filename)
+            .map_err(|_| vec![FileError::FileCreate(filename.to_path_buf()).into()])?;
+        let image = MemoryImage::decode(format, &data, base).map_err(|e| vec![e.into()])?;
+        self.load_image(&image, force).map_err(|e| {
+            e.iter()
+                .map(|e| MemoryRWError::MemWrite(e.clone()).into())
+                .collect::<Vec<MemorySaveLoadError>>()
+        })?;
+        Ok(image)
+    }
+
+    /// Saves the inclusive address range `start..=end` to a file in the given format.
+    pub fn save_image_file(
+        &self,
+        filename: PathBuf,
+        format: ImageFormat,
+        start: u16,
+        end: u16,
+    ) -> Result<(), MemorySaveLoadError> {
+        if fs::metadata(&filename).is_ok() {
+            return Err(FileError::FileExists(filename).into());
+        }
+        let image = self
+            .dump_image(start, end)
+            .map_err(MemoryRWError::MemRead)?;
+        fs::write(&filename, image.encode(format))
+            .map_err(|_| FileError::FileCreate(filename))?;
+        Ok(())
+    }
 }
 
 impl Default for Memory {
@@ -221,14 +369,33 @@ impl MemoryDevice for Memory {
         if let Some(callback) = &self.readcallback {
             callback(addr, data);
         }
+        if let Some(bus) = &self.bus {
+            bus.record(BusCycleKind::MemoryRead, addr, data);
+        }
+        if let Some(access) = &self.access {
+            access.record(MemoryAccessKind::Read, addr, data, data);
+        }
         Ok(data)
     }
+    fn peek_8(&self, addr: u16) -> Result<u8, MemoryReadError> {
+        Memory::peek_8(self, addr)
+    }
     fn write_8(&mut self, addr: u16, data: u8) -> Result<(), MemoryWriteError> {
         let (device_idx, offset) = self.get_elem_idx(addr)?;
+        let old = match &self.access {
+            Some(_) => self.data[device_idx].read_8(offset as u16).ok(),
+            None => None,
+        };
         self.data[device_idx].write_8(offset as u16, data)?;
+        if let (Some(access), Some(old)) = (&self.access, old) {
+            access.record(MemoryAccessKind::Write, addr, old, data);
+        }
         if let Some(callback) = &self.writecallback {
             callback(addr, data);
         }
+        if let Some(bus) = &self.bus {
+            bus.record(BusCycleKind::MemoryWrite, addr, data);
+        }
         if let Some(changes) = &mut self.changes {
             changes.push(addr);
         }
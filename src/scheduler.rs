@@ -0,0 +1,154 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Called when an event is due with the T-state it was scheduled for, returns when it is due
+/// again, if at all.
+pub type EventCallback = Box<dyn FnMut(usize) -> Option<usize> + Send>;
+
+/// Events at absolute T-states of emulated time, fired by the emulator once the instruction
+/// that reaches them is executed. Runs are the same cycle for cycle however fast the host is.
+#[derive(Default)]
+pub struct Scheduler {
+    next_id: usize,
+    /// Pending events by time and id, so events due at the same T-state fire in the order
+    /// they were scheduled
+    queue: BTreeMap<(usize, usize), EventCallback>,
+    times: HashMap<usize, usize>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::default()
+    }
+
+    /// Schedules `callback` at T-state `time` and returns the id of the event, which stays the
+    /// same when the callback asks to be called again.
+    pub fn schedule(
+        &mut self,
+        time: usize,
+        callback: impl FnMut(usize) -> Option<usize> + Send + 'static,
+    ) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.insert(id, time, Box::new(callback));
+        id
+    }
+
+    /// Schedules `callback` every `period` T-states, starting at `first`.
+    pub fn schedule_periodic(
+        &mut self,
+        first: usize,
+        period: usize,
+        mut callback: impl FnMut(usize) + Send + 'static,
+    ) -> usize {
+        self.schedule(first, move |time| {
+            callback(time);
+            Some(time + period)
+        })
+    }
+
+    fn insert(&mut self, id: usize, time: usize, callback: EventCallback) {
+        self.queue.insert((time, id), callback);
+        self.times.insert(id, time);
+    }
+
+    pub fn cancel(&mut self, id: usize) -> bool {
+        match self.times.remove(&id) {
+            Some(time) => self.queue.remove(&(time, id)).is_some(),
+            None => false,
+        }
+    }
+
+    /// T-state the event is due at.
+    pub fn time(&self, id: usize) -> Option<usize> {
+        self.times.get(&id).copied()
+    }
+
+    /// T-state the earliest event is due at.
+    pub fn next_event(&self) -> Option<usize> {
+        self.queue.keys().next().map(|(time, _)| *time)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.times.clear();
+    }
+
+    /// Fires the events due at or before `now` in time order and returns how many fired. An
+    /// event asking to be called again at or before its own time is moved to the next T-state.
+    pub fn run_due(&mut self, now: usize) -> usize {
+        let mut fired = 0;
+        while let Some(entry) = self.queue.first_entry() {
+            let (time, id) = *entry.key();
+            if time > now {
+                break;
+            }
+            let mut callback = entry.remove();
+            self.times.remove(&id);
+            fired += 1;
+            if let Some(next) = callback(time) {
+                self.insert(id, next.max(time + 1), callback);
+            }
+        }
+        fired
+    }
+
+    /// Moves the events for a cycle counter that jumps from `from` to `to`, they stay as many
+    /// T-states away as they were. Events that were due are due at `to`.
+    pub fn rebase(&mut self, from: usize, to: usize) {
+        let queue = std::mem::take(&mut self.queue);
+        self.times.clear();
+        for ((time, id), callback) in queue {
+            self.insert(id, to + time.saturating_sub(from), callback);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_scheduler() {
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let mut scheduler = Scheduler::new();
+        let log = fired.clone();
+        let frame = scheduler.schedule_periodic(100, 100, move |time| {
+            log.lock().unwrap().push(("frame", time))
+        });
+        let log = fired.clone();
+        let once = scheduler.schedule(100, move |time| {
+            log.lock().unwrap().push(("once", time));
+            None
+        });
+        let log = fired.clone();
+        let cancelled = scheduler.schedule(150, move |time| {
+            log.lock().unwrap().push(("cancelled", time));
+            None
+        });
+        assert_eq!(scheduler.next_event(), Some(100));
+        assert_eq!(scheduler.run_due(99), 0);
+        assert!(scheduler.cancel(cancelled));
+        assert!(!scheduler.cancel(once + 10));
+        assert_eq!(scheduler.run_due(250), 3);
+        assert_eq!(
+            *fired.lock().unwrap(),
+            vec![("frame", 100), ("once", 100), ("frame", 200)]
+        );
+        assert_eq!(scheduler.time(frame), Some(300));
+        assert_eq!(scheduler.time(once), None);
+        scheduler.rebase(250, 0);
+        assert_eq!(scheduler.next_event(), Some(50));
+        scheduler.rebase(0, 1000);
+        assert_eq!(scheduler.time(frame), Some(1050));
+        assert_eq!(scheduler.len(), 1);
+    }
+}